@@ -76,6 +76,13 @@ fn run_bench(
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        priority: None,
+        user: None,
+        timeout: None,
+        return_xlora_scalings: None,
+        xlora_scalings: None,
+        session_id: None,
+        truncation_strategy: None,
     });
 
     let mut usages = Vec::new();
@@ -238,6 +245,13 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        priority: None,
+        user: None,
+        timeout: None,
+        return_xlora_scalings: None,
+        xlora_scalings: None,
+        session_id: None,
+        truncation_strategy: None,
     });
 
     sender
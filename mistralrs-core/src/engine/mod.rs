@@ -1,34 +1,43 @@
+use either::Either;
+use futures::FutureExt;
+use indexmap::IndexMap;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet},
+    panic::AssertUnwindSafe,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Mutex,
+};
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
-    pipeline::{AdapterInstruction, CacheInstruction},
-    request::NormalRequest,
-    response::CompletionChoice,
-    CompletionResponse, RequestMessage, Response, DEBUG,
+    pipeline::{AdapterInstruction, CacheInstruction, ContextShift, ModelKind},
+    request::{NormalRequest, TruncationStrategy},
+    response::{CompletionChoice, SYSTEM_FINGERPRINT},
+    CompletionResponse, MessageContent, RequestMessage, Response, DEBUG,
 };
-use candle_core::{Result, Tensor};
+use candle_core::{quantized::GgmlDType, Result, Tensor};
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
+use serde::Serialize;
 use tracing::{info, warn};
 
 use crate::{
-    get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    get_mut_arcmutex, handle_pipeline_forward_error, handle_pipeline_step_panic, handle_seq_error,
     pipeline::Pipeline,
-    prefix_cacher::PrefixCacheManager,
+    prefix_cacher::{PrefixCacheConfig, PrefixCacheManager, PrefixCacheStats},
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
     sampler::Sampler,
-    scheduler::{Scheduler, SchedulerMethod},
+    scheduler::{PriorityBacker, Scheduler, SchedulerMethod},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    xlora_models::check_xlora_scalings,
     Constraint, StopTokens,
 };
 
@@ -36,16 +45,221 @@ const SEED: u64 = 0;
 /// Terminate all sequences on the next scheduling step. Be sure to reset this.
 pub static TERMINATE_ALL_NEXT_STEP: AtomicBool = AtomicBool::new(false);
 
+/// Engine state shared with [`crate::MistralRs`] for health reporting. Updated every engine loop iteration.
+pub(crate) struct EngineState {
+    pub(crate) last_step: Instant,
+    pub(crate) is_idle: bool,
+    pub(crate) running_seqs: usize,
+    pub(crate) waiting_seqs: usize,
+    pub(crate) active_adapters: Option<Vec<String>>,
+    pub(crate) adapters: Vec<String>,
+    pub(crate) isq: Option<GgmlDType>,
+    pub(crate) prefix_cache: PrefixCacheStats,
+}
+
+impl EngineState {
+    pub(crate) fn new(isq: Option<GgmlDType>, adapters: Vec<String>) -> Self {
+        Self {
+            last_step: Instant::now(),
+            is_idle: true,
+            running_seqs: 0,
+            waiting_seqs: 0,
+            active_adapters: None,
+            adapters,
+            isq,
+            prefix_cache: PrefixCacheStats::default(),
+        }
+    }
+}
+
+/// A snapshot of the engine and model state.
+#[derive(Clone, Debug, Serialize)]
+pub struct EngineStatus {
+    pub model_id: String,
+    pub kind: String,
+    pub device: String,
+    pub isq: Option<String>,
+    pub active_adapters: Option<Vec<String>>,
+    /// Names of the LoRA adapters loaded into the model.
+    pub adapters: Vec<String>,
+    pub running_seqs: usize,
+    pub waiting_seqs: usize,
+    pub max_seqs: usize,
+    /// Whether the engine thread is still running.
+    pub is_alive: bool,
+    /// Whether the engine is waiting for requests.
+    pub is_idle: bool,
+    /// Time since the engine loop last made progress.
+    pub secs_since_last_step: f64,
+    pub is_shutting_down: bool,
+    pub prefix_cache: PrefixCacheStats,
+}
+
+/// The adapters to activate for a batch. If its sequences use different adapters, each row of the
+/// batch selects its own adapters. `row_adapters_active` tracks whether the layers hold per-row
+/// adapters which must be reset once the batch uses the same adapters again.
+fn adapter_instruction(
+    seqs: &[&mut Sequence],
+    row_adapters_active: &mut bool,
+) -> AdapterInstruction {
+    let adapters = seqs
+        .iter()
+        .map(|seq| seq.get_adapters())
+        .collect::<Vec<_>>();
+    let all_same = adapters.iter().all(|a| *a == adapters[0]);
+    if all_same && !*row_adapters_active {
+        return adapters[0]
+            .clone()
+            .map(AdapterInstruction::Activate)
+            .unwrap_or(AdapterInstruction::None);
+    }
+    *row_adapters_active = !all_same;
+    AdapterInstruction::ActivateRows(adapters)
+}
+
+/// The names of the adapters the layers activate for a batch, which are those of its sequences if
+/// they all use the same adapters. A batch whose rows use different adapters does not change the
+/// active adapters.
+fn batch_adapter_names(seqs: &[&mut Sequence]) -> Option<Vec<String>> {
+    let adapters = seqs[0].get_adapters()?;
+    if seqs
+        .iter()
+        .any(|seq| seq.get_adapters().as_ref() != Some(&adapters))
+    {
+        return None;
+    }
+    Some(adapters.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Group the messages of a chat, except the system messages and the last message, into turns of
+/// message indices. A turn starts at a user message.
+fn chat_turns(messages: &[IndexMap<String, MessageContent>]) -> Vec<Vec<usize>> {
+    let has_role = |message: &IndexMap<String, MessageContent>, role: &str| matches!(message.get("role"), Some(Either::Left(r)) if r == role);
+    let mut turns: Vec<Vec<usize>> = Vec::new();
+    for (i, message) in messages[..messages.len().saturating_sub(1)]
+        .iter()
+        .enumerate()
+    {
+        if has_role(message, "system") {
+            continue;
+        }
+        match turns.last_mut() {
+            Some(turn) if !has_role(message, "user") => turn.push(i),
+            _ => turns.push(vec![i]),
+        }
+    }
+    turns
+}
+
+/// Drop the fewest `turns`, the oldest first, for the prompt rendered by `render` without the
+/// dropped messages to have at most `max_len` tokens. The first turn is kept with
+/// [`TruncationStrategy::Middle`]. Returns the prompt and the number of dropped messages, or
+/// `None` if the prompt does not fit.
+fn drop_turns(
+    turns: &[Vec<usize>],
+    strategy: TruncationStrategy,
+    max_len: usize,
+    mut render: impl FnMut(&HashSet<usize>) -> anyhow::Result<Vec<u32>>,
+) -> anyhow::Result<Option<(Vec<u32>, usize)>> {
+    let first = match strategy {
+        TruncationStrategy::Middle => 1,
+        TruncationStrategy::Oldest | TruncationStrategy::Tokens => 0,
+    };
+    let mut render = |n_turns: usize| {
+        let dropped = turns[first..first + n_turns]
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<HashSet<_>>();
+        anyhow::Ok((render(&dropped)?, dropped.len()))
+    };
+
+    // The prompt only gets shorter as more turns are dropped, so search for the fewest turns.
+    let n_droppable = turns.len().saturating_sub(first);
+    if n_droppable == 0 {
+        return Ok(None);
+    }
+    let mut best = render(n_droppable)?;
+    if best.0.len() > max_len {
+        return Ok(None);
+    }
+    let (mut lo, mut hi) = (1, n_droppable);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        let candidate = render(mid)?;
+        if candidate.0.len() <= max_len {
+            hi = mid;
+            best = candidate;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Ok(Some(best))
+}
+
+/// Drop whole turns of a chat until its rendered prompt has at most `max_len` tokens, see
+/// [`chat_turns`] and [`drop_turns`].
+fn truncate_chat(
+    pipeline: &dyn Pipeline,
+    messages: &[IndexMap<String, MessageContent>],
+    strategy: TruncationStrategy,
+    max_len: usize,
+) -> anyhow::Result<Option<(Vec<u32>, usize)>> {
+    drop_turns(&chat_turns(messages), strategy, max_len, |dropped| {
+        let kept = messages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !dropped.contains(i))
+            .map(|(_, message)| message.clone())
+            .collect();
+        pipeline.get_processor().process(pipeline, kept, true)
+    })
+}
+
+/// Shift the context of the sequences whose KV cache reached the maximum sequence length. Returns
+/// whether any sequence was shifted, in which case the model cache must be cloned in again.
+fn shift_context(
+    pipeline: &mut dyn Pipeline,
+    context_shift: ContextShift,
+    seqs: &mut [&mut Sequence],
+) -> Result<bool> {
+    let max_seq_len = pipeline.get_metadata().max_seq_len;
+    let ContextShift {
+        sink_tokens,
+        window,
+    } = context_shift;
+    let window = window.expect("The context shift window is resolved in `Engine::new`.");
+    let mut shifted = false;
+    for seq in seqs.iter_mut() {
+        // The last token has not been run yet.
+        let n_positions = seq.len() - 1 - seq.shifted_tokens();
+        if n_positions < max_seq_len {
+            continue;
+        }
+        let n_discard = n_positions - sink_tokens - window;
+        pipeline.shift_context(seq, n_positions, sink_tokens, n_discard)?;
+        seq.add_shifted_tokens(n_discard);
+        shifted = true;
+    }
+    Ok(shifted)
+}
+
 pub struct Engine {
     rx: Receiver<Request>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
-    scheduler: Scheduler<VecDeque<Sequence>>,
+    scheduler: Scheduler<PriorityBacker>,
     id: usize,
     truncate_sequence: bool,
     no_kv_cache: bool,
     prefix_cacher: PrefixCacheManager,
     is_debug: bool,
     disable_eos_stop: bool,
+    shutdown_deadline: Option<Instant>,
+    state: Arc<std::sync::RwLock<EngineState>>,
+    /// Whether the LoRA layers hold per-row adapters from the last batch.
+    row_adapters_active: bool,
+    /// With the window resolved.
+    context_shift: Option<ContextShift>,
 }
 
 impl Engine {
@@ -57,26 +271,70 @@ impl Engine {
         truncate_sequence: bool,
         no_kv_cache: bool,
         no_prefix_cache: bool,
-        prefix_cache_n: usize,
+        prefix_cache_config: PrefixCacheConfig,
         disable_eos_stop: bool,
+        fair_queuing: Option<HashMap<String, f64>>,
+        context_shift: Option<ContextShift>,
+        state: Arc<std::sync::RwLock<EngineState>>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
-        let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
+        let row_adapters = get_mut_arcmutex!(pipeline).get_metadata().row_adapters;
+        let mut prefix_cacher =
+            PrefixCacheManager::new(device, prefix_cache_config, no_prefix_cache);
+        {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            let dtype = pipeline.get_metadata().activation_dtype;
+            let quant = pipeline.kv_cache_quant();
+            if let Err(e) = prefix_cacher.open_disk(&pipeline.name(), dtype, quant) {
+                warn!("Not using the prefix cache disk directory: {e}");
+            }
+        }
+        let context_shift = context_shift.and_then(|context_shift| {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            let max_seq_len = pipeline.get_metadata().max_seq_len;
+            if !pipeline.supports_context_shift() {
+                warn!("Context shifting is not supported for this model, disabling it.");
+                return None;
+            }
+            if no_kv_cache {
+                warn!("Context shifting requires the KV cache, disabling it.");
+                return None;
+            }
+            if context_shift.sink_tokens + 1 >= max_seq_len {
+                warn!(
+                    "{} sink tokens do not fit in the maximum sequence length of {max_seq_len}, disabling context shifting.",
+                    context_shift.sink_tokens
+                );
+                return None;
+            }
+            // At least one position is discarded by each shift.
+            let window = context_shift
+                .window
+                .unwrap_or(max_seq_len / 2)
+                .min(max_seq_len - context_shift.sink_tokens - 1);
+            info!(
+                "Shifting the context with {} sink tokens and a window of {window} tokens.",
+                context_shift.sink_tokens
+            );
+            Some(ContextShift {
+                window: Some(window),
+                ..context_shift
+            })
+        });
         Self {
             rx,
             pipeline,
-            scheduler: Scheduler::new(method),
+            scheduler: Scheduler::new(method, fair_queuing, row_adapters),
             id: 0,
             truncate_sequence,
             no_kv_cache,
-            prefix_cacher: PrefixCacheManager::new(
-                device,
-                prefix_cache_n,
-                is_xlora,
-                no_prefix_cache,
-            ),
+            prefix_cacher,
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
+            shutdown_deadline: None,
+            state,
+            row_adapters_active: false,
+            context_shift,
         }
     }
 
@@ -87,30 +345,43 @@ impl Engine {
             while let Ok(request) = self.rx.try_recv() {
                 self.handle_request(request).await;
             }
+            self.handle_expired_waiting().await;
             let run_start = Instant::now();
             let mut scheduled = self.scheduler.schedule();
 
             if scheduled.completion.len() > 0 {
                 let current_completion_ids: Vec<usize> =
                     scheduled.completion.iter().map(|seq| *seq.id()).collect();
+                if let Some(adapters) = batch_adapter_names(&scheduled.completion) {
+                    self.state.write().unwrap().active_adapters = Some(adapters);
+                }
+                let adapter_inst =
+                    adapter_instruction(&scheduled.completion, &mut self.row_adapters_active);
+                let shifted = match self.context_shift {
+                    Some(context_shift) => shift_context(
+                        &mut *get_mut_arcmutex!(self.pipeline),
+                        context_shift,
+                        &mut scheduled.completion,
+                    ),
+                    None => Ok(false),
+                };
+                let shifted = handle_pipeline_forward_error!(
+                    "context shift",
+                    shifted,
+                    &mut scheduled.completion,
+                    self.pipeline,
+                    'lp,
+                    self.prefix_cacher
+                );
                 let res = {
                     let mut pipeline = get_mut_arcmutex!(self.pipeline);
-                    let pre_op =
-                        if !self.no_kv_cache && last_completion_ids != current_completion_ids {
-                            CacheInstruction::In(
-                                scheduled.completion[0]
-                                    .get_adapters()
-                                    .map(AdapterInstruction::Activate)
-                                    .unwrap_or(AdapterInstruction::None),
-                            )
-                        } else {
-                            CacheInstruction::Nothing(
-                                scheduled.completion[0]
-                                    .get_adapters()
-                                    .map(AdapterInstruction::Activate)
-                                    .unwrap_or(AdapterInstruction::None),
-                            )
-                        };
+                    let pre_op = if !self.no_kv_cache
+                        && (shifted || last_completion_ids != current_completion_ids)
+                    {
+                        CacheInstruction::In(adapter_inst)
+                    } else {
+                        CacheInstruction::Nothing(adapter_inst)
+                    };
                     let post_op = if !self.no_kv_cache {
                         CacheInstruction::Out
                     } else {
@@ -120,19 +391,28 @@ impl Engine {
                         }
                     };
 
-                    pipeline
-                        .step(
-                            &mut scheduled.completion,
-                            false,
-                            &mut self.prefix_cacher,
-                            self.disable_eos_stop,
-                            rng.clone(),
-                            pre_op,
-                            post_op,
-                        )
-                        .await
+                    // Catch panics so that only this batch fails instead of the whole engine.
+                    AssertUnwindSafe(pipeline.step(
+                        &mut scheduled.completion,
+                        false,
+                        &mut self.prefix_cacher,
+                        self.disable_eos_stop,
+                        rng.clone(),
+                        pre_op,
+                        post_op,
+                    ))
+                    .catch_unwind()
+                    .await
                 };
 
+                let res = handle_pipeline_step_panic!(
+                    "completion step",
+                    res,
+                    &mut scheduled.completion,
+                    self.pipeline,
+                    'lp,
+                    self.prefix_cacher
+                );
                 handle_pipeline_forward_error!(
                     "completion step",
                     res,
@@ -146,6 +426,9 @@ impl Engine {
             }
 
             if scheduled.prompt.len() > 0 {
+                if let Some(adapters) = batch_adapter_names(&scheduled.prompt) {
+                    self.state.write().unwrap().active_adapters = Some(adapters);
+                }
                 let logits = {
                     let mut pipeline = get_mut_arcmutex!(self.pipeline);
 
@@ -158,29 +441,44 @@ impl Engine {
                             adapter_inst: AdapterInstruction::None,
                         }
                     };
-                    let adapter_inst = scheduled.prompt[0]
-                        .get_adapters()
-                        .map(AdapterInstruction::Activate)
-                        .unwrap_or(AdapterInstruction::None);
-
-                    // Reset non granular state because the old sequence must be dead.
-                    // Technically we don't need to do this but it is better to be safe.
-                    pipeline
-                        .step(
-                            &mut scheduled.prompt,
-                            true,
-                            &mut self.prefix_cacher,
-                            self.disable_eos_stop,
-                            rng.clone(),
-                            CacheInstruction::Reset {
-                                reset_non_granular: false,
-                                adapter_inst,
-                            },
-                            post_op,
-                        )
-                        .await
+                    let adapter_inst =
+                        adapter_instruction(&scheduled.prompt, &mut self.row_adapters_active);
+
+                    // Prompts prefilled from the prefix cache start from their cache. Sequences
+                    // are bucketed by the number of prefilled tokens, so the batch agrees.
+                    let pre_op = if scheduled.prompt[0].token_offset() > 0 {
+                        CacheInstruction::In(adapter_inst)
+                    } else {
+                        // Reset non granular state because the old sequence must be dead.
+                        // Technically we don't need to do this but it is better to be safe.
+                        CacheInstruction::Reset {
+                            reset_non_granular: false,
+                            adapter_inst,
+                        }
+                    };
+
+                    // Catch panics so that only this batch fails instead of the whole engine.
+                    AssertUnwindSafe(pipeline.step(
+                        &mut scheduled.prompt,
+                        true,
+                        &mut self.prefix_cacher,
+                        self.disable_eos_stop,
+                        rng.clone(),
+                        pre_op,
+                        post_op,
+                    ))
+                    .catch_unwind()
+                    .await
                 };
 
+                let logits = handle_pipeline_step_panic!(
+                    "prompt step",
+                    logits,
+                    &mut scheduled.prompt,
+                    self.pipeline,
+                    'lp,
+                    self.prefix_cacher
+                );
                 handle_pipeline_forward_error!(
                     "prompt step",
                     logits,
@@ -230,18 +528,99 @@ impl Engine {
                     );
                 }
             }
-            if scheduled.prompt.len() == 0
+            let is_idle = scheduled.prompt.len() == 0
                 && scheduled.completion.len() == 0
-                && self.scheduler.waiting_len() == 0
+                && self.scheduler.waiting_len() == 0;
             {
-                // If there is nothing to do, sleep until a request comes in
-                if let Some(request) = self.rx.recv().await {
+                let mut state = self.state.write().unwrap();
+                state.last_step = Instant::now();
+                state.is_idle = is_idle;
+                state.running_seqs = scheduled.prompt.len() + scheduled.completion.len();
+                state.waiting_seqs = self.scheduler.waiting_len();
+                state.prefix_cache = self.prefix_cacher.stats();
+            }
+            if is_idle {
+                if self.shutdown_deadline.is_some() {
+                    info!("All requests drained, stopping the engine.");
+                    if let Err(e) = self.prefix_cacher.persist_to_disk() {
+                        warn!("Failed to save the prefix cache to the disk: {e}");
+                    }
+                    break 'lp;
+                }
+                if let Err(e) = self.prefix_cacher.evict() {
+                    warn!("Failed to evict from the prefix cache: {e}");
+                }
+                // If there is nothing to do, sleep until a request comes in, or until the sessions
+                // are checked for expiry again.
+                let request = match self.prefix_cacher.session_ttl() {
+                    Some(ttl) => tokio::time::timeout(ttl, self.rx.recv())
+                        .await
+                        .unwrap_or(None),
+                    None => self.rx.recv().await,
+                };
+                if let Some(request) = request {
                     self.handle_request(request).await;
                 }
             }
         }
     }
 
+    /// Drop the waiting sequences whose deadline passed before they were scheduled, and
+    /// send one error response per request.
+    async fn handle_expired_waiting(&mut self) {
+        let expired = self.scheduler.remove_expired_waiting();
+        if expired.is_empty() {
+            return;
+        }
+        let model = get_mut_arcmutex!(self.pipeline).name();
+        let mut notified: Vec<Sender<Response>> = Vec::new();
+        for seq in expired {
+            let responder = seq.responder();
+            if notified.iter().any(|r| r.same_channel(&responder)) {
+                continue;
+            }
+            warn!(
+                "Sequence {} timed out before it could be scheduled.",
+                seq.id()
+            );
+            let msg = "Request timed out before it could be scheduled.".to_string();
+            let group = seq.get_mut_group();
+            let response = if group.is_chat {
+                Response::ModelError(
+                    msg,
+                    ChatCompletionResponse {
+                        id: seq.id().to_string(),
+                        choices: vec![],
+                        created: seq.creation_time(),
+                        model: model.clone(),
+                        system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                        object: "chat.completion".to_string(),
+                        usage: group.get_usage(),
+                        warnings: group.warnings.clone(),
+                    },
+                )
+            } else {
+                Response::CompletionModelError(
+                    msg,
+                    CompletionResponse {
+                        id: seq.id().to_string(),
+                        choices: vec![],
+                        created: seq.creation_time(),
+                        model: model.clone(),
+                        system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                        object: "text_completion".to_string(),
+                        usage: group.get_usage(),
+                        warnings: group.warnings.clone(),
+                    },
+                )
+            };
+            drop(group);
+            // The receiver may already be gone, in which case there is nobody to notify.
+            let _ = responder.send(response).await;
+            notified.push(responder);
+        }
+    }
+
     fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
         let recognizer = match constraint {
             Constraint::Regex(rx) => {
@@ -273,15 +652,108 @@ impl Engine {
     async fn handle_request(&mut self, request: Request) {
         match request {
             Request::ActivateAdapters(adapters) => {
-                match get_mut_arcmutex!(self.pipeline).activate_adapters(adapters) {
-                    Ok(n) => info!("Swapped adapters in {n} LoRA layers."),
+                let weighted = adapters.iter().map(|name| (name.clone(), 1.0)).collect();
+                match get_mut_arcmutex!(self.pipeline).activate_adapters(weighted) {
+                    Ok(n) => {
+                        info!("Swapped adapters in {n} LoRA layers.");
+                        self.state.write().unwrap().active_adapters = Some(adapters);
+                    }
                     Err(e) => warn!("Adapter activation failed: {e:?}"),
                 }
             }
+            Request::LoadAdapter {
+                name,
+                source,
+                adapter,
+                response,
+            } => {
+                let result = get_mut_arcmutex!(self.pipeline).load_adapter(name.clone(), &adapter);
+                match &result {
+                    Ok(n) => {
+                        info!("Loaded adapter `{name}` from `{source}` into {n} LoRA layers.");
+                        self.state.write().unwrap().adapters.push(name);
+                    }
+                    Err(e) => warn!("Loading adapter `{name}` failed: {e:?}"),
+                }
+                let _ = response.send(result.map_err(|e| e.to_string())).await;
+            }
+            Request::UnloadAdapter { name, response } => {
+                let result = get_mut_arcmutex!(self.pipeline).unload_adapter(&name);
+                match &result {
+                    Ok(n) => {
+                        info!("Unloaded adapter `{name}` from {n} LoRA layers.");
+                        let mut state = self.state.write().unwrap();
+                        state.adapters.retain(|adapter| *adapter != name);
+                        if let Some(active) = state.active_adapters.as_mut() {
+                            active.retain(|adapter| *adapter != name);
+                        }
+                    }
+                    Err(e) => warn!("Unloading adapter `{name}` failed: {e:?}"),
+                }
+                let _ = response.send(result.map_err(|e| e.to_string())).await;
+            }
+            Request::Normal(request) if self.shutdown_deadline.is_some() => {
+                // The receiver may already be gone, in which case there is nobody to notify.
+                let _ = request
+                    .response
+                    .send(Response::ValidationError(
+                        "The engine is shutting down and does not accept new requests.".into(),
+                    ))
+                    .await;
+            }
             Request::Normal(request) => self.add_request(request).await,
-            Request::ReIsq(level) => {
-                if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
+            Request::Shutdown(drain_timeout) => {
+                info!(
+                    "Draining the engine, running requests will be stopped in {drain_timeout:?}."
+                );
+                let deadline = Instant::now() + drain_timeout;
+                self.shutdown_deadline = Some(deadline);
+                self.scheduler.set_deadline_all(deadline);
+            }
+            Request::ExportSession {
+                session_id,
+                path,
+                response,
+            } => {
+                let result = self
+                    .prefix_cacher
+                    .export_session(&session_id, &path)
+                    .map_err(|e| e.to_string());
+                let _ = response.send(result).await;
+            }
+            Request::ImportSession {
+                session_id,
+                path,
+                response,
+            } => {
+                let (n_layers, dtype, quant, is_xlora) = {
+                    let pipeline = get_mut_arcmutex!(self.pipeline);
+                    let metadata = pipeline.get_metadata();
+                    (
+                        metadata.num_hidden_layers,
+                        metadata.activation_dtype,
+                        pipeline.kv_cache_quant(),
+                        metadata.is_xlora,
+                    )
+                };
+                let result = self
+                    .prefix_cacher
+                    .import_session(session_id, &path, n_layers, dtype, quant, is_xlora)
+                    .map_err(|e| e.to_string());
+                let _ = response.send(result).await;
+            }
+            Request::DropSession(session_id) => {
+                if !self.prefix_cacher.drop_session(&session_id) {
+                    warn!("Cannot drop session `{session_id}`, which does not exist.");
+                }
+            }
+            Request::ReIsq(level, policy) => {
+                if let Err(e) =
+                    get_mut_arcmutex!(self.pipeline).re_isq_model(level, policy.as_ref())
+                {
                     warn!("ISQ requantization failed: {e:?}");
+                } else {
+                    self.state.write().unwrap().isq = Some(level);
                 }
             }
         }
@@ -311,11 +783,11 @@ impl Engine {
                 .get_chat_template()
                 .has_chat_template()
         {
-            request
+            let _ = request
                     .response
                     .send(Response::ValidationError(
                         "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
-                    )).await.expect("Expected receiver.");
+                    )).await;
             return;
         }
 
@@ -327,6 +799,11 @@ impl Engine {
             _ => None,
         };
 
+        // The turns of chats with images are never dropped, as the images belong to them.
+        let chat_messages = match request.messages {
+            RequestMessage::Chat(ref messages) => Some(messages.clone()),
+            _ => None,
+        };
         let mut prompt = match request.messages {
             RequestMessage::Chat(messages)
             | RequestMessage::VisionChat {
@@ -349,45 +826,169 @@ impl Engine {
             RequestMessage::CompletionTokens(it) => it,
         };
         if prompt.is_empty() {
-            request
+            let _ = request
                 .response
                 .send(Response::ValidationError(
                     "Received an empty prompt.".into(),
                 ))
-                .await
-                .expect("Expected receiver.");
+                .await;
+            return;
+        }
+
+        if let Some((name, weight)) = request
+            .adapters
+            .iter()
+            .flatten()
+            .find(|(_, weight)| !weight.is_finite())
+        {
+            let _ = request
+                .response
+                .send(Response::ValidationError(
+                    format!("Adapter `{name}` has a non-finite weight {weight}.").into(),
+                ))
+                .await;
+            return;
+        }
+
+        let xlora_scalings_shape = get_mut_arcmutex!(self.pipeline)
+            .get_metadata()
+            .xlora_scalings_shape;
+        if let Err(err) = check_xlora_scalings(
+            xlora_scalings_shape,
+            request.return_xlora_scalings.is_some(),
+            request.xlora_scalings.as_deref(),
+        ) {
+            let _ = request
+                .response
+                .send(Response::ValidationError(err.into()))
+                .await;
             return;
         }
 
-        if prompt.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
-            if !self.truncate_sequence {
-                request
+        if request.session_id.is_some() {
+            let is_speculative = matches!(
+                get_mut_arcmutex!(self.pipeline).get_metadata().kind,
+                ModelKind::Speculative { .. }
+            );
+            // Like the prefix cache, a session's cache is only keyed by its tokens.
+            let session_error = if is_speculative {
+                Some("Sessions are not supported with speculative decoding.")
+            } else if images.is_some()
+                || request.adapters.is_some()
+                || request.xlora_scalings.is_some()
+            {
+                Some("Sessions are not supported with images, per-request adapters or fixed X-LoRA scalings.")
+            } else if request.sampling_params.n_choices != 1 || best_of != 1 {
+                Some("Sessions require a single choice.")
+            } else {
+                None
+            };
+            if let Some(err) = session_error {
+                let _ = request
                     .response
-                    .send(Response::ValidationError(
-                        format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
-                    )).await.expect("Expected receiver.");
+                    .send(Response::ValidationError(err.into()))
+                    .await;
                 return;
+            }
+        }
+
+        // The outputs of the adapters are summed, so their order is irrelevant. Sorting them lets
+        // sequences with the same adapters share a batch.
+        let adapters = request.adapters.clone().map(|adapters| {
+            let mut adapters = adapters.into_iter().collect::<Vec<_>>();
+            adapters.sort_by(|(a, _), (b, _)| a.cmp(b));
+            adapters
+        });
+
+        let mut warnings = Vec::new();
+        let max_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        if prompt.len() > max_len {
+            let strategy = match request.truncation_strategy {
+                Some(strategy) => strategy,
+                None if !self.truncate_sequence => {
+                    let _ = request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("Prompt sequence length is greater than {max_len}, perhaps consider using `truncate_sequence` or `truncation_strategy`?").into(),
+                        )).await;
+                    return;
+                }
+                None if chat_messages.is_some() => TruncationStrategy::Oldest,
+                None => TruncationStrategy::Tokens,
+            };
+            let prompt_len = prompt.len();
+            let currently_over = prompt_len - max_len;
+            let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
+                if currently_over + sampling_max >= prompt_len {
+                    10
+                } else {
+                    sampling_max
+                }
             } else {
-                let prompt_len = prompt.len();
-                let max_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
-                let currently_over = prompt_len - max_len;
-                let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
-                    if currently_over + sampling_max >= prompt_len {
-                        10
-                    } else {
-                        sampling_max
+                10
+            };
+            let dropped_turns = match (strategy, &chat_messages) {
+                (TruncationStrategy::Oldest | TruncationStrategy::Middle, Some(messages)) => {
+                    let truncated = {
+                        let pipeline = &*get_mut_arcmutex!(self.pipeline);
+                        truncate_chat(
+                            pipeline,
+                            messages,
+                            strategy,
+                            max_len.saturating_sub(sampling_max),
+                        )
+                    };
+                    match handle_seq_error!(truncated, request.response) {
+                        Some(truncated) => Some(truncated),
+                        // Without a requested strategy, a chat whose last turn alone does not fit
+                        // is truncated by tokens instead.
+                        None if request.truncation_strategy.is_none() => None,
+                        None => {
+                            let _ = request
+                                .response
+                                .send(Response::ValidationError(
+                                    format!("The chat does not fit in the maximum sequence length of {max_len} with room for {sampling_max} tokens after dropping its older turns.").into(),
+                                ))
+                                .await;
+                            return;
+                        }
                     }
-                } else {
-                    10
+                }
+                _ => None,
+            };
+            if let Some((truncated, n_dropped)) = dropped_turns {
+                prompt = truncated;
+                let dropped = match strategy {
+                    TruncationStrategy::Middle => "messages after the first turn",
+                    TruncationStrategy::Oldest | TruncationStrategy::Tokens => "oldest messages",
                 };
+                warn!("Prompt for request {} was {} tokens over the model maximum length. The {} {} were dropped to make space for generation.", request.id, currently_over, n_dropped, dropped);
+                warnings.push(format!("The prompt was {currently_over} tokens over the maximum sequence length of {max_len}, so {n_dropped} messages were dropped."));
+            } else {
                 prompt = prompt[(currently_over + sampling_max)..].to_vec();
-                warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
+                warn!("Prompt for request {} was {} tokens over the model maximum length. The first {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
+                warnings.push(format!("The prompt was {currently_over} tokens over the maximum sequence length of {max_len}, so its first {} tokens were dropped.", prompt_len - prompt.len()));
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt),
-            request.response
-        );
+        let session_cache = match &request.session_id {
+            Some(session_id) => handle_seq_error!(
+                self.prefix_cacher.search_session(session_id, &prompt),
+                request.response
+            ),
+            None => None,
+        };
+        // The KV cache depends on the images, adapters and X-LoRA scalings, which are not part of
+        // the key of the prefix cache.
+        let prefill_cache = if session_cache.is_some() {
+            session_cache
+        } else if images.is_none() && adapters.is_none() && request.xlora_scalings.is_none() {
+            handle_seq_error!(
+                self.prefix_cacher.search_for_matching_cache(&prompt),
+                request.response
+            )
+        } else {
+            None
+        };
 
         let topk = request
             .sampling_params
@@ -409,12 +1010,12 @@ impl Engine {
                 for id in i {
                     // We can't use ` ` (space) as a stop token because other tokens like ` moon` start with a space.
                     if tok_trie.has_extensions(tok_trie.token(*id)) {
-                        request
+                        let _ = request
                             .response
                             .send(Response::ValidationError(
                                 format!("Stop token {:?} is also a prefix of other tokens and cannot be used as a stop token.", tok_trie.token_str(*id)).into(),
                             ))
-                            .await .expect("Expected receiver.");
+                            .await;
                         return;
                     }
                 }
@@ -453,12 +1054,14 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+        let mut group = SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
             is_chat,
             best_of,
-        )));
+        );
+        group.warnings = warnings;
+        let group = Arc::new(tokio::sync::Mutex::new(group));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
@@ -466,13 +1069,12 @@ impl Engine {
         let logits_bias = match self.alloc_logits_bias(request.sampling_params.logits_bias) {
             Ok(logits_bias) => logits_bias,
             Err(err) => {
-                request
+                let _ = request
                     .response
                     .send(Response::ValidationError(
                         format!("Failed creation of logits bias. {}", err).into(),
                     ))
-                    .await
-                    .expect("Expected receiver.");
+                    .await;
                 return;
             }
         };
@@ -490,28 +1092,28 @@ impl Engine {
         );
 
         if request.sampling_params.n_choices == 0 {
-            request
+            let _ = request
                 .response
                 .send(Response::ValidationError(
                     "Number of choices must be greater than 0.".into(),
                 ))
-                .await
-                .expect("Expected receiver.");
+                .await;
             return;
         }
 
+        let deadline = request.timeout.map(|timeout| Instant::now() + timeout);
+
         // Add sequences
         for response_index in 0..request.sampling_params.n_choices {
             let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
-                    request
+                    let _ = request
                         .response
                         .send(Response::ValidationError(
                             format!("Invalid grammar. {}", err).into(),
                         ))
-                        .await
-                        .expect("Expected receiver.");
+                        .await;
                     return;
                 }
             };
@@ -543,8 +1145,14 @@ impl Engine {
                 } else {
                     None
                 },
-                request.adapters.clone(),
+                adapters.clone(),
                 images.clone(),
+                request.priority.unwrap_or(0),
+                request.user.clone(),
+                deadline,
+                request.return_xlora_scalings,
+                request.xlora_scalings.clone(),
+                request.session_id.clone(),
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
@@ -560,3 +1168,545 @@ impl Engine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any::Any,
+        collections::{HashMap, HashSet},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use candle_core::{quantized::GgmlDType, DType, Device, Tensor};
+    use either::Either;
+    use indexmap::IndexMap;
+    use rand_isaac::Isaac64Rng;
+    use tokenizers::{decoders::byte_level::ByteLevel, models::bpe::BPE, Tokenizer};
+    use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+    use super::{chat_turns, drop_turns};
+    use crate::{
+        aici::{bintokens::build_tok_trie, toktree::TokTrie},
+        do_sample,
+        pipeline::{
+            text_models_inputs_processor::ModelInputs, AdapterActivationMixin, Cache,
+            CacheManagerMixin, GeneralMetadata, IsqPipelineMixin, IsqPolicy, KvCacheEntry,
+            LoraAdapterFiles, MetadataMixin, ModelCategory, ModelKind, PreProcessingMixin,
+        },
+        prefix_cacher::PrefixCacheManager,
+        request::TruncationStrategy,
+        sequence::Sequence,
+        ChatTemplate, Constraint, EngineStatus, MessageContent, MistralRs, MistralRsBuilder,
+        NormalRequest, Pipeline, Request, RequestMessage, Response, SamplingParams,
+        SchedulerMethod,
+    };
+
+    /// Counters shared between a test and its [`TestPipeline`].
+    #[derive(Clone, Default)]
+    struct Counters {
+        /// Number of forward passes run so far.
+        forwards: Arc<AtomicUsize>,
+        /// Number of the next forward passes which panic while holding the KV cache lock.
+        panics: Arc<AtomicUsize>,
+    }
+
+    /// A model with a 4 token vocabulary which always predicts the first token.
+    struct TestPipeline {
+        metadata: GeneralMetadata,
+        tok_trie: Arc<TokTrie>,
+        tokenizer: Arc<Tokenizer>,
+        chat_template: Arc<ChatTemplate>,
+        cache: Cache,
+        step_delay: Duration,
+        counters: Counters,
+        /// Names of the loaded adapters.
+        adapters: HashSet<String>,
+    }
+
+    impl TestPipeline {
+        fn new(step_delay: Duration, counters: Counters) -> Self {
+            let vocab = ["a", "b", "c", "d"]
+                .into_iter()
+                .map(ToString::to_string)
+                .zip(0u32..)
+                .collect::<HashMap<_, _>>();
+            let mut tokenizer = Tokenizer::new(
+                BPE::builder()
+                    .vocab_and_merges(vocab, vec![])
+                    .build()
+                    .unwrap(),
+            );
+            tokenizer.with_decoder(ByteLevel::default());
+            let tok_trie = Arc::new(build_tok_trie(tokenizer.clone()));
+            let mut chat_template = ChatTemplate::default();
+            chat_template.chat_template = Some(
+                "{% for message in messages %}{{ message['content'] }}{% endfor %}".to_string(),
+            );
+            Self {
+                metadata: GeneralMetadata {
+                    max_seq_len: 1 << 20,
+                    repeat_last_n: 64,
+                    tok_trie: tok_trie.clone(),
+                    has_no_kv_cache: false,
+                    num_hidden_layers: 1,
+                    eos_tok: vec![],
+                    kind: ModelKind::Normal,
+                    is_xlora: false,
+                    isq: None,
+                    adapters: vec![],
+                    row_adapters: false,
+                    xlora_scalings_shape: None,
+                    activation_dtype: DType::F32,
+                },
+                tok_trie,
+                tokenizer: Arc::new(tokenizer),
+                chat_template: Arc::new(chat_template),
+                cache: Cache::new(1, false),
+                step_delay,
+                counters,
+                adapters: HashSet::new(),
+            }
+        }
+    }
+
+    impl PreProcessingMixin for TestPipeline {
+        fn get_chat_template(&self) -> Arc<ChatTemplate> {
+            self.chat_template.clone()
+        }
+        fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+            None
+        }
+    }
+
+    impl IsqPipelineMixin for TestPipeline {
+        fn re_isq_model(&mut self, _: GgmlDType, _: Option<&IsqPolicy>) -> anyhow::Result<()> {
+            anyhow::bail!("The test model cannot be quantized.")
+        }
+    }
+
+    impl CacheManagerMixin for TestPipeline {
+        fn clone_in_cache(&mut self, _: &mut [&mut Sequence], _: bool) {}
+        fn clone_out_cache(&mut self, _: &mut [&mut Sequence], _: bool) {}
+        fn set_none_cache(&mut self, _: bool, _: bool) {
+            for entry in self.cache.lock().iter_mut() {
+                *entry = KvCacheEntry::default();
+            }
+        }
+        fn cache(&self) -> &Cache {
+            &self.cache
+        }
+    }
+
+    impl AdapterActivationMixin for TestPipeline {
+        fn activate_adapters(&mut self, adapters: Vec<(String, f64)>) -> anyhow::Result<usize> {
+            Ok(adapters.len())
+        }
+        fn activate_row_adapters(
+            &mut self,
+            _: Vec<Option<Vec<(String, f64)>>>,
+        ) -> anyhow::Result<usize> {
+            Ok(0)
+        }
+        fn load_adapter(
+            &mut self,
+            name: String,
+            adapter: &LoraAdapterFiles,
+        ) -> anyhow::Result<usize> {
+            if adapter.weights.as_os_str().is_empty() {
+                anyhow::bail!("Adapter `{name}` has no weights.");
+            }
+            if !self.adapters.insert(name.clone()) {
+                anyhow::bail!("Adapter `{name}` is already loaded.");
+            }
+            Ok(1)
+        }
+        fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+            if !self.adapters.remove(name) {
+                anyhow::bail!("Adapter `{name}` is not loaded.");
+            }
+            Ok(1)
+        }
+    }
+
+    impl MetadataMixin for TestPipeline {
+        fn device(&self) -> Device {
+            Device::Cpu
+        }
+        fn tokenizer(&self) -> Arc<Tokenizer> {
+            self.tokenizer.clone()
+        }
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+        fn reset_non_granular_state(&self) {}
+        fn get_metadata(&self) -> &GeneralMetadata {
+            &self.metadata
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Pipeline for TestPipeline {
+        fn forward_inputs(&mut self, inputs: Box<dyn Any>) -> candle_core::Result<Tensor> {
+            let ModelInputs { input_ids, .. } = *inputs.downcast().expect("Downcast failed.");
+            self.counters.forwards.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(self.step_delay);
+            // Like the models, hold the KV cache lock for the whole forward pass.
+            let _cache = self.cache.lock();
+            if self
+                .counters
+                .panics
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                panic!("Injected forward pass panic.");
+            }
+            let batch = input_ids.dim(0)?;
+            Tensor::from_vec(
+                [100f32, 0., 0., 0.].repeat(batch),
+                (batch, 1, 4),
+                &Device::Cpu,
+            )
+        }
+        async fn sample(
+            &self,
+            seqs: &mut [&mut Sequence],
+            logits: Tensor,
+            prefix_cacher: &mut PrefixCacheManager,
+            disable_eos_stop: bool,
+            rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        ) -> candle_core::Result<()> {
+            do_sample!(self, seqs, logits, prefix_cacher, disable_eos_stop, rng)
+        }
+        fn category(&self) -> ModelCategory {
+            ModelCategory::Text
+        }
+    }
+
+    /// Start an engine running a [`TestPipeline`] whose forward passes take `step_delay`.
+    fn test_engine(step_delay: Duration) -> (Arc<MistralRs>, Counters) {
+        let counters = Counters::default();
+        let pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>> = Arc::new(tokio::sync::Mutex::new(
+            TestPipeline::new(step_delay, counters.clone()),
+        ));
+        let mistralrs =
+            MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(4.try_into().unwrap()))
+                .with_no_prefix_cache(true)
+                .build();
+        (mistralrs, counters)
+    }
+
+    fn request(
+        messages: RequestMessage,
+        max_len: Option<usize>,
+        is_streaming: bool,
+    ) -> (Request, Receiver<Response>) {
+        let (tx, rx) = channel(16);
+        let request = Request::Normal(NormalRequest {
+            messages,
+            sampling_params: SamplingParams {
+                max_len,
+                ..Default::default()
+            },
+            response: tx,
+            return_logprobs: false,
+            is_streaming,
+            id: 0,
+            constraint: Constraint::None,
+            suffix: None,
+            adapters: None,
+            priority: None,
+            user: None,
+            timeout: None,
+            return_xlora_scalings: None,
+            xlora_scalings: None,
+            session_id: None,
+            truncation_strategy: None,
+        });
+        (request, rx)
+    }
+
+    fn completion(max_len: Option<usize>) -> (Request, Receiver<Response>) {
+        request(
+            RequestMessage::CompletionTokens(vec![0, 1, 2]),
+            max_len,
+            false,
+        )
+    }
+
+    async fn recv(rx: &mut Receiver<Response>) -> Response {
+        tokio::time::timeout(Duration::from_secs(30), rx.recv())
+            .await
+            .expect("The engine did not respond.")
+            .expect("The engine dropped the request.")
+    }
+
+    /// Poll the engine status until `condition` holds.
+    async fn wait_until(mistralrs: &MistralRs, condition: impl Fn(&EngineStatus) -> bool) {
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !condition(&mistralrs.engine_status()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The engine did not reach the expected state.");
+    }
+
+    /// Send a request which responds on its own channel and wait for the response.
+    async fn call<T>(
+        mistralrs: &MistralRs,
+        request: impl FnOnce(Sender<Result<T, String>>) -> Request,
+    ) -> Result<T, String> {
+        let (tx, mut rx) = channel(1);
+        mistralrs.get_sender().send(request(tx)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(30), rx.recv())
+            .await
+            .expect("The engine did not respond.")
+            .expect("The engine dropped the request.")
+    }
+
+    fn finish_reason(response: Response) -> String {
+        match response {
+            Response::CompletionDone(response) => response.choices[0].finish_reason.clone(),
+            Response::ValidationError(e) | Response::InternalError(e) => {
+                panic!("Expected a completion, got the error `{e}`.")
+            }
+            _ => panic!("Expected a completion."),
+        }
+    }
+
+    #[test]
+    fn chat_turns_start_at_user_messages() {
+        let messages = chat(&[
+            "system",
+            "user",
+            "assistant",
+            "tool",
+            "user",
+            "system",
+            "assistant",
+            "user",
+        ]);
+        // System messages and the last message are not part of any turn.
+        assert_eq!(chat_turns(&messages), [vec![1, 2, 3], vec![4, 6]]);
+        // Messages before the first user message form their own turn.
+        let messages = chat(&["assistant", "user", "assistant", "user"]);
+        assert_eq!(chat_turns(&messages), [vec![0], vec![1, 2]]);
+        assert!(chat_turns(&chat(&["system", "user"])).is_empty());
+    }
+
+    #[test]
+    fn drop_turns_drops_fewest_turns() -> anyhow::Result<()> {
+        // Each message renders to its number of tokens, the last message is not in a turn.
+        let tokens = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3];
+        let turns = (0..tokens.len() - 1).map(|i| vec![i]).collect::<Vec<_>>();
+        for strategy in [TruncationStrategy::Oldest, TruncationStrategy::Middle] {
+            let first = usize::from(strategy == TruncationStrategy::Middle);
+            for max_len in 0..=tokens.iter().sum::<usize>() {
+                let mut n_renders = 0;
+                let truncated = drop_turns(&turns, strategy, max_len, |dropped| {
+                    n_renders += 1;
+                    let kept = (0..tokens.len()).filter(|i| !dropped.contains(i));
+                    Ok(vec![0; kept.map(|i| tokens[i]).sum()])
+                })?;
+                // Linear search for the fewest dropped turns after the kept first turn.
+                let expected = (1..=turns.len() - first).find_map(|n_turns| {
+                    let kept = (0..tokens.len()).filter(|i| *i < first || *i >= first + n_turns);
+                    let len = kept.map(|i| tokens[i]).sum::<usize>();
+                    (len <= max_len).then_some((len, n_turns))
+                });
+                assert_eq!(
+                    truncated.map(|(prompt, n_dropped)| (prompt.len(), n_dropped)),
+                    expected,
+                    "{strategy:?}, max_len = {max_len}"
+                );
+                // Dropping all turns, then a binary search over the number of dropped turns.
+                assert!(n_renders <= 5, "{n_renders} renders");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn drop_turns_keeps_first_turn_with_middle() -> anyhow::Result<()> {
+        let turns = [vec![0, 1], vec![2, 3]];
+        let mut dropped_messages = HashSet::new();
+        let truncated = drop_turns(&turns, TruncationStrategy::Middle, 0, |dropped| {
+            dropped_messages.clone_from(dropped);
+            Ok(Vec::new())
+        })?;
+        assert_eq!(truncated, Some((Vec::new(), 2)));
+        assert_eq!(dropped_messages, HashSet::from([2, 3]));
+        // With a single turn, there is nothing to drop.
+        let truncated = drop_turns(&turns[..1], TruncationStrategy::Middle, 0, |_| {
+            Ok(Vec::new())
+        })?;
+        assert_eq!(truncated, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn step_panics_fail_only_their_batch() {
+        let (mistralrs, counters) = test_engine(Duration::ZERO);
+        // The panic poisons the KV cache lock, which the engine must take again to reset the cache.
+        counters.panics.store(1, Ordering::SeqCst);
+        let (request, mut rx) = completion(Some(4));
+        mistralrs.get_sender().send(request).await.unwrap();
+        assert!(matches!(recv(&mut rx).await, Response::InternalError(_)));
+
+        let (request, mut rx) = completion(Some(4));
+        mistralrs.get_sender().send(request).await.unwrap();
+        assert_eq!(finish_reason(recv(&mut rx).await), "length");
+        mistralrs.shutdown(Duration::ZERO).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_receivers_cancel_their_sequences() {
+        let (mistralrs, counters) = test_engine(Duration::from_millis(1));
+        let messages = vec![IndexMap::from([
+            ("role".to_string(), Either::Left("user".to_string())),
+            ("content".to_string(), Either::Left("abc".to_string())),
+        ])];
+        // Without a maximum length, the sequence would run to the maximum sequence length.
+        let (request, rx) = request(RequestMessage::Chat(messages), None, true);
+        drop(rx);
+        mistralrs.get_sender().send(request).await.unwrap();
+
+        // The sequence is canceled by the first streamed chunk, after which the engine stops stepping.
+        let forwards = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                let forwards = counters.forwards.load(Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if forwards > 0
+                    && forwards == counters.forwards.load(Ordering::SeqCst)
+                    && mistralrs.engine_status().is_idle
+                {
+                    break forwards;
+                }
+            }
+        })
+        .await
+        .expect("The sequence was not canceled.");
+        assert!(forwards < 10, "{forwards} forward passes");
+        mistralrs.shutdown(Duration::ZERO).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_single_message_is_truncated_by_tokens_by_default() {
+        let mut pipeline = TestPipeline::new(Duration::ZERO, Counters::default());
+        pipeline.metadata.max_seq_len = 16;
+        let pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>> =
+            Arc::new(tokio::sync::Mutex::new(pipeline));
+        let mistralrs =
+            MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(4.try_into().unwrap()))
+                .with_no_prefix_cache(true)
+                .with_truncate_sequence(true)
+                .build();
+        let message = || {
+            RequestMessage::Chat(vec![IndexMap::from([
+                ("role".to_string(), Either::Left("user".to_string())),
+                ("content".to_string(), Either::Left("abcd".repeat(8))),
+            ])])
+        };
+
+        // There is no older turn to drop, so the defaulted strategy falls back to dropping tokens.
+        let (defaulted, mut rx) = request(message(), Some(2), false);
+        mistralrs.get_sender().send(defaulted).await.unwrap();
+        assert_eq!(finish_reason(recv(&mut rx).await), "length");
+
+        // A requested strategy is not replaced.
+        let (mut oldest, mut rx) = request(message(), Some(2), false);
+        if let Request::Normal(oldest) = &mut oldest {
+            oldest.truncation_strategy = Some(TruncationStrategy::Oldest);
+        }
+        mistralrs.get_sender().send(oldest).await.unwrap();
+        assert!(matches!(recv(&mut rx).await, Response::ValidationError(_)));
+        mistralrs.shutdown(Duration::ZERO).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn adapters_are_loaded_and_unloaded_at_runtime() {
+        let (mistralrs, _) = test_engine(Duration::ZERO);
+        let load = |name: &str, weights: &str| {
+            let (name, weights) = (name.to_string(), weights.to_string());
+            move |response| Request::LoadAdapter {
+                name,
+                source: weights.clone(),
+                adapter: LoraAdapterFiles {
+                    weights: weights.into(),
+                    config: serde_json::from_str(
+                        r#"{"r": 1, "lora_alpha": 1.0, "target_modules": ["q_proj"]}"#,
+                    )
+                    .unwrap(),
+                },
+                response,
+            }
+        };
+        let unload = |name: &str| {
+            let name = name.to_string();
+            move |response| Request::UnloadAdapter { name, response }
+        };
+        assert_eq!(call(&mistralrs, load("x", "test/x")).await, Ok(1));
+        mistralrs
+            .get_sender()
+            .send(Request::ActivateAdapters(vec!["x".to_string()]))
+            .await
+            .unwrap();
+        let err = call(&mistralrs, load("x", "test/x")).await.unwrap_err();
+        assert!(err.contains("already loaded"), "{err}");
+        let err = call(&mistralrs, load("z", "")).await.unwrap_err();
+        assert!(err.contains("no weights"), "{err}");
+        assert_eq!(call(&mistralrs, load("y", "test/y")).await, Ok(1));
+        let status = mistralrs.engine_status();
+        assert_eq!(status.adapters, ["x", "y"]);
+        assert_eq!(status.active_adapters, Some(vec!["x".to_string()]));
+
+        // Unloading an active adapter deactivates it.
+        assert_eq!(call(&mistralrs, unload("x")).await, Ok(1));
+        let status = mistralrs.engine_status();
+        assert_eq!(status.adapters, ["y"]);
+        assert_eq!(status.active_adapters, Some(vec![]));
+        let err = call(&mistralrs, unload("x")).await.unwrap_err();
+        assert!(err.contains("not loaded"), "{err}");
+        mistralrs.shutdown(Duration::ZERO).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_rejects_new_requests_and_drains_running_ones() {
+        let (mistralrs, _) = test_engine(Duration::from_millis(1));
+        let sender = mistralrs.get_sender();
+        // Requests are handled in order, so the first one is accepted before the shutdown starts.
+        let (running, mut running_rx) = completion(Some(16));
+        sender.send(running).await.unwrap();
+        sender
+            .send(Request::Shutdown(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        let (rejected, mut rejected_rx) = completion(Some(16));
+        sender.send(rejected).await.unwrap();
+
+        assert!(matches!(
+            recv(&mut rejected_rx).await,
+            Response::ValidationError(_)
+        ));
+        assert_eq!(finish_reason(recv(&mut running_rx).await), "length");
+        wait_until(&mistralrs, |status| !status.is_alive).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_requests_at_the_drain_timeout() {
+        let (mistralrs, _) = test_engine(Duration::from_millis(1));
+        // Without a maximum length, the sequence would run to the maximum sequence length.
+        let (request, mut rx) = completion(None);
+        mistralrs.get_sender().send(request).await.unwrap();
+        wait_until(&mistralrs, |status| status.running_seqs > 0).await;
+
+        mistralrs.shutdown(Duration::ZERO).await.unwrap();
+        assert!(mistralrs.is_shutting_down());
+        assert!(!mistralrs.engine_status().is_alive);
+        assert_eq!(finish_reason(recv(&mut rx).await), "timeout");
+    }
+}
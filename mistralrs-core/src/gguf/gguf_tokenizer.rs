@@ -1,14 +1,21 @@
-use std::sync::atomic::Ordering;
+use std::{collections::HashMap, sync::atomic::Ordering};
 
 use anyhow::Result;
 use candle_core::quantized::gguf_file::Content;
 use tokenizers::{
     decoders::{self, byte_fallback::ByteFallback, fuse::Fuse, strip::Strip},
-    models::unigram::Unigram,
+    models::{bpe::BpeBuilder, unigram::Unigram},
     normalizers::{self, Prepend, Replace},
-    AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, Tokenizer,
+    pre_tokenizers::{
+        self,
+        byte_level::ByteLevel,
+        split::{Split, SplitPattern},
+    },
+    processors::template::TemplateProcessing,
+    AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, PostProcessorWrapper,
+    PreTokenizerWrapper, SplitDelimiterBehavior, Tokenizer,
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::DEBUG;
@@ -22,13 +29,16 @@ pub(crate) struct GgufTokenizerConversion {
 
 struct PropsGGUF {
     model: String,
+    pre: Option<String>,
     tokens: Vec<String>,
+    token_types: Option<Vec<i32>>,
     added_tokens: Option<Vec<String>>,
     scores: Option<Vec<f32>>,
     merges: Option<Vec<String>>,
     unk: Option<u32>,
     eos: u32,
     bos: u32,
+    add_bos: Option<bool>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -40,19 +50,37 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
 
         let props = Self {
             model: c.get_value("model")?,
+            pre: c.get_value("pre").ok(),
             tokens: c.get_value("tokens")?,
+            token_types: c.get_value("token_type").ok(),
             added_tokens: c.get_value("added_tokens").ok(),
             scores: c.get_value("scores").ok(),
             merges: c.get_value("merges").ok(),
             unk: c.get_value("unknown_token_id").ok(),
             eos: c.get_value("eos_token_id")?,
             bos: c.get_value("bos_token_id")?,
+            add_bos: c.get_value("add_bos_token").ok(),
         };
 
         Ok(props)
     }
 }
 
+impl PropsGGUF {
+    /// The token with id `id`, which is the value of the metadata `tokenizer.ggml.{key}`.
+    fn token(&self, id: u32, key: &str) -> Result<&str> {
+        self.tokens
+            .get(id as usize)
+            .map(String::as_str)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "`tokenizer.ggml.{key}` is {id}, but there are only {} tokens",
+                    self.tokens.len()
+                )
+            })
+    }
+}
+
 pub fn convert_gguf_to_hf_tokenizer(content: &Content) -> Result<GgufTokenizerConversion> {
     let metadata = ContentMetadata {
         path_prefix: "tokenizer.ggml",
@@ -62,6 +90,7 @@ pub fn convert_gguf_to_hf_tokenizer(content: &Content) -> Result<GgufTokenizerCo
 
     let (tokenizer, kind, special_tokens) = match props.model.as_str() {
         "llama" | "replit" => unigram_tokenizer(&props)?,
+        "gpt2" => bpe_tokenizer(&props)?,
         other => {
             anyhow::bail!("Tokenizer model `{other}` not supported.");
         }
@@ -79,25 +108,42 @@ pub fn convert_gguf_to_hf_tokenizer(content: &Content) -> Result<GgufTokenizerCo
         info!("Tokenizer: {tokenizer:?}");
     }
 
-    let [bos_str, eos_str, unk_str] = special_tokens
-        .try_into()
-        .or_else(|_| anyhow::bail!("Tokenizer is missing required special tokens"))?;
+    // BPE tokenizers do not necessarily have an unk token:
+    let mut special_tokens = special_tokens.into_iter();
+    let (Some(bos_str), Some(eos_str), unk_str) = (
+        special_tokens.next(),
+        special_tokens.next(),
+        special_tokens.next(),
+    ) else {
+        anyhow::bail!("Tokenizer is missing required special tokens");
+    };
 
     Ok(GgufTokenizerConversion {
         tokenizer,
         bos: Some(bos_str),
         eos: Some(eos_str),
-        unk: Some(unk_str),
+        unk: unk_str,
     })
 }
 
-// TODO: Add support for additional tokenizer models: BPE, WordPiece, WordLevel
+// TODO: Add support for additional tokenizer models: WordPiece, WordLevel
 // https://docs.rs/tokenizers/latest/tokenizers/models/enum.ModelWrapper.html
 #[derive(Debug)]
 enum TokenizerKind {
     Unigram,
+    Bpe,
 }
 
+// GGUF token types, reference:
+// https://github.com/ggerganov/llama.cpp/blob/master/gguf-py/gguf/constants.py (`TokenType`)
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+// Pre-tokenizer split patterns for the `tokenizer.ggml.pre` values, reference:
+// https://github.com/ggerganov/llama.cpp/blob/master/llama.cpp (`llm_tokenizer_bpe`)
+const LLAMA3_SPLIT_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const QWEN2_SPLIT_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
 fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, Vec<String>)> {
     let PropsGGUF { unk, eos, bos, .. } = *p;
     // Unigram (SentencePiece) default UNK is 0
@@ -141,8 +187,12 @@ fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, Vec<Str
 
     // Add special tokens (bos, eos, unk):
     let mut special_tokens = Vec::<String>::new();
-    for token_id in [bos, eos, unk] {
-        let token = p.tokens[token_id as usize].as_str();
+    for (token_id, key) in [
+        (bos, "bos_token_id"),
+        (eos, "eos_token_id"),
+        (unk, "unknown_token_id"),
+    ] {
+        let token = p.token(token_id, key)?;
 
         special_tokens.push(token.to_owned());
         tokenizer.add_special_tokens(&[AddedToken::from(token.to_owned(), true)]);
@@ -151,6 +201,125 @@ fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, Vec<Str
     Ok((tokenizer, TokenizerKind::Unigram, special_tokens))
 }
 
+#[allow(clippy::cast_possible_truncation)]
+fn bpe_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, Vec<String>)> {
+    let PropsGGUF { unk, eos, bos, .. } = *p;
+    let pre = p.pre.as_deref();
+    let is_llama3 = matches!(pre, Some("llama-bpe" | "llama3" | "smaug-bpe"));
+
+    // Create the Tokenizer model:
+    let model = {
+        let vocab: HashMap<String, u32> = p
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id as u32))
+            .collect();
+        let Some(merges) = p.merges.as_ref() else {
+            anyhow::bail!(
+                "`gpt2` BPE tokenizer is missing required metadata `tokenizer.ggml.merges`"
+            );
+        };
+        let merges = merges
+            .iter()
+            .map(|merge| {
+                merge
+                    .split_once(' ')
+                    .map(|(a, b)| (a.to_owned(), b.to_owned()))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid BPE merge `{merge}`"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        BpeBuilder::new()
+            .vocab_and_merges(vocab, merges)
+            // Llama 3 matches whole words in the vocab before applying merges
+            .ignore_merges(is_llama3)
+            .build()
+            .map_err(anyhow::Error::msg)?
+    };
+
+    // The pre-tokenizer regex depends on the model family, the byte-level default is GPT-2's:
+    let split_pattern = match pre {
+        _ if is_llama3 => Some(LLAMA3_SPLIT_PATTERN),
+        Some("qwen2") => Some(QWEN2_SPLIT_PATTERN),
+        None | Some("gpt-2" | "default") => None,
+        Some(other) => {
+            warn!("Unknown GGUF BPE pre-tokenizer `{other}`, using the GPT-2 pre-tokenizer which may split text differently than the model was trained with.");
+            None
+        }
+    };
+    let pre_tokenizer: PreTokenizerWrapper = match split_pattern {
+        Some(pattern) => pre_tokenizers::sequence::Sequence::new(vec![
+            Split::new(
+                SplitPattern::Regex(pattern.to_owned()),
+                SplitDelimiterBehavior::Isolated,
+                false,
+            )
+            .map_err(anyhow::Error::msg)?
+            .into(),
+            ByteLevel::new(false, true, false).into(),
+        ])
+        .into(),
+        None => ByteLevel::new(false, true, true).into(),
+    };
+
+    let post_processor: PostProcessorWrapper = if p.add_bos.unwrap_or(false) {
+        let bos_str = p.token(bos, "bos_token_id")?.to_owned();
+        TemplateProcessing::builder()
+            .try_single(format!("{bos_str} $A"))
+            .map_err(anyhow::Error::msg)?
+            .try_pair(format!("{bos_str} $A {bos_str} $B"))
+            .map_err(anyhow::Error::msg)?
+            .special_tokens(vec![(bos_str, bos)])
+            .build()
+            .map_err(anyhow::Error::msg)?
+            .into()
+    } else {
+        ByteLevel::new(false, true, true).into()
+    };
+
+    let mut tokenizer: Tokenizer = TokenizerX::try_builder()
+        .with_model(model)
+        .with_decoder(Decoder::ByteLevel)
+        .with_pre_tokenizer(pre_tokenizer)
+        .with_post_processor(post_processor)
+        .build()?;
+
+    // Control and user defined tokens must not be split by the pre-tokenizer:
+    if let Some(token_types) = p.token_types.as_ref() {
+        let added_tokens = p
+            .tokens
+            .iter()
+            .zip(token_types)
+            .filter_map(|(token, ty)| match *ty {
+                TOKEN_TYPE_CONTROL => Some(AddedToken::from(token.clone(), true)),
+                TOKEN_TYPE_USER_DEFINED => Some(AddedToken::from(token.clone(), false)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        tokenizer.add_tokens(&added_tokens);
+    }
+
+    // Add special tokens (bos, eos, unk):
+    let mut special_tokens = Vec::<String>::new();
+    let special_ids = [
+        (Some(bos), "bos_token_id"),
+        (Some(eos), "eos_token_id"),
+        (unk, "unknown_token_id"),
+    ];
+    for (token_id, key) in special_ids
+        .into_iter()
+        .filter_map(|(id, key)| Some((id?, key)))
+    {
+        let token = p.token(token_id, key)?;
+
+        special_tokens.push(token.to_owned());
+        tokenizer.add_special_tokens(&[AddedToken::from(token.to_owned(), true)]);
+    }
+
+    Ok((tokenizer, TokenizerKind::Bpe, special_tokens))
+}
+
 // This is a workaround to have a better builder API.
 // Upstream `TokenizerBuilder` is difficult to work with:
 // https://github.com/huggingface/tokenizers/issues/1549
@@ -162,6 +331,8 @@ impl TokenizerX {
         with_model: ModelWrapper,
         with_decoder: Option<Decoder<'a>>,
         with_normalizer: Option<Normalizer<'a>>,
+        with_pre_tokenizer: Option<PreTokenizerWrapper>,
+        with_post_processor: Option<PostProcessorWrapper>,
     ) -> Result<Tokenizer> {
         let mut tokenizer = Tokenizer::new(with_model);
 
@@ -174,6 +345,12 @@ impl TokenizerX {
             let n = NormalizerWrapper::try_from(normalizer)?;
             tokenizer.with_normalizer(n);
         }
+        if let Some(pre_tokenizer) = with_pre_tokenizer {
+            tokenizer.with_pre_tokenizer(pre_tokenizer);
+        }
+        if let Some(post_processor) = with_post_processor {
+            tokenizer.with_post_processor(post_processor);
+        }
 
         Ok(tokenizer)
     }
@@ -183,6 +360,7 @@ impl TokenizerX {
 // https://docs.rs/tokenizers/latest/tokenizers/decoders/enum.DecoderWrapper.html
 enum Decoder<'a> {
     ByteFallback,
+    ByteLevel,
     Fuse,
     Replace(&'a str, &'a str),
     Strip(char, usize, usize),
@@ -196,6 +374,7 @@ impl TryFrom<Decoder<'_>> for DecoderWrapper {
     fn try_from(variant: Decoder) -> Result<Self, Self::Error> {
         let value: DecoderWrapper = match variant {
             Decoder::ByteFallback => ByteFallback::default().into(),
+            Decoder::ByteLevel => ByteLevel::default().into(),
             Decoder::Fuse => Fuse::default().into(),
             Decoder::Replace(pattern, content) => Replace::new(pattern, content)
                 .map_err(anyhow::Error::msg)?
@@ -253,7 +432,7 @@ mod tests {
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use tokenizers::Tokenizer;
 
-    use super::convert_gguf_to_hf_tokenizer;
+    use super::{bpe_tokenizer, convert_gguf_to_hf_tokenizer, unigram_tokenizer, PropsGGUF};
 
     #[allow(dead_code)]
     #[derive(Debug)]
@@ -261,6 +440,7 @@ mod tests {
         /// Mistral v0.1 tokenizer
         Llama,
         Replit,
+        /// Qwen2 tokenizer
         Gpt2,
         Rwkv,
     }
@@ -285,6 +465,24 @@ mod tests {
                 .map_err(anyhow::Error::msg)
                 .map(|res| res.tokenizer)
             }
+            TokenizerType::Gpt2 => {
+                let api = ApiBuilder::new().with_progress(true).build().unwrap();
+                let api = api.repo(Repo::with_revision(
+                    "Qwen/Qwen2-0.5B-Instruct-GGUF".to_string(),
+                    RepoType::Model,
+                    "main".to_string(),
+                ));
+
+                let filename = api.get("qwen2-0_5b-instruct-q2_k.gguf").unwrap();
+                let mut file = std::fs::File::open(&filename)?;
+                convert_gguf_to_hf_tokenizer(
+                    &Content::read(&mut file)
+                        .map_err(|e| e.with_path(filename))
+                        .map_err(anyhow::Error::msg)?,
+                )
+                .map_err(anyhow::Error::msg)
+                .map(|res| res.tokenizer)
+            }
             other => anyhow::bail!("Cannot get testing HF tokenizer for type {other:?}"),
         }
     }
@@ -302,6 +500,17 @@ mod tests {
                 let tokenizer_filename = api.get("tokenizer.json").unwrap();
                 Ok(Tokenizer::from_file(tokenizer_filename).unwrap())
             }
+            TokenizerType::Gpt2 => {
+                let api = ApiBuilder::new().with_progress(true).build().unwrap();
+                let api = api.repo(Repo::with_revision(
+                    "Qwen/Qwen2-0.5B-Instruct".to_string(),
+                    RepoType::Model,
+                    "main".to_string(),
+                ));
+
+                let tokenizer_filename = api.get("tokenizer.json").unwrap();
+                Ok(Tokenizer::from_file(tokenizer_filename).unwrap())
+            }
             other => anyhow::bail!("Cannot get testing HF tokenizer for type {other:?}"),
         }
     }
@@ -384,6 +593,94 @@ mod tests {
         let gguf_decoded = decode(&gguf_tokenizer, &tokens, true)?;
         assert_eq!(hf_decoded, gguf_decoded);
 
+        Ok(())
+    }
+    #[test]
+    fn test_encode_gpt2() -> Result<()> {
+        let passage = get_test_passage();
+        let hf_tokenizer = get_hf_tokenizer(TokenizerType::Gpt2)?;
+        let gguf_tokenizer = get_gguf_tokenizer(TokenizerType::Gpt2)?;
+
+        // The token ids must match, not only the decoded text
+        let hf_encoded = hf_tokenizer
+            .encode(passage.as_str(), false)
+            .map_err(anyhow::Error::msg)?;
+        let gguf_encoded = gguf_tokenizer
+            .encode(passage.as_str(), false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(hf_encoded.get_ids(), gguf_encoded.get_ids());
+
+        // Without adding special tokens
+        let hf_decoded = codec_roundtrip(&hf_tokenizer, passage.as_str(), false)?;
+        let gguf_decoded = codec_roundtrip(&gguf_tokenizer, passage.as_str(), false)?;
+        assert_eq!(hf_decoded, gguf_decoded);
+        assert_eq!(passage, gguf_decoded);
+
+        // With special tokens added
+        let hf_decoded = codec_roundtrip(&hf_tokenizer, passage.as_str(), true)?;
+        let gguf_decoded = codec_roundtrip(&gguf_tokenizer, passage.as_str(), true)?;
+        assert_eq!(hf_decoded, gguf_decoded);
+
+        // Special tokens in the text are kept as a single token
+        let chat = "<|im_start|>user\nHello<|im_end|>";
+        let hf_encoded = hf_tokenizer
+            .encode(chat, false)
+            .map_err(anyhow::Error::msg)?;
+        let gguf_encoded = gguf_tokenizer
+            .encode(chat, false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(hf_encoded.get_ids(), gguf_encoded.get_ids());
+
+        Ok(())
+    }
+
+    fn props(model: &str, bos: u32, eos: u32, add_bos: bool) -> PropsGGUF {
+        PropsGGUF {
+            model: model.to_string(),
+            pre: None,
+            tokens: vec!["a".to_string(), "b".to_string()],
+            token_types: None,
+            added_tokens: None,
+            scores: Some(vec![0., 0.]),
+            merges: Some(vec![]),
+            unk: None,
+            eos,
+            bos,
+            add_bos: Some(add_bos),
+        }
+    }
+
+    #[test]
+    fn out_of_range_special_tokens_are_rejected() {
+        let err = |res: Result<_>| res.err().expect("Expected an error.").to_string();
+        assert!(err(bpe_tokenizer(&props("gpt2", 2, 1, true))).contains("bos_token_id"));
+        assert!(err(bpe_tokenizer(&props("gpt2", 0, 7, false))).contains("eos_token_id"));
+        assert!(err(unigram_tokenizer(&props("llama", 0, 2, false))).contains("eos_token_id"));
+        assert!(bpe_tokenizer(&props("gpt2", 0, 1, true)).is_ok());
+    }
+
+    #[test]
+    fn test_decode_gpt2() -> Result<()> {
+        use rand::seq::SliceRandom;
+        use rand::thread_rng;
+
+        let hf_tokenizer = get_hf_tokenizer(TokenizerType::Gpt2)?;
+        let gguf_tokenizer = get_gguf_tokenizer(TokenizerType::Gpt2)?;
+
+        #[allow(clippy::cast_possible_truncation)]
+        let mut tokens = (0..hf_tokenizer.get_vocab_size(false) as u32).collect::<Vec<_>>();
+        tokens.shuffle(&mut thread_rng());
+
+        // Without skipping special tokens
+        let hf_decoded = decode(&hf_tokenizer, &tokens, false)?;
+        let gguf_decoded = decode(&gguf_tokenizer, &tokens, false)?;
+        assert_eq!(hf_decoded, gguf_decoded);
+
+        // With skipping special tokens
+        let hf_decoded = decode(&hf_tokenizer, &tokens, true)?;
+        let gguf_decoded = decode(&gguf_tokenizer, &tokens, true)?;
+        assert_eq!(hf_decoded, gguf_decoded);
+
         Ok(())
     }
 }
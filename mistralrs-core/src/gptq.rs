@@ -0,0 +1,593 @@
+//! Loading of GPTQ and AWQ quantized safetensors checkpoints.
+//!
+//! Both formats store the weight of a linear layer as packed integers (`qweight`) together with
+//! per-group packed zero points (`qzeros`) and `scales`. GPTQ checkpoints may also carry a `g_idx`
+//! tensor mapping every input feature to its group (act-order). A weight is `scale * (q - zero)`.
+//!
+//! Layers whose groups cover whole blocks of 32 input features are transcoded to GGML `Q4_1`
+//! (4-bit) or `Q8_0` (symmetric 8-bit) tensors and use the same quantized matmul as ISQ and GGUF
+//! models. The only loss is the rounding of the zero point to f16. Any other layout (act-order,
+//! 2-bit, asymmetric 8-bit) is dequantized on the CPU and kept as a full precision weight.
+
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{
+    quantized::{ggml_file::qtensor_from_ggml, GgmlDType, QMatMul},
+    DType, Device, Result, Tensor,
+};
+use candle_nn::VarBuilder;
+use half::f16;
+use rayon::prelude::*;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::layers::QLinear;
+
+const GGML_BLOCK_SIZE: usize = 32;
+
+/// Position of output feature `i % 8` in an AWQ packed `int32`: AutoAWQ packs the features in
+/// the order 0, 2, 4, 6, 1, 3, 5, 7.
+const AWQ_REVERSE_ORDER: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuantMethod {
+    Gptq,
+    Awq,
+}
+
+impl std::fmt::Display for QuantMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gptq => write!(f, "GPTQ"),
+            Self::Awq => write!(f, "AWQ"),
+        }
+    }
+}
+
+/// The `quantization_config` of a GPTQ or AWQ checkpoint's `config.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct QuantizationConfig {
+    pub quant_method: QuantMethod,
+    #[serde(alias = "w_bit")]
+    pub bits: usize,
+    /// Number of input features sharing a scale and zero point, `-1` for a single group.
+    #[serde(alias = "q_group_size")]
+    pub group_size: isize,
+    /// AWQ packing layout, only `gemm` is supported.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// GPTQ serialization format. `gptq_v2` stores the zero points without the `-1` offset.
+    #[serde(default)]
+    pub checkpoint_format: Option<String>,
+}
+
+impl QuantizationConfig {
+    /// Read and validate the `quantization_config` of a model `config.json`, if there is one.
+    pub fn from_model_config(config: &str) -> anyhow::Result<Option<Self>> {
+        #[derive(Deserialize)]
+        struct ModelConfig {
+            quantization_config: Option<QuantizationConfig>,
+        }
+
+        let quant = serde_json::from_str::<ModelConfig>(config)?.quantization_config;
+        if let Some(quant) = &quant {
+            quant.validate()?;
+        }
+        Ok(quant)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.quant_method {
+            QuantMethod::Gptq if ![2, 4, 8].contains(&self.bits) => {
+                candle_core::bail!(
+                    "GPTQ checkpoints with {} bits are not supported, expected 2, 4 or 8.",
+                    self.bits
+                )
+            }
+            QuantMethod::Awq if self.bits != 4 => {
+                candle_core::bail!(
+                    "AWQ checkpoints with {} bits are not supported, expected 4.",
+                    self.bits
+                )
+            }
+            QuantMethod::Awq => match self.version.as_deref() {
+                Some(version) if !version.eq_ignore_ascii_case("gemm") => {
+                    candle_core::bail!(
+                        "AWQ checkpoints with version `{version}` are not supported, expected `gemm`."
+                    )
+                }
+                _ => Ok(()),
+            },
+            QuantMethod::Gptq => Ok(()),
+        }
+    }
+
+    fn zero_offset(&self) -> u32 {
+        match self.quant_method {
+            QuantMethod::Gptq if self.checkpoint_format.as_deref() != Some("gptq_v2") => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Load a linear layer without bias, reading the GPTQ/AWQ tensors when the layer is quantized and
+/// the `weight` tensor otherwise.
+pub(crate) fn linear_no_bias(
+    in_dim: usize,
+    out_dim: usize,
+    quant: &Option<QuantizationConfig>,
+    vb: VarBuilder,
+) -> Result<QMatMul> {
+    match quant {
+        Some(quant) if vb.contains_tensor("qweight") => load_qmatmul(in_dim, out_dim, quant, &vb),
+        _ => {
+            let linear = candle_nn::linear_no_bias(in_dim, out_dim, vb)?;
+            Ok(QMatMul::Tensor(linear.weight().clone()))
+        }
+    }
+}
+
+/// Load a linear layer as a [`QLinear`], reading the GPTQ/AWQ tensors when the layer is
+/// quantized and the `weight` tensor otherwise.
+pub(crate) fn linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    quant: &Option<QuantizationConfig>,
+    vb: VarBuilder,
+) -> Result<QLinear> {
+    match quant {
+        Some(quant) if vb.contains_tensor("qweight") => {
+            let inner = load_qmatmul(in_dim, out_dim, quant, &vb)?;
+            let bias = if bias {
+                let bias = vb.get(out_dim, "bias")?;
+                // Quantized matmuls produce f32 outputs.
+                if matches!(inner, QMatMul::QTensor(_)) {
+                    Some(bias.to_dtype(DType::F32)?)
+                } else {
+                    Some(bias)
+                }
+            } else {
+                None
+            };
+            Ok(QLinear::from_qmatmul(inner, bias, vb.dtype()))
+        }
+        _ => Ok(QLinear::from_linear(candle_nn::linear_b(
+            in_dim, out_dim, bias, vb,
+        )?)),
+    }
+}
+
+/// Load a linear layer with bias, see [`linear_b`].
+pub(crate) fn linear(
+    in_dim: usize,
+    out_dim: usize,
+    quant: &Option<QuantizationConfig>,
+    vb: VarBuilder,
+) -> Result<QLinear> {
+    linear_b(in_dim, out_dim, true, quant, vb)
+}
+
+fn load_qmatmul(
+    in_dim: usize,
+    out_dim: usize,
+    quant: &QuantizationConfig,
+    vb: &VarBuilder,
+) -> Result<QMatMul> {
+    let packed = PackedLinear::load(in_dim, out_dim, quant, vb)?;
+    match packed.ggml_dtype(quant.bits) {
+        Some(dtype) => {
+            let data = packed.to_ggml(dtype);
+            let qtensor = qtensor_from_ggml(dtype, &data, vec![out_dim, in_dim], vb.device())?;
+            Ok(QMatMul::QTensor(Arc::new(qtensor)))
+        }
+        None => {
+            let reason = if !packed.is_block_aligned() {
+                "its groups do not cover whole blocks of 32 input features (act-order)".to_string()
+            } else if quant.bits == 8 {
+                "its 8-bit zero points are asymmetric".to_string()
+            } else {
+                format!("{}-bit values have no quantized matmul", quant.bits)
+            };
+            warn!(
+                "{} layer `{}` is dequantized to full precision because {reason}.",
+                quant.quant_method,
+                vb.prefix()
+            );
+            let weight = Tensor::from_vec(packed.dequantize(), (out_dim, in_dim), &Device::Cpu)?
+                .to_dtype(vb.dtype())?
+                .to_device(vb.device())?;
+            Ok(QMatMul::Tensor(weight))
+        }
+    }
+}
+
+/// The unpacked tensors of a GPTQ/AWQ linear layer.
+struct PackedLinear {
+    in_dim: usize,
+    out_dim: usize,
+    /// Quantized values, `[out_dim, in_dim]`.
+    q: Vec<u8>,
+    /// Zero points including the format offset, `[n_groups, out_dim]`.
+    zeros: Vec<u32>,
+    /// Scales, `[n_groups, out_dim]`.
+    scales: Vec<f32>,
+    /// Group of each input feature.
+    groups: Vec<usize>,
+}
+
+fn unpack(packed: i64, shift: usize, bits: usize) -> u32 {
+    // Packed values are `int32`s, which candle loads as `i64`: keep the low 32 bits.
+    ((packed as u32) >> shift) & ((1 << bits) - 1)
+}
+
+impl PackedLinear {
+    fn load(
+        in_dim: usize,
+        out_dim: usize,
+        quant: &QuantizationConfig,
+        vb: &VarBuilder,
+    ) -> Result<Self> {
+        quant.validate()?;
+        let bits = quant.bits;
+        let pack = 32 / bits;
+        let group_size = if quant.group_size > 0 {
+            quant.group_size as usize
+        } else {
+            in_dim
+        };
+        let n_groups = in_dim.div_ceil(group_size);
+
+        let ints = vb.clone().set_dtype(DType::I64).set_device(Device::Cpu);
+        let (q, zeros): (Vec<u8>, Vec<u32>) = match quant.quant_method {
+            QuantMethod::Gptq => {
+                // Packed along the input features: `[in_dim / pack, out_dim]`.
+                let qweight = ints
+                    .get((in_dim / pack, out_dim), "qweight")?
+                    .flatten_all()?
+                    .to_vec1::<i64>()?;
+                let qzeros = ints
+                    .get((n_groups, out_dim / pack), "qzeros")?
+                    .flatten_all()?
+                    .to_vec1::<i64>()?;
+                let q = (0..out_dim * in_dim)
+                    .into_par_iter()
+                    .map(|idx| {
+                        let (o, i) = (idx / in_dim, idx % in_dim);
+                        unpack(qweight[(i / pack) * out_dim + o], (i % pack) * bits, bits) as u8
+                    })
+                    .collect();
+                let zeros = (0..n_groups * out_dim)
+                    .map(|idx| {
+                        let (g, o) = (idx / out_dim, idx % out_dim);
+                        let zero = unpack(
+                            qzeros[g * (out_dim / pack) + o / pack],
+                            (o % pack) * bits,
+                            bits,
+                        );
+                        zero + quant.zero_offset()
+                    })
+                    .collect();
+                (q, zeros)
+            }
+            QuantMethod::Awq => {
+                // Packed along the output features: `[in_dim, out_dim / 8]`.
+                let qweight = ints
+                    .get((in_dim, out_dim / pack), "qweight")?
+                    .flatten_all()?
+                    .to_vec1::<i64>()?;
+                let qzeros = ints
+                    .get((n_groups, out_dim / pack), "qzeros")?
+                    .flatten_all()?
+                    .to_vec1::<i64>()?;
+                let q = (0..out_dim * in_dim)
+                    .into_par_iter()
+                    .map(|idx| {
+                        let (o, i) = (idx / in_dim, idx % in_dim);
+                        let shift = AWQ_REVERSE_ORDER[o % pack] * bits;
+                        unpack(qweight[i * (out_dim / pack) + o / pack], shift, bits) as u8
+                    })
+                    .collect();
+                let zeros = (0..n_groups * out_dim)
+                    .map(|idx| {
+                        let (g, o) = (idx / out_dim, idx % out_dim);
+                        let shift = AWQ_REVERSE_ORDER[o % pack] * bits;
+                        unpack(qzeros[g * (out_dim / pack) + o / pack], shift, bits)
+                    })
+                    .collect();
+                (q, zeros)
+            }
+        };
+
+        let scales = vb
+            .clone()
+            .set_dtype(DType::F32)
+            .set_device(Device::Cpu)
+            .get((n_groups, out_dim), "scales")?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+
+        let groups = if quant.quant_method == QuantMethod::Gptq && vb.contains_tensor("g_idx") {
+            let g_idx = ints.get(in_dim, "g_idx")?.to_vec1::<i64>()?;
+            if let Some(g) = g_idx.iter().find(|g| !(0..n_groups as i64).contains(g)) {
+                candle_core::bail!(
+                    "Invalid GPTQ `g_idx` entry {g}, expected fewer than {n_groups} groups."
+                );
+            }
+            g_idx.into_iter().map(|g| g as usize).collect()
+        } else {
+            (0..in_dim).map(|i| i / group_size).collect()
+        };
+
+        Ok(Self {
+            in_dim,
+            out_dim,
+            q,
+            zeros,
+            scales,
+            groups,
+        })
+    }
+
+    /// Whether every block of 32 input features lies in a single group.
+    fn is_block_aligned(&self) -> bool {
+        self.in_dim % GGML_BLOCK_SIZE == 0
+            && self
+                .groups
+                .chunks(GGML_BLOCK_SIZE)
+                .all(|block| block.iter().all(|g| *g == block[0]))
+    }
+
+    /// The GGML type this layer can be transcoded to, if any.
+    fn ggml_dtype(&self, bits: usize) -> Option<GgmlDType> {
+        let aligned = self.is_block_aligned();
+        match bits {
+            4 if aligned => Some(GgmlDType::Q4_1),
+            8 if aligned && self.zeros.iter().all(|z| *z == 128) => Some(GgmlDType::Q8_0),
+            _ => None,
+        }
+    }
+
+    /// Serialize the layer as raw GGML blocks of `dtype`, row by row.
+    fn to_ggml(&self, dtype: GgmlDType) -> Vec<u8> {
+        let n_blocks = self.in_dim / GGML_BLOCK_SIZE;
+        (0..self.out_dim)
+            .into_par_iter()
+            .flat_map_iter(|o| {
+                let mut row = Vec::with_capacity(n_blocks * dtype.type_size());
+                for b in 0..n_blocks {
+                    let start = b * GGML_BLOCK_SIZE;
+                    let group = self.groups[start] * self.out_dim + o;
+                    let (scale, zero) = (self.scales[group], self.zeros[group] as f32);
+                    let q = &self.q[o * self.in_dim + start..][..GGML_BLOCK_SIZE];
+                    row.extend(f16::from_f32(scale).to_le_bytes());
+                    match dtype {
+                        // `q * d + m`, the low nibbles hold the first half of the block.
+                        GgmlDType::Q4_1 => {
+                            row.extend(f16::from_f32(-scale * zero).to_le_bytes());
+                            row.extend((0..GGML_BLOCK_SIZE / 2).map(|j| q[j] | (q[j + 16] << 4)));
+                        }
+                        // `q * d` with the zero point of 128 folded into the signed value.
+                        GgmlDType::Q8_0 => {
+                            row.extend(q.iter().map(|x| (i16::from(*x) - 128) as i8 as u8));
+                        }
+                        _ => unreachable!("GPTQ/AWQ layers are only transcoded to Q4_1 or Q8_0"),
+                    }
+                }
+                row
+            })
+            .collect()
+    }
+
+    /// The reference full precision weight, `[out_dim, in_dim]`.
+    fn dequantize(&self) -> Vec<f32> {
+        (0..self.out_dim * self.in_dim)
+            .into_par_iter()
+            .map(|idx| {
+                let (o, i) = (idx / self.in_dim, idx % self.in_dim);
+                let group = self.groups[i] * self.out_dim + o;
+                self.scales[group] * (f32::from(self.q[idx]) - self.zeros[group] as f32)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{PackedLinear, QuantMethod, QuantizationConfig, AWQ_REVERSE_ORDER};
+    use candle_core::{
+        quantized::{ggml_file::qtensor_from_ggml, GgmlDType},
+        DType, Device, Tensor,
+    };
+    use candle_nn::VarBuilder;
+
+    fn packed(bits: usize, group_size: usize, sym: bool) -> PackedLinear {
+        let (in_dim, out_dim) = (64, 4);
+        let n_groups = in_dim / group_size;
+        let max = (1u32 << bits) - 1;
+        PackedLinear {
+            in_dim,
+            out_dim,
+            q: (0..in_dim * out_dim)
+                .map(|i| ((i * 7 + 3) as u32 % (max + 1)) as u8)
+                .collect(),
+            zeros: (0..n_groups * out_dim)
+                .map(|i| if sym { max / 2 + 1 } else { i as u32 % max })
+                .collect(),
+            scales: (0..n_groups * out_dim)
+                .map(|i| 0.25 + i as f32 / 16.)
+                .collect(),
+            groups: (0..in_dim).map(|i| i / group_size).collect(),
+        }
+    }
+
+    #[test]
+    fn gptq_transcoding_matches_dequantization() {
+        for (bits, sym, dtype) in [
+            (4, false, Some(GgmlDType::Q4_1)),
+            (8, true, Some(GgmlDType::Q8_0)),
+            (8, false, None),
+            (2, false, None),
+        ] {
+            let layer = packed(bits, 32, sym);
+            assert_eq!(layer.ggml_dtype(bits), dtype);
+            let Some(dtype) = dtype else {
+                continue;
+            };
+            let qtensor = qtensor_from_ggml(
+                dtype,
+                &layer.to_ggml(dtype),
+                vec![layer.out_dim, layer.in_dim],
+                &Device::Cpu,
+            )
+            .unwrap();
+            let transcoded = qtensor
+                .dequantize(&Device::Cpu)
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap();
+            for (a, b) in transcoded.iter().zip(layer.dequantize()) {
+                assert!((a - b).abs() < 1e-2, "{a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn act_order_layers_are_not_transcoded() {
+        let mut layer = packed(4, 32, false);
+        layer.groups.swap(0, 40);
+        assert_eq!(layer.ggml_dtype(4), None);
+    }
+
+    #[test]
+    fn checkpoint_tensors_are_unpacked() -> candle_core::Result<()> {
+        let (in_dim, out_dim, n_groups) = (64, 16, 2);
+        let q = |o: usize, i: usize| ((o * in_dim + i) * 5 % 16) as i64;
+        let zero = |g: usize, o: usize| ((g * out_dim + o) % 15 + 1) as i64;
+        let scale = |g: usize, o: usize| 0.5 + (g * out_dim + o) as f32 / 8.;
+        // Pack 8 values `value(k)` into an `int32`, the `k`-th one at bits `4 * shift(k)`.
+        let pack = |value: &dyn Fn(usize) -> i64, shift: &dyn Fn(usize) -> usize| {
+            (0..8).map(|k| value(k) << (4 * shift(k))).sum::<i64>()
+        };
+        // AutoAWQ packs the output features in the order 0, 2, 4, 6, 1, 3, 5, 7.
+        let awq_shift = |k: usize| {
+            [0, 2, 4, 6, 1, 3, 5, 7]
+                .iter()
+                .position(|f| *f == k)
+                .unwrap()
+        };
+        // Act-order: input features alternate between the groups.
+        let gptq_group = |i: usize| i % n_groups;
+
+        for method in [QuantMethod::Gptq, QuantMethod::Awq] {
+            let (qweight, qzeros) = match method {
+                QuantMethod::Gptq => (
+                    Tensor::from_vec(
+                        (0..in_dim / 8 * out_dim)
+                            .map(|idx| {
+                                let (r, o) = (idx / out_dim, idx % out_dim);
+                                pack(&|k| q(o, r * 8 + k), &|k| k)
+                            })
+                            .collect(),
+                        (in_dim / 8, out_dim),
+                        &Device::Cpu,
+                    )?,
+                    // GPTQ stores the zero points minus one.
+                    Tensor::from_vec(
+                        (0..n_groups * out_dim / 8)
+                            .map(|idx| {
+                                let (g, c) = (idx / (out_dim / 8), idx % (out_dim / 8));
+                                pack(&|k| zero(g, c * 8 + k) - 1, &|k| k)
+                            })
+                            .collect(),
+                        (n_groups, out_dim / 8),
+                        &Device::Cpu,
+                    )?,
+                ),
+                QuantMethod::Awq => (
+                    Tensor::from_vec(
+                        (0..in_dim * out_dim / 8)
+                            .map(|idx| {
+                                let (i, c) = (idx / (out_dim / 8), idx % (out_dim / 8));
+                                pack(&|k| q(c * 8 + k, i), &awq_shift)
+                            })
+                            .collect(),
+                        (in_dim, out_dim / 8),
+                        &Device::Cpu,
+                    )?,
+                    Tensor::from_vec(
+                        (0..n_groups * out_dim / 8)
+                            .map(|idx| {
+                                let (g, c) = (idx / (out_dim / 8), idx % (out_dim / 8));
+                                pack(&|k| zero(g, c * 8 + k), &awq_shift)
+                            })
+                            .collect(),
+                        (n_groups, out_dim / 8),
+                        &Device::Cpu,
+                    )?,
+                ),
+            };
+            let scales = Tensor::from_vec(
+                (0..n_groups * out_dim)
+                    .map(|idx| scale(idx / out_dim, idx % out_dim))
+                    .collect(),
+                (n_groups, out_dim),
+                &Device::Cpu,
+            )?;
+            let mut tensors = HashMap::from([
+                ("qweight".to_string(), qweight),
+                ("qzeros".to_string(), qzeros),
+                ("scales".to_string(), scales),
+            ]);
+            let group = |i: usize| match method {
+                QuantMethod::Gptq => gptq_group(i),
+                QuantMethod::Awq => i / (in_dim / n_groups),
+            };
+            if method == QuantMethod::Gptq {
+                let g_idx = (0..in_dim).map(|i| gptq_group(i) as i64).collect();
+                tensors.insert(
+                    "g_idx".to_string(),
+                    Tensor::from_vec(g_idx, in_dim, &Device::Cpu)?,
+                );
+            }
+
+            let quant = QuantizationConfig {
+                quant_method: method,
+                bits: 4,
+                group_size: (in_dim / n_groups) as isize,
+                version: None,
+                checkpoint_format: None,
+            };
+            let vb = VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu);
+            let layer = PackedLinear::load(in_dim, out_dim, &quant, &vb)?;
+            let expected = (0..out_dim * in_dim).map(|idx| {
+                let (o, i) = (idx / in_dim, idx % in_dim);
+                scale(group(i), o) * (q(o, i) - zero(group(i), o)) as f32
+            });
+            for (a, b) in layer.dequantize().into_iter().zip(expected) {
+                assert_eq!(a, b, "{method}");
+            }
+            let dtype = match method {
+                QuantMethod::Gptq => None,
+                QuantMethod::Awq => Some(GgmlDType::Q4_1),
+            };
+            assert_eq!(layer.ggml_dtype(4), dtype, "{method}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn awq_reverse_order_inverts_pack_order() {
+        let pack_order = [0, 2, 4, 6, 1, 3, 5, 7];
+        for (position, feature) in pack_order.into_iter().enumerate() {
+            assert_eq!(AWQ_REVERSE_ORDER[feature], position);
+        }
+    }
+}
@@ -2,11 +2,12 @@
 
 use std::{
     collections::HashMap,
+    f64::consts::PI,
     ops::{BitAnd, Mul},
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex, PoisonError,
     },
 };
 
@@ -16,6 +17,7 @@ use candle_core::{
 };
 use candle_nn::{Linear, Module, VarBuilder};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::Deserialize;
 
 pub use crate::layers_masker::CausalMasker;
 pub use crate::layers_utils::{flash_attn, repeat_kv};
@@ -242,6 +244,564 @@ impl PhiRotaryEmbedding {
     }
 }
 
+/// The RoPE scaling method, given by the `rope_type` (or legacy `type`) field of `rope_scaling`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RopeScalingType {
+    Linear,
+    Dynamic,
+    Yarn,
+    Llama3,
+}
+
+impl FromStr for RopeScalingType {
+    type Err = candle_core::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Self::Linear),
+            "dynamic" => Ok(Self::Dynamic),
+            "yarn" => Ok(Self::Yarn),
+            "llama3" => Ok(Self::Llama3),
+            _ => Err(candle_core::Error::Msg(format!(
+                "Expected one of `linear`, `dynamic`, `yarn` or `llama3` scaled RoPE type, got `{s}`."
+            ))),
+        }
+    }
+}
+
+/// RoPE scaling parameters, as found in the `rope_scaling` field of a `config.json`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "RopeScalingConfig")]
+pub struct RopeScaling {
+    pub rope_type: RopeScalingType,
+    pub factor: f64,
+    /// The context length the model was pretrained with. Defaults to `max_position_embeddings`.
+    pub original_max_position_embeddings: Option<usize>,
+    /// Llama 3 only.
+    pub low_freq_factor: Option<f64>,
+    /// Llama 3 only.
+    pub high_freq_factor: Option<f64>,
+    /// YaRN only.
+    pub beta_fast: Option<f64>,
+    /// YaRN only.
+    pub beta_slow: Option<f64>,
+    /// YaRN only: the scale applied to the cos and sin, defaults to `0.1 * ln(factor) + 1`.
+    pub attention_factor: Option<f64>,
+}
+
+impl RopeScaling {
+    pub fn new(rope_type: RopeScalingType, factor: f64) -> Self {
+        Self {
+            rope_type,
+            factor,
+            original_max_position_embeddings: None,
+            low_freq_factor: None,
+            high_freq_factor: None,
+            beta_fast: None,
+            beta_slow: None,
+            attention_factor: None,
+        }
+    }
+}
+
+// Newer configs use `rope_type`, older ones `type`, and some include both.
+#[derive(Deserialize)]
+struct RopeScalingConfig {
+    rope_type: Option<RopeScalingType>,
+    #[serde(rename = "type")]
+    legacy_type: Option<RopeScalingType>,
+    factor: f64,
+    original_max_position_embeddings: Option<usize>,
+    low_freq_factor: Option<f64>,
+    high_freq_factor: Option<f64>,
+    beta_fast: Option<f64>,
+    beta_slow: Option<f64>,
+    attention_factor: Option<f64>,
+}
+
+impl TryFrom<RopeScalingConfig> for RopeScaling {
+    type Error = String;
+
+    fn try_from(cfg: RopeScalingConfig) -> std::result::Result<Self, Self::Error> {
+        let rope_type = cfg
+            .rope_type
+            .or(cfg.legacy_type)
+            .ok_or_else(|| "`rope_scaling` must specify `rope_type` or `type`.".to_string())?;
+        Ok(Self {
+            rope_type,
+            factor: cfg.factor,
+            original_max_position_embeddings: cfg.original_max_position_embeddings,
+            low_freq_factor: cfg.low_freq_factor,
+            high_freq_factor: cfg.high_freq_factor,
+            beta_fast: cfg.beta_fast,
+            beta_slow: cfg.beta_slow,
+            attention_factor: cfg.attention_factor,
+        })
+    }
+}
+
+/// RoPE supporting the linear, dynamic NTK, YaRN and Llama 3 scaling methods, as well as
+/// per-frequency factors (used by Llama 3 GGUF files).
+///
+/// Without scaling, this is the fused [`candle_nn::RotaryEmbedding`]. With scaling, the sin and
+/// cos table only grows to the positions which were used, so no table over the (potentially very
+/// long) extended context is kept upfront. The unscaled variant also keeps the equivalent
+/// [`ScaledRope`] to shift cached keys.
+#[derive(Debug, Clone)]
+pub enum ScaledRotaryEmbedding {
+    Unscaled(candle_nn::RotaryEmbedding, ScaledRope),
+    Scaled(ScaledRope),
+}
+
+#[derive(Debug, Clone)]
+pub struct ScaledRope {
+    /// Shape (1, rot_dim / 2)
+    inv_freq: Tensor,
+    /// Scale applied to the sin and cos.
+    attention_factor: f64,
+    dynamic: Option<DynamicNtkParams>,
+    base: f64,
+    rot_dim: usize,
+    is_gpt_neox: bool,
+    /// sin and cos of the positions used so far, shared by the clones.
+    sin_cos: Arc<Mutex<Option<SinCosTable>>>,
+}
+
+/// sin and cos of positions `0..len`, each of shape (len, rot_dim / 2).
+#[derive(Debug)]
+struct SinCosTable {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+/// The sin and cos table grows to a power of two of at least this many positions.
+const MIN_SIN_COS_POSITIONS: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct DynamicNtkParams {
+    factor: f64,
+    original_max_position_embeddings: usize,
+}
+
+fn rope_inv_freq(base: f64, rot_dim: usize) -> Vec<f64> {
+    (0..rot_dim)
+        .step_by(2)
+        .map(|i| 1. / base.powf(i as f64 / rot_dim as f64))
+        .collect()
+}
+
+/// Inverse frequencies for YaRN: interpolate the low frequencies and extrapolate the high
+/// frequencies, with a linear ramp in between.
+fn yarn_inv_freq(
+    base: f64,
+    rot_dim: usize,
+    factor: f64,
+    original_max_position_embeddings: usize,
+    beta_fast: f64,
+    beta_slow: f64,
+) -> Vec<f64> {
+    // The dimension at which the given number of rotations happen over the original context.
+    let correction_dim = |num_rotations: f64| {
+        (rot_dim as f64
+            * (original_max_position_embeddings as f64 / (num_rotations * 2. * PI)).ln())
+            / (2. * base.ln())
+    };
+    let low = correction_dim(beta_fast).floor().max(0.);
+    let high = correction_dim(beta_slow).ceil().min(rot_dim as f64 - 1.);
+    let high = if low == high { high + 0.001 } else { high };
+
+    rope_inv_freq(base, rot_dim)
+        .into_iter()
+        .enumerate()
+        .map(|(i, extrapolation)| {
+            let interpolation = extrapolation / factor;
+            let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+            let extrapolation_factor = 1. - ramp;
+            interpolation * (1. - extrapolation_factor) + extrapolation * extrapolation_factor
+        })
+        .collect()
+}
+
+/// Inverse frequencies for Llama 3: scale the low frequencies by `factor`, keep the high
+/// frequencies and smoothly interpolate in between.
+fn llama3_inv_freq(
+    base: f64,
+    rot_dim: usize,
+    factor: f64,
+    original_max_position_embeddings: usize,
+    low_freq_factor: f64,
+    high_freq_factor: f64,
+) -> Vec<f64> {
+    let original = original_max_position_embeddings as f64;
+    let low_freq_wavelen = original / low_freq_factor;
+    let high_freq_wavelen = original / high_freq_factor;
+    rope_inv_freq(base, rot_dim)
+        .into_iter()
+        .map(|freq| {
+            let wavelen = 2. * PI / freq;
+            if wavelen < high_freq_wavelen {
+                freq
+            } else if wavelen > low_freq_wavelen {
+                freq / factor
+            } else {
+                let smooth =
+                    (original / wavelen - low_freq_factor) / (high_freq_factor - low_freq_factor);
+                (1. - smooth) * freq / factor + smooth * freq
+            }
+        })
+        .collect()
+}
+
+impl ScaledRotaryEmbedding {
+    pub fn new(
+        base: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        rope_scaling: Option<&RopeScaling>,
+        dev: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
+    ) -> Result<Self> {
+        Self::new_partial(
+            base,
+            head_dim,
+            head_dim,
+            max_position_embeddings,
+            rope_scaling,
+            dev,
+            is_gpt_neox,
+            dtype,
+        )
+    }
+
+    /// Only the first `rot_dim` dimensions of each head are rotated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_partial(
+        base: f32,
+        head_dim: usize,
+        rot_dim: usize,
+        max_position_embeddings: usize,
+        rope_scaling: Option<&RopeScaling>,
+        dev: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
+    ) -> Result<Self> {
+        let Some(scaling) = rope_scaling else {
+            return Ok(Self::Unscaled(
+                candle_nn::RotaryEmbedding::new_partial(
+                    base,
+                    head_dim,
+                    rot_dim,
+                    max_position_embeddings,
+                    dev,
+                    is_gpt_neox,
+                    dtype,
+                )?,
+                ScaledRope::new(
+                    rope_inv_freq(base as f64, rot_dim),
+                    1.,
+                    None,
+                    base as f64,
+                    rot_dim,
+                    is_gpt_neox,
+                    dev,
+                )?,
+            ));
+        };
+        let base = base as f64;
+        let factor = scaling.factor;
+        let original_max_position_embeddings = scaling
+            .original_max_position_embeddings
+            .unwrap_or(max_position_embeddings);
+
+        let mut attention_factor = 1.;
+        let mut dynamic = None;
+        let inv_freq = match scaling.rope_type {
+            RopeScalingType::Linear => rope_inv_freq(base, rot_dim)
+                .into_iter()
+                .map(|freq| freq / factor)
+                .collect(),
+            RopeScalingType::Dynamic => {
+                dynamic = Some(DynamicNtkParams {
+                    factor,
+                    original_max_position_embeddings,
+                });
+                rope_inv_freq(base, rot_dim)
+            }
+            RopeScalingType::Yarn => {
+                attention_factor = scaling.attention_factor.unwrap_or(if factor > 1. {
+                    0.1 * factor.ln() + 1.
+                } else {
+                    1.
+                });
+                yarn_inv_freq(
+                    base,
+                    rot_dim,
+                    factor,
+                    original_max_position_embeddings,
+                    scaling.beta_fast.unwrap_or(32.),
+                    scaling.beta_slow.unwrap_or(1.),
+                )
+            }
+            RopeScalingType::Llama3 => llama3_inv_freq(
+                base,
+                rot_dim,
+                factor,
+                original_max_position_embeddings,
+                scaling.low_freq_factor.unwrap_or(1.),
+                scaling.high_freq_factor.unwrap_or(4.),
+            ),
+        };
+        Ok(Self::Scaled(ScaledRope::new(
+            inv_freq,
+            attention_factor,
+            dynamic,
+            base,
+            rot_dim,
+            is_gpt_neox,
+            dev,
+        )?))
+    }
+
+    /// RoPE where each frequency is divided by the corresponding entry of `freq_factors`. This is
+    /// how GGUF files store the Llama 3 frequency scheme (the `rope_freqs.weight` tensor).
+    pub fn from_freq_factors(
+        base: f32,
+        rot_dim: usize,
+        freq_factors: &[f32],
+        dev: &Device,
+        is_gpt_neox: bool,
+    ) -> Result<Self> {
+        if freq_factors.len() != rot_dim / 2 {
+            candle_core::bail!(
+                "Expected {} RoPE frequency factors, got {}.",
+                rot_dim / 2,
+                freq_factors.len()
+            );
+        }
+        let inv_freq = rope_inv_freq(base as f64, rot_dim)
+            .into_iter()
+            .zip(freq_factors)
+            .map(|(freq, factor)| freq / *factor as f64)
+            .collect();
+        Ok(Self::Scaled(ScaledRope::new(
+            inv_freq,
+            1.,
+            None,
+            base as f64,
+            rot_dim,
+            is_gpt_neox,
+            dev,
+        )?))
+    }
+
+    /// Apply RoPE to `q` and `k`, which are of shape (b_sz * seq_len, n_heads, head_dim). They
+    /// may be returned with shape (b_sz, n_heads, seq_len, head_dim).
+    pub fn forward(
+        &self,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: &Tensor,
+        q: &mut Tensor,
+        k: &mut Tensor,
+        b_sz: usize,
+    ) -> Result<()> {
+        match self {
+            Self::Unscaled(rope, _) => {
+                rope.forward(seqlen_offsets, start_offsets_kernel, q, k, b_sz)
+            }
+            Self::Scaled(rope) => rope.forward(seqlen_offsets, q, k, b_sz),
+        }
+    }
+
+    /// Move cached keys of shape (b_sz, n_kv_heads, seq_len, head_dim), which were rotated for
+    /// their positions, back by `delta` positions.
+    pub fn shift_keys(&self, k: &Tensor, delta: usize) -> Result<Tensor> {
+        match self {
+            Self::Unscaled(_, rope) | Self::Scaled(rope) => rope.shift(k, delta),
+        }
+    }
+}
+
+impl ScaledRope {
+    fn new(
+        inv_freq: Vec<f64>,
+        attention_factor: f64,
+        dynamic: Option<DynamicNtkParams>,
+        base: f64,
+        rot_dim: usize,
+        is_gpt_neox: bool,
+        dev: &Device,
+    ) -> Result<Self> {
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(
+            inv_freq.into_iter().map(|x| x as f32).collect::<Vec<_>>(),
+            (1, inv_freq_len),
+            dev,
+        )?;
+        Ok(Self {
+            inv_freq,
+            attention_factor,
+            dynamic,
+            base,
+            rot_dim,
+            is_gpt_neox,
+            sin_cos: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Whether dynamic NTK rescaled the base for a sequence of `seq_len` tokens.
+    fn is_rescaled(&self, seq_len: usize) -> bool {
+        self.dynamic
+            .as_ref()
+            .is_some_and(|dynamic| seq_len > dynamic.original_max_position_embeddings)
+    }
+
+    /// Dynamic NTK rescales the base once the sequence grows past the original context.
+    fn inv_freq(&self, seq_len: usize) -> Result<Tensor> {
+        match &self.dynamic {
+            Some(DynamicNtkParams {
+                factor,
+                original_max_position_embeddings,
+            }) if self.is_rescaled(seq_len) => {
+                let rot_dim = self.rot_dim as f64;
+                let base = self.base
+                    * ((factor * seq_len as f64 / *original_max_position_embeddings as f64)
+                        - (factor - 1.))
+                        .powf(rot_dim / (rot_dim - 2.));
+                let inv_freq = rope_inv_freq(base, self.rot_dim)
+                    .into_iter()
+                    .map(|x| x as f32)
+                    .collect::<Vec<_>>();
+                Tensor::from_vec(inv_freq, (1, self.rot_dim / 2), self.inv_freq.device())
+            }
+            _ => Ok(self.inv_freq.clone()),
+        }
+    }
+
+    /// Returns (sin, cos), each of shape (seq_len, rot_dim / 2)
+    fn sin_cos(
+        &self,
+        inv_freq: &Tensor,
+        offset: usize,
+        seq_len: usize,
+        dtype: DType,
+    ) -> Result<(Tensor, Tensor)> {
+        let t = Tensor::arange(offset as u32, (offset + seq_len) as u32, inv_freq.device())?
+            .to_dtype(DType::F32)?
+            .reshape((seq_len, 1))?;
+        let freqs = t.matmul(inv_freq)?;
+        let sin = (freqs.sin()? * self.attention_factor)?.to_dtype(dtype)?;
+        let cos = (freqs.cos()? * self.attention_factor)?.to_dtype(dtype)?;
+        Ok((sin, cos))
+    }
+
+    /// The sin and cos of the unrescaled frequencies, from the table of the positions used so far.
+    fn cached_sin_cos(
+        &self,
+        offset: usize,
+        seq_len: usize,
+        dtype: DType,
+    ) -> Result<(Tensor, Tensor)> {
+        let end = offset + seq_len;
+        // The table is only replaced whole, so it is still valid if a panic poisoned the mutex.
+        let mut table = self.sin_cos.lock().unwrap_or_else(PoisonError::into_inner);
+        let stale = match table.as_ref() {
+            Some(table) => table.sin.dim(0)? < end || table.sin.dtype() != dtype,
+            None => true,
+        };
+        if stale {
+            let len = end.max(MIN_SIN_COS_POSITIONS).next_power_of_two();
+            let (sin, cos) = self.sin_cos(&self.inv_freq, 0, len, dtype)?;
+            *table = Some(SinCosTable { sin, cos });
+        }
+        let SinCosTable { sin, cos } = table.as_ref().unwrap();
+        Ok((
+            sin.narrow(0, offset, seq_len)?,
+            cos.narrow(0, offset, seq_len)?,
+        ))
+    }
+
+    /// `xs` is of shape (1, n_heads, seq_len, head_dim)
+    fn apply(&self, xs: &Tensor, sin: &Tensor, cos: &Tensor) -> Result<Tensor> {
+        let head_dim = xs.dim(D::Minus1)?;
+        let (xs_rot, xs_pass) = if self.rot_dim < head_dim {
+            (
+                xs.narrow(D::Minus1, 0, self.rot_dim)?,
+                Some(xs.narrow(D::Minus1, self.rot_dim, head_dim - self.rot_dim)?),
+            )
+        } else {
+            (xs.clone(), None)
+        };
+        let xs_rot = xs_rot.contiguous()?;
+        let xs_rot = if self.is_gpt_neox {
+            candle_nn::rotary_emb::rope(&xs_rot, cos, sin)?
+        } else {
+            candle_nn::rotary_emb::rope_i(&xs_rot, cos, sin)?
+        };
+        match xs_pass {
+            Some(xs_pass) => Tensor::cat(&[xs_rot, xs_pass], D::Minus1),
+            None => Ok(xs_rot),
+        }
+    }
+
+    /// Rotating by a position difference is the same for every position, so the keys are rotated
+    /// by the angles of `-delta`. The attention factor is already applied to the keys.
+    fn shift(&self, k: &Tensor, delta: usize) -> Result<Tensor> {
+        let seq_len = k.dim(2)?;
+        let freqs = (self.inv_freq.clone() * -(delta as f64))?;
+        let sin = freqs
+            .sin()?
+            .to_dtype(k.dtype())?
+            .broadcast_as((seq_len, self.rot_dim / 2))?
+            .contiguous()?;
+        let cos = freqs
+            .cos()?
+            .to_dtype(k.dtype())?
+            .broadcast_as((seq_len, self.rot_dim / 2))?
+            .contiguous()?;
+        self.apply(k, &sin, &cos)
+    }
+
+    fn forward(
+        &self,
+        seqlen_offsets: &[usize],
+        q: &mut Tensor,
+        k: &mut Tensor,
+        b_sz: usize,
+    ) -> Result<()> {
+        let (b_sz_seq_len, n_heads, head_dim) = q.dims3()?;
+        let (_, n_kv_heads, _) = k.dims3()?;
+        let seq_len = b_sz_seq_len / b_sz;
+        let q_4d = q
+            .reshape((b_sz, seq_len, n_heads, head_dim))?
+            .transpose(1, 2)?;
+        let k_4d = k
+            .reshape((b_sz, seq_len, n_kv_heads, head_dim))?
+            .transpose(1, 2)?;
+
+        let max_seq_len = seqlen_offsets.iter().max().copied().unwrap_or(0) + seq_len;
+        // The rescaled frequencies change with the sequence length, so they are not cached.
+        let rescaled_inv_freq = if self.is_rescaled(max_seq_len) {
+            Some(self.inv_freq(max_seq_len)?)
+        } else {
+            None
+        };
+        let mut q_embeds = Vec::new();
+        let mut k_embeds = Vec::new();
+        for (i, offset) in seqlen_offsets.iter().enumerate() {
+            let (sin, cos) = match &rescaled_inv_freq {
+                Some(inv_freq) => self.sin_cos(inv_freq, *offset, seq_len, q.dtype())?,
+                None => self.cached_sin_cos(*offset, seq_len, q.dtype())?,
+            };
+            q_embeds.push(self.apply(&q_4d.i(i)?.unsqueeze(0)?, &sin, &cos)?);
+            k_embeds.push(self.apply(&k_4d.i(i)?.unsqueeze(0)?, &sin, &cos)?);
+        }
+        *q = Tensor::cat(&q_embeds, 0)?.contiguous()?;
+        *k = Tensor::cat(&k_embeds, 0)?.contiguous()?;
+        Ok(())
+    }
+}
+
 /// Matrix multiplcation, configurable to be via f16 (to use the faster GEMM kernels) optionally.
 pub struct MatMul;
 
@@ -510,6 +1070,11 @@ impl QLinear {
         }
     }
 
+    /// A layer from an already loaded (possibly quantized) weight. Outputs are cast to `dtype`.
+    pub fn from_qmatmul(inner: QMatMul, bias: Option<Tensor>, dtype: DType) -> Self {
+        Self { inner, bias, dtype }
+    }
+
     pub fn inner(&mut self) -> &mut QMatMul {
         &mut self.inner
     }
@@ -770,3 +1335,159 @@ mod tests {
         );
     }
 }
+
+#[cfg(test)]
+mod rope_tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{
+        llama3_inv_freq, rope_inv_freq, yarn_inv_freq, RopeScaling, RopeScalingType, ScaledRope,
+        ScaledRotaryEmbedding,
+    };
+
+    fn assert_reference(inv_freq: &[f64], reference: &[(usize, f64)]) {
+        assert_eq!(inv_freq.len(), 64);
+        for (i, expected) in reference {
+            let actual = inv_freq[*i];
+            assert!(
+                ((actual - expected) / expected).abs() < 1e-9,
+                "inv_freq[{i}] = {actual}, expected {expected}"
+            );
+        }
+    }
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    /// Reference values of HF transformers' `_compute_yarn_parameters` for a Qwen2-style config:
+    /// rope_theta=1e6, head_dim=128, factor=4, original_max_position_embeddings=32768. The ramp
+    /// spans dimensions 23 to 40.
+    #[test]
+    fn yarn_reference_values() -> Result<()> {
+        let inv_freq = yarn_inv_freq(1e6, 128, 4., 32768, 32., 1.);
+        assert_reference(
+            &inv_freq,
+            &[
+                (0, 1.0),
+                (10, 0.1154781984689458),
+                (20, 0.01333521432163324),
+                (23, 0.006978305848598663),
+                (25, 0.004131738022518394),
+                (30, 0.0010643609812470017),
+                (39, 6.490394320837028e-05),
+                (40, 4.445698525097307e-05),
+                (50, 5.133812566142866e-06),
+                (63, 3.102344401879299e-07),
+            ],
+        );
+
+        let mut scaling = RopeScaling::new(RopeScalingType::Yarn, 4.);
+        scaling.original_max_position_embeddings = Some(32768);
+        let rope = ScaledRotaryEmbedding::new(
+            1e6,
+            128,
+            131072,
+            Some(&scaling),
+            &Device::Cpu,
+            true,
+            DType::F32,
+        )?;
+        let ScaledRotaryEmbedding::Scaled(rope) = rope else {
+            panic!("Expected a scaled RoPE.");
+        };
+        assert!((rope.attention_factor - 1.138629436111989).abs() < 1e-12);
+        Ok(())
+    }
+
+    /// Reference values of HF transformers' `_compute_llama3_parameters` for the Llama 3.1 config:
+    /// rope_theta=500000, head_dim=128, factor=8, low_freq_factor=1, high_freq_factor=4,
+    /// original_max_position_embeddings=8192. Dimensions 29 to 34 are smoothed.
+    #[test]
+    fn llama3_reference_values() {
+        let inv_freq = llama3_inv_freq(500000., 128, 8., 8192, 1., 4.);
+        assert_reference(
+            &inv_freq,
+            &[
+                (0, 1.0),
+                (20, 0.016560440080994446),
+                (29, 0.002166570763503359),
+                (31, 0.0008567514129196321),
+                (34, 0.00017850781276799638),
+                (40, 3.428102195952591e-05),
+                (45, 1.2297638677963614e-05),
+                (63, 3.068925988914511e-07),
+            ],
+        );
+    }
+
+    #[test]
+    fn sin_cos_table_grows_with_positions() -> Result<()> {
+        let rope = ScaledRope::new(
+            rope_inv_freq(10000., 8),
+            1.5,
+            None,
+            10000.,
+            8,
+            true,
+            &Device::Cpu,
+        )?;
+        for (offset, seq_len, table_len) in [(0, 4, 1024), (1500, 3, 2048), (7, 2, 2048)] {
+            let (sin, cos) = rope.cached_sin_cos(offset, seq_len, DType::F32)?;
+            let (sin_ref, cos_ref) = rope.sin_cos(&rope.inv_freq, offset, seq_len, DType::F32)?;
+            assert_eq!(sin.dims(), &[seq_len, 4]);
+            assert!(max_abs_diff(&sin, &sin_ref)? < 1e-6);
+            assert!(max_abs_diff(&cos, &cos_ref)? < 1e-6);
+            let table = rope.sin_cos.lock().unwrap();
+            assert_eq!(table.as_ref().unwrap().sin.dim(0)?, table_len);
+        }
+        Ok(())
+    }
+    /// Shifting keys rotated for position `p` back by `delta` gives the keys rotated for
+    /// `p - delta`.
+    #[test]
+    fn shift_keys_matches_rotation_at_new_position() -> Result<()> {
+        let (seq_len, n_heads, head_dim) = (3, 2, 8);
+        let (position, delta) = (100, 37);
+        let mut llama3 = RopeScaling::new(RopeScalingType::Llama3, 8.);
+        llama3.original_max_position_embeddings = Some(64);
+        let mut yarn = RopeScaling::new(RopeScalingType::Yarn, 4.);
+        yarn.original_max_position_embeddings = Some(64);
+        let q = Tensor::randn(0f32, 1., (seq_len, n_heads, head_dim), &Device::Cpu)?;
+        let k = Tensor::randn(0f32, 1., (seq_len, n_heads, head_dim), &Device::Cpu)?;
+        for scaling in [None, Some(&llama3), Some(&yarn)] {
+            for is_gpt_neox in [true, false] {
+                let rope = ScaledRotaryEmbedding::new(
+                    10000.,
+                    head_dim,
+                    4096,
+                    scaling,
+                    &Device::Cpu,
+                    is_gpt_neox,
+                    DType::F32,
+                )?;
+                // The keys at `offset`, of shape (1, n_heads, seq_len, head_dim).
+                let rotate = |offset: usize| -> Result<Tensor> {
+                    let positions = (offset as i64..(offset + seq_len) as i64).collect::<Vec<_>>();
+                    let kernel = Tensor::from_slice(&positions, seq_len, &Device::Cpu)?;
+                    let (mut q, mut k) = (q.clone(), k.clone());
+                    rope.forward(&[offset], &kernel.unsqueeze(0)?, &mut q, &mut k, 1)?;
+                    match k.rank() {
+                        3 => k
+                            .reshape((1, seq_len, n_heads, head_dim))?
+                            .transpose(1, 2)?
+                            .contiguous(),
+                        _ => Ok(k),
+                    }
+                };
+                let shifted = rope.shift_keys(&rotate(position)?, delta)?;
+                let diff = max_abs_diff(&shifted, &rotate(position - delta)?)?;
+                assert!(
+                    diff < 1e-4,
+                    "{scaling:?}, is_gpt_neox = {is_gpt_neox}: {diff}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
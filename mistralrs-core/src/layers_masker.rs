@@ -4,6 +4,8 @@ use std::ops::Add;
 
 use candle_core::{DType, Device, Result, Tensor, WithDType};
 
+use crate::pipeline::KvCacheEntry;
+
 // https://github.com/huggingface/transformers/blob/main/src/transformers/modeling_attn_mask_utils.py
 pub struct CausalMasker;
 
@@ -64,22 +66,14 @@ impl CausalMasker {
         )
     }
 
-    pub fn calculate_past_kv_len(
-        &self,
-        cache: &[Option<(Tensor, Tensor)>],
-    ) -> candle_core::Result<usize> {
-        let kv_cache_1 = &cache[0];
-        if kv_cache_1.is_none() {
-            return Ok(0);
-        }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        return Ok(k_cache_1.dims()[2]);
+    pub fn calculate_past_kv_len(&self, cache: &[KvCacheEntry]) -> candle_core::Result<usize> {
+        Ok(cache[0].seq_len().unwrap_or(0))
     }
 
     pub fn make_causal_mask_as_attn_bias(
         &self,
         input_ids: &Tensor,
-        cache: &[Option<(Tensor, Tensor)>],
+        cache: &[KvCacheEntry],
         dtype: DType,
         n_attn_heads: usize,
     ) -> Result<Option<Tensor>> {
@@ -121,7 +115,7 @@ impl CausalMasker {
     pub fn make_causal_mask_with_sliding_window_as_attn_bias(
         &self,
         input_ids: &Tensor,
-        cache: &[Option<(Tensor, Tensor)>],
+        cache: &[KvCacheEntry],
         sliding_window: Option<usize>,
         dtype: DType,
         n_attn_heads: usize,
@@ -177,7 +171,7 @@ impl CausalMasker {
     pub fn make_causal_mask(
         &self,
         input_ids: &Tensor,
-        cache: &[Option<(Tensor, Tensor)>],
+        cache: &[KvCacheEntry],
     ) -> Result<Option<Tensor>> {
         let past_kv_len = self.calculate_past_kv_len(cache)?;
         let (b_sz, tgt_len) = input_ids.dims2()?;
@@ -201,7 +195,7 @@ impl CausalMasker {
     pub fn make_causal_mask_with_sliding_window(
         &self,
         input_ids: &Tensor,
-        cache: &[Option<(Tensor, Tensor)>],
+        cache: &[KvCacheEntry],
         sliding_window: Option<usize>,
     ) -> Result<Option<Tensor>> {
         if sliding_window.is_none() {
@@ -1,19 +1,28 @@
 #![deny(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use cublaslt::setup_cublas_lt_wrapper;
-use engine::Engine;
-pub use engine::TERMINATE_ALL_NEXT_STEP;
-pub use lora::Ordering;
+use engine::{Engine, EngineState};
+pub use engine::{EngineStatus, TERMINATE_ALL_NEXT_STEP};
+pub use lora::{merge_weighted_adapters, Ordering};
 use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
+use prefix_cacher::PrefixCacheConfig;
+pub use prefix_cacher::{
+    PrefixCacheEviction, PrefixCacheStats, DEFAULT_PREFIX_CACHE_HOST_BYTES, DEFAULT_SESSION_TTL,
+};
 use std::{
     cell::RefCell,
+    collections::HashMap,
     error::Error,
     fs::OpenOptions,
     io::Write,
-    sync::{atomic::AtomicBool, Arc, Mutex},
-    thread,
-    time::{SystemTime, UNIX_EPOCH},
+    path::PathBuf,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{channel, Sender};
 
@@ -28,6 +37,7 @@ pub use model_selected::ModelSelected;
 
 mod cublaslt;
 mod gguf;
+mod gptq;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
@@ -46,15 +56,19 @@ mod xlora_models;
 
 pub use device_map::{DeviceMapMetadata, LayerDeviceMapper};
 pub use pipeline::{
-    chat_template::ChatTemplate, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFArchitecture, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, LlamaLoader,
-    Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
-    Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
-    TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionModelLoader,
-    VisionSpecificConfig,
+    chat_template::ChatTemplate, default_isq_artifacts_dir, parse_isq_value, ContextShift,
+    GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFArchitecture, GGUFLoader,
+    GGUFLoaderBuilder, GGUFSpecificConfig, Gemma2Loader, GemmaLoader, IsqPolicy, KvCacheQuant,
+    LlamaLoader, Loader, LocalModelPaths, LoraAdapterFiles, MistralLoader, MixtralLoader,
+    ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
+    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
+    SpeculativeLoader, SpeculativePipeline, TokenSource, VisionLoader, VisionLoaderBuilder,
+    VisionLoaderType, VisionModelLoader, VisionSpecificConfig,
+};
+pub use request::{
+    parse_timeout, Constraint, MessageContent, NormalRequest, Request, RequestMessage,
+    TruncationStrategy, XLoraScalingsOutput,
 };
-pub use request::{Constraint, MessageContent, NormalRequest, Request, RequestMessage};
 pub use response::Response;
 pub use response::*;
 pub use sampler::{SamplingParams, StopTokens, TopLogprob};
@@ -76,6 +90,12 @@ pub struct MistralRs {
     id: String,
     creation_time: u64,
     next_request_id: Mutex<RefCell<usize>>,
+    engine_handler: Mutex<Option<JoinHandle<()>>>,
+    engine_state: Arc<RwLock<EngineState>>,
+    is_shutting_down: AtomicBool,
+    kind: String,
+    device: String,
+    max_seqs: usize,
 }
 
 /// The MistralRsBuilder takes the pipeline and a scheduler method and constructs
@@ -89,8 +109,19 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_device_bytes: Option<usize>,
+    prefix_cache_host_bytes: Option<usize>,
+    prefix_cache_eviction: Option<PrefixCacheEviction>,
+    prefix_cache_block_size: Option<usize>,
+    prefix_cache_disk_dir: Option<PathBuf>,
+    prefix_cache_disk_bytes: Option<usize>,
+    session_device_bytes: Option<usize>,
+    session_ttl: Option<Duration>,
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
+    fair_queuing: Option<HashMap<String, f64>>,
+    kv_cache_quant: Option<KvCacheQuant>,
+    context_shift: Option<ContextShift>,
 }
 
 impl MistralRsBuilder {
@@ -103,8 +134,19 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_device_bytes: None,
+            prefix_cache_host_bytes: None,
+            prefix_cache_eviction: None,
+            prefix_cache_block_size: None,
+            prefix_cache_disk_dir: None,
+            prefix_cache_disk_bytes: None,
+            session_device_bytes: None,
+            session_ttl: None,
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
+            fair_queuing: None,
+            kv_cache_quant: None,
+            context_shift: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -131,6 +173,47 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Limit the size of the prefix caches on the device. Caches over the limit are moved to the host.
+    pub fn with_prefix_cache_device_bytes(mut self, bytes: usize) -> Self {
+        self.prefix_cache_device_bytes = Some(bytes);
+        self
+    }
+    /// Limit the size of the prefix caches on the host. Caches over the limit are deleted. Defaults to
+    /// [`DEFAULT_PREFIX_CACHE_HOST_BYTES`].
+    pub fn with_prefix_cache_host_bytes(mut self, bytes: usize) -> Self {
+        self.prefix_cache_host_bytes = Some(bytes);
+        self
+    }
+    pub fn with_prefix_cache_eviction(mut self, eviction: PrefixCacheEviction) -> Self {
+        self.prefix_cache_eviction = Some(eviction);
+        self
+    }
+    /// Reuse the cache of prompts which only partially match a prefix cache in blocks of this many tokens.
+    pub fn with_prefix_cache_block_size(mut self, block_size: usize) -> Self {
+        self.prefix_cache_block_size = Some(block_size);
+        self
+    }
+    /// Save the prefix caches deleted from the host to this directory, and reuse them after a restart.
+    pub fn with_prefix_cache_disk_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.prefix_cache_disk_dir = Some(dir.into());
+        self
+    }
+    /// Limit the size of the prefix caches on the disk. The least recently used caches are deleted.
+    pub fn with_prefix_cache_disk_bytes(mut self, bytes: usize) -> Self {
+        self.prefix_cache_disk_bytes = Some(bytes);
+        self
+    }
+    /// Limit the size of the sessions on the device. The least recently used sessions are moved to the host.
+    pub fn with_session_device_bytes(mut self, bytes: usize) -> Self {
+        self.session_device_bytes = Some(bytes);
+        self
+    }
+    /// Unpin the sessions unused for this long, saving them to the prefix cache disk directory if one is set.
+    /// Defaults to [`DEFAULT_SESSION_TTL`].
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
@@ -139,6 +222,23 @@ impl MistralRsBuilder {
         self.gemm_full_precision_f16 = Some(gemm_full_precision);
         self
     }
+    /// Enable weighted fair queuing between tenants (the request `user`). Tenants without a weight get 1.0.
+    pub fn with_fair_queuing(mut self, tenant_weights: HashMap<String, f64>) -> Self {
+        self.fair_queuing = Some(tenant_weights);
+        self
+    }
+
+    /// Store the KV cache quantized. Like the GEMM precision, this applies to every model in the process.
+    pub fn with_kv_cache_quant(mut self, kv_cache_quant: KvCacheQuant) -> Self {
+        self.kv_cache_quant = Some(kv_cache_quant);
+        self
+    }
+
+    /// Keep generating past the maximum sequence length by discarding the middle of the KV cache.
+    pub fn with_context_shift(mut self, context_shift: ContextShift) -> Self {
+        self.context_shift = Some(context_shift);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -192,8 +292,19 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_device_bytes,
+            prefix_cache_host_bytes,
+            prefix_cache_eviction,
+            prefix_cache_block_size,
+            prefix_cache_disk_dir,
+            prefix_cache_disk_bytes,
+            session_device_bytes,
+            session_ttl,
             disable_eos_stop,
             gemm_full_precision_f16,
+            fair_queuing,
+            kv_cache_quant,
+            context_shift,
         } = config;
 
         let model_supports_reduced_gemm = match pipeline.try_lock().unwrap().category() {
@@ -204,26 +315,49 @@ impl MistralRs {
             set_gemm_reduced_precision_f16();
         }
         setup_cublas_lt_wrapper();
+        if let Some(kv_cache_quant) = kv_cache_quant {
+            tracing::info!("Using a {kv_cache_quant} quantized KV cache.");
+        }
+        pipeline
+            .try_lock()
+            .unwrap()
+            .set_kv_cache_quant(kv_cache_quant);
 
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
-        let prefix_cache_n = prefix_cache_n.unwrap_or(16);
+        let prefix_cache_config = PrefixCacheConfig {
+            n_on_device: prefix_cache_n.unwrap_or(16),
+            device_bytes: prefix_cache_device_bytes,
+            host_bytes: Some(prefix_cache_host_bytes.unwrap_or(DEFAULT_PREFIX_CACHE_HOST_BYTES)),
+            eviction: prefix_cache_eviction.unwrap_or_default(),
+            block_size: prefix_cache_block_size.unwrap_or(16),
+            disk_dir: prefix_cache_disk_dir,
+            disk_bytes: prefix_cache_disk_bytes,
+            session_device_bytes,
+            session_ttl: Some(session_ttl.unwrap_or(DEFAULT_SESSION_TTL)),
+        };
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
 
         let (tx, rx) = channel(10_000);
 
-        let this = Arc::new(Self {
-            sender: tx,
-            log,
-            id: pipeline.try_lock().unwrap().name(),
-            creation_time: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time travel has occurred!")
-                .as_secs(),
-            next_request_id: Mutex::new(RefCell::new(0)),
-        });
-        thread::spawn(move || {
+        let (id, kind, device, isq, adapters) = {
+            let pipeline = pipeline.try_lock().unwrap();
+            let metadata = pipeline.get_metadata();
+            (
+                pipeline.name(),
+                metadata.kind.to_string(),
+                format!("{:?}", pipeline.device()),
+                metadata.isq,
+                metadata.adapters.clone(),
+            )
+        };
+        let max_seqs = match &method {
+            SchedulerMethod::Fixed(n) => **n,
+        };
+        let engine_state = Arc::new(RwLock::new(EngineState::new(isq, adapters)));
+        let engine_state_clone = engine_state.clone();
+        let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async move {
                 let mut engine = Engine::new(
@@ -233,14 +367,32 @@ impl MistralRs {
                     truncate_sequence,
                     no_kv_cache,
                     no_prefix_cache,
-                    prefix_cache_n,
+                    prefix_cache_config,
                     disable_eos_stop,
+                    fair_queuing,
+                    context_shift,
+                    engine_state_clone,
                 );
                 engine.run().await;
             });
         });
 
-        this
+        Arc::new(Self {
+            sender: tx,
+            log,
+            id,
+            creation_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time travel has occurred!")
+                .as_secs(),
+            next_request_id: Mutex::new(RefCell::new(0)),
+            engine_handler: Mutex::new(Some(engine_handler)),
+            engine_state,
+            is_shutting_down: AtomicBool::new(false),
+            kind,
+            device,
+            max_seqs,
+        })
     }
 
     pub fn get_sender(&self) -> Sender<Request> {
@@ -263,6 +415,151 @@ impl MistralRs {
         last_v
     }
 
+    /// Get a snapshot of the engine state. This does not wait for the current engine step to finish.
+    pub fn engine_status(&self) -> EngineStatus {
+        let state = self.engine_state.read().unwrap();
+        let is_alive = self
+            .engine_handler
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|h| !h.is_finished());
+        EngineStatus {
+            model_id: self.id.clone(),
+            kind: self.kind.clone(),
+            device: self.device.clone(),
+            isq: state.isq.map(|isq| format!("{isq:?}")),
+            active_adapters: state.active_adapters.clone(),
+            adapters: state.adapters.clone(),
+            running_seqs: state.running_seqs,
+            waiting_seqs: state.waiting_seqs,
+            max_seqs: self.max_seqs,
+            is_alive,
+            is_idle: state.is_idle,
+            secs_since_last_step: state.last_step.elapsed().as_secs_f64(),
+            is_shutting_down: self.is_shutting_down(),
+            prefix_cache: state.prefix_cache,
+        }
+    }
+
+    /// Whether [`MistralRs::shutdown`] has been called. New requests will be rejected by the engine.
+    pub fn is_shutting_down(&self) -> bool {
+        self.is_shutting_down.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Stop accepting new requests, wait for the running requests to finish, and stop the engine.
+    /// Requests still running after `drain_timeout` are stopped and return their partial output.
+    pub async fn shutdown(&self, drain_timeout: Duration) -> anyhow::Result<()> {
+        if self.is_shutting_down.swap(true, atomic::Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.sender.send(Request::Shutdown(drain_timeout)).await?;
+        let handler = self.engine_handler.lock().unwrap().take();
+        if let Some(handler) = handler {
+            tokio::task::spawn_blocking(move || handler.join())
+                .await?
+                .map_err(|_| anyhow::Error::msg("The engine thread panicked."))?;
+        }
+        if let Some(file) = &self.log {
+            let mut f = OpenOptions::new().append(true).create(true).open(file)?;
+            let time = chrono::offset::Local::now();
+            f.write_all(format!("Shutdown at {time}\n\n").as_bytes())?;
+            f.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Save the tokens and KV cache pinned under a session to a safetensors file.
+    pub async fn export_session(
+        &self,
+        session_id: impl ToString,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        self.sender
+            .send(Request::ExportSession {
+                session_id: session_id.to_string(),
+                path: path.into(),
+                response: tx,
+            })
+            .await?;
+        Self::engine_result(rx.recv().await)
+    }
+
+    /// Pin the tokens and KV cache saved by [`MistralRs::export_session`] under a session id, so
+    /// that the next request of the session continues from them.
+    pub async fn import_session(
+        &self,
+        session_id: impl ToString,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        self.sender
+            .send(Request::ImportSession {
+                session_id: session_id.to_string(),
+                path: path.into(),
+                response: tx,
+            })
+            .await?;
+        Self::engine_result(rx.recv().await)
+    }
+
+    fn engine_result<T>(result: Option<Result<T, String>>) -> anyhow::Result<T> {
+        match result {
+            Some(result) => result.map_err(anyhow::Error::msg),
+            None => anyhow::bail!("The engine stopped before handling the request."),
+        }
+    }
+
+    /// Load the LoRA adapter at `source`, a local directory or a Hugging Face model id, under
+    /// `name`. The adapter files are resolved and downloaded with the cached Hugging Face token
+    /// before the engine loads them. Returns the number of layers the adapter was loaded into.
+    pub async fn load_adapter(
+        &self,
+        name: impl ToString,
+        source: impl ToString,
+    ) -> anyhow::Result<usize> {
+        let source = source.to_string();
+        let adapter = {
+            let source = source.clone();
+            tokio::task::spawn_blocking(move || {
+                LoraAdapterFiles::resolve(&source, &TokenSource::CacheToken)
+            })
+            .await??
+        };
+        let (tx, mut rx) = channel(1);
+        self.sender
+            .send(Request::LoadAdapter {
+                name: name.to_string(),
+                source,
+                adapter,
+                response: tx,
+            })
+            .await?;
+        Self::engine_result(rx.recv().await)
+    }
+
+    /// Unload a LoRA adapter, deactivating it if it is active. Returns the number of layers the
+    /// adapter was unloaded from.
+    pub async fn unload_adapter(&self, name: impl ToString) -> anyhow::Result<usize> {
+        let (tx, mut rx) = channel(1);
+        self.sender
+            .send(Request::UnloadAdapter {
+                name: name.to_string(),
+                response: tx,
+            })
+            .await?;
+        Self::engine_result(rx.recv().await)
+    }
+
+    /// Unpin the KV cache of a session.
+    pub async fn drop_session(&self, session_id: impl ToString) -> anyhow::Result<()> {
+        self.sender
+            .send(Request::DropSession(session_id.to_string()))
+            .await?;
+        Ok(())
+    }
+
     pub fn maybe_log_request(this: Arc<Self>, repr: String) {
         if let Some(file) = &this.log {
             let mut f = OpenOptions::new()
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, iter::zip, ops::Mul};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::zip,
+    ops::Mul,
+};
 
 use candle_core::{
     bail,
@@ -11,8 +15,9 @@ use either::Either;
 use crate::layers::QLinear;
 
 use super::{
-    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge,
+    apply_row_adapters, apply_scalings_to_x, get_maybe_topk_scalings, is_target_module,
+    make_adapter, make_row_adapters, make_runtime_adapter, Adapter, AdapterSwapper,
+    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, RowAdapters, StackedAdapters,
 };
 
 #[derive(Debug)]
@@ -24,6 +29,15 @@ pub struct LoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    linear_config: LoraLinearConfig,
+    prefix: String,
+    active_adapters: Vec<(String, f64)>,
+    /// Adapters loaded at runtime which do not target this layer.
+    untargeted_adapters: HashSet<String>,
+    /// Per-row adapters of the current batch, if its rows use different adapters.
+    row_adapters: Option<RowAdapters>,
+    /// The stacked adapters for [`RowAdapters`], until an adapter is loaded or unloaded.
+    stacked_adapters: Option<StackedAdapters>,
 }
 
 impl LoraLinear {
@@ -43,6 +57,10 @@ impl LoraLinear {
         let mut state = None;
         let mut all_same = true;
         let mut adapters = HashMap::new();
+        let active_adapters = config
+            .iter()
+            .map(|((_, adapter_name), _)| (adapter_name.clone(), 1.0))
+            .collect();
         for ((name_id, adapter_name), cfg) in config.iter() {
             let a_pp = a_vb.pp(name_id);
             let b_pp = b_vb.pp(name_id);
@@ -113,6 +131,12 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                prefix: vb.prefix(),
+                active_adapters,
+                untargeted_adapters: HashSet::new(),
+                row_adapters: None,
+                stacked_adapters: None,
             })
         } else {
             Ok(LoraLinear {
@@ -123,13 +147,51 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                prefix: vb.prefix(),
+                active_adapters,
+                untargeted_adapters: HashSet::new(),
+                row_adapters: None,
+                stacked_adapters: None,
             })
         }
     }
 }
 
+impl LoraLinear {
+    /// A layer over `weight` without any adapters, into which adapters are loaded at runtime.
+    pub(super) fn from_weight(weight: Tensor, prefix: String) -> Result<Self> {
+        let (out_features, in_features) = weight.dims2()?;
+        Ok(LoraLinear {
+            old: QLinear::from_parts(weight, None),
+            a_adapters: Either::Left(Vec::new()),
+            b_adapters: Either::Left(Vec::new()),
+            scale_adapters: Vec::new(),
+            layer_n: 0,
+            merged: false,
+            adapters: HashMap::new(),
+            linear_config: LoraLinearConfig::new(in_features, out_features),
+            prefix,
+            active_adapters: Vec::new(),
+            untargeted_adapters: HashSet::new(),
+            row_adapters: None,
+            stacked_adapters: None,
+        })
+    }
+
+    /// Keep the adapters as separate layers so that the set of adapters can change.
+    fn unstack_adapters(&mut self) {
+        if let Either::Right((_, a)) = &self.a_adapters {
+            self.a_adapters = Either::Left(a.clone());
+        }
+        if let Either::Right((_, b)) = &self.b_adapters {
+            self.b_adapters = Either::Left(b.clone());
+        }
+    }
+}
+
 impl AdapterSwapper for LoraLinear {
-    fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()> {
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -139,24 +201,92 @@ impl AdapterSwapper for LoraLinear {
                 a.clear();
                 b.clear();
                 s.clear();
-                for adapter_name in adapter_names {
+                for (adapter_name, weight) in adapters {
                     let Adapter {
                         a: a_w,
                         b: b_w,
                         scale,
                     } = match self.adapters.get(adapter_name) {
                         Some(a) => a,
+                        None if self.untargeted_adapters.contains(adapter_name) => continue,
                         None => bail!("Cannot load adapter `{adapter_name}`."),
                     };
                     a.push(a_w.clone());
                     b.push(b_w.clone());
-                    s.push(*scale);
+                    s.push(scale * weight);
                 }
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
         }
+        self.active_adapters = adapters.to_vec();
+        self.row_adapters = None;
         Ok(())
     }
+    fn _activate_rows(&mut self, rows: &[Option<Vec<(String, f64)>>]) -> Result<()> {
+        match make_row_adapters(
+            rows,
+            &self.active_adapters,
+            &self.adapters,
+            &mut self.stacked_adapters,
+            &self.untargeted_adapters,
+        )? {
+            Either::Left(adapters) => {
+                self.row_adapters = None;
+                if adapters != self.active_adapters {
+                    self._activate_adapters(&adapters)?;
+                }
+            }
+            Either::Right(row_adapters) => self.row_adapters = Some(row_adapters),
+        }
+        Ok(())
+    }
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if !is_target_module(&self.prefix, cfg) {
+            self.untargeted_adapters.insert(name.to_string());
+            return Ok(0);
+        }
+        if self.merged {
+            bail!("Cannot load adapter `{name}`, the LoRA weights were merged when the model was loaded. Specify `preload_adapters` in the ordering to keep the adapters swappable.");
+        }
+        if self.adapters.contains_key(name) {
+            bail!("Adapter `{name}` is already loaded.");
+        }
+        let adapter = make_runtime_adapter(
+            name,
+            &self.prefix,
+            vb,
+            cfg,
+            &self.linear_config,
+            self.adapters.values().next(),
+        )?;
+        self.unstack_adapters();
+        self.adapters.insert(name.to_string(), adapter);
+        self.stacked_adapters = None;
+        Ok(1)
+    }
+    fn _unload_adapter(&mut self, name: &str) -> Result<usize> {
+        self.untargeted_adapters.remove(name);
+        self.row_adapters = None;
+        if self.adapters.remove(name).is_none() {
+            return Ok(0);
+        }
+        self.stacked_adapters = None;
+        if self
+            .active_adapters
+            .iter()
+            .any(|(active, _)| active == name)
+        {
+            let active = self
+                .active_adapters
+                .iter()
+                .filter(|(active, _)| active != name)
+                .cloned()
+                .collect::<Vec<_>>();
+            self.unstack_adapters();
+            self._activate_adapters(&active)?;
+        }
+        Ok(1)
+    }
     fn can_load(&self) -> bool {
         true
     }
@@ -228,6 +358,10 @@ impl LinearLayerLike for LoraLinear {
             return Ok(result);
         }
 
+        if let Some(row_adapters) = &self.row_adapters {
+            return apply_row_adapters(input, result, row_adapters, global_scaling_weight);
+        }
+
         let scalings =
             scalings.map(|scalings| get_maybe_topk_scalings(scalings, self.layer_n).unwrap());
         if self.a_adapters.is_left()
@@ -0,0 +1,186 @@
+use std::{fs, path::Path};
+
+use candle_core::{quantized::QMatMul, DType, Device};
+use tracing::info;
+
+use crate::{
+    pipeline::{get_base_model_files, get_lora_adapter_paths},
+    utils::varbuilder_utils::load_runtime_adapter,
+    TokenSource,
+};
+
+use super::{AdapterSwapper, LinearLayerLike, LoraLinear, Merge};
+
+/// Bake a weighted combination of LoRA adapters into the weights of a base model, so that the result
+/// can be served without any adapters. Each adapter is given by its `source` (a local directory or a
+/// Hugging Face model id) and the weight of its delta.
+///
+/// The merged safetensors are written to `output_dir` with the file names of the base model, along
+/// with its configuration and tokenizer files. Merging runs on the CPU.
+pub fn merge_weighted_adapters(
+    base_model: &str,
+    adapters: &[(String, f64)],
+    token_source: &TokenSource,
+    output_dir: &Path,
+) -> anyhow::Result<()> {
+    if adapters.is_empty() {
+        anyhow::bail!("No adapters to merge into `{base_model}`.");
+    }
+    let adapters = adapters
+        .iter()
+        .enumerate()
+        .map(|(i, (source, weight))| {
+            let (weights, cfg) = get_lora_adapter_paths(source, token_source)?;
+            Ok((
+                format!("adapter_{i}"),
+                load_runtime_adapter(&weights)?,
+                cfg,
+                *weight,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (weights, other_files) = get_base_model_files(base_model, token_source)?;
+
+    fs::create_dir_all(output_dir)?;
+    let mut merged = 0;
+    for path in weights {
+        let mut tensors = candle_core::safetensors::load(&path, &Device::Cpu)?;
+        for (name, tensor) in tensors.iter_mut() {
+            let Some(prefix) = name.strip_suffix(".weight") else {
+                continue;
+            };
+            if tensor.rank() != 2 || !tensor.dtype().is_float() {
+                continue;
+            }
+            let targeting = adapters
+                .iter()
+                .filter(|(_, vb, _, _)| vb.contains_tensor(&format!("{prefix}.lora_A.weight")))
+                .collect::<Vec<_>>();
+            if targeting.is_empty() {
+                continue;
+            }
+
+            let dtype = tensor.dtype();
+            let mut layer =
+                LoraLinear::from_weight(tensor.to_dtype(DType::F32)?, prefix.to_string())?;
+            for (adapter, vb, cfg, _) in &targeting {
+                layer.load_adapter(adapter, vb, cfg)?;
+            }
+            layer.activate(
+                &targeting
+                    .iter()
+                    .map(|(adapter, _, _, weight)| (adapter.clone(), *weight))
+                    .collect::<Vec<_>>(),
+            )?;
+            layer.merge_weights()?;
+            *tensor = match layer.inner() {
+                QMatMul::Tensor(w) | QMatMul::TensorF16(w) => w.to_dtype(dtype)?,
+                QMatMul::QTensor(_) => unreachable!("Merged weights are not quantized."),
+            };
+            merged += 1;
+        }
+        candle_core::safetensors::save(&tensors, output_dir.join(path.file_name().unwrap()))?;
+    }
+    if merged == 0 {
+        anyhow::bail!("None of the adapters have weights for a layer of `{base_model}`.");
+    }
+
+    for path in other_files {
+        fs::copy(&path, output_dir.join(path.file_name().unwrap()))?;
+    }
+    info!(
+        "Merged {} adapters into {merged} weights of `{base_model}`, written to `{}`.",
+        adapters.len(),
+        output_dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::Path};
+
+    use candle_core::{Device, Tensor};
+
+    use super::merge_weighted_adapters;
+    use crate::TokenSource;
+
+    fn write_adapter(
+        dir: &Path,
+        rank: usize,
+        alpha: f64,
+        a: &Tensor,
+        b: &Tensor,
+    ) -> anyhow::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(
+            dir.join("adapter_config.json"),
+            format!(
+                r#"{{"r": {rank}, "lora_alpha": {alpha}, "lora_dropout": null, "target_modules": ["q_proj"]}}"#
+            ),
+        )?;
+        let tensors = HashMap::from([
+            ("layer.q_proj.lora_A.weight".to_string(), a.clone()),
+            ("layer.q_proj.lora_B.weight".to_string(), b.clone()),
+        ]);
+        candle_core::safetensors::save(&tensors, dir.join("adapter_model.safetensors"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn merge_adds_weighted_scaled_deltas() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let root =
+            std::env::temp_dir().join(format!("mistralrs-lora-merge-{}", std::process::id()));
+        let (base, first, second, out) = (
+            root.join("base"),
+            root.join("first"),
+            root.join("second"),
+            root.join("out"),
+        );
+
+        let w = Tensor::arange(0f32, 12., &dev)?.reshape((3, 4))?;
+        let k = Tensor::ones((3, 4), candle_core::DType::F32, &dev)?;
+        fs::create_dir_all(&base)?;
+        fs::write(base.join("config.json"), "{}")?;
+        let tensors = HashMap::from([
+            ("layer.q_proj.weight".to_string(), w.clone()),
+            ("layer.k_proj.weight".to_string(), k.clone()),
+        ]);
+        candle_core::safetensors::save(&tensors, base.join("model.safetensors"))?;
+
+        // Rank 2 with alpha 4 gives a scale of 2, rank 1 with alpha 1 a scale of 1.
+        let a1 = Tensor::new(&[[1f32, 0., -1., 2.], [0., 1., 1., 0.]], &dev)?;
+        let b1 = Tensor::new(&[[1f32, 0.], [0., 2.], [-1., 1.]], &dev)?;
+        let a2 = Tensor::new(&[[0.5f32, -0.5, 1., 0.]], &dev)?;
+        let b2 = Tensor::new(&[[2f32], [0.], [-1.]], &dev)?;
+        write_adapter(&first, 2, 4., &a1, &b1)?;
+        write_adapter(&second, 1, 1., &a2, &b2)?;
+
+        merge_weighted_adapters(
+            base.to_str().unwrap(),
+            &[
+                (first.to_string_lossy().to_string(), 0.5),
+                (second.to_string_lossy().to_string(), 2.),
+            ],
+            &TokenSource::CacheToken,
+            &out,
+        )?;
+
+        let merged = candle_core::safetensors::load(out.join("model.safetensors"), &dev)?;
+        let expected = ((w + (b1.matmul(&a1)? * (0.5 * 2.))?)? + (b2.matmul(&a2)? * 2.)?)?;
+        assert_eq!(
+            merged["layer.q_proj.weight"].to_vec2::<f32>()?,
+            expected.to_vec2::<f32>()?
+        );
+        // Weights no adapter targets are copied unchanged, as are the other files.
+        assert_eq!(
+            merged["layer.k_proj.weight"].to_vec2::<f32>()?,
+            k.to_vec2::<f32>()?
+        );
+        assert!(out.join("config.json").exists());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -1,17 +1,20 @@
-#![allow(clippy::cast_precision_loss)]
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::{collections::HashSet, fmt::Debug, sync::Arc};
+use std::{collections::HashSet, fmt::Debug, iter::zip, ops::Mul, sync::Arc};
 
 use candle_core::{
     quantized::{QMatMul, QTensor},
     IndexOp, Result, Tensor, D,
 };
 use candle_nn::{init, Linear, Module, VarBuilder};
+use either::Either;
 use loralinear::LoraLinear;
+pub use merge::merge_weighted_adapters;
 pub use qloralinear::QLoraLinear;
 use serde::Deserialize;
 
 mod loralinear;
+mod merge;
 mod qloralinear;
 
 use std::collections::HashMap;
@@ -96,6 +99,194 @@ fn make_adapter(
     Ok(Adapter { a, b, scale })
 }
 
+/// Load an adapter into the layer at `prefix` at runtime, matching the dtype and device of the
+/// adapters already in the layer.
+fn make_runtime_adapter(
+    name: &str,
+    prefix: &str,
+    vb: &VarBuilder,
+    cfg: &LoraConfig,
+    linear_config: &LoraLinearConfig,
+    like: Option<&Adapter>,
+) -> Result<Adapter> {
+    let vb = vb.set_prefix(prefix);
+    let (a_vb, b_vb) = (vb.pp("lora_A"), vb.pp("lora_B"));
+    if !a_vb.contains_tensor("weight") || !b_vb.contains_tensor("weight") {
+        candle_core::bail!("Adapter `{name}` has no LoRA weights for `{prefix}`.");
+    }
+    let mut adapter = make_adapter(a_vb, b_vb, cfg, linear_config)?;
+    if let Some(like) = like {
+        let (device, dtype) = (like.a.weight().device(), like.a.weight().dtype());
+        adapter.a = Linear::new(adapter.a.weight().to_device(device)?.to_dtype(dtype)?, None);
+        adapter.b = Linear::new(adapter.b.weight().to_device(device)?.to_dtype(dtype)?, None);
+    }
+    Ok(adapter)
+}
+
+/// All adapters of a layer, stacked so that the rows of a batch can select their adapters by
+/// index. They are built for the first batch whose rows use different adapters and kept until an
+/// adapter is loaded or unloaded.
+#[derive(Debug)]
+struct StackedAdapters {
+    /// The index of each adapter in the stacks.
+    indices: HashMap<String, u32>,
+    /// The transposed A and B matrices, zero padded to the largest rank, of shapes
+    /// (n_adapters, in_features, rank) and (n_adapters, rank, out_features).
+    a: Tensor,
+    b: Tensor,
+}
+
+impl StackedAdapters {
+    fn new(adapters: &HashMap<String, Adapter>) -> Result<Option<Self>> {
+        let mut names = adapters.keys().collect::<Vec<_>>();
+        names.sort();
+        let Some(rank) = adapters
+            .values()
+            .map(|adapter| adapter.a.weight().dim(0))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .max()
+        else {
+            return Ok(None);
+        };
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        for name in &names {
+            let adapter = &adapters[*name];
+            let pad = rank - adapter.a.weight().dim(0)?;
+            a.push(adapter.a.weight().t()?.pad_with_zeros(1, 0, pad)?);
+            b.push(adapter.b.weight().t()?.pad_with_zeros(0, 0, pad)?);
+        }
+        Ok(Some(Self {
+            indices: names
+                .into_iter()
+                .enumerate()
+                .map(|(index, name)| (name.clone(), index as u32))
+                .collect(),
+            a: Tensor::stack(&a, 0)?,
+            b: Tensor::stack(&b, 0)?,
+        }))
+    }
+}
+
+/// The adapters of a batch whose rows use different adapters. Each row selects its adapters from
+/// the [`StackedAdapters`] by index, and one batched matmul serves all rows.
+#[derive(Debug)]
+struct RowAdapters {
+    /// The A and B stacks of [`StackedAdapters`]. `None` if no row uses an adapter of this layer.
+    weights: Option<(Tensor, Tensor)>,
+    slots: Vec<RowSlot>,
+}
+
+/// One of the weighted adapters of each row.
+#[derive(Debug)]
+struct RowSlot {
+    /// The index of the adapter of each row.
+    indices: Tensor,
+    /// The scale of the adapter of each row, 0 for rows with fewer adapters.
+    scales: Tensor,
+}
+
+/// Resolve the weighted adapters of each row of a batch, where `None` uses the `active` adapters.
+/// If all rows use the same adapters, those are returned so they can be activated as usual.
+/// `stacked` holds the stacks of `adapters`, and is built if it is `None`.
+fn make_row_adapters(
+    rows: &[Option<Vec<(String, f64)>>],
+    active: &[(String, f64)],
+    adapters: &HashMap<String, Adapter>,
+    stacked: &mut Option<StackedAdapters>,
+    untargeted: &HashSet<String>,
+) -> Result<Either<Vec<(String, f64)>, RowAdapters>> {
+    let rows = rows
+        .iter()
+        .map(|row| row.as_deref().unwrap_or(active))
+        .collect::<Vec<_>>();
+    if rows.iter().all(|row| *row == rows[0]) {
+        return Ok(Either::Left(rows[0].to_vec()));
+    }
+    let mut indices: Vec<Vec<u32>> = Vec::new();
+    let mut scales: Vec<Vec<f32>> = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let mut slot = 0;
+        for (name, weight) in row.iter() {
+            let adapter = match adapters.get(name) {
+                Some(adapter) => adapter,
+                None if untargeted.contains(name) => continue,
+                None => candle_core::bail!("Cannot load adapter `{name}`."),
+            };
+            if stacked.is_none() {
+                *stacked = StackedAdapters::new(adapters)?;
+            }
+            let Some(index) = stacked.as_ref().and_then(|s| s.indices.get(name)) else {
+                candle_core::bail!("Adapter `{name}` is missing from the stacked adapters.");
+            };
+            if slot == indices.len() {
+                indices.push(vec![0; rows.len()]);
+                scales.push(vec![0.; rows.len()]);
+            }
+            indices[slot][i] = *index;
+            scales[slot][i] = (adapter.scale * weight) as f32;
+            slot += 1;
+        }
+    }
+    let Some(stacked) = stacked.as_ref().filter(|_| !indices.is_empty()) else {
+        return Ok(Either::Right(RowAdapters {
+            weights: None,
+            slots: Vec::new(),
+        }));
+    };
+    let (device, dtype) = (stacked.a.device(), stacked.a.dtype());
+    let slots = zip(indices, scales)
+        .map(|(indices, scales)| {
+            Ok(RowSlot {
+                indices: Tensor::new(indices, device)?,
+                scales: Tensor::new(scales, device)?.to_dtype(dtype)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Either::Right(RowAdapters {
+        weights: Some((stacked.a.clone(), stacked.b.clone())),
+        slots,
+    }))
+}
+
+/// Add the output of the adapters of each row to the rows of `result`.
+fn apply_row_adapters(
+    input: &Tensor,
+    mut result: Tensor,
+    row_adapters: &RowAdapters,
+    global_scaling_weight: f64,
+) -> Result<Tensor> {
+    let Some((a, b)) = &row_adapters.weights else {
+        return Ok(result);
+    };
+    let (n_rows, in_features) = (input.dim(0)?, input.dim(D::Minus1)?);
+    let input = input.to_dtype(a.dtype())?.reshape((
+        n_rows,
+        input.elem_count() / (n_rows * in_features),
+        in_features,
+    ))?;
+    for RowSlot { indices, scales } in &row_adapters.slots {
+        let res = input
+            .matmul(&a.index_select(indices, 0)?)?
+            .matmul(&b.index_select(indices, 0)?)?
+            .broadcast_mul(&scales.reshape((n_rows, 1, 1))?)?
+            .mul(global_scaling_weight)?
+            .to_dtype(result.dtype())?
+            .reshape(result.shape())?;
+        result = (result + res)?;
+    }
+    Ok(result)
+}
+
+/// Whether `prefix` names one of the `target_modules` of an adapter.
+fn is_target_module(prefix: &str, cfg: &LoraConfig) -> bool {
+    prefix
+        .split('.')
+        .last()
+        .is_some_and(|module| cfg.target_modules.contains(module))
+}
+
 /// Any layer that is linear-like.
 pub trait LinearLayerLike: Debug + Merge + AdapterSwapper {
     fn inner(&mut self) -> &mut QMatMul;
@@ -119,15 +310,47 @@ pub trait Merge {
 }
 
 pub trait AdapterSwapper {
-    fn activate(&mut self, adapter_names: &[String]) -> Result<usize> {
+    /// Activate the given adapters, scaling the output of each by its weight.
+    fn activate(&mut self, adapters: &[(String, f64)]) -> Result<usize> {
         if self.can_load() {
-            self._activate_adapters(adapter_names)?;
+            self._activate_adapters(adapters)?;
             Ok(1)
         } else {
             Ok(0)
         }
     }
-    fn _activate_adapters(&mut self, adapters: &[String]) -> Result<()>;
+    /// Load a new adapter from `vb` if this layer is one of its target modules. Returns the number
+    /// of layers the adapter was loaded into.
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.can_load() {
+            self._load_adapter(name, vb, cfg)
+        } else {
+            Ok(0)
+        }
+    }
+    /// Unload an adapter, deactivating it if it is active. Returns the number of layers the
+    /// adapter was unloaded from.
+    fn unload_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.can_load() {
+            self._unload_adapter(name)
+        } else {
+            Ok(0)
+        }
+    }
+    /// Select the adapters of each row of the next batch, where `None` uses the active adapters.
+    /// Rows using different adapters are served by the same forward pass.
+    fn activate_rows(&mut self, rows: &[Option<Vec<(String, f64)>>]) -> Result<usize> {
+        if self.can_load() {
+            self._activate_rows(rows)?;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()>;
+    fn _activate_rows(&mut self, rows: &[Option<Vec<(String, f64)>>]) -> Result<()>;
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize>;
+    fn _unload_adapter(&mut self, name: &str) -> Result<usize>;
     fn can_load(&self) -> bool;
 }
 
@@ -141,7 +364,16 @@ impl Merge for Linear {
 }
 
 impl AdapterSwapper for Linear {
-    fn _activate_adapters(&mut self, _adapter: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, _adapter: &[(String, f64)]) -> Result<()> {
+        unreachable!()
+    }
+    fn _load_adapter(&mut self, _: &str, _: &VarBuilder, _: &LoraConfig) -> Result<usize> {
+        unreachable!()
+    }
+    fn _activate_rows(&mut self, _rows: &[Option<Vec<(String, f64)>>]) -> Result<()> {
+        unreachable!()
+    }
+    fn _unload_adapter(&mut self, _: &str) -> Result<usize> {
         unreachable!()
     }
     fn can_load(&self) -> bool {
@@ -321,3 +553,196 @@ pub fn linear_b(
 pub fn get_lora_cfg(tensor: &QTensor) -> LoraLinearConfig {
     LoraLinearConfig::new(tensor.shape().dims()[1], tensor.shape().dims()[0])
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use candle_core::{DType, Device, Module, Result, Tensor};
+    use candle_nn::{Linear, VarBuilder};
+    use either::Either;
+
+    use super::{
+        apply_row_adapters, make_row_adapters, Adapter, AdapterSwapper, LinearLayerLike,
+        LoraConfig, LoraLinear, StackedAdapters,
+    };
+
+    const PREFIX: &str = "model.layers.0.self_attn.q_proj";
+
+    fn adapter(rank: usize, scale: f64) -> Result<Adapter> {
+        let a = Tensor::randn(0f32, 1., (rank, 8), &Device::Cpu)?;
+        let b = Tensor::randn(0f32, 1., (6, rank), &Device::Cpu)?;
+        Ok(Adapter {
+            a: Linear::new(a, None),
+            b: Linear::new(b, None),
+            scale,
+        })
+    }
+
+    #[test]
+    fn mixed_adapter_batch_matches_single_rows() -> Result<()> {
+        let adapters = HashMap::from([
+            ("x".to_string(), adapter(2, 0.5)?),
+            ("y".to_string(), adapter(4, 2.)?),
+        ]);
+        let untargeted = HashSet::from(["z".to_string()]);
+        let active = vec![("x".to_string(), 1.), ("y".to_string(), 0.5)];
+        let rows = vec![
+            Some(vec![("x".to_string(), 1.)]),
+            Some(vec![("y".to_string(), 0.25), ("z".to_string(), 1.)]),
+            None,
+            Some(vec![]),
+        ];
+        let mut stacked = None;
+        let same = make_row_adapters(
+            &[None, Some(active.clone())],
+            &active,
+            &adapters,
+            &mut stacked,
+            &untargeted,
+        )?;
+        assert_eq!(same.left(), Some(active.clone()));
+        assert!(stacked.is_none());
+
+        let input = Tensor::randn(0f32, 1., (4, 3, 8), &Device::Cpu)?;
+        let base = Tensor::randn(0f32, 1., (4, 3, 6), &Device::Cpu)?;
+        let Either::Right(row_adapters) =
+            make_row_adapters(&rows, &active, &adapters, &mut stacked, &untargeted)?
+        else {
+            panic!("Expected per-row adapters.");
+        };
+        let mixed = apply_row_adapters(&input, base.clone(), &row_adapters, 0.5)?;
+        for (i, row) in rows.iter().enumerate() {
+            // Run the row on its own, applying its adapters one by one.
+            let input = input.get(i)?;
+            let mut expected = base.get(i)?;
+            for (name, weight) in row.as_deref().unwrap_or(&active) {
+                let Some(adapter) = adapters.get(name) else {
+                    continue;
+                };
+                let res = adapter.b.forward(&adapter.a.forward(&input)?)?;
+                expected = (expected + (res * (adapter.scale * weight * 0.5))?)?;
+            }
+            let max_err = (mixed.get(i)? - expected)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(max_err < 1e-4, "row {i}: {max_err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stacked_adapters_are_reused_across_batches() -> Result<()> {
+        let adapters = HashMap::from([
+            ("x".to_string(), adapter(2, 1.)?),
+            ("y".to_string(), adapter(4, 1.)?),
+        ]);
+        let untargeted = HashSet::new();
+        let mut stacked = None;
+        let mut stack_ids = Vec::new();
+        for rows in [
+            vec![Some(vec![("x".to_string(), 1.)]), None],
+            vec![None, Some(vec![("y".to_string(), 0.5)])],
+        ] {
+            let Either::Right(row_adapters) =
+                make_row_adapters(&rows, &[], &adapters, &mut stacked, &untargeted)?
+            else {
+                panic!("Expected per-row adapters.");
+            };
+            let (a, b) = row_adapters.weights.unwrap();
+            assert_eq!(a.dims(), [2, 8, 4]);
+            assert_eq!(b.dims(), [2, 4, 6]);
+            stack_ids.push((a.id(), b.id()));
+        }
+        // Only the indices and scales of the rows change between batches.
+        assert_eq!(stack_ids[0], stack_ids[1]);
+        let StackedAdapters { a, .. } = stacked.unwrap();
+        assert_eq!(a.id(), stack_ids[0].0);
+        Ok(())
+    }
+
+    fn lora_config(target_modules: &[&str]) -> LoraConfig {
+        LoraConfig {
+            rank: 2,
+            alpha: 4.,
+            dropout: None,
+            target_modules: target_modules.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// The weights of an adapter for the 8 -> 6 layer at `prefix`.
+    fn adapter_weights(prefix: &str) -> Result<VarBuilder<'static>> {
+        let tensors = HashMap::from([
+            (
+                format!("{prefix}.lora_A.weight"),
+                Tensor::randn(0f32, 1., (2, 8), &Device::Cpu)?,
+            ),
+            (
+                format!("{prefix}.lora_B.weight"),
+                Tensor::randn(0f32, 1., (6, 2), &Device::Cpu)?,
+            ),
+        ]);
+        Ok(VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu))
+    }
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn runtime_adapters_change_and_revert_output() -> Result<()> {
+        let weight = Tensor::randn(0f32, 1., (6, 8), &Device::Cpu)?;
+        let mut layer = LoraLinear::from_weight(weight, PREFIX.to_string())?;
+        let input = Tensor::randn(0f32, 1., (1, 3, 8), &Device::Cpu)?;
+        let base = layer.lora_forward(&input, None, 1., None)?;
+
+        let vb = adapter_weights(PREFIX)?;
+        assert_eq!(layer.load_adapter("x", &vb, &lora_config(&["q_proj"]))?, 1);
+        // Loading does not activate the adapter.
+        assert_eq!(
+            max_diff(&base, &layer.lora_forward(&input, None, 1., None)?)?,
+            0.
+        );
+
+        assert_eq!(layer.activate(&[("x".to_string(), 1.)])?, 1);
+        let adapted = layer.lora_forward(&input, None, 1., None)?;
+        let a = vb.get((2, 8), &format!("{PREFIX}.lora_A.weight"))?;
+        let b = vb.get((6, 2), &format!("{PREFIX}.lora_B.weight"))?;
+        // The scale is alpha / rank.
+        let delta = input.broadcast_matmul(&a.t()?)?.broadcast_matmul(&b.t()?)?;
+        let expected = (&base + (delta * 2.)?)?;
+        assert!(max_diff(&base, &adapted)? > 1e-3);
+        assert!(max_diff(&expected, &adapted)? < 1e-4);
+
+        // Unloading deactivates the adapter, so it can no longer be activated.
+        assert_eq!(layer.unload_adapter("x")?, 1);
+        assert_eq!(
+            max_diff(&base, &layer.lora_forward(&input, None, 1., None)?)?,
+            0.
+        );
+        assert!(layer.activate(&[("x".to_string(), 1.)]).is_err());
+        assert_eq!(layer.unload_adapter("x")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_adapter_without_weights_for_target_is_rejected() -> Result<()> {
+        let weight = Tensor::randn(0f32, 1., (6, 8), &Device::Cpu)?;
+        let mut layer = LoraLinear::from_weight(weight, PREFIX.to_string())?;
+        let other = adapter_weights("model.layers.0.self_attn.k_proj")?;
+        assert!(layer
+            .load_adapter("x", &other, &lora_config(&["q_proj"]))
+            .is_err());
+        assert!(layer.activate(&[("x".to_string(), 1.)]).is_err());
+
+        // Layers which are not targeted by the adapter skip it.
+        assert_eq!(
+            layer.load_adapter("y", &other, &lora_config(&["k_proj"]))?,
+            0
+        );
+        assert_eq!(layer.activate(&[("y".to_string(), 1.)])?, 1);
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, iter::zip, ops::Mul};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::zip,
+    ops::Mul,
+};
 
 use candle_core::{
     bail,
@@ -9,8 +13,9 @@ use candle_nn::{Linear, VarBuilder};
 use either::Either;
 
 use super::{
-    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
+    apply_row_adapters, apply_scalings_to_x, get_maybe_topk_scalings, is_target_module,
+    make_adapter, make_row_adapters, make_runtime_adapter, Adapter, AdapterSwapper,
+    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering, RowAdapters, StackedAdapters,
 };
 
 #[derive(Debug)]
@@ -22,7 +27,15 @@ pub struct QLoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    prefix: String,
+    active_adapters: Vec<(String, f64)>,
     linear_config: Option<LoraLinearConfig>,
+    /// Adapters loaded at runtime which do not target this layer.
+    untargeted_adapters: HashSet<String>,
+    /// Per-row adapters of the current batch, if its rows use different adapters.
+    row_adapters: Option<RowAdapters>,
+    /// The stacked adapters for [`RowAdapters`], until an adapter is loaded or unloaded.
+    stacked_adapters: Option<StackedAdapters>,
 }
 
 /// Specialized QLoRA for no bias
@@ -59,6 +72,11 @@ impl QLoraLinear {
                 merged: false,
                 adapters: HashMap::default(),
                 linear_config: None,
+                prefix,
+                active_adapters: Vec::new(),
+                untargeted_adapters: HashSet::new(),
+                row_adapters: None,
+                stacked_adapters: None,
             });
         }
 
@@ -73,6 +91,10 @@ impl QLoraLinear {
         let mut state = None;
         let mut all_same = true;
         let mut adapters = HashMap::new();
+        let active_adapters = config
+            .iter()
+            .map(|((_, adapter_name), _)| (adapter_name.clone(), 1.0))
+            .collect();
         for ((name_id, adapter_name), cfg) in config.iter() {
             let a_pp = a_vb.pp(name_id);
             let b_pp = b_vb.pp(name_id);
@@ -150,6 +172,11 @@ impl QLoraLinear {
                 merged: false,
                 adapters,
                 linear_config: Some(linear_config.clone()),
+                prefix: vb.prefix(),
+                active_adapters,
+                untargeted_adapters: HashSet::new(),
+                row_adapters: None,
+                stacked_adapters: None,
             })
         } else {
             Ok(QLoraLinear {
@@ -161,13 +188,30 @@ impl QLoraLinear {
                 merged: false,
                 adapters,
                 linear_config: Some(linear_config.clone()),
+                prefix: vb.prefix(),
+                active_adapters,
+                untargeted_adapters: HashSet::new(),
+                row_adapters: None,
+                stacked_adapters: None,
             })
         }
     }
 }
 
+impl QLoraLinear {
+    /// Keep the adapters as separate layers so that the set of adapters can change.
+    fn unstack_adapters(&mut self) {
+        if let Either::Right((_, a)) = &self.a_adapters {
+            self.a_adapters = Either::Left(a.clone());
+        }
+        if let Either::Right((_, b)) = &self.b_adapters {
+            self.b_adapters = Either::Left(b.clone());
+        }
+    }
+}
+
 impl AdapterSwapper for QLoraLinear {
-    fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()> {
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -177,24 +221,92 @@ impl AdapterSwapper for QLoraLinear {
                 a.clear();
                 b.clear();
                 s.clear();
-                for adapter_name in adapter_names {
+                for (adapter_name, weight) in adapters {
                     let Adapter {
                         a: a_w,
                         b: b_w,
                         scale,
                     } = match self.adapters.get(adapter_name) {
                         Some(a) => a,
+                        None if self.untargeted_adapters.contains(adapter_name) => continue,
                         None => bail!("Cannot load adapter `{adapter_name}`."),
                     };
                     a.push(a_w.clone());
                     b.push(b_w.clone());
-                    s.push(*scale);
+                    s.push(scale * weight);
                 }
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
         }
+        self.active_adapters = adapters.to_vec();
+        self.row_adapters = None;
+        Ok(())
+    }
+    fn _activate_rows(&mut self, rows: &[Option<Vec<(String, f64)>>]) -> Result<()> {
+        match make_row_adapters(
+            rows,
+            &self.active_adapters,
+            &self.adapters,
+            &mut self.stacked_adapters,
+            &self.untargeted_adapters,
+        )? {
+            Either::Left(adapters) => {
+                self.row_adapters = None;
+                if adapters != self.active_adapters {
+                    self._activate_adapters(&adapters)?;
+                }
+            }
+            Either::Right(row_adapters) => self.row_adapters = Some(row_adapters),
+        }
         Ok(())
     }
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if !is_target_module(&self.prefix, cfg) {
+            self.untargeted_adapters.insert(name.to_string());
+            return Ok(0);
+        }
+        if self.merged {
+            bail!("Cannot load adapter `{name}`, the LoRA weights were merged when the model was loaded. Specify `preload_adapters` in the ordering to keep the adapters swappable.");
+        }
+        if self.adapters.contains_key(name) {
+            bail!("Adapter `{name}` is already loaded.");
+        }
+        let adapter = make_runtime_adapter(
+            name,
+            &self.prefix,
+            vb,
+            cfg,
+            self.linear_config.as_ref().unwrap(),
+            self.adapters.values().next(),
+        )?;
+        self.unstack_adapters();
+        self.adapters.insert(name.to_string(), adapter);
+        self.stacked_adapters = None;
+        Ok(1)
+    }
+    fn _unload_adapter(&mut self, name: &str) -> Result<usize> {
+        self.untargeted_adapters.remove(name);
+        self.row_adapters = None;
+        if self.adapters.remove(name).is_none() {
+            return Ok(0);
+        }
+        self.stacked_adapters = None;
+        if self
+            .active_adapters
+            .iter()
+            .any(|(active, _)| active == name)
+        {
+            let active = self
+                .active_adapters
+                .iter()
+                .filter(|(active, _)| active != name)
+                .cloned()
+                .collect::<Vec<_>>();
+            self.unstack_adapters();
+            self._activate_adapters(&active)?;
+        }
+        Ok(1)
+    }
     fn can_load(&self) -> bool {
         self.linear_config.is_some()
     }
@@ -254,6 +366,10 @@ impl LinearLayerLike for QLoraLinear {
             return Ok(result);
         }
 
+        if let Some(row_adapters) = &self.row_adapters {
+            return apply_row_adapters(input, result, row_adapters, global_scaling_weight);
+        }
+
         if self
             .a_adapters
             .as_ref()
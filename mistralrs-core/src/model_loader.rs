@@ -1,12 +1,15 @@
-use std::fs::{self, File};
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+};
 
 use crate::{
     pipeline::{
         GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig,
         NormalSpecificConfig,
     },
-    Loader, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, VisionLoaderBuilder,
-    VisionSpecificConfig,
+    IsqPolicy, Loader, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector,
+    VisionLoaderBuilder, VisionSpecificConfig,
 };
 
 /// A builder for a loader using the selected model.
@@ -15,6 +18,8 @@ pub struct LoaderBuilder {
     no_kv_cache: bool,
     chat_template: Option<String>,
     use_flash_attn: bool,
+    isq_policy: Option<IsqPolicy>,
+    isq_artifacts: Option<PathBuf>,
 }
 
 impl LoaderBuilder {
@@ -24,6 +29,8 @@ impl LoaderBuilder {
             no_kv_cache: false,
             chat_template: None,
             use_flash_attn: false,
+            isq_policy: None,
+            isq_artifacts: None,
         }
     }
 
@@ -39,6 +46,16 @@ impl LoaderBuilder {
         self.use_flash_attn = use_flash_attn;
         self
     }
+    /// Mixed-precision ISQ policy, used together with the ISQ dtype.
+    pub fn with_isq_policy(mut self, isq_policy: Option<IsqPolicy>) -> Self {
+        self.isq_policy = isq_policy;
+        self
+    }
+    /// Directory to save ISQ artifacts to and load them from. ISQ artifacts are not used if `None`.
+    pub fn with_isq_artifacts(mut self, isq_artifacts: Option<PathBuf>) -> Self {
+        self.isq_artifacts = isq_artifacts;
+        self
+    }
 
     pub fn build(self) -> anyhow::Result<Box<dyn Loader>> {
         loader_from_model_selected(self)
@@ -82,6 +99,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 use_flash_attn,
                 chat_template: args.chat_template,
                 no_kv_cache: args.no_kv_cache,
+                isq_policy: args.isq_policy,
+                isq_artifacts: args.isq_artifacts,
             };
             (selector, args).try_into()?
         }
@@ -99,6 +118,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             tokenizer_json,
             Some(model_id),
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
         ModelSelected::XLora {
             model_id,
@@ -126,6 +147,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             args.no_kv_cache,
             tgt_non_granular_index,
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
         ModelSelected::Lora {
             model_id,
@@ -150,6 +173,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?,
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
         ModelSelected::GGUF {
             tok_model_id,
@@ -294,6 +319,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             tokenizer_json,
             Some(model_id),
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
     };
     Ok(loader)
@@ -101,7 +101,8 @@ pub enum ModelSelected {
     GGUF {
         /// `tok_model_id` is the local or remote model ID where you can find a `tokenizer_config.json` file.
         /// If the `chat_template` is specified, then it will be treated as a path and used over remote files,
-        /// removing all remote accesses.
+        /// removing all remote accesses. If neither is specified, the tokenizer and chat template are
+        /// read from the GGUF file metadata.
         #[arg(short, long)]
         tok_model_id: Option<String>,
 
@@ -3,12 +3,16 @@
 use std::sync::Arc;
 
 use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
-use candle_nn::{linear_b as linear, Activation, RotaryEmbedding, VarBuilder};
+use candle_nn::{Activation, RotaryEmbedding, VarBuilder};
 
 use crate::{
     device_map::DeviceMapper,
+    gptq::{linear_b as linear, QuantizationConfig},
     layers::{repeat_kv, CausalMasker, MatMul, QLinear, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    pipeline::{
+        extract_logits, Cache, IsqModel, IsqTensor, IsqTensorRole, KvCacheEntry,
+        NormalLoadingMetadata, NormalModel,
+    },
 };
 
 fn default_max_position_embeddings() -> usize {
@@ -34,6 +38,7 @@ pub struct Config {
     #[serde(default = "default_max_position_embeddings")]
     pub max_position_embeddings: usize,
     pub use_flash_attn: bool,
+    pub quantization_config: Option<QuantizationConfig>,
 }
 
 impl Config {
@@ -91,13 +96,31 @@ impl MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let gate_proj = linear(hidden_sz, intermediate_sz, false, vb.pp("gate_proj"))?;
-        let up_proj = linear(hidden_sz, intermediate_sz, false, vb.pp("up_proj"))?;
-        let down_proj = linear(intermediate_sz, hidden_sz, false, vb.pp("down_proj"))?;
+        let gate_proj = linear(
+            hidden_sz,
+            intermediate_sz,
+            false,
+            &cfg.quantization_config,
+            vb.pp("gate_proj"),
+        )?;
+        let up_proj = linear(
+            hidden_sz,
+            intermediate_sz,
+            false,
+            &cfg.quantization_config,
+            vb.pp("up_proj"),
+        )?;
+        let down_proj = linear(
+            intermediate_sz,
+            hidden_sz,
+            false,
+            &cfg.quantization_config,
+            vb.pp("down_proj"),
+        )?;
         Ok(Self {
-            gate_proj: QLinear::from_linear(gate_proj),
-            up_proj: QLinear::from_linear(up_proj),
-            down_proj: QLinear::from_linear(down_proj),
+            gate_proj,
+            up_proj,
+            down_proj,
             act_fn: cfg.hidden_act()?,
         })
     }
@@ -142,15 +165,39 @@ impl Attention {
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = cfg.head_dim;
         let bias = cfg.attention_bias;
-        let q_proj = linear(hidden_sz, num_heads * head_dim, bias, vb.pp("q_proj"))?;
-        let k_proj = linear(hidden_sz, num_kv_heads * head_dim, bias, vb.pp("k_proj"))?;
-        let v_proj = linear(hidden_sz, num_kv_heads * head_dim, bias, vb.pp("v_proj"))?;
-        let o_proj = linear(num_heads * head_dim, hidden_sz, bias, vb.pp("o_proj"))?;
+        let q_proj = linear(
+            hidden_sz,
+            num_heads * head_dim,
+            bias,
+            &cfg.quantization_config,
+            vb.pp("q_proj"),
+        )?;
+        let k_proj = linear(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            bias,
+            &cfg.quantization_config,
+            vb.pp("k_proj"),
+        )?;
+        let v_proj = linear(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            bias,
+            &cfg.quantization_config,
+            vb.pp("v_proj"),
+        )?;
+        let o_proj = linear(
+            num_heads * head_dim,
+            hidden_sz,
+            bias,
+            &cfg.quantization_config,
+            vb.pp("o_proj"),
+        )?;
         Ok(Self {
-            q_proj: QLinear::from_linear(q_proj),
-            k_proj: QLinear::from_linear(k_proj),
-            v_proj: QLinear::from_linear(v_proj),
-            o_proj: QLinear::from_linear(o_proj),
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
             num_heads,
             num_kv_heads,
             num_kv_groups,
@@ -166,7 +213,7 @@ impl Attention {
         attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -282,7 +329,7 @@ impl DecoderLayer {
         attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
@@ -410,17 +457,33 @@ impl Model {
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, IsqTensorRole::LmHead));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((layer.self_attn.q_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.k_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.v_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.o_proj.inner(), Some(i)));
-            tensors.push((layer.mlp.down_proj.inner(), Some(i)));
-            tensors.push((layer.mlp.gate_proj.inner(), Some(i)));
-            tensors.push((layer.mlp.up_proj.inner(), Some(i)));
+            tensors.push((
+                layer.self_attn.q_proj.inner(),
+                Some(i),
+                IsqTensorRole::AttnQ,
+            ));
+            tensors.push((
+                layer.self_attn.k_proj.inner(),
+                Some(i),
+                IsqTensorRole::AttnK,
+            ));
+            tensors.push((
+                layer.self_attn.v_proj.inner(),
+                Some(i),
+                IsqTensorRole::AttnV,
+            ));
+            tensors.push((
+                layer.self_attn.o_proj.inner(),
+                Some(i),
+                IsqTensorRole::AttnO,
+            ));
+            tensors.push((layer.mlp.down_proj.inner(), Some(i), IsqTensorRole::Mlp));
+            tensors.push((layer.mlp.gate_proj.inner(), Some(i), IsqTensorRole::Mlp));
+            tensors.push((layer.mlp.up_proj.inner(), Some(i), IsqTensorRole::Mlp));
         }
         (tensors, &*self.mapper)
     }
@@ -1,16 +1,20 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor};
-use candle_nn::{
-    embedding, linear_no_bias as linear, Embedding, Module, RotaryEmbedding, VarBuilder,
-};
+use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use serde::Deserialize;
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
-    pipeline::{extract_logits, IsqModel, NormalLoadingMetadata, NormalModel},
+    gptq::{linear_no_bias as linear, QuantizationConfig},
+    layers::{
+        repeat_kv, CausalMasker, MatMul, RmsNorm, RopeScaling, ScaledDotProductAttention,
+        ScaledRotaryEmbedding,
+    },
+    pipeline::{
+        extract_logits, IsqModel, IsqTensor, IsqTensorRole, NormalLoadingMetadata, NormalModel,
+    },
 };
 
 #[derive(Debug, Clone, Deserialize)]
@@ -25,6 +29,8 @@ pub struct Config {
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
     pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
+    pub quantization_config: Option<QuantizationConfig>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,7 +43,7 @@ struct CausalSelfAttention {
     num_key_value_heads: usize,
     head_dim: usize,
     use_flash_attn: bool,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     max_seq_len: usize,
 }
 
@@ -49,7 +55,7 @@ impl CausalSelfAttention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         block_idx: usize,
-        kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache: &mut [crate::pipeline::KvCacheEntry],
     ) -> Result<Tensor> {
         let (b_sz, seq_len, hidden_size) = x.dims3()?;
 
@@ -116,19 +122,19 @@ impl CausalSelfAttention {
         Ok(y)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config, rope: Arc<RotaryEmbedding>) -> Result<Self> {
+    fn load(vb: VarBuilder, cfg: &Config, rope: Arc<ScaledRotaryEmbedding>) -> Result<Self> {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
-        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
-        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
-        let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
+        let q_proj = linear(size_in, size_q, &cfg.quantization_config, vb.pp("q_proj"))?;
+        let k_proj = linear(size_in, size_kv, &cfg.quantization_config, vb.pp("k_proj"))?;
+        let v_proj = linear(size_in, size_kv, &cfg.quantization_config, vb.pp("v_proj"))?;
+        let o_proj = linear(size_q, size_in, &cfg.quantization_config, vb.pp("o_proj"))?;
         Ok(Self {
-            q_proj: QMatMul::Tensor(q_proj.weight().clone()),
-            k_proj: QMatMul::Tensor(k_proj.weight().clone()),
-            v_proj: QMatMul::Tensor(v_proj.weight().clone()),
-            o_proj: QMatMul::Tensor(o_proj.weight().clone()),
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
             num_attention_heads: cfg.num_attention_heads,
             num_key_value_heads: cfg.num_key_value_heads,
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
@@ -165,13 +171,13 @@ impl Mlp {
     fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
-        let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
-        let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
+        let c_fc1 = linear(h_size, i_size, &cfg.quantization_config, vb.pp("gate_proj"))?;
+        let c_fc2 = linear(h_size, i_size, &cfg.quantization_config, vb.pp("up_proj"))?;
+        let c_proj = linear(i_size, h_size, &cfg.quantization_config, vb.pp("down_proj"))?;
         Ok(Self {
-            c_fc1: QMatMul::Tensor(c_fc1.weight().clone()),
-            c_fc2: QMatMul::Tensor(c_fc2.weight().clone()),
-            c_proj: QMatMul::Tensor(c_proj.weight().clone()),
+            c_fc1,
+            c_fc2,
+            c_proj,
         })
     }
 }
@@ -192,7 +198,7 @@ impl Block {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         block_idx: usize,
-        kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache: &mut [crate::pipeline::KvCacheEntry],
     ) -> Result<Tensor> {
         let residual = x;
         let x = self.rms_1.forward(x)?;
@@ -215,7 +221,7 @@ impl Block {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<RotaryEmbedding>,
+        rope: Arc<ScaledRotaryEmbedding>,
     ) -> Result<Self> {
         let attn = CausalSelfAttention::load(
             mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
@@ -306,6 +312,7 @@ impl Llama {
         let lm_head = linear(
             cfg.hidden_size,
             cfg.vocab_size,
+            &cfg.quantization_config,
             mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
         )?;
         let ln_f = RmsNorm::new(
@@ -317,10 +324,11 @@ impl Llama {
         let blocks: Vec<_> = (0..cfg.num_hidden_layers)
             .map(|i| {
                 let rotary_emb = Arc::new(
-                    RotaryEmbedding::new(
+                    ScaledRotaryEmbedding::new(
                         cfg.rope_theta,
                         head_dim,
                         cfg.max_position_embeddings,
+                        cfg.rope_scaling.as_ref(),
                         mapper
                             .device_for(i, false)
                             .unwrap_or(&normal_loading_metadata.real_device),
@@ -345,7 +353,7 @@ impl Llama {
             wte,
             blocks,
             ln_f,
-            lm_head: QMatMul::Tensor(lm_head.weight().clone()),
+            lm_head,
             kv_cache: crate::pipeline::Cache::new(cfg.num_hidden_layers, false),
             device: normal_loading_metadata.real_device,
             mapper,
@@ -354,17 +362,17 @@ impl Llama {
 }
 
 impl IsqModel for Llama {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, IsqTensorRole::LmHead));
         for (i, layer) in self.blocks.iter_mut().enumerate() {
-            tensors.push((&mut layer.attn.q_proj, Some(i)));
-            tensors.push((&mut layer.attn.k_proj, Some(i)));
-            tensors.push((&mut layer.attn.v_proj, Some(i)));
-            tensors.push((&mut layer.attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.c_fc1, Some(i)));
-            tensors.push((&mut layer.mlp.c_fc2, Some(i)));
-            tensors.push((&mut layer.mlp.c_proj, Some(i)));
+            tensors.push((&mut layer.attn.q_proj, Some(i), IsqTensorRole::AttnQ));
+            tensors.push((&mut layer.attn.k_proj, Some(i), IsqTensorRole::AttnK));
+            tensors.push((&mut layer.attn.v_proj, Some(i), IsqTensorRole::AttnV));
+            tensors.push((&mut layer.attn.o_proj, Some(i), IsqTensorRole::AttnO));
+            tensors.push((&mut layer.mlp.c_fc1, Some(i), IsqTensorRole::Mlp));
+            tensors.push((&mut layer.mlp.c_fc2, Some(i), IsqTensorRole::Mlp));
+            tensors.push((&mut layer.mlp.c_proj, Some(i), IsqTensorRole::Mlp));
         }
         (tensors, &*self.mapper)
     }
@@ -413,4 +421,12 @@ impl NormalModel for Llama {
     fn max_seq_len(&self) -> usize {
         self.blocks[0].attn.max_seq_len
     }
+    fn rotary_embeddings(&self) -> Option<Vec<&ScaledRotaryEmbedding>> {
+        Some(
+            self.blocks
+                .iter()
+                .map(|block| &*block.attn.rotary_emb)
+                .collect(),
+        )
+    }
 }
@@ -3,11 +3,14 @@
 use candle_core::quantized::{ggml_file, gguf_file};
 use candle_core::quantized::{QMatMul, QTensor};
 use candle_core::{DType, Device, Result, Tensor};
-use candle_nn::{Embedding, Module, RotaryEmbedding};
+use candle_nn::{Embedding, Module};
 
 use crate::device_map::DeviceMapper;
-use crate::layers::{repeat_kv, CausalMasker, MatMul, QRmsNorm, ScaledDotProductAttention};
-use crate::pipeline::{extract_logits, Cache};
+use crate::layers::{
+    repeat_kv, CausalMasker, MatMul, QRmsNorm, RopeScaling, ScaledDotProductAttention,
+    ScaledRotaryEmbedding,
+};
+use crate::pipeline::{extract_logits, Cache, KvCacheEntry};
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::DeviceMapMetadata;
@@ -122,7 +125,7 @@ struct LayerWeights {
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
-    rotary: RotaryEmbedding,
+    rotary: ScaledRotaryEmbedding,
 }
 
 impl LayerWeights {
@@ -132,7 +135,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
 
@@ -197,11 +200,12 @@ pub struct ModelWeights {
 impl ModelConfig::FromGGML for ModelWeights {
     fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let rotary = RotaryEmbedding::new_partial(
+        let rotary = ScaledRotaryEmbedding::new_partial(
             10000.,
             head_dim,
             ct.hparams.n_rot as usize,
             MAX_SEQ_LEN as usize,
+            None,
             &ct.device,
             false,
             DType::F32,
@@ -270,6 +274,7 @@ pub(crate) struct PropsGGUF {
     pub rms_norm_eps: f32,
     pub max_seq_len: usize,
     pub rope_freq_base: f32,
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -305,6 +310,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
                 .ok()
                 .unwrap_or(MAX_SEQ_LEN as u64) as usize,
             rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
+            rope_scaling: c.get_rope_scaling()?,
         };
 
         Ok(props)
@@ -334,6 +340,7 @@ impl ModelConfig::FromGGUF for ModelWeights {
             rms_norm_eps,
             max_seq_len,
             rope_freq_base,
+            rope_scaling,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
         let head_dim = embedding_length / head_count;
@@ -344,20 +351,35 @@ impl ModelConfig::FromGGUF for ModelWeights {
             rms_norm_eps,
         )?;
         let output = ct.tensor(reader, "output.weight", device)?;
+        // Llama 3 GGUF files store the scaled RoPE frequencies as per-frequency factors.
+        let rope_freq_factors = match ct.tensor(reader, "rope_freqs.weight", device) {
+            Ok(factors) => Some(factors.dequantize(device)?.to_vec1::<f32>()?),
+            Err(_) => None,
+        };
         let mut layers = Vec::with_capacity(block_count);
         let mapper = mapper.into_mapper(block_count, device)?;
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
             let device = mapper.device_for(layer_idx, false).unwrap_or(device);
-            let rotary = RotaryEmbedding::new_partial(
-                rope_freq_base,
-                head_dim,
-                rope_dim,
-                max_seq_len,
-                device,
-                false,
-                DType::F32,
-            )?;
+            let rotary = match &rope_freq_factors {
+                Some(factors) => ScaledRotaryEmbedding::from_freq_factors(
+                    rope_freq_base,
+                    rope_dim,
+                    factors,
+                    device,
+                    false,
+                )?,
+                None => ScaledRotaryEmbedding::new_partial(
+                    rope_freq_base,
+                    head_dim,
+                    rope_dim,
+                    max_seq_len,
+                    rope_scaling.as_ref(),
+                    device,
+                    false,
+                    DType::F32,
+                )?,
+            };
 
             let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
             let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
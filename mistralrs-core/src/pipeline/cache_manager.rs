@@ -1,8 +1,12 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
-use candle_core::{Tensor, D};
+use candle_core::{DType, Tensor, D};
 
-use crate::{get_mut_arcmutex, sequence::Sequence};
+use crate::{sequence::Sequence, xlora_models::FixedScalings};
 
 use super::{CacheManagerMixin, MetadataMixin};
 
@@ -22,69 +26,423 @@ pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
     fn set_none_cache(&self, pipeline: &mut T, modify_draft_cache: bool);
 }
 
+/// The KV cache of each layer. If the KV cache is quantized (see [`KvCacheQuant`]), each entry
+/// holds the packed quantized K and V values and their scales instead of K and V. In both cases,
+/// dimension 0 is the batch and dimension 2 is the sequence.
 pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
 
+/// Quantization of the KV cache. Keys and values are stored quantized and dequantized to the
+/// activation dtype when read in the attention path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvCacheQuant {
+    /// 8-bit values with a scale per head and token.
+    Int8,
+    /// 4-bit values with a scale per group of 32 values of each head and token.
+    Q4,
+}
+
+const KV_CACHE_Q4_GROUP_SIZE: usize = 32;
+
+impl KvCacheQuant {
+    fn max_q(&self) -> f64 {
+        match self {
+            Self::Int8 => 127.,
+            Self::Q4 => 7.,
+        }
+    }
+
+    fn n_groups(&self, head_dim: usize) -> usize {
+        match self {
+            Self::Q4 if head_dim % KV_CACHE_Q4_GROUP_SIZE == 0 => head_dim / KV_CACHE_Q4_GROUP_SIZE,
+            Self::Int8 | Self::Q4 => 1,
+        }
+    }
+
+    /// The head dimension of a cache entry created by [`KvCacheQuant::quantize_kv`].
+    fn head_dim(&self, values: &Tensor) -> candle_core::Result<usize> {
+        Ok(match self {
+            Self::Int8 => values.dim(D::Minus1)? / 2,
+            Self::Q4 => values.dim(D::Minus1)?,
+        })
+    }
+
+    /// Quantize `xs` of shape (bs, n_heads, seq_len, head_dim) into `u8` values and `f32` scales of
+    /// shape (bs, n_heads, seq_len, n_groups).
+    fn quantize(&self, xs: &Tensor) -> candle_core::Result<(Tensor, Tensor)> {
+        let (bs, n_heads, seq_len, head_dim) = xs.dims4()?;
+        let n_groups = self.n_groups(head_dim);
+        let max_q = self.max_q();
+        let xs = xs.to_dtype(DType::F32)?.reshape((
+            bs,
+            n_heads,
+            seq_len,
+            n_groups,
+            head_dim / n_groups,
+        ))?;
+        let scales = (xs.abs()?.max_keepdim(D::Minus1)? / max_q)?.clamp(1e-8f32, f32::MAX)?;
+        // Symmetric quantization, offset so that the values are unsigned.
+        let values = (xs.broadcast_div(&scales)?.round()?.clamp(-max_q, max_q)? + (max_q + 1.))?;
+        let values = match self {
+            Self::Int8 => values.reshape((bs, n_heads, seq_len, head_dim))?,
+            Self::Q4 => {
+                // Pack two 4-bit values into each byte.
+                let values = values.reshape((bs, n_heads, seq_len, head_dim / 2, 2))?;
+                let lo = values.narrow(D::Minus1, 0, 1)?;
+                let hi = values.narrow(D::Minus1, 1, 1)?;
+                (lo + (hi * 16.)?)?.reshape((bs, n_heads, seq_len, head_dim / 2))?
+            }
+        };
+        Ok((values.to_dtype(DType::U8)?, scales.squeeze(D::Minus1)?))
+    }
+
+    /// Inverse of [`KvCacheQuant::quantize`].
+    fn dequantize(
+        &self,
+        values: &Tensor,
+        scales: &Tensor,
+        head_dim: usize,
+        dtype: DType,
+    ) -> candle_core::Result<Tensor> {
+        let (bs, n_heads, seq_len, _) = values.dims4()?;
+        let n_groups = scales.dim(D::Minus1)?;
+        let values = values.to_dtype(DType::F32)?;
+        let values = match self {
+            Self::Int8 => values,
+            Self::Q4 => {
+                let hi = (&values / 16.)?.floor()?;
+                let lo = (values - (&hi * 16.)?)?;
+                Tensor::stack(&[lo, hi], D::Minus1)?
+            }
+        };
+        let values = (values.reshape((bs, n_heads, seq_len, n_groups, head_dim / n_groups))?
+            - (self.max_q() + 1.))?;
+        values
+            .broadcast_mul(&scales.unsqueeze(D::Minus1)?)?
+            .reshape((bs, n_heads, seq_len, head_dim))?
+            .to_dtype(dtype)
+    }
+
+    /// Quantize K and V into a single cache entry.
+    fn quantize_kv(&self, k: &Tensor, v: &Tensor) -> candle_core::Result<(Tensor, Tensor)> {
+        let (k_values, k_scales) = self.quantize(k)?;
+        let (v_values, v_scales) = self.quantize(v)?;
+        Ok((
+            Tensor::cat(&[k_values, v_values], D::Minus1)?,
+            Tensor::cat(&[k_scales, v_scales], D::Minus1)?,
+        ))
+    }
+
+    /// Dequantize a cache entry created by [`KvCacheQuant::quantize_kv`] into K and V.
+    fn dequantize_kv(
+        &self,
+        values: &Tensor,
+        scales: &Tensor,
+        head_dim: usize,
+        dtype: DType,
+    ) -> candle_core::Result<(Tensor, Tensor)> {
+        let n_values = values.dim(D::Minus1)? / 2;
+        let n_scales = scales.dim(D::Minus1)? / 2;
+        let k = self.dequantize(
+            &values.narrow(D::Minus1, 0, n_values)?,
+            &scales.narrow(D::Minus1, 0, n_scales)?,
+            head_dim,
+            dtype,
+        )?;
+        let v = self.dequantize(
+            &values.narrow(D::Minus1, n_values, n_values)?,
+            &scales.narrow(D::Minus1, n_scales, n_scales)?,
+            head_dim,
+            dtype,
+        )?;
+        Ok((k, v))
+    }
+}
+
+impl FromStr for KvCacheQuant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "int8" | "q8" => Ok(Self::Int8),
+            "q4" => Ok(Self::Q4),
+            other => Err(format!(
+                "Unknown KV cache quantization `{other}`, expected `int8` or `q4`."
+            )),
+        }
+    }
+}
+
+impl Display for KvCacheQuant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int8 => write!(f, "int8"),
+            Self::Q4 => write!(f, "q4"),
+        }
+    }
+}
+
+/// The KV cache of one layer of the batch running on a model.
+#[derive(Clone, Debug, Default)]
+pub struct KvCacheEntry {
+    /// K and V, or the quantized K and V if `quant` is set, as in [`LayerCaches`].
+    kv: Option<(Tensor, Tensor)>,
+    quant: Option<KvCacheQuant>,
+}
+
+impl KvCacheEntry {
+    pub(crate) fn new(kv: Option<(Tensor, Tensor)>, quant: Option<KvCacheQuant>) -> Self {
+        Self { kv, quant }
+    }
+
+    pub(crate) fn kv(&self) -> Option<&(Tensor, Tensor)> {
+        self.kv.as_ref()
+    }
+
+    /// The number of cached positions, or `None` if the cache is empty.
+    pub(crate) fn seq_len(&self) -> Option<usize> {
+        self.kv.as_ref().map(|(k, _)| k.dims()[2])
+    }
+
+    /// Keep `len` cached positions, starting at `start`.
+    pub(crate) fn narrow(&mut self, start: usize, len: usize) -> candle_core::Result<()> {
+        if let Some((k, v)) = &mut self.kv {
+            *k = k.narrow(2, start, len)?;
+            *v = v.narrow(2, start, len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Context shifting, as in StreamingLLM: once the KV cache of a sequence reaches the maximum
+/// sequence length, the first `sink_tokens` positions ("attention sinks") and the most recent
+/// `window` positions are kept and the positions in between are discarded, so that generation can
+/// continue past the maximum sequence length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextShift {
+    pub sink_tokens: usize,
+    /// Defaults to half of the maximum sequence length.
+    pub window: Option<usize>,
+}
+
+impl Default for ContextShift {
+    fn default() -> Self {
+        Self {
+            sink_tokens: 4,
+            window: None,
+        }
+    }
+}
+
+/// Discard the cache of positions `n_sink..n_sink + n_discard` of a sequence whose cache holds its
+/// last positions up to `n_positions`, and move the following positions back by `n_discard`.
+/// `quant` is the quantization of the cache, if any. `shift_k` re-rotates the keys of a layer to
+/// their new positions; only these keys are dequantized and requantized.
+pub(crate) fn shift_context_cache(
+    cache: &mut LayerCaches,
+    n_positions: usize,
+    n_sink: usize,
+    n_discard: usize,
+    quant: Option<KvCacheQuant>,
+    shift_k: impl Fn(usize, &Tensor) -> candle_core::Result<Tensor>,
+) -> candle_core::Result<()> {
+    for (layer, entry) in cache.iter_mut().enumerate() {
+        let Some((a, b)) = entry.take() else {
+            continue;
+        };
+        // With a sliding window, the cache only holds the last positions.
+        let cache_len = a.dim(2)?;
+        let first = n_positions - cache_len;
+        let n_kept_sink = n_sink.saturating_sub(first).min(cache_len);
+        let rest_start = (n_sink + n_discard).saturating_sub(first).min(cache_len);
+        let rest_len = cache_len - rest_start;
+        let (a_rest, b_rest) = (
+            a.narrow(2, rest_start, rest_len)?,
+            b.narrow(2, rest_start, rest_len)?,
+        );
+        let (a_rest, b_rest) = match quant {
+            None => (shift_k(layer, &a_rest)?, b_rest),
+            Some(quant) => {
+                // Each position is quantized on its own, so only the moved keys are requantized and
+                // the sink keys and all values keep their bytes.
+                let n_values = a.dim(D::Minus1)? / 2;
+                let n_scales = b.dim(D::Minus1)? / 2;
+                let k_rest = quant.dequantize(
+                    &a_rest.narrow(D::Minus1, 0, n_values)?,
+                    &b_rest.narrow(D::Minus1, 0, n_scales)?,
+                    quant.head_dim(&a)?,
+                    DType::F32,
+                )?;
+                let (k_values, k_scales) = quant.quantize(&shift_k(layer, &k_rest)?)?;
+                (
+                    Tensor::cat(
+                        &[k_values, a_rest.narrow(D::Minus1, n_values, n_values)?],
+                        D::Minus1,
+                    )?,
+                    Tensor::cat(
+                        &[k_scales, b_rest.narrow(D::Minus1, n_scales, n_scales)?],
+                        D::Minus1,
+                    )?,
+                )
+            }
+        };
+        *entry = Some((
+            Tensor::cat(&[a.narrow(2, 0, n_kept_sink)?, a_rest], 2)?.contiguous()?,
+            Tensor::cat(&[b.narrow(2, 0, n_kept_sink)?, b_rest], 2)?.contiguous()?,
+        ));
+    }
+    Ok(())
+}
+
+/// Lock one of the mutexes of a [`Cache`]. A panic in a forward pass poisons the mutexes the model
+/// held, after which the engine resets the cache, so the poison is cleared instead of propagated.
+fn lock_cache<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
-    cache: Arc<Mutex<LayerCaches>>,
-    xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
-    draft_cache: Arc<Mutex<LayerCaches>>,
+    cache: Arc<Mutex<Vec<KvCacheEntry>>>,
+    xlora_cache: Option<Arc<Mutex<Vec<KvCacheEntry>>>>,
+    draft_cache: Arc<Mutex<Vec<KvCacheEntry>>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    fixed_scalings: Option<Arc<Mutex<Option<FixedScalings>>>>,
+    last_scalings: Option<Arc<Mutex<Option<Tensor>>>>,
+    kv_cache_quant: Arc<Mutex<Option<KvCacheQuant>>>,
 }
 
 impl Cache {
     pub(crate) fn new(len: usize, is_xlora: bool) -> Self {
         Self {
-            cache: Arc::new(Mutex::new(vec![None; len])),
+            cache: Arc::new(Mutex::new(vec![KvCacheEntry::default(); len])),
             xlora_cache: if is_xlora {
-                Some(Arc::new(Mutex::new(vec![None; len])))
+                Some(Arc::new(Mutex::new(vec![KvCacheEntry::default(); len])))
             } else {
                 None
             },
-            draft_cache: Arc::new(Mutex::new(vec![None; len])),
+            draft_cache: Arc::new(Mutex::new(vec![KvCacheEntry::default(); len])),
             scalings_cache: if is_xlora {
                 Some(Arc::new(Mutex::new(None)))
             } else {
                 None
             },
+            fixed_scalings: if is_xlora {
+                Some(Arc::new(Mutex::new(None)))
+            } else {
+                None
+            },
+            last_scalings: if is_xlora {
+                Some(Arc::new(Mutex::new(None)))
+            } else {
+                None
+            },
+            kv_cache_quant: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub(crate) fn lock(&self) -> MutexGuard<'_, LayerCaches> {
-        get_mut_arcmutex!(self.cache)
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Vec<KvCacheEntry>> {
+        lock_cache(&self.cache)
     }
 
-    pub(crate) fn draft_lock(&self) -> MutexGuard<'_, LayerCaches> {
-        get_mut_arcmutex!(self.draft_cache)
+    pub(crate) fn draft_lock(&self) -> MutexGuard<'_, Vec<KvCacheEntry>> {
+        lock_cache(&self.draft_cache)
     }
 
     /// # Panics
     /// If there is no xlora cache
-    pub(crate) fn xlora_lock(&self) -> MutexGuard<'_, LayerCaches> {
-        get_mut_arcmutex!(self.xlora_cache.as_ref().expect("No X-LoRA cache."))
+    pub(crate) fn xlora_lock(&self) -> MutexGuard<'_, Vec<KvCacheEntry>> {
+        lock_cache(self.xlora_cache.as_ref().expect("No X-LoRA cache."))
     }
 
     /// # Panics
     /// If there is no xlora cache
     pub(crate) fn get_scalings_cache(&self) -> MutexGuard<'_, Option<Tensor>> {
-        get_mut_arcmutex!(self
-            .scalings_cache
-            .as_ref()
-            .expect("No X-LoRA scalings cache."))
+        lock_cache(
+            self.scalings_cache
+                .as_ref()
+                .expect("No X-LoRA scalings cache."),
+        )
+    }
+
+    /// The fixed scalings of the next batch, which replace those of the X-LoRA classifier.
+    ///
+    /// # Panics
+    /// If there is no xlora cache
+    pub(crate) fn get_fixed_scalings(&self) -> MutexGuard<'_, Option<FixedScalings>> {
+        lock_cache(
+            self.fixed_scalings
+                .as_ref()
+                .expect("No X-LoRA fixed scalings."),
+        )
+    }
+
+    /// The scalings used by the last X-LoRA forward pass.
+    ///
+    /// # Panics
+    /// If there is no xlora cache
+    pub(crate) fn get_last_scalings(&self) -> MutexGuard<'_, Option<Tensor>> {
+        lock_cache(
+            self.last_scalings
+                .as_ref()
+                .expect("No X-LoRA last scalings."),
+        )
     }
 
     pub(crate) fn is_xlora(&self) -> bool {
         self.xlora_cache.is_some()
     }
 
+    /// The quantization of the KV cache, if any.
+    pub fn kv_cache_quant(&self) -> Option<KvCacheQuant> {
+        *lock_cache(&self.kv_cache_quant)
+    }
+
+    /// Set the quantization of the KV cache. This applies to the caches of the following prompts.
+    pub fn set_kv_cache_quant(&self, quant: Option<KvCacheQuant>) {
+        *lock_cache(&self.kv_cache_quant) = quant;
+    }
+
+    /// An empty layer cache for the KV cache quantization of this model.
+    pub(crate) fn empty_entry(&self) -> KvCacheEntry {
+        KvCacheEntry::new(None, self.kv_cache_quant())
+    }
+
+    /// Append k and v to a quantized cache entry and return the dequantized (k,v). Only the
+    /// quantized values are kept: the whole cache is dequantized again in each step, so that it
+    /// takes a fraction of the memory of a full precision cache.
+    fn update_quantized_kv_cache(
+        quant: KvCacheQuant,
+        cache: &mut KvCacheEntry,
+        k: Tensor,
+        v: Tensor,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let (head_dim, dtype) = (k.dim(3)?, k.dtype());
+        let (values, scales) = quant.quantize_kv(&k, &v)?;
+        let (values, scales) = match cache.kv.take() {
+            None => (values, scales),
+            Some((prev_values, prev_scales)) => (
+                Tensor::cat(&[prev_values, values], 2)?,
+                Tensor::cat(&[prev_scales, scales], 2)?,
+            ),
+        };
+        let (k, v) = quant.dequantize_kv(&values, &scales, head_dim, dtype)?;
+        cache.kv = Some((values, scales));
+        Ok((k, v))
+    }
+
     /// Update the KV cache and return (k,v)
     pub(crate) fn update_kv_cache(
-        cache: &mut Option<(Tensor, Tensor)>,
+        cache: &mut KvCacheEntry,
         k: Tensor,
         v: Tensor,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor), candle_core::Error> {
-        let (k, v) = match &*cache {
+        if let Some(quant) = cache.quant {
+            return Self::update_quantized_kv_cache(quant, cache, k, v);
+        }
+        let (k, v) = match &cache.kv {
             None => (k, v),
             Some((k_cache, v_cache)) => {
                 if !slow_cat {
@@ -98,51 +456,41 @@ impl Cache {
                 }
             }
         };
-        *cache = Some((k.clone(), v.clone()));
+        cache.kv = Some((k.clone(), v.clone()));
         Ok((k, v))
     }
 
     /// Update the KV cache and return (k,v,attn_mask)
     pub(crate) fn update_kv_cache_sliding_window(
-        cache: &mut Option<(Tensor, Tensor)>,
+        cache: &mut KvCacheEntry,
         k: Tensor,
         v: Tensor,
         attention_mask: Option<&Tensor>,
         sliding_window: Option<usize>,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor, Option<Tensor>), candle_core::Error> {
-        let (k, v, attention_mask) = match cache.clone() {
-            None => (k, v, attention_mask.cloned()),
-            Some((mut prev_k, mut prev_v)) => {
-                let mut mask = attention_mask.cloned();
-                if let Some(sliding_window) = sliding_window {
-                    let kv_seq_len = prev_k.dim(2)?;
-                    if kv_seq_len > sliding_window {
-                        prev_k = prev_k.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        prev_v = prev_v.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        if let Some(ref mut mask) = mask {
-                            let mask_len = mask.dim(1)?;
-                            *mask = mask.narrow(
-                                1,
-                                mask_len - (sliding_window - 1),
-                                sliding_window - 1,
-                            )?;
-                            *mask = Tensor::cat(
-                                &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
-                                D::Minus1,
-                            )?;
-                        }
-                    }
+        let mut attention_mask = attention_mask.cloned();
+        if let (Some(sliding_window), Some(kv_seq_len)) = (sliding_window, cache.seq_len()) {
+            if kv_seq_len > sliding_window {
+                cache.narrow(kv_seq_len - (sliding_window - 1), sliding_window - 1)?;
+                if let Some(ref mut mask) = attention_mask {
+                    let mask_len = mask.dim(1)?;
+                    *mask = mask.narrow(1, mask_len - (sliding_window - 1), sliding_window - 1)?;
+                    *mask = Tensor::cat(
+                        &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
+                        D::Minus1,
+                    )?;
                 }
-                let (k, v) = if !slow_cat {
+            }
+        }
+        if let Some(quant) = cache.quant {
+            let (k, v) = Self::update_quantized_kv_cache(quant, cache, k, v)?;
+            return Ok((k, v, attention_mask));
+        }
+        let (k, v) = match cache.kv.take() {
+            None => (k, v),
+            Some((prev_k, prev_v)) => {
+                if !slow_cat {
                     let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
                     let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
                     (k, v)
@@ -150,39 +498,44 @@ impl Cache {
                     let k = Tensor::cat(&[prev_k, k], 2)?.contiguous()?;
                     let v = Tensor::cat(&[prev_v, v], 2)?.contiguous()?;
                     (k, v)
-                };
-                (k, v, mask)
+                }
             }
         };
-        *cache = Some((k.clone(), v.clone()));
+        cache.kv = Some((k.clone(), v.clone()));
         Ok((k, v, attention_mask))
     }
 }
 
 pub struct DefaultCacheManager;
 
+#[derive(Clone, Copy)]
 enum SeqCache {
     Normal,
     XLora,
     Draft,
 }
 
+fn seq_layer_caches(seq: &mut Sequence, src: SeqCache) -> &mut LayerCaches {
+    match src {
+        SeqCache::Normal => seq.cache(),
+        SeqCache::XLora => seq.xlora_cache(),
+        SeqCache::Draft => seq.draft_cache(),
+    }
+}
+
 fn clone_in_cache(
     num_hidden_layers: usize,
-    cache: &mut LayerCaches,
+    cache: &mut Vec<KvCacheEntry>,
     seqs: &mut [&mut crate::sequence::Sequence],
     src: SeqCache,
+    quant: Option<KvCacheQuant>,
 ) {
     let mut new_cache = Vec::new();
     for layer in 0..num_hidden_layers {
         let mut k_vec = Vec::new();
         let mut v_vec = Vec::new();
         for seq in &mut *seqs {
-            let src_cache = match src {
-                SeqCache::Normal => seq.cache(),
-                SeqCache::XLora => seq.xlora_cache(),
-                SeqCache::Draft => seq.draft_cache(),
-            };
+            let src_cache = seq_layer_caches(seq, src);
             let cache = src_cache.get(layer).unwrap();
             let cache = cache
                 .as_ref()
@@ -190,32 +543,35 @@ fn clone_in_cache(
             k_vec.push(cache.0.clone());
             v_vec.push(cache.1.clone());
         }
-        new_cache.push(Some((
-            if k_vec.len() > 1 {
-                Tensor::cat(&k_vec, 0).unwrap()
-            } else {
-                k_vec[0].clone()
-            },
-            if v_vec.len() > 1 {
-                Tensor::cat(&v_vec, 0).unwrap()
-            } else {
-                v_vec[0].clone()
-            },
-        )));
+        new_cache.push(KvCacheEntry::new(
+            Some((
+                if k_vec.len() > 1 {
+                    Tensor::cat(&k_vec, 0).unwrap()
+                } else {
+                    k_vec[0].clone()
+                },
+                if v_vec.len() > 1 {
+                    Tensor::cat(&v_vec, 0).unwrap()
+                } else {
+                    v_vec[0].clone()
+                },
+            )),
+            quant,
+        ));
     }
     *cache = new_cache;
 }
 
 fn clone_out_cache(
     num_hidden_layers: usize,
-    cache: &mut LayerCaches,
+    cache: &mut [KvCacheEntry],
     seqs: &mut [&mut crate::sequence::Sequence],
     target: SeqCache,
 ) {
     for layer in 0..num_hidden_layers {
         let cache = cache.get(layer).unwrap();
-        let k_cache = cache.as_ref().unwrap().0.clone();
-        let v_cache = cache.as_ref().unwrap().1.clone();
+        let k_cache = cache.kv().unwrap().0.clone();
+        let v_cache = cache.kv().unwrap().1.clone();
 
         let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
         debug_assert_eq!(k_caches.len(), seqs.len());
@@ -223,11 +579,7 @@ fn clone_out_cache(
         debug_assert_eq!(v_caches.len(), seqs.len());
 
         for (seq_i, seq) in seqs.iter_mut().enumerate() {
-            let output_cache = match target {
-                SeqCache::Normal => seq.cache(),
-                SeqCache::XLora => seq.xlora_cache(),
-                SeqCache::Draft => seq.draft_cache(),
-            };
+            let output_cache = seq_layer_caches(seq, target);
             let seq_cache = &mut output_cache[layer];
             let k = k_caches.get(seq_i).unwrap().clone();
             let v = v_caches.get(seq_i).unwrap().clone();
@@ -243,12 +595,14 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         seqs: &mut [&mut crate::sequence::Sequence],
         modify_draft_cache: bool,
     ) {
+        let quant = pipeline.cache().kv_cache_quant();
         if modify_draft_cache {
             clone_in_cache(
                 pipeline.get_metadata().num_hidden_layers,
                 &mut pipeline.cache().lock(),
                 seqs,
                 SeqCache::Draft,
+                quant,
             );
             return;
         }
@@ -257,6 +611,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
             &mut pipeline.cache().lock(),
             seqs,
             SeqCache::Normal,
+            quant,
         );
         if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
             clone_in_cache(
@@ -264,6 +619,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
                 &mut pipeline.cache().xlora_lock(),
                 seqs,
                 SeqCache::XLora,
+                quant,
             );
         }
         if pipeline.get_metadata().is_xlora {
@@ -280,12 +636,14 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         seqs: &mut [&mut crate::sequence::Sequence],
         modify_draft_cache: bool,
     ) {
+        let quant = pipeline.cache().kv_cache_quant();
         if modify_draft_cache {
             clone_out_cache(
                 pipeline.get_metadata().num_hidden_layers,
                 &mut pipeline.cache().lock(),
                 seqs,
                 SeqCache::Draft,
+                quant,
             );
             return;
         }
@@ -294,6 +652,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
             &mut pipeline.cache().lock(),
             seqs,
             SeqCache::Normal,
+            quant,
         );
         if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
             clone_out_cache(
@@ -301,6 +660,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
                 &mut pipeline.cache().xlora_lock(),
                 seqs,
                 SeqCache::XLora,
+                quant,
             );
         }
         if pipeline.get_metadata().is_xlora {
@@ -313,7 +673,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
     fn set_none_cache(&self, pipeline: &mut T, modify_draft_cache: bool) {
         let mut new_cache = Vec::new();
         for _ in 0..pipeline.get_metadata().num_hidden_layers {
-            new_cache.push(None);
+            new_cache.push(pipeline.cache().empty_entry());
         }
         pipeline.cache().lock().clone_from(&new_cache);
         if modify_draft_cache {
@@ -324,3 +684,132 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor, D};
+
+    use super::{Cache, KvCacheEntry, KvCacheQuant};
+
+    #[test]
+    fn kv_cache_quant_roundtrip() -> candle_core::Result<()> {
+        let k = Tensor::randn(0f32, 1., (2, 4, 5, 64), &Device::Cpu)?;
+        let v = Tensor::randn(0f32, 1., (2, 4, 5, 64), &Device::Cpu)?;
+        for (quant, tolerance) in [(KvCacheQuant::Int8, 0.05), (KvCacheQuant::Q4, 0.5)] {
+            let (values, scales) = quant.quantize_kv(&k, &v)?;
+            assert_eq!(values.dtype(), DType::U8);
+            assert_eq!(values.dim(2)?, 5);
+            assert_eq!(scales.dim(2)?, 5);
+            let (k_deq, v_deq) = quant.dequantize_kv(&values, &scales, 64, DType::F32)?;
+            for (x, x_deq) in [(&k, k_deq), (&v, v_deq)] {
+                let max_err = (x - x_deq)?.abs()?.flatten_all()?.max(0)?;
+                assert!(max_err.to_scalar::<f32>()? < tolerance, "{quant}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_kv_cache_matches_dequantized_cache() -> candle_core::Result<()> {
+        let quant = KvCacheQuant::Int8;
+        let mut cache = KvCacheEntry::new(None, Some(quant));
+        for step in 0..4 {
+            let k = Tensor::randn(0f32, 1., (1, 2, 2, 32), &Device::Cpu)?;
+            let v = Tensor::randn(0f32, 1., (1, 2, 2, 32), &Device::Cpu)?;
+            let (k, v, _) =
+                Cache::update_kv_cache_sliding_window(&mut cache, k, v, None, Some(5), false)?;
+            // The returned K and V match dequantizing the whole cache, also once the window slides.
+            assert_eq!(k.dim(2)?, [2, 4, 6, 6][step]);
+            let (values, scales) = cache.kv().unwrap();
+            let (k_all, v_all) = quant.dequantize_kv(values, scales, 32, DType::F32)?;
+            for (x, x_all) in [(k, k_all), (v, v_all)] {
+                let max_err = (x - x_all)?.abs()?.flatten_all()?.max(0)?;
+                assert_eq!(max_err.to_scalar::<f32>()?, 0.);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_kv_cache_entries_are_smaller() -> candle_core::Result<()> {
+        let entry_bytes = |entry: &KvCacheEntry| {
+            let (a, b) = entry.kv().unwrap();
+            a.elem_count() * a.dtype().size_in_bytes() + b.elem_count() * b.dtype().size_in_bytes()
+        };
+        let mut entries = [
+            KvCacheEntry::new(None, None),
+            KvCacheEntry::new(None, Some(KvCacheQuant::Int8)),
+            KvCacheEntry::new(None, Some(KvCacheQuant::Q4)),
+        ];
+        for _ in 0..3 {
+            let k = Tensor::randn(0f32, 1., (1, 4, 8, 64), &Device::Cpu)?.to_dtype(DType::F16)?;
+            let v = Tensor::randn(0f32, 1., (1, 4, 8, 64), &Device::Cpu)?.to_dtype(DType::F16)?;
+            for entry in &mut entries {
+                Cache::update_kv_cache(entry, k.clone(), v.clone(), true)?;
+            }
+        }
+        let [full, int8, q4] = entries.each_ref().map(entry_bytes);
+        // 64 bytes and a scale per head and token instead of 128 bytes for Int8, 32 bytes and two
+        // scales for Q4.
+        assert_eq!(full, 2 * 4 * 24 * 64 * 2);
+        assert_eq!(int8, 2 * 4 * 24 * (64 + 4));
+        assert_eq!(q4, 2 * 4 * 24 * (32 + 2 * 4));
+        Ok(())
+    }
+
+    #[test]
+    fn shift_context_cache() -> candle_core::Result<()> {
+        // Each position holds its index, and keys are "re-rotated" by subtracting the shift.
+        let positions = Tensor::arange(0f32, 10., &Device::Cpu)?.reshape((1, 1, 10, 1))?;
+        let mut cache = vec![Some((positions.clone(), positions))];
+        super::shift_context_cache(&mut cache, 10, 2, 5, None, |_, k| k - 5.)?;
+        let (k, v) = cache[0].as_ref().unwrap();
+        assert_eq!(k.flatten_all()?.to_vec1::<f32>()?, [0., 1., 2., 3., 4.]);
+        assert_eq!(v.flatten_all()?.to_vec1::<f32>()?, [0., 1., 7., 8., 9.]);
+        Ok(())
+    }
+
+    #[test]
+    fn shift_quantized_context_cache() -> candle_core::Result<()> {
+        for quant in [KvCacheQuant::Int8, KvCacheQuant::Q4] {
+            let k = Tensor::randn(0f32, 1., (1, 2, 10, 32), &Device::Cpu)?;
+            let v = Tensor::randn(0f32, 1., (1, 2, 10, 32), &Device::Cpu)?;
+            let (values, scales) = quant.quantize_kv(&k, &v)?;
+            let mut cache = vec![Some((values.clone(), scales.clone()))];
+            // Negating the keys keeps their scales, so requantizing them only rounds the scales.
+            super::shift_context_cache(&mut cache, 10, 2, 5, Some(quant), |_, k| k.neg())?;
+            let (new_values, new_scales) = cache[0].as_ref().unwrap();
+            assert_eq!(new_values.dim(2)?, 5);
+
+            // The sink keys and all values keep their bytes.
+            let n_values = values.dim(D::Minus1)? / 2;
+            let keep = |x: &Tensor| -> candle_core::Result<Tensor> {
+                Tensor::cat(&[x.narrow(2, 0, 2)?, x.narrow(2, 7, 3)?], 2)
+            };
+            let kept = keep(&values)?;
+            assert_eq!(
+                new_values.narrow(2, 0, 2)?.flatten_all()?.to_vec1::<u8>()?,
+                kept.narrow(2, 0, 2)?.flatten_all()?.to_vec1::<u8>()?
+            );
+            assert_eq!(
+                new_values
+                    .narrow(D::Minus1, n_values, n_values)?
+                    .flatten_all()?
+                    .to_vec1::<u8>()?,
+                kept.narrow(D::Minus1, n_values, n_values)?
+                    .flatten_all()?
+                    .to_vec1::<u8>()?
+            );
+
+            let (k_deq, v_deq) = quant.dequantize_kv(&values, &scales, 32, DType::F32)?;
+            let (new_k, new_v) = quant.dequantize_kv(new_values, new_scales, 32, DType::F32)?;
+            let expected_k =
+                Tensor::cat(&[k_deq.narrow(2, 0, 2)?, k_deq.narrow(2, 7, 3)?.neg()?], 2)?;
+            for (x, expected) in [(new_k, expected_k), (new_v, keep(&v_deq)?)] {
+                let max_err = (x - expected)?.abs()?.flatten_all()?.max(0)?;
+                assert!(max_err.to_scalar::<f32>()? < 1e-5, "{quant}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -37,10 +37,10 @@ pub struct BeginEndUnkTok(
 );
 
 #[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 /// Template for chat models including bos/eos/unk as well as the chat template.
 pub struct ChatTemplate {
-    add_bos_token: Option<bool>,
+    pub add_bos_token: Option<bool>,
     add_eos_token: Option<bool>,
     added_tokens_decoder: Option<HashMap<String, AddedTokensDecoder>>,
     additional_special_tokens: Option<Vec<String>>,
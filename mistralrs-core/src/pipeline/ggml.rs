@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind, TokenSource,
-    XLoraPaths,
+    get_adapter_names, get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs,
+    AdapterKind, CacheManager, GeneralMetadata, IsqPolicy, Loader, LoraAdapterFiles, ModelKind,
+    ModelPaths, QuantizationKind, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, CacheManagerMixin, IsqPipelineMixin, MetadataMixin, ModelCategory,
@@ -19,6 +19,7 @@ use crate::sequence::Sequence;
 use crate::utils::debug::setup_logger_and_debug;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::varbuilder_utils::load_runtime_adapter;
 use crate::xlora_models::NonGranularState;
 use crate::{do_sample, get_mut_arcmutex, get_paths, DeviceMapMetadata, Pipeline, DEBUG};
 use crate::{
@@ -326,6 +327,15 @@ impl Loader for GGMLLoader {
             Model::Llama(ref l) => l.max_seq_len,
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
         };
+        let row_adapters = self.kind.is_adapted_and(|a| a.is_lora())
+            && match model {
+                Model::XLoraLlama(ref model) => model.supports_row_adapters(),
+                _ => false,
+            };
+        let xlora_scalings_shape = match model {
+            Model::XLoraLlama(ref model) => model.xlora_scalings_shape(),
+            _ => None,
+        };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = match model {
             Model::Llama(ref model) => model.cache.lock().len(),
@@ -354,6 +364,11 @@ impl Loader for GGMLLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 is_xlora,
+                isq: None,
+                adapters: get_adapter_names(paths),
+                row_adapters,
+                xlora_scalings_shape,
+                activation_dtype: DType::F32,
             },
         })))
     }
@@ -403,7 +418,7 @@ impl PreProcessingMixin for GGMLPipeline {
 }
 
 impl IsqPipelineMixin for GGMLPipeline {
-    fn re_isq_model(&mut self, _dtype: GgmlDType) -> Result<()> {
+    fn re_isq_model(&mut self, _dtype: GgmlDType, _policy: Option<&IsqPolicy>) -> Result<()> {
         anyhow::bail!(
             "You are trying to in-situ requantize a GGML model. This will not do anything."
         )
@@ -432,7 +447,7 @@ impl CacheManagerMixin for GGMLPipeline {
 }
 
 impl AdapterActivationMixin for GGMLPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> anyhow::Result<usize> {
         let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
         if !is_lora {
             anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
@@ -445,6 +460,70 @@ impl AdapterActivationMixin for GGMLPipeline {
             _ => unreachable!(),
         }
     }
+    fn activate_row_adapters(
+        &mut self,
+        rows: Vec<Option<Vec<(String, f64)>>>,
+    ) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .activate_row_adapters(rows)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
+    fn load_adapter(&mut self, name: String, adapter: &LoraAdapterFiles) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Loading adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        if self.metadata.adapters.contains(&name) {
+            anyhow::bail!("Adapter `{name}` is already loaded.");
+        }
+        let vb = load_runtime_adapter(&adapter.weights)?;
+        let loaded = match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .load_adapter(&name, &vb, &adapter.config)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        };
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // Remove the adapter from the layers it was already loaded into.
+                let _ = self.unload_adapter(&name);
+                return Err(e);
+            }
+        };
+        if loaded == 0 {
+            anyhow::bail!("Adapter `{name}` does not target any layer of the model.");
+        }
+        self.metadata.adapters.push(name);
+        Ok(loaded)
+    }
+    fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Unloading adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        let unloaded = match self.model {
+            Model::XLoraLlama(ref mut model) => {
+                model.unload_adapter(name).map_err(anyhow::Error::msg)?
+            }
+            _ => unreachable!(),
+        };
+        if unloaded == 0 {
+            anyhow::bail!("Adapter `{name}` is not loaded.");
+        }
+        self.metadata.adapters.retain(|adapter| adapter != name);
+        Ok(unloaded)
+    }
 }
 
 impl MetadataMixin for GGMLPipeline {
@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource, XLoraPaths,
+    get_adapter_names, get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs,
+    AdapterKind, CacheManager, GeneralMetadata, IsqPolicy, Loader, LoraAdapterFiles, ModelKind,
+    ModelPaths, PrettyName, QuantizationKind, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, CacheManagerMixin, IsqPipelineMixin, MetadataMixin, ModelCategory,
@@ -18,14 +18,19 @@ use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
 use crate::utils::debug::setup_logger_and_debug;
+use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::varbuilder_utils::load_runtime_adapter;
 use crate::xlora_models::NonGranularState;
 use crate::{do_sample, get_mut_arcmutex, get_paths_gguf, DeviceMapMetadata, Pipeline, DEBUG};
 use crate::{
+    models::quantized_gemma::ModelWeights as QGemma,
     models::quantized_llama::ModelWeights as QLlama,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
+    models::quantized_qwen2::ModelWeights as QQwen2,
+    models::quantized_starcoder2::ModelWeights as QStarcoder2,
     utils::tokens::get_token,
     xlora_models::{XLoraQLlama, XLoraQPhi3},
 };
@@ -46,7 +51,7 @@ use std::sync::Arc;
 use strum::EnumString;
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{info, warn};
 
 enum Model {
     Llama(QLlama),
@@ -54,6 +59,9 @@ enum Model {
     XLoraLlama(XLoraQLlama),
     XLoraPhi3(XLoraQPhi3),
     Phi3(QPhi3),
+    Qwen2(QQwen2),
+    Gemma(QGemma),
+    Starcoder2(QStarcoder2),
 }
 
 pub struct GGUFPipeline {
@@ -95,15 +103,22 @@ pub enum GGUFArchitecture {
     Rwkv,
     Phi2,
     Phi3,
+    Qwen2,
+    Gemma,
+    Starcoder2,
 }
 
+const SUPPORTED_GGUF_ARCHITECTURES: &str = "Supported GGUF architectures are `llama` (including Mistral and Mixtral), `phi2`, `phi3`, `qwen2`, `gemma` and `starcoder2`.";
+
 // Wraps from_str() for some convenience:
 // - Case-insensitive variant matching (TODO: is this desirable?)
 // - Customized error until potential upstream support: https://github.com/Peternator7/strum/issues/332
 impl GGUFArchitecture {
     fn from_value<T: AsRef<str> + std::fmt::Display>(value: T) -> Result<Self> {
         Self::from_str(&value.as_ref().to_ascii_lowercase())
-            .with_context(|| format!("Unknown GGUF architecture `{value}`"))
+            .with_context(|| {
+                format!("Unknown GGUF architecture `{value}`. {SUPPORTED_GGUF_ARCHITECTURES}")
+            })
             .map_err(anyhow::Error::msg)
     }
 }
@@ -378,6 +393,32 @@ impl Loader for GGUFLoader {
             }
         };
 
+        // Read before the content is consumed by the model:
+        let gguf_chat_template = ContentMetadata {
+            path_prefix: "tokenizer",
+            metadata: &model.metadata,
+        }
+        .get_value::<String>("chat_template")
+        .ok();
+        // The chat template from the metadata is rendered with the BOS token from the metadata.
+        let gguf_tokenizer_metadata = ContentMetadata {
+            path_prefix: "tokenizer.ggml",
+            metadata: &model.metadata,
+        };
+        let gguf_bos_token = gguf_tokenizer_metadata
+            .get_value::<u32>("bos_token_id")
+            .ok()
+            .and_then(|id| {
+                gguf_tokenizer_metadata
+                    .get_value::<Vec<String>>("tokens")
+                    .ok()?
+                    .get(id as usize)
+                    .cloned()
+            });
+        let gguf_add_bos_token = gguf_tokenizer_metadata
+            .get_value::<bool>("add_bos_token")
+            .ok();
+
         let has_adapter = self.kind.is_adapted();
         let is_xlora = self.kind.is_adapted_and(|a| a.is_x_lora());
 
@@ -405,13 +446,20 @@ impl Loader for GGUFLoader {
                 GGUFArchitecture::Llama => Model::Llama(QLlama::try_from(model_config)?),
                 GGUFArchitecture::Phi2 => Model::Phi2(QPhi::try_from(model_config)?),
                 GGUFArchitecture::Phi3 => Model::Phi3(QPhi3::try_from(model_config)?),
-                a => bail!("Unsupported architecture `{a:?}` for GGUF"),
+                GGUFArchitecture::Qwen2 => Model::Qwen2(QQwen2::try_from(model_config)?),
+                GGUFArchitecture::Gemma => Model::Gemma(QGemma::try_from(model_config)?),
+                GGUFArchitecture::Starcoder2 => {
+                    Model::Starcoder2(QStarcoder2::try_from(model_config)?)
+                }
+                a => bail!(
+                    "Unsupported architecture `{a:?}` for GGUF. {SUPPORTED_GGUF_ARCHITECTURES}"
+                ),
             },
             ModelKind::AdapterQuantized { adapter, .. } => match arch {
                 GGUFArchitecture::Llama => Model::XLoraLlama(XLoraQLlama::try_from(model_config)?),
                 GGUFArchitecture::Phi3 => Model::XLoraPhi3(XLoraQPhi3::try_from(model_config)?),
                 a => bail!(
-                    "Unsupported architecture `{a:?}` for GGUF {kind}",
+                    "Unsupported architecture `{a:?}` for GGUF {kind}. Only `llama` and `phi3` are supported with adapters.",
                     kind = adapter.pretty_name()
                 ),
             },
@@ -421,7 +469,21 @@ impl Loader for GGUFLoader {
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-        let mut chat_template = get_chat_template(paths, &self.chat_template);
+        let mut chat_template = if paths.get_template_filename().to_string_lossy().is_empty()
+            && self.chat_template.is_none()
+        {
+            info!("Using the chat template from the GGUF metadata because no tokenizer model ID or chat template was specified.");
+            if gguf_chat_template.is_none() {
+                warn!("The GGUF metadata does not contain a chat template, only completion requests will be supported.");
+            }
+            let mut chat_template = ChatTemplate::default();
+            chat_template.chat_template = gguf_chat_template;
+            chat_template.add_bos_token = gguf_add_bos_token;
+            chat_template.bos_token = gguf_bos_token.map(|bos| BeginEndUnkTok(Either::Left(bos)));
+            chat_template
+        } else {
+            get_chat_template(paths, &self.chat_template)
+        };
 
         let max_seq_len = match model {
             Model::Llama(ref l) => l.max_seq_len,
@@ -429,6 +491,20 @@ impl Loader for GGUFLoader {
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
             Model::Phi3(ref p) => p.max_seq_len,
             Model::XLoraPhi3(ref p) => p.max_seq_len,
+            Model::Qwen2(ref p) => p.max_seq_len,
+            Model::Gemma(ref p) => p.max_seq_len,
+            Model::Starcoder2(ref p) => p.max_seq_len,
+        };
+        let row_adapters = self.kind.is_adapted_and(|a| a.is_lora())
+            && match model {
+                Model::XLoraLlama(ref model) => model.supports_row_adapters(),
+                Model::XLoraPhi3(_) => true,
+                _ => false,
+            };
+        let xlora_scalings_shape = match model {
+            Model::XLoraLlama(ref model) => model.xlora_scalings_shape(),
+            Model::XLoraPhi3(ref model) => model.xlora_scalings_shape(),
+            _ => None,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = match model {
@@ -437,6 +513,9 @@ impl Loader for GGUFLoader {
             Model::XLoraLlama(ref model) => model.cache.lock().len(),
             Model::Phi3(ref model) => model.cache.lock().len(),
             Model::XLoraPhi3(ref model) => model.cache.lock().len(),
+            Model::Qwen2(ref model) => model.cache.lock().len(),
+            Model::Gemma(ref model) => model.cache.lock().len(),
+            Model::Starcoder2(ref model) => model.cache.lock().len(),
         };
 
         if chat_template.bos_token.is_none() && bos.is_some() {
@@ -475,6 +554,11 @@ impl Loader for GGUFLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 is_xlora,
+                isq: None,
+                adapters: get_adapter_names(paths),
+                row_adapters,
+                xlora_scalings_shape,
+                activation_dtype: DType::F32,
             },
         })))
     }
@@ -501,7 +585,7 @@ impl PreProcessingMixin for GGUFPipeline {
 }
 
 impl IsqPipelineMixin for GGUFPipeline {
-    fn re_isq_model(&mut self, _dtype: GgmlDType) -> Result<()> {
+    fn re_isq_model(&mut self, _dtype: GgmlDType, _policy: Option<&IsqPolicy>) -> Result<()> {
         anyhow::bail!(
             "You are trying to in-situ requantize a GGML model. This will not do anything."
         )
@@ -528,12 +612,15 @@ impl CacheManagerMixin for GGUFPipeline {
             Model::XLoraLlama(ref model) => &model.cache,
             Model::Phi3(ref model) => &model.cache,
             Model::XLoraPhi3(ref model) => &model.cache,
+            Model::Qwen2(ref model) => &model.cache,
+            Model::Gemma(ref model) => &model.cache,
+            Model::Starcoder2(ref model) => &model.cache,
         }
     }
 }
 
 impl AdapterActivationMixin for GGUFPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> anyhow::Result<usize> {
         let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
         if !is_lora {
             anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
@@ -549,6 +636,79 @@ impl AdapterActivationMixin for GGUFPipeline {
             _ => unreachable!(),
         }
     }
+    fn activate_row_adapters(
+        &mut self,
+        rows: Vec<Option<Vec<(String, f64)>>>,
+    ) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .activate_row_adapters(rows)
+                .map_err(anyhow::Error::msg),
+            Model::XLoraPhi3(ref mut model) => model
+                .activate_row_adapters(rows)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
+    fn load_adapter(&mut self, name: String, adapter: &LoraAdapterFiles) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Loading adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        if self.metadata.adapters.contains(&name) {
+            anyhow::bail!("Adapter `{name}` is already loaded.");
+        }
+        let vb = load_runtime_adapter(&adapter.weights)?;
+        let loaded = match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .load_adapter(&name, &vb, &adapter.config)
+                .map_err(anyhow::Error::msg),
+            Model::XLoraPhi3(ref mut model) => model
+                .load_adapter(&name, &vb, &adapter.config)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        };
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // Remove the adapter from the layers it was already loaded into.
+                let _ = self.unload_adapter(&name);
+                return Err(e);
+            }
+        };
+        if loaded == 0 {
+            anyhow::bail!("Adapter `{name}` does not target any layer of the model.");
+        }
+        self.metadata.adapters.push(name);
+        Ok(loaded)
+    }
+    fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Unloading adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        let unloaded = match self.model {
+            Model::XLoraLlama(ref mut model) => {
+                model.unload_adapter(name).map_err(anyhow::Error::msg)?
+            }
+            Model::XLoraPhi3(ref mut model) => {
+                model.unload_adapter(name).map_err(anyhow::Error::msg)?
+            }
+            _ => unreachable!(),
+        };
+        if unloaded == 0 {
+            anyhow::bail!("Adapter `{name}` is not loaded.");
+        }
+        self.metadata.adapters.retain(|adapter| adapter != name);
+        Ok(unloaded)
+    }
 }
 
 impl MetadataMixin for GGUFPipeline {
@@ -559,6 +719,9 @@ impl MetadataMixin for GGUFPipeline {
             Model::XLoraLlama(ref model) => model.device.clone(),
             Model::Phi3(ref model) => model.device.clone(),
             Model::XLoraPhi3(ref model) => model.device.clone(),
+            Model::Qwen2(ref model) => model.device.clone(),
+            Model::Gemma(ref model) => model.device.clone(),
+            Model::Starcoder2(ref model) => model.device.clone(),
         }
     }
     fn tokenizer(&self) -> Arc<Tokenizer> {
@@ -622,6 +785,24 @@ impl Pipeline for GGUFPipeline {
                 &self.non_granular_state,
                 context_lens,
             ),
+            Model::Qwen2(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::Gemma(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::Starcoder2(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
         }
     }
     async fn sample(
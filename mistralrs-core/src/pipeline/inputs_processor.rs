@@ -76,7 +76,7 @@ pub mod text_models_inputs_processor {
             let offset = if let Some((_, offset)) = last_n_context_len {
                 offset
             } else {
-                0
+                seq.token_offset()
             };
             seqlen_offsets.push(offset);
 
@@ -85,30 +85,21 @@ pub mod text_models_inputs_processor {
                 seq.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
                 last_n_context_len.map(|(a, _)| a).unwrap_or(1),
             ));
-            position_ids.push(seq.len());
+            position_ids.push(seq.token_offset() + seq.len());
 
             seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
-            for pos in (0..seqs_tensors.len())
-                .map(|i| {
-                    (*seqlen_offsets.get(i).unwrap() as i64
-                        ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
-        } else {
-            for pos in (0..seqs_tensors.len())
-                .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
+        for pos in (0..seqs_tensors.len())
+            .map(|i| {
+                (*seqlen_offsets.get(i).unwrap() as i64
+                    ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+        {
+            tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
         }
         let positions_kernel = Tensor::cat(&tmp, 0)?;
         let input = Tensor::cat(&seqs_tensors, 0).unwrap();
@@ -145,9 +136,10 @@ pub mod text_models_inputs_processor {
         for (seq, ctxt) in input_seqs.iter().zip(toks) {
             let start_pos = ctxt.len().saturating_sub(1);
             let ctxt = ctxt[start_pos..].to_vec();
-            seqlen_offsets.push(start_pos);
+            // Positions discarded by context shifting are no longer in the KV cache.
+            seqlen_offsets.push(start_pos - seq.shifted_tokens());
             context_lens.push((0, 1));
-            position_ids.push(seq.len());
+            position_ids.push(seq.len() - seq.shifted_tokens());
 
             seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
         }
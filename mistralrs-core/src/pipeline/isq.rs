@@ -1,14 +1,19 @@
-use std::sync::{atomic::AtomicUsize, Arc};
-use std::fs;
-use std::path::Path;
-use std::vec::Vec;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+    time::UNIX_EPOCH,
+};
 
 use candle_core::{
-    shape::Shape,
-    quantized::{GgmlDType, QMatMul, QTensor, QStorage::Cpu},
-    Device, Tensor,
+    quantized::{ggml_file::qtensor_from_ggml, gguf_file, GgmlDType, QMatMul, QTensor},
+    Device, Shape, Tensor,
 };
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::Deserialize;
 use tracing::{info, warn};
 
 use crate::device_map::DeviceMapper;
@@ -57,58 +62,491 @@ fn get_quantization_behaviour(tensor: &Tensor, dtype: GgmlDType) -> Quantization
     }
 }
 
+/// The role of a tensor returned by [`IsqModel::get_tensors`], used by an [`IsqPolicy`] to select
+/// tensors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IsqTensorRole {
+    AttnQ,
+    AttnK,
+    AttnV,
+    /// A fused query, key and value projection.
+    AttnQkv,
+    AttnO,
+    Mlp,
+    MoeGate,
+    MoeExpert,
+    LmHead,
+}
+
+/// A tensor to quantize in-situ, the layer it belongs to (if any) and its role.
+pub type IsqTensor<'a> = (&'a mut QMatMul, Option<usize>, IsqTensorRole);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IsqRoleSelector {
+    All,
+    Attn,
+    Moe,
+    Role(IsqTensorRole),
+}
+
+impl IsqRoleSelector {
+    fn matches(&self, role: IsqTensorRole) -> bool {
+        match self {
+            Self::All => true,
+            Self::Attn => matches!(
+                role,
+                IsqTensorRole::AttnQ
+                    | IsqTensorRole::AttnK
+                    | IsqTensorRole::AttnV
+                    | IsqTensorRole::AttnQkv
+                    | IsqTensorRole::AttnO
+            ),
+            Self::Moe => matches!(role, IsqTensorRole::MoeGate | IsqTensorRole::MoeExpert),
+            Self::Role(r) => *r == role,
+        }
+    }
+}
+
+impl FromStr for IsqRoleSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "*" | "all" => Ok(Self::All),
+            "attn" => Ok(Self::Attn),
+            "attn_q" => Ok(Self::Role(IsqTensorRole::AttnQ)),
+            "attn_k" => Ok(Self::Role(IsqTensorRole::AttnK)),
+            "attn_v" => Ok(Self::Role(IsqTensorRole::AttnV)),
+            "attn_qkv" => Ok(Self::Role(IsqTensorRole::AttnQkv)),
+            "attn_o" => Ok(Self::Role(IsqTensorRole::AttnO)),
+            "mlp" => Ok(Self::Role(IsqTensorRole::Mlp)),
+            "moe" => Ok(Self::Moe),
+            "moe_gate" => Ok(Self::Role(IsqTensorRole::MoeGate)),
+            "moe_experts" => Ok(Self::Role(IsqTensorRole::MoeExpert)),
+            "lm_head" => Ok(Self::Role(IsqTensorRole::LmHead)),
+            other => Err(format!("Unknown ISQ tensor role `{other}`.")),
+        }
+    }
+}
+
+/// An inclusive range of decoder layers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IsqLayerRange {
+    First(usize),
+    Last(usize),
+    Range(usize, usize),
+}
+
+impl IsqLayerRange {
+    fn contains(&self, layer: usize, n_layers: usize) -> bool {
+        match *self {
+            Self::First(n) => layer < n,
+            Self::Last(n) => layer + n >= n_layers,
+            Self::Range(start, end) => (start..=end).contains(&layer),
+        }
+    }
+}
+
+impl FromStr for IsqLayerRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid layer range `{s}`."))
+        };
+        if let Some(n) = s.strip_prefix("first:") {
+            Ok(Self::First(parse(n)?))
+        } else if let Some(n) = s.strip_prefix("last:") {
+            Ok(Self::Last(parse(n)?))
+        } else if let Some((start, end)) = s.split_once('-') {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(format!("Invalid layer range `{s}`."));
+            }
+            Ok(Self::Range(start, end))
+        } else {
+            let layer = parse(s)?;
+            Ok(Self::Range(layer, layer))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct IsqRule {
+    /// Matches every role if empty.
+    roles: Vec<IsqRoleSelector>,
+    layers: Option<IsqLayerRange>,
+    /// `None` skips quantization.
+    dtype: Option<GgmlDType>,
+}
+
+impl IsqRule {
+    fn matches(&self, role: IsqTensorRole, layer: Option<usize>, n_layers: usize) -> bool {
+        let role_matches = self.roles.is_empty() || self.roles.iter().any(|r| r.matches(role));
+        let layer_matches = match (&self.layers, layer) {
+            (None, _) => true,
+            (Some(range), Some(layer)) => range.contains(layer, n_layers),
+            // Tensors outside of the decoder layers, like the lm head, are not in any range.
+            (Some(_), None) => false,
+        };
+        role_matches && layer_matches
+    }
+}
+
+/// Parse an ISQ dtype such as `Q4K` or `Q8_0`.
+pub fn parse_isq_value(s: &str) -> Result<GgmlDType, String> {
+    let dtype = match s {
+        "Q4_0" => GgmlDType::Q4_0,
+        "Q4_1" => GgmlDType::Q4_1,
+        "Q5_0" => GgmlDType::Q5_0,
+        "Q5_1" => GgmlDType::Q5_1,
+        "Q8_0" => GgmlDType::Q8_0,
+        "Q8_1" => GgmlDType::Q8_1,
+        "Q2K" => GgmlDType::Q2K,
+        "Q3K" => GgmlDType::Q3K,
+        "Q4K" => GgmlDType::Q4K,
+        "Q5K" => GgmlDType::Q5K,
+        "Q6K" => GgmlDType::Q6K,
+        "Q8K" => GgmlDType::Q8K,
+        _ => return Err(format!("GGML type {s} unknown")),
+    };
+    Ok(dtype)
+}
+
+/// A mixed-precision ISQ policy, mapping tensor roles and layer ranges to a dtype or to skipping
+/// quantization. Tensors not matched by any rule are quantized to the ISQ dtype.
+///
+/// A policy is written as comma separated `target=action` rules, where the first matching rule
+/// wins:
+/// - `target` is `roles`, `roles@layers` or `@layers`. Roles are joined with `|` and are one of
+///   `attn`, `attn_q`, `attn_k`, `attn_v`, `attn_qkv`, `attn_o`, `mlp`, `moe`, `moe_gate`,
+///   `moe_experts`, `lm_head` or `*`. Layers are `first:N`, `last:N`, `A-B` or a single layer.
+/// - `action` is a GGML dtype such as `Q8_0` or `Q4K`, or `skip` to keep the tensor unquantized.
+///
+/// For example, `attn=Q8_0,@first:2=Q6K,lm_head=skip` keeps the attention at Q8_0, the first two
+/// layers at Q6K and the lm head unquantized.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IsqPolicy {
+    spec: String,
+    rules: Vec<IsqRule>,
+}
+
+impl IsqPolicy {
+    /// The dtype to quantize a tensor to, or `None` to skip it.
+    fn dtype_for(
+        &self,
+        role: IsqTensorRole,
+        layer: Option<usize>,
+        n_layers: usize,
+        default: GgmlDType,
+    ) -> Option<GgmlDType> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(role, layer, n_layers))
+            .map_or(Some(default), |rule| rule.dtype)
+    }
+}
+
+impl FromStr for IsqPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (target, action) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("ISQ policy rule `{rule}` must be `target=action`."))?;
+            let (roles, layers) = match target.trim().split_once('@') {
+                Some((roles, layers)) => (roles, Some(layers.trim().parse()?)),
+                None => (target, None),
+            };
+            let roles = roles
+                .split('|')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            let dtype = match action.trim() {
+                "skip" => None,
+                dtype => Some(parse_isq_value(dtype)?),
+            };
+            rules.push(IsqRule {
+                roles,
+                layers,
+                dtype,
+            });
+        }
+        if rules.is_empty() {
+            return Err("ISQ policy has no rules.".to_string());
+        }
+        Ok(Self {
+            spec: s.trim().to_string(),
+            rules,
+        })
+    }
+}
+
+impl TryFrom<String> for IsqPolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for IsqPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+/// The dtype for each tensor, or `None` if it is skipped.
+fn isq_plan(
+    tensors: &[IsqTensor<'_>],
+    dtype: GgmlDType,
+    policy: Option<&IsqPolicy>,
+) -> Vec<Option<GgmlDType>> {
+    let n_layers = tensors
+        .iter()
+        .filter_map(|(_, layer, _)| *layer)
+        .max()
+        .map_or(0, |layer| layer + 1);
+    tensors
+        .iter()
+        .map(|(_, layer, role)| match policy {
+            Some(policy) => policy.dtype_for(*role, *layer, n_layers, dtype),
+            None => Some(dtype),
+        })
+        .collect()
+}
+
 macro_rules! generate_isq {
     ($tensor:expr, $device:expr, $dtype:expr, $n_quantized:expr) => {
         if let QMatMul::Tensor(t) = $tensor {
             let t = t.to_device(&$device).unwrap();
-            let quantization_behaviour = get_quantization_behaviour(&t, $dtype);
-            *$tensor =  match quantization_behaviour{
-                QuantizationBehaviour::Skip => {
-                    let shape = t.shape();
-                    warn!("Skipping quantization of tensor with shape {shape:?} as it is not quantizable.");
-                    QMatMul::QTensor(Arc::new(QTensor::quantize(&t, GgmlDType::F32).unwrap()))
-                },
-                QuantizationBehaviour::Quantize(dtype) => {
-                    let isq_exists = Path::new("isq").exists();
-                    if !isq_exists {
-                        info!("ISQ does not exist, creating new!");
-                        $n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        let qtensor = QTensor::quantize(&t, dtype).unwrap();
-                        let data = qtensor.data().unwrap();
-                        let shape = Shape::dims(&qtensor.shape()).into_iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",");
-                        info!("Writing ISQ to file isq");
-                        fs::write("isq", data).expect("Unable to write ISQ!");
-                        fs::write("isq_shape", shape).expect("Unable to write ISQ!");
-                        // save the data here
-                        QMatMul::QTensor(Arc::new(qtensor))
+            *$tensor = match $dtype {
+                // Skipped by the ISQ policy, keep the tensor unquantized.
+                None => QMatMul::Tensor(t),
+                Some(dtype) => match get_quantization_behaviour(&t, dtype) {
+                    QuantizationBehaviour::Skip => {
+                        let shape = t.shape();
+                        warn!("Skipping quantization of tensor with shape {shape:?} as it is not quantizable.");
+                        QMatMul::QTensor(Arc::new(QTensor::quantize(&t, GgmlDType::F32).unwrap()))
                     }
-                    else {
+                    QuantizationBehaviour::Quantize(dtype) => {
                         $n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        let qtensor = QTensor::quantize(&t, dtype).unwrap();
-                        let data = qtensor.data().unwrap();
-                        let read_data:Vec<u8> = fs::read("isq")?;
-                        let recreated_tensor = QTensor::new(Cpu(Box::new(read_data)), qtensor.shape());
-
-                        info!("Writing ISQ to file isq");
-                        // save the data here
-                        QMatMul::QTensor(Arc::new(qtensor))
+                        QMatMul::QTensor(Arc::new(QTensor::quantize(&t, dtype).unwrap()))
                     }
-                }
+                },
+            }
+        } else if let QMatMul::QTensor(t) = $tensor {
+            // Tensors loaded pre-quantized (GPTQ/AWQ) stay on the loading device, move their blocks.
+            if !t.device().same_device(&$device) {
+                let moved = {
+                    let data = t.data().unwrap();
+                    qtensor_from_ggml(t.dtype(), &data, t.shape().dims().to_vec(), &$device)
+                        .unwrap()
+                };
+                *$tensor = QMatMul::QTensor(Arc::new(moved));
             }
         }
     };
 }
 
+/// The version of the ISQ artifact layout. Bump it when the tensor names or the order of the
+/// tensors of a layer and role in [`IsqModel::get_tensors`] change.
+const ISQ_ARTIFACT_VERSION: u32 = 1;
+
+const VERSION_KEY: &str = "mistralrs.isq.version";
+const CRATE_VERSION_KEY: &str = "mistralrs.isq.crate_version";
+const MODEL_ID_KEY: &str = "mistralrs.isq.model_id";
+const REVISION_KEY: &str = "mistralrs.isq.revision";
+const DTYPE_KEY: &str = "mistralrs.isq.dtype";
+const POLICY_KEY: &str = "mistralrs.isq.policy";
+
+/// The default directory ISQ artifacts are stored in, `<cache dir>/mistralrs/isq`.
+pub fn default_isq_artifacts_dir() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join("mistralrs").join("isq"))
+}
+
+/// A persisted ISQ artifact: the quantized tensors of a model, stored as a GGUF file together with
+/// the artifact layout and crate versions, and the model id, revision, ISQ dtype and ISQ policy it
+/// was created for. Loading a matching artifact skips requantization.
+pub struct IsqArtifact {
+    path: PathBuf,
+    model_id: String,
+    revision: String,
+    dtype: GgmlDType,
+    policy: String,
+}
+
+impl IsqArtifact {
+    /// The artifact in `dir` for the model whose `config.json` is at `config_filename` and whose
+    /// weights are `weight_filenames`.
+    pub fn new(
+        dir: &Path,
+        model_id: &str,
+        config_filename: &Path,
+        weight_filenames: &[PathBuf],
+        dtype: GgmlDType,
+        policy: Option<&IsqPolicy>,
+    ) -> Self {
+        let revision = Self::revision_of(config_filename, weight_filenames);
+        let sanitize = |s: &str| {
+            s.chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                        c
+                    } else {
+                        '-'
+                    }
+                })
+                .collect::<String>()
+        };
+        let policy = policy.map(ToString::to_string).unwrap_or_default();
+        let policy_suffix = if policy.is_empty() {
+            String::new()
+        } else {
+            format!("-{:016x}", fnv1a(&policy))
+        };
+        let path = dir.join(format!(
+            "{}--{}--{dtype:?}{policy_suffix}.gguf",
+            sanitize(model_id),
+            sanitize(&revision)
+        ));
+        Self {
+            path,
+            model_id: model_id.to_string(),
+            revision,
+            dtype,
+            policy,
+        }
+    }
+
+    /// Files from the Hugging Face cache live in `snapshots/<commit hash>/`, so use the commit
+    /// hash. Otherwise, this is a local model and the size and modification time of the config
+    /// and weight files are used.
+    fn revision_of(config_filename: &Path, weight_filenames: &[PathBuf]) -> String {
+        let snapshot = config_filename.parent();
+        let commit = snapshot.and_then(|s| Some((s.file_name()?, s.parent()?.file_name()?)));
+        match commit {
+            Some((commit, snapshots)) if snapshots == "snapshots" => {
+                commit.to_string_lossy().to_string()
+            }
+            _ => {
+                let stamps = std::iter::once(config_filename)
+                    .chain(weight_filenames.iter().map(PathBuf::as_path))
+                    .map(|file| {
+                        let metadata = fs::metadata(file).ok();
+                        let size = metadata.as_ref().map(|m| m.len()).unwrap_or_default();
+                        let modified = metadata
+                            .and_then(|m| m.modified().ok())
+                            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                            .map(|d| d.as_nanos())
+                            .unwrap_or_default();
+                        format!("{}:{size}:{modified}", file.display())
+                    })
+                    .collect::<Vec<_>>();
+                format!("local-{:016x}", fnv1a(&stamps.join("\n")))
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn metadata(&self) -> [(&'static str, String); 6] {
+        [
+            (VERSION_KEY, ISQ_ARTIFACT_VERSION.to_string()),
+            (CRATE_VERSION_KEY, env!("CARGO_PKG_VERSION").to_string()),
+            (MODEL_ID_KEY, self.model_id.clone()),
+            (REVISION_KEY, self.revision.clone()),
+            (DTYPE_KEY, format!("{:?}", self.dtype)),
+            (POLICY_KEY, self.policy.clone()),
+        ]
+    }
+
+    fn verify(&self, content: &gguf_file::Content) -> candle_core::Result<()> {
+        for (key, expected) in self.metadata() {
+            let value = content.metadata.get(key).and_then(|v| v.to_string().ok());
+            if value != Some(&expected) {
+                candle_core::bail!("ISQ artifact has `{key}` {value:?}, expected `{expected}`.");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The names of the tensors in an ISQ artifact: their layer, role and index among the tensors of
+/// that layer and role, such as `blk.3.Mlp.1`. Unlike the index in [`IsqModel::get_tensors`], these
+/// do not depend on the order of the tensors of different layers and roles.
+fn artifact_tensor_names(tensors: &[IsqTensor<'_>]) -> Vec<String> {
+    let mut counts = HashMap::new();
+    tensors
+        .iter()
+        .map(|(_, layer, role)| {
+            let n = counts.entry((*layer, *role)).or_insert(0usize);
+            let name = match layer {
+                Some(layer) => format!("blk.{layer}.{role:?}.{n}"),
+                None => format!("{role:?}.{n}"),
+            };
+            *n += 1;
+            name
+        })
+        .collect()
+}
+
+/// A stable hash, so artifact file names do not change between builds.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn qmatmul_shape(matmul: &QMatMul) -> Option<Shape> {
+    match matmul {
+        QMatMul::QTensor(qtensor) => Some(qtensor.shape().clone()),
+        QMatMul::Tensor(tensor) => Some(tensor.shape().clone()),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
 pub trait IsqModel {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper);
-    /// Quantize the model in-situ.
-    fn quantize(&mut self, dtype: GgmlDType, device: Device) -> candle_core::Result<()> {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper);
+
+    /// Whether any tensor is already quantized. Quantized tensors are not quantized again, so
+    /// requantizing such a model would not change it.
+    fn is_quantized(&mut self) -> bool {
+        let (tensors, _) = self.get_tensors();
+        tensors
+            .iter()
+            .any(|(tensor, _, _)| matches!(&**tensor, QMatMul::QTensor(_)))
+    }
+
+    /// Quantize the model in-situ. The `policy` overrides `dtype` for the tensors it matches.
+    fn quantize(
+        &mut self,
+        dtype: GgmlDType,
+        device: Device,
+        policy: Option<&IsqPolicy>,
+    ) -> candle_core::Result<()> {
         let (tensors, mapper) = self.get_tensors();
+        let plan = isq_plan(&tensors, dtype, policy);
         let total_tensors = tensors.len();
         let n_quantized = AtomicUsize::new(0);
-        info!(
-            "Applying in-situ quantization into {dtype:?} to {total_tensors} tensors in parallel."
-        );
+        match policy {
+            Some(policy) => info!(
+                "Applying in-situ quantization into {dtype:?} with policy `{policy}` to {total_tensors} tensors in parallel."
+            ),
+            None => info!(
+                "Applying in-situ quantization into {dtype:?} to {total_tensors} tensors in parallel."
+            ),
+        }
         let bar = ProgressBar::new(total_tensors as u64);
         bar.set_style(
             ProgressStyle::default_bar()
@@ -118,7 +556,7 @@ pub trait IsqModel {
         );
 
         let mut devices = Vec::new();
-        for (_, layer) in &tensors {
+        for (_, layer, _) in &tensors {
             let device = if let Some(layer) = layer {
                 mapper.device_for(*layer, false).unwrap_or(&device)
             } else {
@@ -134,10 +572,10 @@ pub trait IsqModel {
             tensors
                 .into_par_iter()
                 .zip(devices)
+                .zip(plan)
                 .progress_with(bar)
-                .for_each(|((tensor, _), device)| {
-                    let isq = generate_isq!(tensor, device, dtype, n_quantized);
-                    isq
+                .for_each(|(((tensor, _, _), device), dtype)| {
+                    generate_isq!(tensor, device, dtype, n_quantized)
                 });
         }
 
@@ -147,14 +585,341 @@ pub trait IsqModel {
             tensors
                 .into_iter()
                 .zip(devices)
+                .zip(plan)
                 .progress_with(bar)
-                .for_each(|((tensor, _), device)| {
-                    let isq = generate_isq!(tensor, device, dtype, n_quantized);
-                    isq
+                .for_each(|(((tensor, _, _), device), dtype)| {
+                    generate_isq!(tensor, device, dtype, n_quantized)
                 });
         }
         info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors.");
 
         Ok(())
     }
+
+    /// Quantize the model in-situ, reusing the persisted `artifact` if it matches this model.
+    /// Otherwise, quantize and persist the quantized tensors to `artifact`.
+    fn quantize_with_artifact(
+        &mut self,
+        dtype: GgmlDType,
+        device: Device,
+        policy: Option<&IsqPolicy>,
+        artifact: Option<&IsqArtifact>,
+    ) -> candle_core::Result<()> {
+        let Some(artifact) = artifact else {
+            return self.quantize(dtype, device, policy);
+        };
+        if artifact.path().exists() {
+            match self.load_isq_artifact(artifact, &device, dtype, policy) {
+                Ok(()) => {
+                    info!(
+                        "Loaded in-situ quantized tensors from ISQ artifact `{}`.",
+                        artifact.path().display()
+                    );
+                    return Ok(());
+                }
+                Err(e) => warn!(
+                    "Could not load ISQ artifact `{}`, quantizing instead: {e}",
+                    artifact.path().display()
+                ),
+            }
+        }
+        self.quantize(dtype, device, policy)?;
+        match self.save_isq_artifact(artifact) {
+            Ok(()) => info!("Saved ISQ artifact to `{}`.", artifact.path().display()),
+            Err(e) => warn!(
+                "Could not save ISQ artifact `{}`: {e}",
+                artifact.path().display()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Write the quantized tensors to `artifact`. Tensors are named by their layer and role, and
+    /// tensors skipped by the ISQ policy are not written.
+    fn save_isq_artifact(&mut self, artifact: &IsqArtifact) -> candle_core::Result<()> {
+        let (tensors, _) = self.get_tensors();
+        let mut names = Vec::with_capacity(tensors.len());
+        let mut qtensors = Vec::with_capacity(tensors.len());
+        for ((tensor, _, _), name) in tensors.iter().zip(artifact_tensor_names(&tensors)) {
+            match &**tensor {
+                QMatMul::QTensor(qtensor) => {
+                    names.push(name);
+                    qtensors.push(qtensor.clone());
+                }
+                QMatMul::Tensor(_) => (),
+                #[allow(unreachable_patterns)]
+                _ => candle_core::bail!("Cannot save an ISQ artifact of this tensor."),
+            }
+        }
+        let qtensors = names
+            .iter()
+            .map(String::as_str)
+            .zip(qtensors.iter().map(|q| &**q))
+            .collect::<Vec<_>>();
+        let metadata = artifact
+            .metadata()
+            .map(|(key, value)| (key, gguf_file::Value::String(value)));
+        let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+
+        if let Some(dir) = artifact.path().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so an interrupted write never leaves a truncated artifact.
+        let tmp = artifact.path().with_extension("gguf.tmp");
+        let mut file = File::create(&tmp)?;
+        gguf_file::write(&mut file, &metadata, &qtensors)?;
+        fs::rename(&tmp, artifact.path())?;
+        Ok(())
+    }
+
+    /// Replace the tensors with the quantized tensors from `artifact`, and move the tensors
+    /// skipped by the ISQ `policy` to their device. Nothing is replaced unless the artifact matches
+    /// the model id, revision, dtype and policy, and every tensor shape matches.
+    fn load_isq_artifact(
+        &mut self,
+        artifact: &IsqArtifact,
+        device: &Device,
+        dtype: GgmlDType,
+        policy: Option<&IsqPolicy>,
+    ) -> candle_core::Result<()> {
+        let mut file = File::open(artifact.path())?;
+        let content = gguf_file::Content::read(&mut file)?;
+        artifact.verify(&content)?;
+
+        let (tensors, mapper) = self.get_tensors();
+        let plan = isq_plan(&tensors, dtype, policy);
+        let n_expected = plan.iter().filter(|dtype| dtype.is_some()).count();
+        if content.tensor_infos.len() != n_expected {
+            candle_core::bail!(
+                "ISQ artifact has {} tensors, expected {n_expected}.",
+                content.tensor_infos.len(),
+            );
+        }
+        let names = artifact_tensor_names(&tensors);
+        let mut replacements = Vec::with_capacity(tensors.len());
+        for (((tensor, layer, _), dtype), name) in tensors.iter().zip(&plan).zip(&names) {
+            let device = layer
+                .and_then(|layer| mapper.device_for(layer, false))
+                .unwrap_or(device);
+            if dtype.is_none() {
+                let replacement = match &**tensor {
+                    QMatMul::Tensor(t) => QMatMul::Tensor(t.to_device(device)?),
+                    other => other.clone(),
+                };
+                replacements.push(replacement);
+                continue;
+            }
+            let qtensor = content.tensor(&mut file, name, device)?;
+            if qmatmul_shape(tensor).is_some_and(|shape| &shape != qtensor.shape()) {
+                candle_core::bail!(
+                    "ISQ artifact tensor `{name}` has shape {:?}, expected {:?}.",
+                    qtensor.shape(),
+                    qmatmul_shape(tensor)
+                );
+            }
+            replacements.push(QMatMul::QTensor(Arc::new(qtensor)));
+        }
+        for ((tensor, _, _), replacement) in tensors.into_iter().zip(replacements) {
+            *tensor = replacement;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, iter::zip};
+
+    use candle_core::{
+        quantized::{GgmlDType, QMatMul},
+        Device, Result, Tensor,
+    };
+
+    use super::{IsqArtifact, IsqModel, IsqPolicy, IsqTensor, IsqTensorRole};
+    use crate::device_map::{DeviceMapMetadata, DeviceMapper};
+
+    struct TestModel {
+        tensors: Vec<(QMatMul, Option<usize>, IsqTensorRole)>,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+    }
+
+    impl TestModel {
+        fn new() -> Result<Self> {
+            let weight = |start: f32| -> Result<QMatMul> {
+                let t =
+                    (Tensor::arange(start, start + 128., &Device::Cpu)?.reshape((4, 32))? / 16.)?;
+                Ok(QMatMul::Tensor(t))
+            };
+            Ok(Self {
+                tensors: vec![
+                    (weight(0.)?, Some(0), IsqTensorRole::AttnQ),
+                    (weight(-64.)?, Some(1), IsqTensorRole::Mlp),
+                    (weight(32.)?, None, IsqTensorRole::LmHead),
+                ],
+                mapper: DeviceMapMetadata::dummy().into_mapper(2, &Device::Cpu)?,
+            })
+        }
+    }
+
+    impl IsqModel for TestModel {
+        fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
+            let tensors = self
+                .tensors
+                .iter_mut()
+                .map(|(tensor, layer, role)| (tensor, *layer, *role))
+                .collect();
+            (tensors, &*self.mapper)
+        }
+    }
+
+    fn artifact(path: &std::path::Path, dtype: GgmlDType, policy: &IsqPolicy) -> IsqArtifact {
+        IsqArtifact {
+            path: path.join("model.gguf"),
+            model_id: "test/model".to_string(),
+            revision: "local-0".to_string(),
+            dtype,
+            policy: policy.to_string(),
+        }
+    }
+
+    #[test]
+    fn isq_artifact_roundtrip() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mistralrs-isq-test-{}", std::process::id()));
+        let policy: IsqPolicy = "mlp=Q4_0,lm_head=skip".parse().unwrap();
+        let saved = artifact(&dir, GgmlDType::Q8_0, &policy);
+        let mut model = TestModel::new()?;
+        model.quantize_with_artifact(GgmlDType::Q8_0, Device::Cpu, Some(&policy), Some(&saved))?;
+        assert!(saved.path().exists());
+
+        let mut loaded = TestModel::new()?;
+        loaded.load_isq_artifact(&saved, &Device::Cpu, GgmlDType::Q8_0, Some(&policy))?;
+        let mut dtypes = Vec::new();
+        for ((expected, _, _), (actual, _, _)) in zip(&model.tensors, &loaded.tensors) {
+            let (expected, actual) = match (expected, actual) {
+                (QMatMul::QTensor(expected), QMatMul::QTensor(actual)) => {
+                    assert_eq!(expected.shape(), actual.shape());
+                    dtypes.push(Some(actual.dtype()));
+                    (
+                        expected.dequantize(&Device::Cpu)?,
+                        actual.dequantize(&Device::Cpu)?,
+                    )
+                }
+                (QMatMul::Tensor(expected), QMatMul::Tensor(actual)) => {
+                    dtypes.push(None);
+                    (expected.clone(), actual.clone())
+                }
+                _ => panic!("Loaded tensor is not of the saved kind."),
+            };
+            let diff = (expected - actual)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0.);
+        }
+        assert_eq!(
+            dtypes,
+            vec![Some(GgmlDType::Q8_0), Some(GgmlDType::Q4_0), None]
+        );
+
+        // An artifact of another dtype does not match the saved one.
+        let mismatched = artifact(&dir, GgmlDType::Q4K, &policy);
+        let mut unloaded = TestModel::new()?;
+        assert!(unloaded
+            .load_isq_artifact(&mismatched, &Device::Cpu, GgmlDType::Q4K, Some(&policy))
+            .is_err());
+        assert!(matches!(unloaded.tensors[0].0, QMatMul::Tensor(_)));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn isq_artifact_tensors_are_matched_by_layer_and_role() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-isq-order-test-{}", std::process::id()));
+        let policy: IsqPolicy = "lm_head=Q8_0".parse().unwrap();
+        let saved = artifact(&dir, GgmlDType::Q8_0, &policy);
+        let mut model = TestModel::new()?;
+        model.quantize_with_artifact(GgmlDType::Q8_0, Device::Cpu, Some(&policy), Some(&saved))?;
+
+        // The tensors all have the same shape, so only their names tell them apart.
+        let mut reordered = TestModel::new()?;
+        reordered.tensors.reverse();
+        reordered.load_isq_artifact(&saved, &Device::Cpu, GgmlDType::Q8_0, Some(&policy))?;
+        for (expected, actual) in zip(&model.tensors, reordered.tensors.iter().rev()) {
+            assert_eq!(expected.2, actual.2);
+            let (QMatMul::QTensor(expected), QMatMul::QTensor(actual)) = (&expected.0, &actual.0)
+            else {
+                panic!("Expected quantized tensors.");
+            };
+            let diff = (expected.dequantize(&Device::Cpu)? - actual.dequantize(&Device::Cpu)?)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0.);
+        }
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn local_revision_changes_with_weights() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-isq-revision-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir)?;
+        let config = dir.join("config.json");
+        let weights = vec![dir.join("model.safetensors")];
+        fs::write(&config, "{}")?;
+        fs::write(&weights[0], [0u8; 16])?;
+
+        let revision = IsqArtifact::revision_of(&config, &weights);
+        assert!(revision.starts_with("local-"));
+        assert_eq!(revision, IsqArtifact::revision_of(&config, &weights));
+
+        // Replacing the weights without touching the config changes the revision.
+        fs::write(&weights[0], [0u8; 32])?;
+        assert_ne!(revision, IsqArtifact::revision_of(&config, &weights));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn isq_policy_first_match_wins() {
+        let policy: IsqPolicy = "attn_v|attn_o=Q6K, @first:2=Q8_0, mlp@last:1=Q5K, lm_head=skip"
+            .parse()
+            .unwrap();
+        let dtype_for = |role, layer| policy.dtype_for(role, layer, 4, GgmlDType::Q4K);
+
+        assert_eq!(
+            dtype_for(IsqTensorRole::AttnV, Some(0)),
+            Some(GgmlDType::Q6K)
+        );
+        assert_eq!(
+            dtype_for(IsqTensorRole::AttnQ, Some(1)),
+            Some(GgmlDType::Q8_0)
+        );
+        assert_eq!(
+            dtype_for(IsqTensorRole::AttnQ, Some(2)),
+            Some(GgmlDType::Q4K)
+        );
+        assert_eq!(dtype_for(IsqTensorRole::Mlp, Some(3)), Some(GgmlDType::Q5K));
+        assert_eq!(
+            dtype_for(IsqTensorRole::MoeExpert, Some(3)),
+            Some(GgmlDType::Q4K)
+        );
+        assert_eq!(dtype_for(IsqTensorRole::LmHead, None), None);
+    }
+
+    #[test]
+    fn isq_policy_rejects_invalid_rules() {
+        for spec in ["", "attn", "ffn=Q4K", "attn=Q9K", "@3-1=Q4K", "@last:x=Q4K"] {
+            assert!(spec.parse::<IsqPolicy>().is_err(), "{spec}");
+        }
+    }
 }
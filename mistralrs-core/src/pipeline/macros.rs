@@ -213,13 +213,16 @@ macro_rules! get_paths_gguf {
             } else {
                 PathBuf::from_str("")?
             }
+        } else if $this.model_id.is_none() {
+            // Will be loaded from inside gguf file
+            PathBuf::from_str("")?
         } else {
             info!("Loading `tokenizer_config.json` at `{}` because no chat template file was specified.", this_model_id);
             $crate::api_get_file!(
                 api,
                 "tokenizer_config.json",
                 model_id
-            ) // Will be loaded from inside gguf file
+            )
         };
 
         let filenames = get_model_paths(
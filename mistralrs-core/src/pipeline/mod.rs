@@ -23,13 +23,20 @@ use chat_template::ChatTemplate;
 use core::fmt;
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFArchitecture, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
-pub use isq::IsqModel;
+pub(crate) use isq::IsqArtifact;
+pub use isq::{
+    default_isq_artifacts_dir, parse_isq_value, IsqModel, IsqPolicy, IsqTensor, IsqTensorRole,
+};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
 pub use normal_loaders::{
-    GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType,
+    Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType,
     NormalLoadingMetadata, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3RopeScaling, Qwen2Loader,
 };
-pub(crate) use paths::{get_chat_template, get_model_paths, get_xlora_paths, XLoraPaths};
+pub use paths::LoraAdapterFiles;
+pub(crate) use paths::{
+    get_base_model_files, get_chat_template, get_lora_adapter_paths, get_model_paths,
+    get_xlora_paths, XLoraPaths,
+};
 pub(crate) use processing::{BasicProcessor, MessagesAction, Processor, ProcessorCreator};
 use rand_isaac::Isaac64Rng;
 pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
@@ -44,13 +51,17 @@ pub use vision_loaders::{Phi3VLoader, VisionLoaderType, VisionModelLoader};
 
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
 
 use crate::{
+    layers::ScaledRotaryEmbedding,
     sequence::Sequence,
-    xlora_models::{NonGranularState, XLoraConfig},
+    xlora_models::{make_fixed_scalings, record_scalings, NonGranularState, XLoraConfig},
 };
 
-pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
+pub use self::cache_manager::{
+    Cache, CacheManager, ContextShift, KvCacheEntry, KvCacheQuant, LayerCaches,
+};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
@@ -422,10 +433,40 @@ pub struct GeneralMetadata {
     pub kind: ModelKind,
     // TODO: Replace is_xlora queries to check via kind instead:
     pub is_xlora: bool,
+    /// The in-situ quantization currently applied to the model, if any.
+    pub isq: Option<GgmlDType>,
+    /// Names of the LoRA adapters loaded into the model.
+    pub adapters: Vec<String>,
+    /// Whether sequences using different LoRA adapters can share a batch.
+    pub row_adapters: bool,
+    /// The shape of the X-LoRA scalings as (LoRA layers, adapters), if this is an X-LoRA model.
+    pub xlora_scalings_shape: Option<(usize, usize)>,
+    /// The dtype of the activations and of the KV cache before any KV cache quantization.
+    pub activation_dtype: DType,
+}
+
+/// Names of the adapters in the ordering file, followed by the preloaded adapters.
+#[allow(clippy::borrowed_box)]
+pub(crate) fn get_adapter_names(paths: &Box<dyn ModelPaths>) -> Vec<String> {
+    let mut names = paths
+        .get_adapter_configs()
+        .iter()
+        .flatten()
+        .map(|((_, name), _)| name.clone())
+        .collect::<Vec<_>>();
+    if let Some(preload) = paths.get_lora_preload_adapter_info() {
+        let mut preload = preload.keys().cloned().collect::<Vec<_>>();
+        preload.sort();
+        names.extend(preload);
+    }
+    names
 }
 
 pub enum AdapterInstruction {
-    Activate(Vec<String>),
+    /// The adapters to activate and the weight of each.
+    Activate(Vec<(String, f64)>),
+    /// The weighted adapters of each sequence of a batch whose sequences use different adapters.
+    ActivateRows(Vec<Option<Vec<(String, f64)>>>),
     None,
 }
 
@@ -448,7 +489,7 @@ pub trait PreProcessingMixin: MetadataMixin {
 }
 
 pub trait IsqPipelineMixin {
-    fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()>;
+    fn re_isq_model(&mut self, dtype: GgmlDType, policy: Option<&IsqPolicy>) -> Result<()>;
 }
 
 pub trait CacheManagerMixin {
@@ -463,11 +504,42 @@ pub trait CacheManagerMixin {
     /// This may also reset the non granular state if applicable.
     fn set_none_cache(&mut self, reset_non_granular: bool, modify_draft_cache: bool);
     fn cache(&self) -> &Cache;
+    /// The quantization of the KV cache of the model, if any.
+    fn kv_cache_quant(&self) -> Option<KvCacheQuant> {
+        self.cache().kv_cache_quant()
+    }
+    /// Set the quantization of the KV cache of the model. See [`Cache::set_kv_cache_quant`].
+    fn set_kv_cache_quant(&self, quant: Option<KvCacheQuant>) {
+        self.cache().set_kv_cache_quant(quant)
+    }
+    /// Whether [`CacheManagerMixin::shift_context`] is supported.
+    fn supports_context_shift(&self) -> bool {
+        false
+    }
+    /// Discard positions `n_sink..n_sink + n_discard` from the cache of the sequence, which holds
+    /// `n_positions` positions, and move the later positions back. See [`ContextShift`].
+    fn shift_context(
+        &mut self,
+        _seq: &mut Sequence,
+        _n_positions: usize,
+        _n_sink: usize,
+        _n_discard: usize,
+    ) -> candle_core::Result<()> {
+        candle_core::bail!("Context shifting is not supported for this model.");
+    }
 }
 
 pub trait AdapterActivationMixin {
-    /// Returns the number of activated adapters.
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize>;
+    /// Activate the adapters, scaling the output of each by its weight. Returns the number of
+    /// activated adapters.
+    fn activate_adapters(&mut self, adapters: Vec<(String, f64)>) -> Result<usize>;
+    /// Select the adapters of each sequence of the next batch, in order. `None` uses the active
+    /// adapters. Returns the number of LoRA layers which were updated.
+    fn activate_row_adapters(&mut self, rows: Vec<Option<Vec<(String, f64)>>>) -> Result<usize>;
+    /// Load a LoRA adapter under `name`. Returns the number of layers the adapter was loaded into.
+    fn load_adapter(&mut self, name: String, adapter: &LoraAdapterFiles) -> Result<usize>;
+    /// Returns the number of layers the adapter was unloaded from.
+    fn unload_adapter(&mut self, name: &str) -> Result<usize>;
 }
 
 pub trait MetadataMixin {
@@ -532,6 +604,13 @@ pub trait Pipeline:
                             >>::as_ref(&e))
                         })?
                     }
+                    AdapterInstruction::ActivateRows(rows) => {
+                        self.activate_row_adapters(rows).map_err(|e| {
+                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                dyn std::error::Error,
+                            >>::as_ref(&e))
+                        })?
+                    }
                     AdapterInstruction::None => 0,
                 };
                 self.clone_in_cache(input_seqs, false)
@@ -545,6 +624,13 @@ pub trait Pipeline:
                             >>::as_ref(&e))
                         })?
                     }
+                    AdapterInstruction::ActivateRows(rows) => {
+                        self.activate_row_adapters(rows).map_err(|e| {
+                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                dyn std::error::Error,
+                            >>::as_ref(&e))
+                        })?
+                    }
                     AdapterInstruction::None => 0,
                 };
             }
@@ -560,6 +646,13 @@ pub trait Pipeline:
                             >>::as_ref(&e))
                         })?
                     }
+                    AdapterInstruction::ActivateRows(rows) => {
+                        self.activate_row_adapters(rows).map_err(|e| {
+                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                dyn std::error::Error,
+                            >>::as_ref(&e))
+                        })?
+                    }
                     AdapterInstruction::None => 0,
                 };
                 self.set_none_cache(reset_non_granular, false)
@@ -567,8 +660,19 @@ pub trait Pipeline:
             _ => unreachable!("Unreachable PRE cache op."),
         }
 
+        if self.get_metadata().is_xlora {
+            *self.cache().get_fixed_scalings() =
+                make_fixed_scalings(input_seqs, self.get_metadata().xlora_scalings_shape)?;
+        }
+
         let logits = self.forward_inputs(inputs)?;
 
+        if self.get_metadata().is_xlora {
+            if let Some(scalings) = self.cache().get_last_scalings().take() {
+                record_scalings(input_seqs, &scalings, is_prompt)?;
+            }
+        }
+
         match post_op {
             CacheInstruction::Out => self.clone_out_cache(input_seqs, false),
             CacheInstruction::Nothing(_) => (),
@@ -623,12 +727,39 @@ pub trait NormalModel: IsqModel {
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
     fn max_seq_len(&self) -> usize;
-    fn activate_adapters(&mut self, _: Vec<String>) -> candle_core::Result<usize> {
+    /// The shape of the X-LoRA scalings as (LoRA layers, adapters), if this is an X-LoRA model.
+    fn xlora_scalings_shape(&self) -> Option<(usize, usize)> {
+        None
+    }
+    fn activate_adapters(&mut self, _: Vec<(String, f64)>) -> candle_core::Result<usize> {
         // NOTE: While X-LoRA shares a similar name, it is not equivalent. Its adapter set must remain the same.
         candle_core::bail!(
             "Activating adapters is only supported for models fine-tuned with LoRA."
         );
     }
+    fn activate_row_adapters(
+        &mut self,
+        _: Vec<Option<Vec<(String, f64)>>>,
+    ) -> candle_core::Result<usize> {
+        candle_core::bail!(
+            "Activating adapters is only supported for models fine-tuned with LoRA."
+        );
+    }
+    fn load_adapter(
+        &mut self,
+        _: &str,
+        _: &VarBuilder,
+        _: &LoraConfig,
+    ) -> candle_core::Result<usize> {
+        candle_core::bail!("Loading adapters is only supported for models fine-tuned with LoRA.");
+    }
+    fn unload_adapter(&mut self, _: &str) -> candle_core::Result<usize> {
+        candle_core::bail!("Unloading adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// The RoPE of each layer, in order, if the cached keys can be moved to other positions.
+    fn rotary_embeddings(&self) -> Option<Vec<&ScaledRotaryEmbedding>> {
+        None
+    }
 }
 
 pub trait VisionModel: IsqModel {
@@ -1,12 +1,12 @@
-use super::cache_manager::DefaultCacheManager;
+use super::cache_manager::{shift_context_cache, DefaultCacheManager};
 use super::normal_loaders::{
-    GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader,
-    Phi3Loader, Qwen2Loader,
+    Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType,
+    Phi2Loader, Phi3Loader, Qwen2Loader,
 };
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    get_adapter_names, get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs,
+    AdapterKind, CacheManager, GeneralMetadata, IsqArtifact, IsqPolicy, Loader, LoraAdapterFiles,
+    ModelKind, ModelPaths, NormalModel, NormalModelLoader, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, CacheManagerMixin, IsqPipelineMixin, MetadataMixin, ModelCategory,
@@ -14,6 +14,7 @@ use super::{
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
+use crate::gptq::QuantizationConfig;
 use crate::lora::Ordering;
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::{get_chat_template, Cache};
@@ -22,7 +23,10 @@ use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
 use crate::utils::debug::setup_logger_and_debug;
 use crate::utils::tokenizer::get_tokenizer;
-use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::utils::{
+    tokens::get_token,
+    varbuilder_utils::{from_mmaped_safetensors, load_runtime_adapter},
+};
 use crate::xlora_models::NonGranularState;
 use crate::{
     do_sample, get_mut_arcmutex, get_paths, lora_model_loader, normal_model_loader,
@@ -65,6 +69,8 @@ pub struct NormalLoader {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    isq_policy: Option<IsqPolicy>,
+    isq_artifacts: Option<PathBuf>,
 }
 
 #[derive(Default)]
@@ -79,6 +85,8 @@ pub struct NormalLoaderBuilder {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    isq_policy: Option<IsqPolicy>,
+    isq_artifacts: Option<PathBuf>,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -153,6 +161,19 @@ impl NormalLoaderBuilder {
         self.with_adapter(lora_model_id, lora_order, false, None)
     }
 
+    /// Use a mixed-precision ISQ policy when in-situ quantizing. See [`IsqPolicy`].
+    pub fn with_isq_policy(mut self, isq_policy: Option<IsqPolicy>) -> Self {
+        self.isq_policy = isq_policy;
+        self
+    }
+
+    /// Save in-situ quantized tensors as ISQ artifacts in this directory, and load them from it
+    /// instead of quantizing again.
+    pub fn with_isq_artifacts(mut self, isq_artifacts: Option<PathBuf>) -> Self {
+        self.isq_artifacts = isq_artifacts;
+        self
+    }
+
     pub fn build(self, loader: NormalLoaderType) -> Box<dyn Loader> {
         setup_logger_and_debug();
 
@@ -164,6 +185,7 @@ impl NormalLoaderBuilder {
             NormalLoaderType::Phi2 => Box::new(Phi2Loader),
             NormalLoaderType::Phi3 => Box::new(Phi3Loader),
             NormalLoaderType::Qwen2 => Box::new(Qwen2Loader),
+            NormalLoaderType::Gemma2 => Box::new(Gemma2Loader),
         };
         Box::new(NormalLoader {
             inner: loader,
@@ -176,6 +198,8 @@ impl NormalLoaderBuilder {
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
             tgt_non_granular_index: self.tgt_non_granular_index,
+            isq_policy: self.isq_policy,
+            isq_artifacts: self.isq_artifacts,
         })
     }
 }
@@ -233,6 +257,19 @@ impl Loader for NormalLoader {
                 .get_config_repr(&config, self.config.use_flash_attn)?
         );
 
+        if let Some(quant) = QuantizationConfig::from_model_config(&config)? {
+            if !matches!(self.kind, ModelKind::Normal) {
+                anyhow::bail!(
+                    "{} checkpoints are not supported with LoRA or X-LoRA adapters.",
+                    quant.quant_method
+                );
+            }
+            info!(
+                "Loading {}-bit {} checkpoint with group size {}.",
+                quant.bits, quant.quant_method, quant.group_size
+            );
+        }
+
         let load_device = if in_situ_quant.is_none() {
             device.clone()
         } else {
@@ -295,10 +332,33 @@ impl Loader for NormalLoader {
         let chat_template = get_chat_template(paths, &self.chat_template);
 
         if let Some(in_situ_quant) = in_situ_quant {
-            model.quantize(in_situ_quant, device.clone())?;
+            // Adapter models have different tensors than their base model.
+            let artifact_id = match &self.xlora_model_id {
+                Some(adapter_id) => format!("{}+{adapter_id}", self.model_id),
+                None => self.model_id.clone(),
+            };
+            let artifact = self.isq_artifacts.as_deref().map(|dir| {
+                IsqArtifact::new(
+                    dir,
+                    &artifact_id,
+                    paths.get_config_filename(),
+                    paths.get_weight_filenames(),
+                    in_situ_quant,
+                    self.isq_policy.as_ref(),
+                )
+            });
+            model.quantize_with_artifact(
+                in_situ_quant,
+                device.clone(),
+                self.isq_policy.as_ref(),
+                artifact.as_ref(),
+            )?;
         }
 
         let max_seq_len = model.max_seq_len();
+        let row_adapters =
+            self.kind.is_adapted_and(|a| a.is_lora()) && model.supports_row_adapters();
+        let xlora_scalings_shape = model.xlora_scalings_shape();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = model.cache().lock().len();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
@@ -324,6 +384,11 @@ impl Loader for NormalLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 is_xlora,
+                isq: in_situ_quant,
+                adapters: get_adapter_names(paths),
+                row_adapters,
+                xlora_scalings_shape,
+                activation_dtype: dtype.unwrap_or(default_dtype),
             },
         })))
     }
@@ -350,11 +415,18 @@ impl PreProcessingMixin for NormalPipeline {
 }
 
 impl IsqPipelineMixin for NormalPipeline {
-    fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()> {
+    fn re_isq_model(&mut self, dtype: GgmlDType, policy: Option<&IsqPolicy>) -> Result<()> {
+        if self.model.is_quantized() {
+            anyhow::bail!(
+                "The model is already quantized, and quantized tensors cannot be requantized."
+            );
+        }
         let device = self.device().clone();
         self.model
-            .quantize(dtype, device)
-            .map_err(anyhow::Error::msg)
+            .quantize(dtype, device, policy)
+            .map_err(anyhow::Error::msg)?;
+        self.metadata.isq = Some(dtype);
+        Ok(())
     }
 }
 
@@ -374,14 +446,82 @@ impl CacheManagerMixin for NormalPipeline {
     fn cache(&self) -> &Cache {
         self.model.cache()
     }
+    fn supports_context_shift(&self) -> bool {
+        !self.model.is_xlora() && self.model.rotary_embeddings().is_some()
+    }
+    fn shift_context(
+        &mut self,
+        seq: &mut Sequence,
+        n_positions: usize,
+        n_sink: usize,
+        n_discard: usize,
+    ) -> candle_core::Result<()> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Context shifting is not supported for X-LoRA models.");
+        }
+        let Some(ropes) = self.model.rotary_embeddings() else {
+            candle_core::bail!("Context shifting is not supported for this model.");
+        };
+        let quant = self.model.cache().kv_cache_quant();
+        shift_context_cache(
+            seq.cache(),
+            n_positions,
+            n_sink,
+            n_discard,
+            quant,
+            |layer, k| ropes[layer].shift_keys(k, n_discard),
+        )
+    }
 }
 
 impl AdapterActivationMixin for NormalPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> anyhow::Result<usize> {
         self.model
             .activate_adapters(adapter_names)
             .map_err(anyhow::Error::msg)
     }
+    fn activate_row_adapters(
+        &mut self,
+        rows: Vec<Option<Vec<(String, f64)>>>,
+    ) -> anyhow::Result<usize> {
+        self.model
+            .activate_row_adapters(rows)
+            .map_err(anyhow::Error::msg)
+    }
+    fn load_adapter(&mut self, name: String, adapter: &LoraAdapterFiles) -> anyhow::Result<usize> {
+        if self.metadata.adapters.contains(&name) {
+            anyhow::bail!("Adapter `{name}` is already loaded.");
+        }
+        let vb = load_runtime_adapter(&adapter.weights)?;
+        let loaded = self
+            .model
+            .load_adapter(&name, &vb, &adapter.config)
+            .map_err(anyhow::Error::msg);
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // Remove the adapter from the layers it was already loaded into.
+                let _ = self.unload_adapter(&name);
+                return Err(e);
+            }
+        };
+        if loaded == 0 {
+            anyhow::bail!("Adapter `{name}` does not target any layer of the model.");
+        }
+        self.metadata.adapters.push(name);
+        Ok(loaded)
+    }
+    fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+        let unloaded = self
+            .model
+            .unload_adapter(name)
+            .map_err(anyhow::Error::msg)?;
+        if unloaded == 0 {
+            anyhow::bail!("Adapter `{name}` is not loaded.");
+        }
+        self.metadata.adapters.retain(|adapter| adapter != name);
+        Ok(unloaded)
+    }
 }
 
 impl MetadataMixin for NormalPipeline {
@@ -47,6 +47,8 @@ pub trait NormalModelLoader {
 
 use super::NormalModel;
 use crate::{
+    gptq::QuantizationConfig,
+    layers::RopeScaling,
     models,
     xlora_models::{self, XLoraConfig},
     DeviceMapMetadata,
@@ -70,6 +72,8 @@ pub enum NormalLoaderType {
     Phi3,
     #[serde(rename = "qwen2")]
     Qwen2,
+    #[serde(rename = "gemma2")]
+    Gemma2,
 }
 
 impl FromStr for NormalLoaderType {
@@ -83,6 +87,7 @@ impl FromStr for NormalLoaderType {
             "phi2" => Ok(Self::Phi2),
             "phi3" => Ok(Self::Phi3),
             "qwen2" => Ok(Self::Qwen2),
+            "gemma2" => Ok(Self::Gemma2),
             a => Err(format!("Unknown architecture `{a}`")),
         }
     }
@@ -103,6 +108,8 @@ struct MistralBasicConfig {
     rms_norm_eps: f64,
     rope_theta: f64,
     sliding_window: Option<usize>,
+    rope_scaling: Option<RopeScaling>,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 impl MistralBasicConfig {
@@ -120,7 +127,9 @@ impl MistralBasicConfig {
             rms_norm_eps: basic_config.rms_norm_eps,
             rope_theta: basic_config.rope_theta,
             sliding_window: basic_config.sliding_window,
+            rope_scaling: basic_config.rope_scaling,
             use_flash_attn,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -199,6 +208,7 @@ struct GemmaBasicConfig {
 
     #[serde(default = "default_max_position_embeddings")]
     max_position_embeddings: usize,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 impl GemmaBasicConfig {
@@ -219,6 +229,7 @@ impl GemmaBasicConfig {
             attention_bias: basic_config.attention_bias,
             head_dim: basic_config.head_dim,
             use_flash_attn,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -290,6 +301,8 @@ struct LlamaBasicConfig {
     #[serde(default = "default_rope")]
     rope_theta: f32,
     max_position_embeddings: usize,
+    rope_scaling: Option<RopeScaling>,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 fn default_rope() -> f32 {
@@ -312,6 +325,8 @@ impl LlamaBasicConfig {
             rope_theta: basic_config.rope_theta,
             use_flash_attn,
             max_position_embeddings: basic_config.max_position_embeddings,
+            rope_scaling: basic_config.rope_scaling,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -386,6 +401,7 @@ struct MixtralBasicConfig {
     sliding_window: usize,
     num_experts_per_tok: usize,
     num_local_experts: usize,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 impl MixtralBasicConfig {
@@ -406,6 +422,7 @@ impl MixtralBasicConfig {
             use_flash_attn,
             num_experts_per_tok: basic_config.num_experts_per_tok,
             num_local_experts: basic_config.num_local_experts,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -477,6 +494,7 @@ struct Phi2BasicConfig {
     rope_theta: f32,
     partial_rotary_factor: f64,
     qk_layernorm: bool,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 impl Phi2BasicConfig {
@@ -497,6 +515,7 @@ impl Phi2BasicConfig {
             partial_rotary_factor: basic_config.partial_rotary_factor,
             qk_layernorm: basic_config.qk_layernorm,
             use_flash_attn,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -576,6 +595,7 @@ struct Phi3BasicConfig {
     max_position_embeddings: usize,
     original_max_position_embeddings: usize,
     sliding_window: Option<usize>,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 impl Phi3BasicConfig {
@@ -598,6 +618,7 @@ impl Phi3BasicConfig {
             original_max_position_embeddings: basic_config.original_max_position_embeddings,
             use_flash_attn,
             sliding_window: basic_config.sliding_window,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -673,6 +694,8 @@ struct Qwen2BasicConfig {
     rms_norm_eps: f64,
     use_sliding_window: bool,
     hidden_act: Activation,
+    rope_scaling: Option<RopeScaling>,
+    quantization_config: Option<QuantizationConfig>,
 }
 
 impl Qwen2BasicConfig {
@@ -693,7 +716,9 @@ impl Qwen2BasicConfig {
             max_window_layers: basic_config.max_window_layers,
             tie_word_embeddings: basic_config.tie_word_embeddings,
             use_sliding_window: basic_config.use_sliding_window,
+            rope_scaling: basic_config.rope_scaling,
             use_flash_attn,
+            quantization_config: basic_config.quantization_config,
         })
     }
 }
@@ -718,6 +743,117 @@ impl NormalModelLoader for Qwen2Loader {
             normal_loading_metadata,
         )?))
     }
+    fn load_xlora(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        normal_loading_metadata: NormalLoadingMetadata,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(xlora_models::XLoraQwen2::new(
+            &Qwen2BasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            lora_config,
+            xlora_config,
+            xlora_ordering,
+            self.is_gptx(),
+            normal_loading_metadata,
+            preload_adapters,
+        )?))
+    }
+    fn is_gptx(&self) -> bool {
+        true
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Qwen2BasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+}
+
+// ======================== Gemma2 loader
+
+fn default_gemma2_max_position_embeddings() -> usize {
+    8192
+}
+
+#[derive(Deserialize)]
+struct Gemma2BasicConfig {
+    attention_bias: bool,
+    head_dim: usize,
+    // The Gemma 2 configs include both hidden_act and hidden_activation.
+    hidden_act: Option<Activation>,
+    hidden_activation: Option<Activation>,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_attention_heads: usize,
+    num_hidden_layers: usize,
+    num_key_value_heads: usize,
+    rms_norm_eps: f64,
+    rope_theta: f64,
+    vocab_size: usize,
+    sliding_window: usize,
+    attn_logit_softcapping: Option<f64>,
+    final_logit_softcapping: Option<f64>,
+    query_pre_attn_scalar: usize,
+
+    #[serde(default = "default_gemma2_max_position_embeddings")]
+    max_position_embeddings: usize,
+    quantization_config: Option<QuantizationConfig>,
+}
+
+impl Gemma2BasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::gemma2::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::gemma2::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            hidden_act: basic_config.hidden_act,
+            hidden_activation: basic_config.hidden_activation,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            rope_theta: basic_config.rope_theta,
+            attention_bias: basic_config.attention_bias,
+            head_dim: basic_config.head_dim,
+            sliding_window: basic_config.sliding_window,
+            attn_logit_softcapping: basic_config.attn_logit_softcapping,
+            final_logit_softcapping: basic_config.final_logit_softcapping,
+            query_pre_attn_scalar: basic_config.query_pre_attn_scalar,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Gemma 2 model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct Gemma2Loader;
+
+impl NormalModelLoader for Gemma2Loader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::gemma2::Model::new(
+            &Gemma2BasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            normal_loading_metadata,
+        )?))
+    }
     fn load_xlora(
         &self,
         _config: &str,
@@ -729,13 +865,13 @@ impl NormalModelLoader for Qwen2Loader {
         _normal_loading_metadata: NormalLoadingMetadata,
         _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Box<dyn NormalModel + Send + Sync>> {
-        todo!()
+        anyhow::bail!("X-LoRA and LoRA are not yet supported for Gemma 2 models.")
     }
     fn is_gptx(&self) -> bool {
         true
     }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
-        Ok(Box::new(Qwen2BasicConfig::deserialize(
+        Ok(Box::new(Gemma2BasicConfig::deserialize(
             config,
             use_flash_attn,
         )?))
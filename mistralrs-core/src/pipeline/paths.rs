@@ -246,6 +246,112 @@ pub fn get_xlora_paths(
     })
 }
 
+/// Resolve the weights and configuration of a LoRA adapter loaded at runtime. The `source` is either
+/// a local directory or a Hugging Face model id containing `adapter_config.json` and the adapter
+/// safetensors, preferably named `adapter_model.safetensors`.
+pub(crate) fn get_lora_adapter_paths(
+    source: &str,
+    token_source: &TokenSource,
+) -> Result<(PathBuf, LoraConfig)> {
+    let local = Path::new(source);
+    let (config, weights) = if local.is_dir() {
+        let files = fs::read_dir(local)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let config = local.join("adapter_config.json");
+        if !config.exists() {
+            anyhow::bail!("Adapter `{source}` has no `adapter_config.json`.");
+        }
+        (
+            config,
+            select_adapter_weights(&files).map(|f| local.join(f)),
+        )
+    } else {
+        let api = ApiBuilder::new()
+            .with_progress(true)
+            .with_token(get_token(token_source)?)
+            .build()?
+            .model(source.to_string());
+        let files = api
+            .info()?
+            .siblings
+            .into_iter()
+            .map(|x| x.rfilename)
+            .collect::<Vec<_>>();
+        let weights = select_adapter_weights(&files)
+            .map(|f| api.get(f))
+            .transpose()?;
+        (api.get("adapter_config.json")?, weights)
+    };
+    let Some(weights) = weights else {
+        anyhow::bail!("Adapter `{source}` has no safetensors weights.");
+    };
+    let config: LoraConfig = serde_json::from_str(&fs::read_to_string(config)?)?;
+    Ok((weights, config))
+}
+
+/// The weights and configuration of a LoRA adapter to load at runtime. They are resolved, and
+/// downloaded if needed, before the adapter is sent to the engine, so that the engine does not wait
+/// on the Hugging Face Hub.
+#[derive(Clone, Debug)]
+pub struct LoraAdapterFiles {
+    pub(crate) weights: PathBuf,
+    pub(crate) config: LoraConfig,
+}
+
+impl LoraAdapterFiles {
+    /// Resolve the files of the adapter at `source`, a local directory or a Hugging Face model id.
+    pub fn resolve(source: &str, token_source: &TokenSource) -> Result<Self> {
+        let (weights, config) = get_lora_adapter_paths(source, token_source)?;
+        Ok(Self { weights, config })
+    }
+}
+
+/// Resolve the safetensors weights of a base model, and the configuration and tokenizer files next
+/// to them. The `source` is either a local directory or a Hugging Face model id.
+pub(crate) fn get_base_model_files(
+    source: &str,
+    token_source: &TokenSource,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let is_model_file =
+        |f: &str| f.ends_with(".safetensors") || f.ends_with(".json") || f == "tokenizer.model";
+    let local = Path::new(source);
+    let files = if local.is_dir() {
+        fs::read_dir(local)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_model_file(&entry.file_name().to_string_lossy()))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>()
+    } else {
+        let api = ApiBuilder::new()
+            .with_progress(true)
+            .with_token(get_token(token_source)?)
+            .build()?
+            .model(source.to_string());
+        api.info()?
+            .siblings
+            .into_iter()
+            .filter(|x| is_model_file(&x.rfilename))
+            .map(|x| api.get(&x.rfilename))
+            .collect::<std::result::Result<Vec<_>, _>>()?
+    };
+    let (weights, other): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|f| f.extension().is_some_and(|ext| ext == "safetensors"));
+    if weights.is_empty() {
+        anyhow::bail!("Model `{source}` has no safetensors weights.");
+    }
+    Ok((weights, other))
+}
+
+fn select_adapter_weights(files: &[String]) -> Option<&String> {
+    files
+        .iter()
+        .find(|f| f.ends_with("adapter_model.safetensors"))
+        .or_else(|| files.iter().find(|f| f.ends_with(".safetensors")))
+}
+
 pub fn get_model_paths(
     revision: String,
     token_source: &TokenSource,
@@ -39,7 +39,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                     if let Some(reason) = is_done {
                         if $use_prefix_cacher {
                             $prefix_cacher.add_sequence($seq);
-                            $prefix_cacher.evict_to_cpu()?;
+                            $prefix_cacher.evict()?;
                         }
                         $seq.set_state($crate::sequence::SequenceState::Done(reason));
                         $this.reset_non_granular_state();
@@ -97,7 +97,8 @@ macro_rules! finish_and_add_tokens_to_seq {
                     | $crate::sequence::StopReason::ModelLength(_)
                     | $crate::sequence::StopReason::Eos
                     | $crate::sequence::StopReason::StopTok(_)
-                    | $crate::sequence::StopReason::Canceled => {
+                    | $crate::sequence::StopReason::Canceled
+                    | $crate::sequence::StopReason::Timeout => {
                         String::from_utf8_lossy($seq.completion_bytes())
                             .trim_start()
                             .to_string()
@@ -120,6 +121,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                             role: "assistant".to_string(),
                         },
                         logprobs: logprobs.map(|l| $crate::Logprobs { content: Some(l) }),
+                        xlora_scalings: $seq.xlora_scalings(),
                     };
                     $seq.add_choice_to_group(choice);
                 } else {
@@ -128,17 +130,18 @@ macro_rules! finish_and_add_tokens_to_seq {
                         index: $seq.get_response_index(),
                         text,
                         logprobs: None,
+                        xlora_scalings: $seq.xlora_scalings(),
                     };
                     $seq.add_completion_choice_to_group(choice);
                 }
 
                 if $use_prefix_cacher {
                     $prefix_cacher.add_sequence($seq);
-                    $prefix_cacher.evict_to_cpu()?;
+                    $prefix_cacher.evict()?;
                 }
 
                 let group = $seq.get_mut_group();
-                if group.is_chat {
+                let sent = if group.is_chat {
                     group
                         .maybe_send_done_response(
                             $crate::ChatCompletionResponse {
@@ -149,11 +152,11 @@ macro_rules! finish_and_add_tokens_to_seq {
                                 system_fingerprint: $crate::SYSTEM_FINGERPRINT.to_string(),
                                 object: "chat.completion".to_string(),
                                 usage: group.get_usage(),
+                                warnings: group.warnings.clone(),
                             },
                             $seq.responder(),
                         )
                         .await
-                        .map_err(candle_core::Error::msg)?;
                 } else {
                     group
                         .maybe_send_completion_done_response(
@@ -165,11 +168,17 @@ macro_rules! finish_and_add_tokens_to_seq {
                                 system_fingerprint: $crate::SYSTEM_FINGERPRINT.to_string(),
                                 object: "text_completion".to_string(),
                                 usage: group.get_usage(),
+                                warnings: group.warnings.clone(),
                             },
                             $seq.responder(),
                         )
                         .await
-                        .map_err(candle_core::Error::msg)?;
+                };
+                if sent.is_err() {
+                    // The receiver is gone, so the client disconnected: treat it as a cancellation
+                    $seq.set_state($crate::sequence::SequenceState::Done(
+                        $crate::sequence::StopReason::Canceled,
+                    ));
                 }
             }
             $this.reset_non_granular_state();
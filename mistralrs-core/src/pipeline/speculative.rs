@@ -5,7 +5,7 @@ use std::{
 };
 
 use anyhow::Result as anyhowResult;
-use candle_core::{quantized::GgmlDType, DType, Device, IndexOp, Result, Tensor};
+use candle_core::{quantized::GgmlDType, DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
 
@@ -13,7 +13,7 @@ use crate::{
     finish_and_add_tokens_to_seq, get_mut_arcmutex,
     pipeline::{
         sampling::{sample_sequence, sample_target_sequence_speculative},
-        AdapterInstruction, Cache,
+        AdapterInstruction, Cache, KvCacheQuant,
     },
     prefix_cacher::PrefixCacheManager,
     sequence::{Sequence, SequenceRecognizer},
@@ -23,7 +23,8 @@ use crate::{
 use super::{
     cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
     AdapterActivationMixin, CacheInstruction, CacheManager, CacheManagerMixin, GeneralMetadata,
-    IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
+    IsqPipelineMixin, IsqPolicy, LoraAdapterFiles, MetadataMixin, ModelCategory, ModelPaths,
+    PreProcessingMixin,
 };
 
 /// A loader for a speculative pipeline using 2 [`Loader`]s.
@@ -170,7 +171,9 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' input processors do not match. This is required for speculative decoding.");
         }
-        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let mut metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        // Speculative decoding always uses the X-LoRA classifier.
+        metadata.xlora_scalings_shape = None;
         let category = get_mut_arcmutex!(target).category();
         // TODO: some checks or relaxation here?
         Ok(Self {
@@ -194,9 +197,11 @@ impl PreProcessingMixin for SpeculativePipeline {
 }
 
 impl IsqPipelineMixin for SpeculativePipeline {
-    fn re_isq_model(&mut self, dtype: GgmlDType) -> anyhow::Result<()> {
-        get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+    fn re_isq_model(&mut self, dtype: GgmlDType, policy: Option<&IsqPolicy>) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model(dtype, policy)?;
+        get_mut_arcmutex!(self.draft).re_isq_model(dtype, policy)?;
+        self.metadata.isq = Some(dtype);
+        Ok(())
     }
 }
 
@@ -228,16 +233,46 @@ impl CacheManagerMixin for SpeculativePipeline {
     fn cache(&self) -> &Cache {
         unreachable!()
     }
+    fn kv_cache_quant(&self) -> Option<KvCacheQuant> {
+        get_mut_arcmutex!(self.target).kv_cache_quant()
+    }
+    fn set_kv_cache_quant(&self, quant: Option<KvCacheQuant>) {
+        get_mut_arcmutex!(self.draft).set_kv_cache_quant(quant);
+        get_mut_arcmutex!(self.target).set_kv_cache_quant(quant);
+    }
 }
 
 impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters(&mut self, adapters: Vec<(String, f64)>) -> anyhow::Result<usize> {
         let mut res = 0;
         res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
         res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
         Ok(res)
     }
+    fn activate_row_adapters(
+        &mut self,
+        rows: Vec<Option<Vec<(String, f64)>>>,
+    ) -> anyhow::Result<usize> {
+        let mut res = 0;
+        res += get_mut_arcmutex!(self.draft).activate_row_adapters(rows.clone())?;
+        res += get_mut_arcmutex!(self.target).activate_row_adapters(rows)?;
+        Ok(res)
+    }
+    fn load_adapter(&mut self, name: String, adapter: &LoraAdapterFiles) -> anyhow::Result<usize> {
+        let mut res = 0;
+        res += get_mut_arcmutex!(self.draft).load_adapter(name.clone(), adapter)?;
+        res += get_mut_arcmutex!(self.target).load_adapter(name.clone(), adapter)?;
+        self.metadata.adapters.push(name);
+        Ok(res)
+    }
+    fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+        let mut res = 0;
+        res += get_mut_arcmutex!(self.draft).unload_adapter(name)?;
+        res += get_mut_arcmutex!(self.target).unload_adapter(name)?;
+        self.metadata.adapters.retain(|adapter| adapter != name);
+        Ok(res)
+    }
 }
 
 impl MetadataMixin for SpeculativePipeline {
@@ -299,6 +334,13 @@ impl Pipeline for SpeculativePipeline {
                             >>::as_ref(&e))
                         })?
                     }
+                    AdapterInstruction::ActivateRows(rows) => {
+                        self.activate_row_adapters(rows).map_err(|e| {
+                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                dyn std::error::Error,
+                            >>::as_ref(&e))
+                        })?
+                    }
                     AdapterInstruction::None => 0,
                 };
                 self.clone_in_cache(input_seqs, false)
@@ -312,6 +354,13 @@ impl Pipeline for SpeculativePipeline {
                             >>::as_ref(&e))
                         })?
                     }
+                    AdapterInstruction::ActivateRows(rows) => {
+                        self.activate_row_adapters(rows).map_err(|e| {
+                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                dyn std::error::Error,
+                            >>::as_ref(&e))
+                        })?
+                    }
                     AdapterInstruction::None => 0,
                 };
             }
@@ -327,6 +376,13 @@ impl Pipeline for SpeculativePipeline {
                             >>::as_ref(&e))
                         })?
                     }
+                    AdapterInstruction::ActivateRows(rows) => {
+                        self.activate_row_adapters(rows).map_err(|e| {
+                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                dyn std::error::Error,
+                            >>::as_ref(&e))
+                        })?
+                    }
                     AdapterInstruction::None => 0,
                 };
                 self.set_none_cache(reset_non_granular, false)
@@ -402,8 +458,7 @@ impl Pipeline for SpeculativePipeline {
         // ======================= Run the model with all draft tokens. ============================
 
         let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
-            .as_ref()
-            .map(|(k, _)| k.dims()[2])
+            .seq_len()
             .unwrap_or(0);
 
         // ========= Run the model ============
@@ -459,44 +514,36 @@ impl Pipeline for SpeculativePipeline {
 
         // ======================= Narrow caches to account for rejections ============================
         let n_not_accepted = self.gamma - accepted_tokens.len();
-        for (k, v) in get_mut_arcmutex!(self.draft)
-            .cache()
-            .lock()
-            .iter_mut()
-            .flatten()
-        {
-            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+        for entry in get_mut_arcmutex!(self.draft).cache().lock().iter_mut() {
+            if let Some(len) = entry.seq_len() {
+                entry.narrow(0, len - n_not_accepted)?;
+            }
         }
         if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-            for (k, v) in get_mut_arcmutex!(self.draft)
+            for entry in get_mut_arcmutex!(self.draft)
                 .cache()
                 .xlora_lock()
                 .iter_mut()
-                .flatten()
             {
-                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                if let Some(len) = entry.seq_len() {
+                    entry.narrow(0, len - n_not_accepted)?;
+                }
             }
         }
-        for (k, v) in get_mut_arcmutex!(self.target)
-            .cache()
-            .lock()
-            .iter_mut()
-            .flatten()
-        {
-            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+        for entry in get_mut_arcmutex!(self.target).cache().lock().iter_mut() {
+            if let Some(len) = entry.seq_len() {
+                entry.narrow(0, len - n_not_accepted)?;
+            }
         }
         if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-            for (k, v) in get_mut_arcmutex!(self.target)
+            for entry in get_mut_arcmutex!(self.target)
                 .cache()
                 .xlora_lock()
                 .iter_mut()
-                .flatten()
             {
-                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                if let Some(len) = entry.seq_len() {
+                    entry.narrow(0, len - n_not_accepted)?;
+                }
             }
         }
 
@@ -2,9 +2,9 @@ use super::cache_manager::DefaultCacheManager;
 use super::vision_loaders::{Phi3VLoader, VisionLoaderType};
 use super::{
     get_model_paths, get_xlora_paths, AdapterActivationMixin, Cache, CacheManager,
-    CacheManagerMixin, GeneralMetadata, IsqPipelineMixin, Loader, MetadataMixin, ModelCategory,
-    ModelKind, ModelPaths, PreProcessingMixin, Processor, TokenSource, VisionModel,
-    VisionModelLoader, XLoraPaths,
+    CacheManagerMixin, GeneralMetadata, IsqArtifact, IsqPipelineMixin, IsqPolicy, Loader,
+    LoraAdapterFiles, MetadataMixin, ModelCategory, ModelKind, ModelPaths, PreProcessingMixin,
+    Processor, TokenSource, VisionModel, VisionModelLoader, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -56,6 +56,8 @@ pub struct VisionLoader {
     tokenizer_json: Option<String>,
     xlora_model_id: Option<String>,
     xlora_order: Option<Ordering>,
+    isq_policy: Option<IsqPolicy>,
+    isq_artifacts: Option<PathBuf>,
 }
 
 #[derive(Default)]
@@ -66,6 +68,8 @@ pub struct VisionLoaderBuilder {
     kind: ModelKind,
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
+    isq_policy: Option<IsqPolicy>,
+    isq_artifacts: Option<PathBuf>,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -88,9 +92,24 @@ impl VisionLoaderBuilder {
             tokenizer_json,
             model_id,
             kind: ModelKind::Normal,
+            isq_policy: None,
+            isq_artifacts: None,
         }
     }
 
+    /// Use a mixed-precision ISQ policy when in-situ quantizing. See [`IsqPolicy`].
+    pub fn with_isq_policy(mut self, isq_policy: Option<IsqPolicy>) -> Self {
+        self.isq_policy = isq_policy;
+        self
+    }
+
+    /// Save in-situ quantized tensors as ISQ artifacts in this directory, and load them from it
+    /// instead of quantizing again.
+    pub fn with_isq_artifacts(mut self, isq_artifacts: Option<PathBuf>) -> Self {
+        self.isq_artifacts = isq_artifacts;
+        self
+    }
+
     pub fn build(self, loader: VisionLoaderType) -> Box<dyn Loader> {
         setup_logger_and_debug();
 
@@ -106,6 +125,8 @@ impl VisionLoaderBuilder {
             tokenizer_json: self.tokenizer_json,
             xlora_model_id: None,
             xlora_order: None,
+            isq_policy: self.isq_policy,
+            isq_artifacts: self.isq_artifacts,
         })
     }
 }
@@ -216,7 +237,22 @@ impl Loader for VisionLoader {
         let chat_template = get_chat_template(paths, &self.chat_template);
 
         if let Some(in_situ_quant) = in_situ_quant {
-            model.quantize(in_situ_quant, device.clone())?;
+            let artifact = self.isq_artifacts.as_deref().map(|dir| {
+                IsqArtifact::new(
+                    dir,
+                    &self.model_id,
+                    paths.get_config_filename(),
+                    paths.get_weight_filenames(),
+                    in_situ_quant,
+                    self.isq_policy.as_ref(),
+                )
+            });
+            model.quantize_with_artifact(
+                in_situ_quant,
+                device.clone(),
+                self.isq_policy.as_ref(),
+                artifact.as_ref(),
+            )?;
         }
 
         let max_seq_len = model.max_seq_len();
@@ -238,6 +274,11 @@ impl Loader for VisionLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 has_no_kv_cache: false,
+                isq: in_situ_quant,
+                adapters: Vec::new(),
+                row_adapters: false,
+                xlora_scalings_shape: None,
+                activation_dtype: dtype.unwrap_or(default_dtype),
             },
             processor,
             preprocessor_config: Arc::new(preprocessor_config),
@@ -266,11 +307,18 @@ impl PreProcessingMixin for VisionPipeline {
 }
 
 impl IsqPipelineMixin for VisionPipeline {
-    fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()> {
+    fn re_isq_model(&mut self, dtype: GgmlDType, policy: Option<&IsqPolicy>) -> Result<()> {
+        if self.model.is_quantized() {
+            anyhow::bail!(
+                "The model is already quantized, and quantized tensors cannot be requantized."
+            );
+        }
         let device = self.device().clone();
         self.model
-            .quantize(dtype, device)
-            .map_err(anyhow::Error::msg)
+            .quantize(dtype, device, policy)
+            .map_err(anyhow::Error::msg)?;
+        self.metadata.isq = Some(dtype);
+        Ok(())
     }
 }
 
@@ -293,9 +341,18 @@ impl CacheManagerMixin for VisionPipeline {
 }
 
 impl AdapterActivationMixin for VisionPipeline {
-    fn activate_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, _adapters: Vec<(String, f64)>) -> Result<usize> {
         anyhow::bail!("Vision models do not support adapter activation.");
     }
+    fn activate_row_adapters(&mut self, _rows: Vec<Option<Vec<(String, f64)>>>) -> Result<usize> {
+        anyhow::bail!("Vision models do not support adapter activation.");
+    }
+    fn load_adapter(&mut self, _name: String, _adapter: &LoraAdapterFiles) -> Result<usize> {
+        anyhow::bail!("Vision models do not support adapter loading.");
+    }
+    fn unload_adapter(&mut self, _name: &str) -> Result<usize> {
+        anyhow::bail!("Vision models do not support adapter loading.");
+    }
 }
 
 impl MetadataMixin for VisionPipeline {
@@ -1,11 +1,26 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor};
+#[cfg(feature = "pyo3_macros")]
+use pyo3::pyclass;
 use radix_trie::{Trie, TrieCommon, TrieKey};
+use serde::Serialize;
+use tracing::{info, warn};
 
-use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
+use crate::{
+    pipeline::{KvCacheQuant, LayerCaches},
+    sequence::Sequence,
+};
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone)]
 struct Tokens(Vec<u32>);
 
 impl TrieKey for Tokens {
@@ -23,15 +38,209 @@ impl From<Vec<u32>> for Tokens {
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+/// How the prefix cache chooses the entry to move to the host or delete when it is over a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PrefixCacheEviction {
+    /// Evict the least recently used entry.
+    #[default]
+    Lru,
+    /// Evict the least frequently used entry, breaking ties by recency.
+    Lfu,
+}
+
+impl FromStr for PrefixCacheEviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            other => Err(format!(
+                "Unknown prefix cache eviction policy `{other}`, expected `lru` or `lfu`."
+            )),
+        }
+    }
+}
+
+impl Display for PrefixCacheEviction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lru => write!(f, "lru"),
+            Self::Lfu => write!(f, "lfu"),
+        }
+    }
+}
+
+/// Default limit of the size of the prefix caches on the host, 4 GiB.
+pub const DEFAULT_PREFIX_CACHE_HOST_BYTES: usize = 4 << 30;
+
+/// Default time after which unused sessions are unpinned, 30 minutes.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Limits of the prefix cache. Entries over the device limits are moved to the host, and entries
+/// over the host limit are deleted, or moved to the disk if a disk directory is set.
+#[derive(Clone, Debug)]
+pub struct PrefixCacheConfig {
+    /// Maximum number of entries on the device.
+    pub n_on_device: usize,
+    /// Maximum size in bytes of the entries on the device.
+    pub device_bytes: Option<usize>,
+    /// Maximum size in bytes of the entries on the host. Without one, the host tier grows with
+    /// the number of distinct prompts.
+    pub host_bytes: Option<usize>,
+    pub eviction: PrefixCacheEviction,
+    /// Granularity in tokens of the prefixes shared with a prompt which only partially matches an
+    /// entry.
+    pub block_size: usize,
+    /// Directory in which entries deleted from the host are saved, so that they can be reused by
+    /// later prompts and after a restart.
+    pub disk_dir: Option<PathBuf>,
+    /// Maximum size in bytes of the entries on the disk.
+    pub disk_bytes: Option<usize>,
+    /// Maximum size in bytes of the sessions on the device. The least recently used sessions over
+    /// it are moved to the host.
+    pub session_device_bytes: Option<usize>,
+    /// Sessions unused for longer are unpinned, and saved to the disk directory if one is set.
+    pub session_ttl: Option<Duration>,
+}
+
+impl Default for PrefixCacheConfig {
+    fn default() -> Self {
+        Self {
+            n_on_device: 16,
+            device_bytes: None,
+            host_bytes: Some(DEFAULT_PREFIX_CACHE_HOST_BYTES),
+            eviction: PrefixCacheEviction::Lru,
+            block_size: 16,
+            disk_dir: None,
+            disk_bytes: None,
+            session_device_bytes: None,
+            session_ttl: Some(DEFAULT_SESSION_TTL),
+        }
+    }
+}
+
+/// Counters and current size of the prefix cache.
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct PrefixCacheStats {
+    /// Prompts which reused a cached prefix.
+    pub hits: u64,
+    /// Prompts for which no cached prefix was found.
+    pub misses: u64,
+    /// Prompt tokens which were not run because their cache was reused.
+    pub hit_tokens: u64,
+    pub inserted: u64,
+    /// Sequences which were not inserted or entries which were deleted because another entry
+    /// holds their cache.
+    pub deduplicated: u64,
+    pub moved_to_host: u64,
+    /// Entries deleted to stay within the host limit.
+    pub evicted: u64,
+    pub entries: usize,
+    pub device_entries: usize,
+    pub device_bytes: usize,
+    pub host_bytes: usize,
+    /// Prompts which reused a cached prefix loaded from the disk.
+    pub disk_hits: u64,
+    pub written_to_disk: u64,
+    /// Entries deleted from the disk to stay within the disk limit.
+    pub disk_evicted: u64,
+    pub disk_entries: usize,
+    pub disk_bytes: usize,
+    /// Prompts which continued from the cache of their session.
+    pub session_hits: u64,
+    /// Sessions whose cache is pinned.
+    pub sessions: usize,
+    pub session_bytes: usize,
+    pub session_device_bytes: usize,
+    pub sessions_moved_to_host: u64,
+    /// Sessions unpinned because they were unused for longer than their TTL.
+    pub sessions_expired: u64,
+    /// Expired sessions saved to the disk, which are loaded again when they are next used.
+    pub saved_sessions: usize,
+}
+
+struct CacheEntry {
+    normal: LayerCaches,
+    positions: usize,
+    xlora: Option<LayerCaches>,
+    bytes: usize,
+    on_device: bool,
+    last_use: u64,
+    uses: u64,
+}
+
+/// The cache of the last sequence of a session, which is not evicted with the prefix cache entries.
+struct Session {
+    toks: Vec<u32>,
+    normal: LayerCaches,
+    xlora: Option<LayerCaches>,
+    bytes: usize,
+    on_device: bool,
+    last_use: Instant,
+}
+
+/// An expired session saved to the disk.
+struct SavedSession {
+    path: PathBuf,
+    /// The background thread writing the file, if it was not joined yet.
+    write: Option<JoinHandle<Result<()>>>,
+}
+
+/// Wait for a background thread writing the file at `path`.
+fn join_write(write: JoinHandle<Result<()>>, path: &Path) -> Result<()> {
+    match write.join() {
+        Ok(res) => res,
+        Err(_) => candle_core::bail!("The thread writing `{}` panicked.", path.display()),
+    }
+}
+
+/// A cache saved by [`PrefixCacheManager::write_to_disk`].
+struct DiskEntry {
+    path: PathBuf,
+    positions: usize,
+    /// The size of the file, estimated from the size of the cache until it is written.
+    bytes: usize,
+    last_use: u64,
+    /// The background thread writing the file, if it was not joined yet.
+    write: Option<JoinHandle<Result<()>>>,
+}
+
+impl DiskEntry {
+    /// Wait for the file to be written, and update the size of the entry in `stats`.
+    fn finish_write(&mut self, stats: &mut PrefixCacheStats) -> Result<()> {
+        let Some(write) = self.write.take() else {
+            return Ok(());
+        };
+        join_write(write, &self.path)?;
+        let bytes = fs::metadata(&self.path)?.len() as usize;
+        stats.disk_bytes = stats.disk_bytes - self.bytes + bytes;
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+/// The saved caches of one model and KV cache dtype.
+struct DiskTier {
+    dir: PathBuf,
+    entries: Trie<Tokens, DiskEntry>,
+    /// The entries whose file is being written in the background.
+    pending: Vec<Tokens>,
+}
 
 pub struct PrefixCacheManager {
-    caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
-    xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
+    caches: Trie<Tokens, CacheEntry>,
+    disk: Option<DiskTier>,
+    sessions: HashMap<String, Session>,
+    saved_sessions: HashMap<String, SavedSession>,
     device: Device,
-    pub n_on_device: usize,
+    config: PrefixCacheConfig,
     no_prefix_cache: bool,
-    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    /// Incremented on every insertion and lookup, to order the entries by recency.
+    tick: u64,
+    stats: PrefixCacheStats,
 }
 
 #[derive(Clone)]
@@ -41,37 +250,398 @@ pub struct MatchingCache {
     pub toks: Vec<u32>,
 }
 
+/// Whether a cache is on the device rather than the host.
+fn is_on_device(cache: &LayerCaches) -> bool {
+    cache
+        .iter()
+        .flatten()
+        .next()
+        .is_some_and(|(k, _)| !matches!(k.device(), Device::Cpu))
+}
+
+fn cache_bytes(cache: &LayerCaches) -> usize {
+    cache
+        .iter()
+        .flatten()
+        .map(|(k, v)| {
+            k.elem_count() * k.dtype().size_in_bytes() + v.elem_count() * v.dtype().size_in_bytes()
+        })
+        .sum()
+}
+
+/// An entry of a tier, keyed by the tokens of its cache.
+trait CachedPositions {
+    /// The number of positions held by its cache.
+    fn positions(&self) -> usize;
+}
+
+impl CachedPositions for CacheEntry {
+    fn positions(&self) -> usize {
+        self.positions
+    }
+}
+
+impl CachedPositions for DiskEntry {
+    fn positions(&self) -> usize {
+        self.positions
+    }
+}
+
+/// The key of an entry whose tokens start with `toks`, and whose cache can be narrowed to them.
+fn find_extension<V: CachedPositions>(trie: &Trie<Tokens, V>, toks: &[u32]) -> Option<Tokens> {
+    let subtrie = trie.get_raw_descendant(&Tokens(toks.to_vec()))?;
+    subtrie
+        .iter()
+        .find(|(key, entry)| key.0.starts_with(toks) && entry.positions() + 1 == key.0.len())
+        .map(|(key, _)| key.clone())
+}
+
+/// The entry sharing the longest prefix with `toks`, and the length of that prefix. Unless an
+/// entry starts with all of `toks`, the prefix is a whole number of blocks.
+fn find_longest_prefix<V: CachedPositions>(
+    trie: &Trie<Tokens, V>,
+    toks: &[u32],
+    block_size: usize,
+) -> Option<(Tokens, usize)> {
+    if let Some(key) = find_extension(trie, toks) {
+        return Some((key, toks.len()));
+    }
+    // If an entry starts with some blocks of `toks`, it also starts with fewer blocks, so the
+    // number of shared blocks can be found by bisection.
+    let block_size = block_size.max(1);
+    let (mut lo, mut hi) = (0, toks.len() / block_size);
+    let mut found = None;
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        match find_extension(trie, &toks[..mid * block_size]) {
+            Some(key) => {
+                lo = mid;
+                found = Some(key);
+            }
+            None => hi = mid - 1,
+        }
+    }
+    found.map(|key| (key, lo * block_size))
+}
+
+/// 64-bit FNV-1a hash of the tokens, which is stable across runs unlike the std hasher.
+fn token_hash(toks: &[u32]) -> u64 {
+    toks.iter()
+        .flat_map(|tok| tok.to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// The directory of the caches of a model, for example `mistralai_Mistral-7B-v0.1/bf16-int8` with
+/// an int8 quantized KV cache.
+fn disk_namespace(model_id: &str, dtype: DType, quant: Option<KvCacheQuant>) -> PathBuf {
+    let model_id = model_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let mut dtype = format!("{dtype:?}").to_lowercase();
+    if let Some(quant) = quant {
+        dtype = format!("{dtype}-{quant}");
+    }
+    Path::new(&model_id).join(dtype)
+}
+
+fn save_caches(
+    path: &Path,
+    toks: &[u32],
+    normal: &LayerCaches,
+    xlora: Option<&LayerCaches>,
+) -> Result<()> {
+    let mut tensors = HashMap::new();
+    tensors.insert("tokens".to_string(), Tensor::new(toks, &Device::Cpu)?);
+    // The number of normal and X-LoRA layers, and whether there is an X-LoRA cache.
+    let layers = [
+        normal.len() as u32,
+        xlora.map_or(0, |xlora| xlora.len() as u32),
+        xlora.is_some() as u32,
+    ];
+    tensors.insert("layers".to_string(), Tensor::new(&layers, &Device::Cpu)?);
+    for (name, cache) in [("normal", Some(normal)), ("xlora", xlora)] {
+        for (i, layer) in cache.into_iter().flatten().enumerate() {
+            if let Some((k, v)) = layer {
+                tensors.insert(format!("{name}.{i}.k"), k.to_device(&Device::Cpu)?);
+                tensors.insert(format!("{name}.{i}.v"), v.to_device(&Device::Cpu)?);
+            }
+        }
+    }
+    // Write to a temporary file first so that an interrupted write does not leave a corrupt cache.
+    let tmp = path.with_extension("safetensors.tmp");
+    candle_core::safetensors::save(&tensors, &tmp)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn load_caches(path: &Path) -> Result<(Vec<u32>, LayerCaches, Option<LayerCaches>)> {
+    let mut tensors = candle_core::safetensors::load(path, &Device::Cpu)?;
+    let (toks, layers) = match (tensors.get("tokens"), tensors.get("layers")) {
+        (Some(toks), Some(layers)) => (toks.to_vec1::<u32>()?, layers.to_vec1::<u32>()?),
+        _ => candle_core::bail!("`{}` is not a saved KV cache.", path.display()),
+    };
+    let mut take = |name: &str, n_layers: u32| -> LayerCaches {
+        (0..n_layers)
+            .map(|i| {
+                let k = tensors.remove(&format!("{name}.{i}.k"))?;
+                let v = tensors.remove(&format!("{name}.{i}.v"))?;
+                Some((k, v))
+            })
+            .collect()
+    };
+    let normal = take("normal", layers[0]);
+    let xlora = (layers[2] != 0).then(|| take("xlora", layers[1]));
+    Ok((toks, normal, xlora))
+}
+
+/// The tokens and number of positions of a saved cache, read without loading the layers.
+fn load_tokens(path: &Path) -> Result<(Vec<u32>, usize)> {
+    let tensors = unsafe { candle_core::safetensors::MmapedSafetensors::new(path)? };
+    let toks = tensors.load("tokens", &Device::Cpu)?.to_vec1::<u32>()?;
+    let n_layers = tensors.load("layers", &Device::Cpu)?.to_vec1::<u32>()?[0];
+    let positions = (0..n_layers)
+        .find_map(|i| tensors.get(&format!("normal.{i}.k")).ok())
+        .map_or(0, |k| k.shape()[2]);
+    Ok((toks, positions))
+}
+
+/// The first `len` positions of a cache, copied to `device`.
+fn narrow_cache(cache: &LayerCaches, len: usize, device: &Device) -> Result<LayerCaches> {
+    cache
+        .iter()
+        .map(|layer| {
+            layer
+                .as_ref()
+                .map(|(k, v)| {
+                    Ok((
+                        k.narrow(2, 0, len)?.to_device(device)?,
+                        v.narrow(2, 0, len)?.to_device(device)?,
+                    ))
+                })
+                .transpose()
+        })
+        .collect()
+}
+
+/// A copy of a cache on the host.
+fn host_cache(cache: &LayerCaches) -> Result<LayerCaches> {
+    cache
+        .iter()
+        .map(|layer| {
+            layer
+                .as_ref()
+                .map(|(k, v)| Ok((k.to_device(&Device::Cpu)?, v.to_device(&Device::Cpu)?)))
+                .transpose()
+        })
+        .collect()
+}
+
+/// The number of positions held by a cache.
+fn cache_len(cache: &LayerCaches) -> Result<usize> {
+    match cache.iter().flatten().next() {
+        Some((k, _)) => k.dim(2),
+        None => Ok(0),
+    }
+}
+
+/// Whether position `i` of the cache of a finished sequence holds token `i`, so that it can be
+/// narrowed to a prefix of `toks`. It holds all but the last sampled token, unless a sliding window
+/// dropped its first positions.
+fn holds_prefix(cache: &LayerCaches, toks: &[u32]) -> bool {
+    cache_len(cache).is_ok_and(|len| len + 1 == toks.len())
+}
+
 impl PrefixCacheManager {
-    pub fn new(device: Device, n_on_device: usize, is_xlora: bool, no_prefix_cache: bool) -> Self {
+    pub fn new(device: Device, config: PrefixCacheConfig, no_prefix_cache: bool) -> Self {
         PrefixCacheManager {
             caches: Trie::new(),
-            xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+            disk: None,
+            sessions: HashMap::new(),
+            saved_sessions: HashMap::new(),
             device,
-            n_on_device,
+            config,
             no_prefix_cache,
-            eviction_cache_ptrs: Vec::new(),
+            tick: 0,
+            stats: PrefixCacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
+    }
+
+    pub fn session_ttl(&self) -> Option<Duration> {
+        self.config.session_ttl
+    }
+
+    /// Use the caches of a model saved in the disk directory, if one is set. Caches are only
+    /// reused by a model with the same id, KV cache dtype and KV cache quantization.
+    pub fn open_disk(
+        &mut self,
+        model_id: &str,
+        dtype: DType,
+        quant: Option<KvCacheQuant>,
+    ) -> Result<()> {
+        let Some(disk_dir) = self
+            .config
+            .disk_dir
+            .as_ref()
+            .filter(|_| !self.no_prefix_cache)
+        else {
+            return Ok(());
+        };
+        let dir = disk_dir.join(disk_namespace(model_id, dtype, quant));
+        fs::create_dir_all(&dir)?;
+        // Sessions saved by an earlier run can no longer be found by their id.
+        let _ = fs::remove_dir_all(dir.join("sessions"));
+        let mut entries = Trie::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("safetensors") {
+                continue;
+            }
+            match load_tokens(&path) {
+                Ok((toks, positions)) => {
+                    let bytes = fs::metadata(&path)?.len() as usize;
+                    self.stats.disk_entries += 1;
+                    self.stats.disk_bytes += bytes;
+                    entries.insert(
+                        Tokens(toks),
+                        DiskEntry {
+                            path,
+                            positions,
+                            bytes,
+                            last_use: 0,
+                            write: None,
+                        },
+                    );
+                }
+                Err(e) => warn!("Ignoring prefix cache `{}`: {e}", path.display()),
+            }
         }
+        info!(
+            "Found {} prefix caches in `{}`.",
+            self.stats.disk_entries,
+            dir.display()
+        );
+        self.disk = Some(DiskTier {
+            dir,
+            entries,
+            pending: Vec::new(),
+        });
+        self.gc_disk();
+        Ok(())
     }
 
-    /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
-    /// some caches will be evicted.
+    /// Add the cache of a finished sequence. It is kept on the device until [`Self::evict`] moves it
+    /// to the host. A sequence whose tokens are a prefix of an entry is not added, and entries
+    /// whose tokens are a prefix of the sequence's are deleted. Neither is a sequence whose cache
+    /// was cut by a sliding window, nor is its session pinned.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
-        if self.no_prefix_cache {
+        // After context shifting, the cache no longer corresponds to the tokens.
+        if seq.shifted_tokens() > 0 {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
-            self.xlora_caches
-                .as_mut()
-                .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
+        if let Some(session_id) = seq.session_id().map(ToString::to_string) {
+            let normal = seq.cache().clone();
+            let xlora = seq.is_xlora().then(|| seq.xlora_cache().clone());
+            self.pin_session(session_id, seq.get_toks().to_vec(), normal, xlora);
+        }
+        // The cache of sequences with their own adapters or X-LoRA scalings cannot be shared.
+        if self.no_prefix_cache
+            || seq.get_adapters().is_some()
+            || seq.fixed_xlora_scalings().is_some()
+        {
+            return;
+        }
+        let toks = seq.get_toks().to_vec();
+        let normal = seq.cache().clone();
+        let xlora = seq.is_xlora().then(|| seq.xlora_cache().clone());
+        self.insert(toks, normal, xlora);
+    }
+
+    fn insert(&mut self, toks: Vec<u32>, normal: LayerCaches, xlora: Option<LayerCaches>) {
+        if !holds_prefix(&normal, &toks) {
+            return;
+        }
+        self.tick += 1;
+        let positions = toks.len() - 1;
+        let toks = Tokens(toks);
+
+        if let Some(key) = find_extension(&self.caches, &toks.0) {
+            let entry = self.caches.get_mut(&key).unwrap();
+            entry.last_use = self.tick;
+            self.stats.deduplicated += 1;
+            return;
+        }
+        // As the cache holds a prefix of the tokens, it also holds the caches of the entries whose
+        // tokens are a prefix of them.
+        while let Some(key) = self
+            .caches
+            .get_ancestor(&toks)
+            .and_then(|ancestor| ancestor.key().cloned())
+        {
+            self.remove(&key);
+            self.stats.deduplicated += 1;
+        }
+
+        let bytes = cache_bytes(&normal) + xlora.as_ref().map_or(0, cache_bytes);
+        // Caches loaded from the disk are inserted on the host.
+        let on_device = is_on_device(&normal);
+        if on_device {
+            self.stats.device_entries += 1;
+            self.stats.device_bytes += bytes;
         } else {
-            self.eviction_cache_ptrs.push((cache, None));
+            self.stats.host_bytes += bytes;
         }
+        self.stats.entries += 1;
+        self.stats.inserted += 1;
+        self.caches.insert(
+            toks,
+            CacheEntry {
+                positions,
+                normal,
+                xlora,
+                bytes,
+                on_device,
+                last_use: self.tick,
+                uses: 0,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &Tokens) {
+        if let Some(entry) = self.caches.remove(key) {
+            if entry.on_device {
+                self.stats.device_entries -= 1;
+                self.stats.device_bytes -= entry.bytes;
+            } else {
+                self.stats.host_bytes -= entry.bytes;
+            }
+            self.stats.entries -= 1;
+        }
+    }
+
+    /// The entry of a tier to evict first.
+    fn victim(&self, on_device: bool) -> Option<Tokens> {
+        let eviction = self.config.eviction;
+        self.caches
+            .iter()
+            .filter(|(_, entry)| entry.on_device == on_device)
+            .min_by_key(|(_, entry)| match eviction {
+                PrefixCacheEviction::Lru => (entry.last_use, 0),
+                PrefixCacheEviction::Lfu => (entry.uses, entry.last_use),
+            })
+            .map(|(key, _)| key.clone())
     }
 
     fn cache_to<'a>(
@@ -86,111 +656,825 @@ impl PrefixCacheManager {
         Ok(())
     }
 
-    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
-    /// the maximum allowed. Returns the number of evicted sequences.
-    pub fn evict_to_cpu(&mut self) -> Result<usize> {
-        if self.no_prefix_cache {
-            return Ok(0);
+    fn move_to_host(&mut self, key: &Tokens) -> Result<()> {
+        let entry = self.caches.get_mut(key).unwrap();
+        Self::cache_to(entry.normal.iter_mut(), &Device::Cpu)?;
+        if let Some(xlora) = entry.xlora.as_mut() {
+            Self::cache_to(xlora.iter_mut(), &Device::Cpu)?;
+        }
+        entry.on_device = false;
+        self.stats.device_entries -= 1;
+        self.stats.device_bytes -= entry.bytes;
+        self.stats.host_bytes += entry.bytes;
+        self.stats.moved_to_host += 1;
+        Ok(())
+    }
+
+    /// Save an entry to the disk, unless a saved cache already holds it. The file is written by a
+    /// background thread, so that the engine does not wait for it.
+    fn write_to_disk(&mut self, key: &Tokens) -> Result<()> {
+        let Some(disk) = self.disk.as_mut() else {
+            return Ok(());
+        };
+        if let Some(saved) = find_extension(&disk.entries, &key.0) {
+            disk.entries.get_mut(&saved).unwrap().last_use = self.tick;
+            return Ok(());
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
+        let entry = self.caches.get(key).unwrap();
+        let path = disk
+            .dir
+            .join(format!("{:016x}.safetensors", token_hash(&key.0)));
+        // Saved caches which are a prefix of this one are redundant, as is a cache with the same
+        // hash whose file is overwritten.
+        let stale = disk
+            .entries
+            .iter()
+            .filter(|(saved, entry)| key.0.starts_with(&saved.0) || entry.path == path)
+            .map(|(saved, _)| saved.clone())
+            .collect::<Vec<_>>();
+        for saved in stale {
+            let mut entry = disk.entries.remove(&saved).unwrap();
+            let _ = entry.finish_write(&mut self.stats);
+            if entry.path != path {
+                let _ = fs::remove_file(&entry.path);
+            }
+            self.stats.disk_entries -= 1;
+            self.stats.disk_bytes -= entry.bytes;
+        }
+
+        // Only the file is written by the thread, the cache is copied to the host here.
+        let normal = host_cache(&entry.normal)?;
+        let xlora = entry.xlora.as_ref().map(host_cache).transpose()?;
+        let (toks, file) = (key.0.clone(), path.clone());
+        let write = thread::spawn(move || save_caches(&file, &toks, &normal, xlora.as_ref()));
+        let bytes = entry.bytes;
+        self.stats.disk_entries += 1;
+        self.stats.disk_bytes += bytes;
+        self.stats.written_to_disk += 1;
+        disk.entries.insert(
+            key.clone(),
+            DiskEntry {
+                path,
+                positions: key.0.len() - 1,
+                bytes,
+                last_use: self.tick,
+                write: Some(write),
+            },
+        );
+        disk.pending.push(key.clone());
+        Ok(())
+    }
+
+    /// Account for the files which were written in the background, deleting the entries whose file
+    /// could not be written. With `wait`, also wait for the files which are still being written.
+    fn finish_disk_writes(&mut self, wait: bool) {
+        let Some(disk) = self.disk.as_mut() else {
+            return;
+        };
+        let mut pending = Vec::new();
+        for key in std::mem::take(&mut disk.pending) {
+            let Some(entry) = disk.entries.get_mut(&key) else {
+                continue;
+            };
+            if !wait
+                && entry
+                    .write
                     .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
+                    .is_some_and(|write| !write.is_finished())
+            {
+                pending.push(key);
+                continue;
+            }
+            if let Err(e) = entry.finish_write(&mut self.stats) {
+                warn!("Failed to save a prefix cache to the disk: {e}");
+                let entry = disk.entries.remove(&key).unwrap();
+                let _ = fs::remove_file(&entry.path);
+                self.stats.disk_entries -= 1;
+                self.stats.disk_bytes -= entry.bytes;
             }
         }
-        let mut n_evicted = 0;
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if n_on_device - n_evicted == self.n_on_device {
+        disk.pending = pending;
+    }
+
+    /// Delete the least recently used saved caches until the disk is within its limit.
+    fn gc_disk(&mut self) {
+        let Some(disk) = self.disk.as_mut() else {
+            return;
+        };
+        while self
+            .config
+            .disk_bytes
+            .is_some_and(|limit| self.stats.disk_bytes > limit)
+        {
+            let Some(key) = disk
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_use)
+                .map(|(key, _)| key.clone())
+            else {
                 break;
+            };
+            let mut entry = disk.entries.remove(&key).unwrap();
+            let _ = entry.finish_write(&mut self.stats);
+            if let Err(e) = fs::remove_file(&entry.path) {
+                warn!(
+                    "Failed to delete prefix cache `{}`: {e}",
+                    entry.path.display()
+                );
             }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
-                n_evicted += 1;
+            self.stats.disk_entries -= 1;
+            self.stats.disk_bytes -= entry.bytes;
+            self.stats.disk_evicted += 1;
+        }
+    }
+
+    /// If a saved cache shares a longer prefix with `toks` than the `shared` tokens found in
+    /// memory, load it into the host tier. Returns whether it was loaded.
+    fn load_from_disk(&mut self, toks: &[u32], shared: usize) -> bool {
+        let Some(disk) = self.disk.as_mut() else {
+            return false;
+        };
+        let Some((key, disk_shared)) =
+            find_longest_prefix(&disk.entries, toks, self.config.block_size)
+        else {
+            return false;
+        };
+        if disk_shared <= shared {
+            return false;
+        }
+        let entry = disk.entries.get_mut(&key).unwrap();
+        entry.last_use = self.tick;
+        match entry
+            .finish_write(&mut self.stats)
+            .and_then(|()| load_caches(&entry.path))
+        {
+            Ok((_, normal, xlora)) => {
+                self.insert(key.0, normal, xlora);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Deleting unreadable prefix cache `{}`: {e}",
+                    entry.path.display()
+                );
+                let _ = fs::remove_file(&entry.path);
+                let entry = disk.entries.remove(&key).unwrap();
+                self.stats.disk_entries -= 1;
+                self.stats.disk_bytes -= entry.bytes;
+                false
+            }
+        }
+    }
+
+    /// Save all the entries to the disk, if a disk directory is set, so that they are reused after a
+    /// restart. Returns once all the files are written.
+    pub fn persist_to_disk(&mut self) -> Result<()> {
+        if self.no_prefix_cache || self.disk.is_none() {
+            return Ok(());
+        }
+        let keys = self.caches.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.write_to_disk(&key)?;
+        }
+        self.finish_disk_writes(true);
+        self.gc_disk();
+        Ok(())
+    }
+
+    /// Move entries to the host until the device is within its limits, then delete entries until
+    /// the host is within its limit, saving them to the disk if a disk directory is set. Sessions are
+    /// evicted within their own limits. Returns the number of deleted entries.
+    pub fn evict(&mut self) -> Result<usize> {
+        self.evict_sessions()?;
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        self.finish_disk_writes(false);
+        while self.stats.device_entries > self.config.n_on_device
+            || self
+                .config
+                .device_bytes
+                .is_some_and(|limit| self.stats.device_bytes > limit)
+        {
+            let Some(key) = self.victim(true) else {
+                break;
+            };
+            self.move_to_host(&key)?;
+        }
+        let mut n_evicted = 0;
+        while self
+            .config
+            .host_bytes
+            .is_some_and(|limit| self.stats.host_bytes > limit)
+        {
+            let Some(key) = self.victim(false) else {
+                break;
+            };
+            // Failing to save only loses the entry, as it would without a disk directory.
+            if let Err(e) = self.write_to_disk(&key) {
+                warn!("Failed to save a prefix cache to the disk: {e}");
             }
+            self.remove(&key);
+            n_evicted += 1;
         }
-        Ok(self.caches.len().saturating_sub(self.n_on_device))
+        self.stats.evicted += n_evicted as u64;
+        self.gc_disk();
+        Ok(n_evicted)
     }
 
-    /// Evict all the caches to CPU.
+    /// Move all the entries and sessions to the host, then delete entries until the host is within
+    /// its limit. Returns the number of remaining entries.
     pub fn evict_all_to_cpu(&mut self) -> Result<usize> {
+        let on_device = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.on_device)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for session_id in on_device {
+            self.move_session_to_host(&session_id)?;
+        }
         if self.no_prefix_cache {
             return Ok(0);
         }
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
+        while let Some(key) = self.victim(true) {
+            self.move_to_host(&key)?;
+        }
+        self.evict()?;
+        Ok(self.caches.len())
+    }
+
+    fn pin_session(
+        &mut self,
+        session_id: String,
+        toks: Vec<u32>,
+        normal: LayerCaches,
+        xlora: Option<LayerCaches>,
+    ) {
+        self.drop_session(&session_id);
+        if !holds_prefix(&normal, &toks) {
+            return;
+        }
+        let bytes = cache_bytes(&normal) + xlora.as_ref().map_or(0, cache_bytes);
+        let on_device = is_on_device(&normal);
+        self.stats.sessions += 1;
+        self.stats.session_bytes += bytes;
+        if on_device {
+            self.stats.session_device_bytes += bytes;
+        }
+        self.sessions.insert(
+            session_id,
+            Session {
+                toks,
+                normal,
+                xlora,
+                bytes,
+                on_device,
+                last_use: Instant::now(),
+            },
+        );
+    }
+
+    fn unpin_session(&mut self, session_id: &str) -> Option<Session> {
+        let session = self.sessions.remove(session_id)?;
+        self.stats.sessions -= 1;
+        self.stats.session_bytes -= session.bytes;
+        if session.on_device {
+            self.stats.session_device_bytes -= session.bytes;
+        }
+        Some(session)
+    }
+
+    /// Unpin the cache of a session, deleting it from the disk if it was saved there. Returns
+    /// whether the session existed.
+    pub fn drop_session(&mut self, session_id: &str) -> bool {
+        let pinned = self.unpin_session(session_id).is_some();
+        let saved = match self.saved_sessions.remove(session_id) {
+            Some(mut saved) => {
+                if let Some(write) = saved.write.take() {
+                    let _ = join_write(write, &saved.path);
                 }
+                let _ = fs::remove_file(&saved.path);
+                self.stats.saved_sessions -= 1;
+                true
             }
+            None => false,
+        };
+        pinned || saved
+    }
+
+    fn move_session_to_host(&mut self, session_id: &str) -> Result<()> {
+        let session = self.sessions.get_mut(session_id).unwrap();
+        Self::cache_to(session.normal.iter_mut(), &Device::Cpu)?;
+        if let Some(xlora) = session.xlora.as_mut() {
+            Self::cache_to(xlora.iter_mut(), &Device::Cpu)?;
         }
-        Ok(self.caches.len())
+        session.on_device = false;
+        self.stats.session_device_bytes -= session.bytes;
+        self.stats.sessions_moved_to_host += 1;
+        Ok(())
+    }
+
+    /// Unpin the sessions unused for longer than their TTL, saving them to the disk if a disk
+    /// directory is set, then move the least recently used sessions to the host until the
+    /// sessions on the device are within their limit.
+    fn evict_sessions(&mut self) -> Result<()> {
+        if let Some(ttl) = self.config.session_ttl {
+            let expired = self
+                .sessions
+                .iter()
+                .filter(|(_, session)| session.last_use.elapsed() > ttl)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for session_id in expired {
+                let session = self.unpin_session(&session_id).unwrap();
+                self.stats.sessions_expired += 1;
+                let dir = self.disk.as_ref().map(|disk| disk.dir.join("sessions"));
+                if let Some(dir) = dir {
+                    self.save_session(dir, session_id, session)?;
+                }
+            }
+        }
+        while self
+            .config
+            .session_device_bytes
+            .is_some_and(|limit| self.stats.session_device_bytes > limit)
+        {
+            let Some(session_id) = self
+                .sessions
+                .iter()
+                .filter(|(_, session)| session.on_device)
+                .min_by_key(|(_, session)| session.last_use)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            self.move_session_to_host(&session_id)?;
+        }
+        Ok(())
+    }
+
+    /// Save an unpinned session to a file in `dir`, written by a background thread.
+    fn save_session(&mut self, dir: PathBuf, session_id: String, session: Session) -> Result<()> {
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{:016x}.safetensors",
+            token_hash(&session_id.bytes().map(u32::from).collect::<Vec<_>>())
+        ));
+        let normal = host_cache(&session.normal)?;
+        let xlora = session.xlora.as_ref().map(host_cache).transpose()?;
+        let file = path.clone();
+        let write =
+            thread::spawn(move || save_caches(&file, &session.toks, &normal, xlora.as_ref()));
+        self.stats.saved_sessions += 1;
+        self.saved_sessions.insert(
+            session_id,
+            SavedSession {
+                path,
+                write: Some(write),
+            },
+        );
+        Ok(())
+    }
+
+    /// Pin a session saved by [`Self::save_session`] again, on the host.
+    fn load_session(&mut self, session_id: &str) -> Result<()> {
+        let Some(mut saved) = self.saved_sessions.remove(session_id) else {
+            return Ok(());
+        };
+        self.stats.saved_sessions -= 1;
+        if let Some(write) = saved.write.take() {
+            join_write(write, &saved.path)?;
+        }
+        let (toks, normal, xlora) = load_caches(&saved.path)?;
+        let _ = fs::remove_file(&saved.path);
+        self.pin_session(session_id.to_string(), toks, normal, xlora);
+        Ok(())
+    }
+
+    /// Continue from the cache of a session, narrowed to the prefix its tokens share with `toks`.
+    /// Like [`Self::search_for_matching_cache`], at least the last token is left to be run. A
+    /// session saved to the disk after its TTL is loaded again.
+    pub fn search_session(
+        &mut self,
+        session_id: &str,
+        toks: &[u32],
+    ) -> Result<Option<MatchingCache>> {
+        self.load_session(session_id)?;
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return Ok(None);
+        };
+        session.last_use = Instant::now();
+        let shared = session
+            .toks
+            .iter()
+            .zip(toks)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let n_cached = cache_len(&session.normal)?
+            .min(shared)
+            .min(toks.len().saturating_sub(1));
+        if n_cached == 0 {
+            return Ok(None);
+        }
+        let normal = narrow_cache(&session.normal, n_cached, &self.device)?;
+        let xlora = session
+            .xlora
+            .as_ref()
+            .map(|xlora| narrow_cache(xlora, n_cached, &self.device))
+            .transpose()?;
+        self.stats.session_hits += 1;
+        self.stats.hit_tokens += n_cached as u64;
+        Ok(Some(MatchingCache {
+            normal,
+            xlora,
+            toks: toks[n_cached..].to_vec(),
+        }))
+    }
+
+    /// Save the tokens and cache of a session to a safetensors file.
+    pub fn export_session(&mut self, session_id: &str, path: &Path) -> Result<()> {
+        self.load_session(session_id)?;
+        let Some(session) = self.sessions.get(session_id) else {
+            candle_core::bail!("There is no session `{session_id}`.");
+        };
+        save_caches(path, &session.toks, &session.normal, session.xlora.as_ref())
     }
 
-    /// Search for a matching cache given some toks
+    /// Pin a session saved by [`Self::export_session`] under `session_id`. The saved cache must
+    /// have `n_layers` layers of the given dtype and KV cache quantization, and an X-LoRA cache if
+    /// `is_xlora`.
+    pub fn import_session(
+        &mut self,
+        session_id: String,
+        path: &Path,
+        n_layers: usize,
+        dtype: DType,
+        quant: Option<KvCacheQuant>,
+        is_xlora: bool,
+    ) -> Result<()> {
+        let (toks, mut normal, mut xlora) = load_caches(path)?;
+        let saved_dtype = normal.iter().flatten().next().map(|(k, _)| k.dtype());
+        let dtype = if quant.is_some() { DType::U8 } else { dtype };
+        if normal.len() != n_layers || saved_dtype.is_some_and(|saved| saved != dtype) {
+            candle_core::bail!(
+                "`{}` holds {} layers of {saved_dtype:?}, but the model has {n_layers} layers of {dtype:?}.",
+                path.display(),
+                normal.len()
+            );
+        }
+        if !holds_prefix(&normal, &toks) {
+            candle_core::bail!(
+                "`{}` holds a cache of {} positions for {} tokens.",
+                path.display(),
+                cache_len(&normal)?,
+                toks.len()
+            );
+        }
+        if xlora.is_some() != is_xlora {
+            candle_core::bail!(
+                "`{}` {} an X-LoRA cache, but the model {} an X-LoRA model.",
+                path.display(),
+                if xlora.is_some() {
+                    "holds"
+                } else {
+                    "does not hold"
+                },
+                if is_xlora { "is" } else { "is not" },
+            );
+        }
+        Self::cache_to(normal.iter_mut(), &self.device)?;
+        if let Some(xlora) = xlora.as_mut() {
+            Self::cache_to(xlora.iter_mut(), &self.device)?;
+        }
+        self.pin_session(session_id, toks, normal, xlora);
+        Ok(())
+    }
+
+    /// Search for the cache sharing the longest prefix with `toks`, narrowed to that prefix. At
+    /// least the last token is left to be run, so that the prompt produces logits. The returned
+    /// cache is on the device; the entry stays in its tier. A saved cache sharing a longer prefix
+    /// than the entries in memory is first loaded into the host tier, evicting other entries if
+    /// needed.
     pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
-        if self.no_prefix_cache {
+        if self.no_prefix_cache || toks.is_empty() {
             return Ok(None);
         }
+        self.tick += 1;
 
-        let toks = Tokens(toks.to_vec());
-        if let Some(cache) = self.caches.get(&toks) {
-            Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
-            let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
-                let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
-                Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
-            } else {
-                None
+        let mut found = find_longest_prefix(&self.caches, toks, self.config.block_size);
+        let from_disk = self.load_from_disk(toks, found.as_ref().map_or(0, |(_, shared)| *shared));
+        if from_disk {
+            found = find_longest_prefix(&self.caches, toks, self.config.block_size);
+        }
+        let Some((key, shared)) = found else {
+            self.stats.misses += 1;
+            return Ok(None);
+        };
+        let entry = self.caches.get_mut(&key).unwrap();
+        entry.last_use = self.tick;
+        entry.uses += 1;
+        let n_cached = cache_len(&entry.normal)?.min(shared).min(toks.len() - 1);
+        if n_cached == 0 {
+            self.stats.misses += 1;
+            if from_disk {
+                self.evict()?;
+            }
+            return Ok(None);
+        }
+
+        let normal = narrow_cache(&entry.normal, n_cached, &self.device)?;
+        let xlora = entry
+            .xlora
+            .as_ref()
+            .map(|xlora| narrow_cache(xlora, n_cached, &self.device))
+            .transpose()?;
+        // The loaded cache may put the host over its limit.
+        if from_disk {
+            self.evict()?;
+        }
+        self.stats.hits += 1;
+        self.stats.disk_hits += from_disk as u64;
+        self.stats.hit_tokens += n_cached as u64;
+        Ok(Some(MatchingCache {
+            normal,
+            xlora,
+            toks: toks[n_cached..].to_vec(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{
+        disk_namespace, save_caches, PrefixCacheConfig, PrefixCacheEviction, PrefixCacheManager,
+    };
+    use crate::pipeline::LayerCaches;
+
+    /// A cache of 2 layers holding `len` positions, of 16 bytes each per layer.
+    fn cache(len: usize) -> Result<LayerCaches> {
+        let kv = Tensor::zeros((1, 1, len, 2), DType::F32, &Device::Cpu)?;
+        Ok(vec![Some((kv.clone(), kv.clone())), Some((kv.clone(), kv))])
+    }
+
+    #[test]
+    fn prefix_cache_dedup_and_match() -> Result<()> {
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, PrefixCacheConfig::default(), false);
+        cacher.insert(vec![1, 2, 3], cache(2)?, None);
+        // Extends the first entry, which is replaced.
+        cacher.insert(vec![1, 2, 3, 4, 5], cache(4)?, None);
+        // A prefix of the second entry, which already holds its cache.
+        cacher.insert(vec![1, 2], cache(1)?, None);
+        let stats = cacher.stats();
+        assert_eq!((stats.entries, stats.deduplicated), (1, 2));
+        assert_eq!(stats.host_bytes, 4 * 32);
+
+        let matching = cacher.search_for_matching_cache(&[1, 2, 3])?.unwrap();
+        assert_eq!(matching.toks, vec![3]);
+        assert_eq!(matching.normal[0].as_ref().unwrap().0.dim(2)?, 2);
+        assert!(cacher.search_for_matching_cache(&[1, 3])?.is_none());
+        let stats = cacher.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_partial_match() -> Result<()> {
+        let config = PrefixCacheConfig {
+            block_size: 2,
+            ..Default::default()
+        };
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, config, false);
+        cacher.insert(vec![1, 2, 3, 4, 5, 6], cache(5)?, None);
+
+        // Shares 3 tokens, of which 1 whole block is reused.
+        let matching = cacher.search_for_matching_cache(&[1, 2, 3, 9, 9])?.unwrap();
+        assert_eq!(matching.toks, vec![3, 9, 9]);
+        assert_eq!(matching.normal[1].as_ref().unwrap().1.dim(2)?, 2);
+
+        let matching = cacher.search_for_matching_cache(&[1, 2, 3, 4, 7])?.unwrap();
+        assert_eq!(matching.toks, vec![7]);
+        assert_eq!(cacher.stats().hit_tokens, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_host_limit() -> Result<()> {
+        for (eviction, kept) in [
+            // The second entry is the least recently used, and the third the least frequently used.
+            (PrefixCacheEviction::Lru, vec![1, 3]),
+            (PrefixCacheEviction::Lfu, vec![1, 2]),
+        ] {
+            let config = PrefixCacheConfig {
+                host_bytes: Some(2 * 4 * 32),
+                eviction,
+                ..Default::default()
             };
-            let ancestor = &self
-                .caches
-                .get_ancestor(&toks)
-                .expect("No ancestor.")
-                .key()
-                .expect("Cannot get the key.")
-                .0;
-            // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
-            Ok(Some(MatchingCache {
-                normal: cache,
-                xlora: xlora_cache,
-                toks: toks.0[ancestor.len()..].to_vec(),
-            }))
-        } else {
-            Ok(None)
+            let mut cacher = PrefixCacheManager::new(Device::Cpu, config, false);
+            cacher.insert(vec![1; 5], cache(4)?, None);
+            cacher.insert(vec![2; 5], cache(4)?, None);
+            cacher.search_for_matching_cache(&[1, 1])?.unwrap();
+            cacher.search_for_matching_cache(&[2, 2])?.unwrap();
+            cacher.search_for_matching_cache(&[1, 1])?.unwrap();
+            cacher.insert(vec![3; 5], cache(4)?, None);
+            assert_eq!(cacher.evict()?, 1);
+            for tok in [1, 2, 3] {
+                let found = cacher.search_for_matching_cache(&[tok, tok])?.is_some();
+                assert_eq!(found, kept.contains(&tok), "{eviction}");
+            }
         }
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_disk_tier() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-prefix-cache-{}", std::process::id()));
+        let config = PrefixCacheConfig {
+            host_bytes: Some(4 * 32),
+            block_size: 1,
+            disk_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, config.clone(), false);
+        cacher.open_disk("org/model", DType::F32, None)?;
+        cacher.insert(vec![1; 5], cache(4)?, None);
+        cacher.insert(vec![2; 5], cache(4)?, None);
+        // The first entry is deleted from the host and saved.
+        assert_eq!(cacher.evict()?, 1);
+        assert_eq!(cacher.stats().disk_entries, 1);
+
+        let matching = cacher.search_for_matching_cache(&[1, 1, 7])?.unwrap();
+        assert_eq!(matching.toks, vec![7]);
+        // Loading the first entry into the host deletes the second, which is saved.
+        let stats = cacher.stats();
+        assert_eq!(stats.disk_hits, 1);
+        assert_eq!((stats.entries, stats.host_bytes), (1, 4 * 32));
+        assert_eq!(stats.disk_entries, 2);
+        // Waits for the files written in the background.
+        cacher.persist_to_disk()?;
+
+        // After a restart, both entries are found on the disk, but not by another model.
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, config.clone(), false);
+        cacher.open_disk("org/model", DType::F32, None)?;
+        assert_eq!(cacher.stats().disk_entries, 2);
+        assert!(cacher.search_for_matching_cache(&[2, 2, 7])?.is_some());
+        let mut other = PrefixCacheManager::new(Device::Cpu, config, false);
+        other.open_disk("org/model", DType::BF16, None)?;
+        assert_eq!(other.stats().disk_entries, 0);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_sessions() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs-session-{}.safetensors",
+            std::process::id()
+        ));
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, PrefixCacheConfig::default(), false);
+        // The cache holds all but the last sampled token.
+        cacher.pin_session("a".to_string(), vec![1, 2, 3, 4], cache(3)?, None);
+
+        // The next turn re-renders the conversation, which diverges after 3 tokens.
+        let matching = cacher.search_session("a", &[1, 2, 3, 5, 6])?.unwrap();
+        assert_eq!(matching.toks, vec![5, 6]);
+        assert!(cacher.search_session("b", &[1, 2, 3])?.is_none());
+
+        cacher.export_session("a", &path)?;
+        assert!(cacher.drop_session("a"));
+        assert_eq!(cacher.stats().sessions, 0);
+        assert!(cacher
+            .import_session("b".to_string(), &path, 3, DType::F32, None, false)
+            .is_err());
+        cacher.import_session("b".to_string(), &path, 2, DType::F32, None, false)?;
+        let matching = cacher.search_session("b", &[1, 2, 3, 4, 9])?.unwrap();
+        assert_eq!(matching.toks, vec![4, 9]);
+        assert_eq!(cacher.stats().session_hits, 2);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_sliding_window() -> Result<()> {
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, PrefixCacheConfig::default(), false);
+        // A sliding window of 3 positions dropped the cache of the first 2 tokens, so the cache
+        // does not hold a prefix of the tokens.
+        cacher.insert(vec![1, 2, 3, 4, 5, 6], cache(3)?, None);
+        assert_eq!(cacher.stats().entries, 0);
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3, 4, 9])?
+            .is_none());
+
+        // The session keeps its earlier turn until the window drops positions, and is then unpinned.
+        cacher.pin_session("a".to_string(), vec![1, 2, 3], cache(2)?, None);
+        assert!(cacher.search_session("a", &[1, 2, 3, 4])?.is_some());
+        cacher.pin_session("a".to_string(), vec![1, 2, 3, 4, 5, 6], cache(3)?, None);
+        assert!(cacher
+            .search_session("a", &[1, 2, 3, 4, 5, 6, 7])?
+            .is_none());
+        assert_eq!(cacher.stats().sessions, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_skips_unaligned_saved_caches() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-prefix-cache-window-{}",
+            std::process::id()
+        ));
+        let config = PrefixCacheConfig {
+            block_size: 1,
+            disk_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let namespace = dir.join(disk_namespace("org/model", DType::F32, None));
+        std::fs::create_dir_all(&namespace)?;
+        let window = [1, 2, 3, 4, 5, 6];
+        save_caches(
+            &namespace.join("window.safetensors"),
+            &window,
+            &cache(3)?,
+            None,
+        )?;
+        save_caches(
+            &namespace.join("prefix.safetensors"),
+            &[1, 2, 3],
+            &cache(2)?,
+            None,
+        )?;
+
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, config, false);
+        cacher.open_disk("org/model", DType::F32, None)?;
+        assert_eq!(cacher.stats().disk_entries, 2);
+        // The cache cut by a sliding window shares more tokens, but only the prefix is reused.
+        let matching = cacher
+            .search_for_matching_cache(&[1, 2, 3, 4, 5, 9])?
+            .unwrap();
+        assert_eq!(matching.toks, vec![3, 4, 5, 9]);
+        assert!(cacher.search_for_matching_cache(&window)?.is_some());
+        assert_eq!(cacher.stats().entries, 1);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_cache_session_ttl() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-prefix-cache-sessions-{}",
+            std::process::id()
+        ));
+        let config = PrefixCacheConfig {
+            disk_dir: Some(dir.clone()),
+            session_ttl: Some(Duration::from_millis(1)),
+            ..Default::default()
+        };
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, config.clone(), false);
+        cacher.open_disk("org/model", DType::F32, None)?;
+        cacher.pin_session("a".to_string(), vec![1, 2, 3, 4], cache(3)?, None);
+        cacher.pin_session("b".to_string(), vec![5, 6], cache(1)?, None);
+        std::thread::sleep(Duration::from_millis(10));
+
+        // Both sessions expire and are saved to the disk.
+        cacher.evict()?;
+        let stats = cacher.stats();
+        assert_eq!((stats.sessions, stats.session_bytes), (0, 0));
+        assert_eq!((stats.sessions_expired, stats.saved_sessions), (2, 2));
+
+        // The next turn of a session loads it again.
+        let matching = cacher.search_session("a", &[1, 2, 3, 5])?.unwrap();
+        assert_eq!(matching.toks, vec![5]);
+        assert_eq!(
+            (cacher.stats().sessions, cacher.stats().saved_sessions),
+            (1, 1)
+        );
+        assert!(cacher.drop_session("b"));
+        assert_eq!(cacher.stats().saved_sessions, 0);
+        let sessions_dir = dir
+            .join(disk_namespace("org/model", DType::F32, None))
+            .join("sessions");
+        assert_eq!(std::fs::read_dir(sessions_dir)?.count(), 0);
+
+        // Without a disk directory, expired sessions are dropped.
+        let config = PrefixCacheConfig {
+            disk_dir: None,
+            ..config
+        };
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, config, false);
+        cacher.pin_session("a".to_string(), vec![1, 2, 3, 4], cache(3)?, None);
+        std::thread::sleep(Duration::from_millis(10));
+        cacher.evict()?;
+        assert!(cacher.search_session("a", &[1, 2, 3, 5])?.is_none());
+        assert_eq!(cacher.stats().sessions_expired, 1);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
     }
 }
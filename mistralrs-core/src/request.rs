@@ -2,8 +2,8 @@ use candle_core::quantized::GgmlDType;
 use either::Either;
 use indexmap::IndexMap;
 
-use crate::{response::Response, sampler::SamplingParams};
-use std::fmt::Debug;
+use crate::{response::Response, sampler::SamplingParams, IsqPolicy, LoraAdapterFiles};
+use std::{fmt::Debug, path::PathBuf, time::Duration};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
@@ -16,6 +16,18 @@ pub enum Constraint {
 
 pub type MessageContent = Either<String, Vec<IndexMap<String, String>>>;
 
+/// Parse a timeout in seconds, rejecting negative, infinite and NaN values instead of running
+/// without a deadline.
+pub fn parse_timeout(timeout: Option<f64>) -> Result<Option<Duration>, String> {
+    timeout
+        .map(|t| {
+            Duration::try_from_secs_f64(t).map_err(|_| {
+                format!("Timeout must be a finite, non-negative number of seconds, got {t}.")
+            })
+        })
+        .transpose()
+}
+
 #[derive(Clone, Debug)]
 /// Message or messages for a [`Request`].
 pub enum RequestMessage {
@@ -32,6 +44,26 @@ pub enum RequestMessage {
     },
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Which X-LoRA scalings are returned in the response to a request.
+pub enum XLoraScalingsOutput {
+    /// The scalings of every token, along with their mean.
+    PerToken,
+    /// Only the mean of the scalings over all tokens.
+    Mean,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How a prompt longer than the maximum sequence length is truncated to leave room for generation.
+pub enum TruncationStrategy {
+    /// Drop tokens from the start of the rendered prompt.
+    Tokens,
+    /// Drop the oldest turns of a chat, keeping the system messages and the last message.
+    Oldest,
+    /// Like [`TruncationStrategy::Oldest`], but also keep the first turn of the chat.
+    Middle,
+}
+
 #[derive(Clone)]
 /// A normal request request to the `MistralRs`
 pub struct NormalRequest {
@@ -43,7 +75,28 @@ pub struct NormalRequest {
     pub id: usize,
     pub constraint: Constraint,
     pub suffix: Option<String>,
-    pub adapters: Option<Vec<String>>,
+    /// LoRA adapters to use and the weight each one's output is scaled by. The outputs of the
+    /// adapters are summed, so a weight of 1 for each adapter applies them as trained.
+    pub adapters: Option<IndexMap<String, f64>>,
+    /// Scheduling priority. Higher values are admitted first; defaults to 0.
+    pub priority: Option<i32>,
+    /// Tenant key used for weighted fair queuing.
+    pub user: Option<String>,
+    /// Wall-clock limit for the request, measured from when the engine receives it.
+    /// Requests which expire while waiting are dropped, and running requests return their partial output.
+    pub timeout: Option<Duration>,
+    /// Return the adapter scalings of an X-LoRA model in the response.
+    pub return_xlora_scalings: Option<XLoraScalingsOutput>,
+    /// Fixed X-LoRA scalings, indexed by `[layer][adapter]`, which are used instead of those from
+    /// the classifier. A single row of scalings is used for every layer.
+    pub xlora_scalings: Option<Vec<Vec<f64>>>,
+    /// Continue from the KV cache pinned under this session id, if any, and pin the KV cache of
+    /// the sequence under it once it finishes.
+    pub session_id: Option<String>,
+    /// Truncate the prompt if it is too long, even if the engine does not truncate sequences. The
+    /// turns of chats with images are never dropped, and other prompts are truncated by tokens.
+    /// Defaults to [`TruncationStrategy::Oldest`] for chats if the engine truncates sequences.
+    pub truncation_strategy: Option<TruncationStrategy>,
 }
 
 #[derive(Clone)]
@@ -51,8 +104,40 @@ pub struct NormalRequest {
 /// the `mspc` response `Sender` used to return the [`Response`].
 pub enum Request {
     Normal(NormalRequest),
-    ReIsq(GgmlDType),
+    /// Requantize the model in-situ, optionally with a mixed-precision [`IsqPolicy`].
+    ReIsq(GgmlDType, Option<IsqPolicy>),
     ActivateAdapters(Vec<String>),
+    /// Load the LoRA adapter at `source`, a local directory or a Hugging Face model id, under `name`.
+    /// The `response` receives the number of layers the adapter was loaded into.
+    LoadAdapter {
+        name: String,
+        source: String,
+        adapter: LoraAdapterFiles,
+        response: Sender<Result<usize, String>>,
+    },
+    /// Unload a LoRA adapter, deactivating it if it is active. The `response` receives the number
+    /// of layers the adapter was unloaded from.
+    UnloadAdapter {
+        name: String,
+        response: Sender<Result<usize, String>>,
+    },
+    /// Stop accepting new requests and let the running ones finish. Sequences still running
+    /// after the drain timeout are stopped with their partial output, and then the engine exits.
+    Shutdown(Duration),
+    /// Save the tokens and KV cache of a session to a safetensors file.
+    ExportSession {
+        session_id: String,
+        path: PathBuf,
+        response: Sender<Result<(), String>>,
+    },
+    /// Pin the tokens and KV cache saved by [`Request::ExportSession`] under a session id.
+    ImportSession {
+        session_id: String,
+        path: PathBuf,
+        response: Sender<Result<(), String>>,
+    },
+    /// Unpin the KV cache of a session.
+    DropSession(String),
 }
 
 impl Debug for Request {
@@ -68,17 +153,63 @@ impl Debug for Request {
                 constraint: _,
                 suffix: _,
                 adapters,
+                priority,
+                user,
+                timeout,
+                return_xlora_scalings,
+                xlora_scalings,
+                session_id,
             }) => {
                 write!(
                     f,
-                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}}}",
+                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}, priority: {priority:?}, user: {user:?}, timeout: {timeout:?}, return_xlora_scalings: {return_xlora_scalings:?}, xlora_scalings: {xlora_scalings:?}, session_id: {session_id:?}}}",
                 )
             }
             Request::ActivateAdapters(adapters) => {
                 write!(f, "Activate Adapters Request {adapters:?}",)
             }
-            Request::ReIsq(tp) => {
-                write!(f, "Re ISQ Request {tp:?}",)
+            Request::LoadAdapter {
+                name,
+                source,
+                adapter: _,
+                response: _,
+            } => {
+                write!(
+                    f,
+                    "Load Adapter Request {{ name: {name}, source: {source} }}",
+                )
+            }
+            Request::UnloadAdapter { name, response: _ } => {
+                write!(f, "Unload Adapter Request {name}",)
+            }
+            Request::ReIsq(tp, policy) => {
+                write!(f, "Re ISQ Request {tp:?} {{ policy: {policy:?} }}",)
+            }
+            Request::Shutdown(timeout) => {
+                write!(f, "Shutdown Request {{ drain_timeout: {timeout:?} }}",)
+            }
+            Request::ExportSession {
+                session_id,
+                path,
+                response: _,
+            } => {
+                write!(
+                    f,
+                    "Export Session Request {{ session_id: {session_id}, path: {path:?} }}",
+                )
+            }
+            Request::ImportSession {
+                session_id,
+                path,
+                response: _,
+            } => {
+                write!(
+                    f,
+                    "Import Session Request {{ session_id: {session_id}, path: {path:?} }}",
+                )
+            }
+            Request::DropSession(session_id) => {
+                write!(f, "Drop Session Request {session_id}",)
             }
         }
     }
@@ -65,6 +65,19 @@ pub struct Logprobs {
 
 generate_repr!(Logprobs);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The adapter scalings computed by an X-LoRA model for a choice, including its prompt.
+pub struct XLoraScalings {
+    /// Scalings of each token, indexed by `[token][layer][adapter]`, if they were requested.
+    pub tokens: Option<Vec<Vec<Vec<f32>>>>,
+    /// Mean of the scalings over all tokens, indexed by `[layer][adapter]`.
+    pub mean: Vec<Vec<f32>>,
+}
+
+generate_repr!(XLoraScalings);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -74,6 +87,7 @@ pub struct Choice {
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
+    pub xlora_scalings: Option<XLoraScalings>,
 }
 
 generate_repr!(Choice);
@@ -121,6 +135,8 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// Warnings about how the request was handled, such as a truncated prompt.
+    pub warnings: Vec<String>,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -136,6 +152,8 @@ pub struct ChatCompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Warnings about how the request was handled. Only sent in the first chunk.
+    pub warnings: Vec<String>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
@@ -149,6 +167,7 @@ pub struct CompletionChoice {
     pub index: usize,
     pub text: String,
     pub logprobs: Option<()>,
+    pub xlora_scalings: Option<XLoraScalings>,
 }
 
 generate_repr!(CompletionChoice);
@@ -165,6 +184,8 @@ pub struct CompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// Warnings about how the request was handled, such as a truncated prompt.
+    pub warnings: Vec<String>,
 }
 
 generate_repr!(CompletionResponse);
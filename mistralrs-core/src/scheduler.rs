@@ -1,6 +1,8 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::Ordering as CmpOrdering,
+    collections::{HashMap, HashSet, VecDeque},
     sync::atomic::Ordering,
+    time::Instant,
 };
 
 use crate::{
@@ -13,8 +15,13 @@ pub trait FcfsBacker: Default {
     fn new() -> Self;
     fn add(&mut self, item: Sequence);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
+    fn iter(&self) -> impl Iterator<Item = &Sequence>;
     fn len(&self) -> usize;
     fn sort_ascending_ids(&mut self);
+    /// Order the waiting sequences for admission. By default this is FCFS.
+    fn sort_for_admission(&mut self, _fair_share: &FairShare) {
+        self.sort_ascending_ids()
+    }
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -27,6 +34,9 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        VecDeque::iter(self)
+    }
     fn sort_ascending_ids(&mut self) {
         let slice = self.make_contiguous();
         slice.sort_by_key(|seq| *seq.id());
@@ -36,6 +46,136 @@ impl FcfsBacker for VecDeque<Sequence> {
     }
 }
 
+/// Number of scheduling passes a sequence must wait for its effective priority to increase by one.
+/// This prevents low priority sequences from being starved by a constant stream of higher priority ones.
+const STARVATION_AGING_PASSES: usize = 64;
+
+/// A waiting queue which admits sequences by effective priority (request priority plus aging),
+/// then by the weighted fair share of their tenant, and finally FCFS.
+#[derive(Default)]
+pub struct PriorityBacker(VecDeque<Sequence>);
+
+impl FcfsBacker for PriorityBacker {
+    fn new() -> Self {
+        Self(VecDeque::new())
+    }
+    fn add(&mut self, item: Sequence) {
+        self.0.push_back(item)
+    }
+    fn into_iter(self) -> impl Iterator<Item = Sequence> {
+        self.0.into_iter()
+    }
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        self.0.iter()
+    }
+    fn sort_ascending_ids(&mut self) {
+        self.0.sort_ascending_ids()
+    }
+    fn sort_for_admission(&mut self, fair_share: &FairShare) {
+        let slice = self.0.make_contiguous();
+        slice.sort_by(|a, b| {
+            b.effective_priority(STARVATION_AGING_PASSES)
+                .partial_cmp(&a.effective_priority(STARVATION_AGING_PASSES))
+                .unwrap_or(CmpOrdering::Equal)
+                .then_with(|| {
+                    fair_share
+                        .virtual_time(a.user())
+                        .partial_cmp(&fair_share.virtual_time(b.user()))
+                        .unwrap_or(CmpOrdering::Equal)
+                })
+                .then_with(|| a.id().cmp(b.id()))
+        });
+    }
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Weighted fair queuing state across tenants, keyed by the request `user`.
+///
+/// Each tenant accumulates a virtual time equal to the number of tokens processed for it
+/// divided by its weight. Waiting sequences of tenants with a lower virtual time are admitted first.
+/// Requests without a `user` are charged to one shared anonymous tenant with a weight of 1.
+/// Only tenants with running or waiting sequences are tracked, so the state does not grow with
+/// every `user` ever seen. If fair queuing is disabled, all tenants have the same virtual time.
+#[derive(Default)]
+pub struct FairShare {
+    enabled: bool,
+    weights: HashMap<String, f64>,
+    /// Virtual times by tenant. `None` is the shared anonymous tenant.
+    virtual_times: HashMap<Option<String>, f64>,
+}
+
+impl FairShare {
+    pub fn new(weights: Option<HashMap<String, f64>>) -> Self {
+        Self {
+            enabled: weights.is_some(),
+            weights: weights.unwrap_or_default(),
+            virtual_times: HashMap::new(),
+        }
+    }
+
+    fn weight(&self, user: Option<&str>) -> f64 {
+        user.and_then(|u| self.weights.get(u))
+            .copied()
+            .unwrap_or(1.0)
+            .max(f64::EPSILON)
+    }
+
+    /// The virtual time of a tenant. Tenants without history have the minimum virtual time.
+    pub fn virtual_time(&self, user: Option<&str>) -> f64 {
+        if !self.enabled {
+            return 0.;
+        }
+        match self.virtual_times.get(&user.map(ToString::to_string)) {
+            Some(t) => *t,
+            None => self.min_virtual_time(),
+        }
+    }
+
+    fn min_virtual_time(&self) -> f64 {
+        self.virtual_times
+            .values()
+            .copied()
+            .min_by(|a, b| a.partial_cmp(b).unwrap_or(CmpOrdering::Equal))
+            .unwrap_or(0.)
+    }
+
+    /// Register a tenant. New tenants start at the current minimum virtual time so that they
+    /// cannot monopolize the engine by having no history.
+    fn register(&mut self, user: Option<&str>) {
+        if !self.enabled {
+            return;
+        }
+        let tenant = user.map(ToString::to_string);
+        if !self.virtual_times.contains_key(&tenant) {
+            let min = self.min_virtual_time();
+            self.virtual_times.insert(tenant, min);
+        }
+    }
+
+    /// Forget the tenants which are not `active`. A returning tenant starts again at the minimum
+    /// virtual time, like a new one.
+    fn retain(&mut self, active: &HashSet<Option<&str>>) {
+        self.virtual_times
+            .retain(|tenant, _| active.contains(&tenant.as_deref()));
+    }
+
+    /// Charge a tenant for the tokens processed on its behalf in this step.
+    #[allow(clippy::cast_precision_loss)]
+    fn charge(&mut self, user: Option<&str>, n_tokens: usize) {
+        if !self.enabled {
+            return;
+        }
+        let weight = self.weight(user);
+        let min = self.min_virtual_time();
+        *self
+            .virtual_times
+            .entry(user.map(ToString::to_string))
+            .or_insert(min) += n_tokens as f64 / weight;
+    }
+}
+
 pub struct SchedulerOutput<'a> {
     pub completion: Box<[&'a mut Sequence]>,
     pub prompt: Box<[&'a mut Sequence]>,
@@ -64,11 +204,36 @@ pub trait BucketingManager<Backer: FcfsBacker> {
     ) -> BucketedSeqs<Backer>;
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters and their weights as bits, cache length, prefilled prompt tokens, (has_imgs && is_prompt))
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+type BucketKey = (Option<Vec<(String, u64)>>, usize, usize, bool);
 
-struct FixedBucketingManager;
+struct FixedBucketingManager {
+    /// If the model can batch sequences using different LoRA adapters, they share a bucket.
+    row_adapters: bool,
+}
+
+impl FixedBucketingManager {
+    fn bucket_key(&self, seq: &Sequence) -> BucketKey {
+        let adapters = if self.row_adapters {
+            None
+        } else {
+            seq.get_adapters().map(|adapters| {
+                adapters
+                    .into_iter()
+                    .map(|(name, weight)| (name, weight.to_bits()))
+                    .collect()
+            })
+        };
+        (
+            adapters,
+            // Context shifting shortens the KV cache.
+            seq.len() - seq.shifted_tokens(),
+            seq.token_offset(),
+            seq.images().is_some() && seq.is_prompt(),
+        )
+    }
+}
 
 impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
@@ -84,43 +249,19 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
         for seq in running {
-            let len = seq.len();
-            match seq_buckets.get_mut(&(
-                seq.get_adapters(),
-                len,
-                seq.images().is_some() && seq.is_prompt(),
-            )) {
+            let key = self.bucket_key(&seq);
+            match seq_buckets.get_mut(&key) {
                 Some(bucket) => {
                     if !discrete {
-                        *seq_priorities
-                            .get_mut(&(
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ))
-                            .unwrap() += seq.compute_priority();
+                        *seq_priorities.get_mut(&key).unwrap() += seq.compute_priority();
                     }
                     bucket.push(seq);
                 }
                 None => {
                     if !discrete {
-                        seq_priorities.insert(
-                            (
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ),
-                            seq.compute_priority(),
-                        );
+                        seq_priorities.insert(key.clone(), seq.compute_priority());
                     }
-                    seq_buckets.insert(
-                        (
-                            seq.get_adapters(),
-                            len,
-                            seq.images().is_some() && seq.is_prompt(),
-                        ),
-                        vec![seq],
-                    );
+                    seq_buckets.insert(key, vec![seq]);
                 }
             }
         }
@@ -136,7 +277,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             // Allow the min seqs to catch up.
             let min = seq_buckets
                 .keys()
-                .min_by_key(|(_, x, _)| *x)
+                .min_by_key(|(_, x, _, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
             let len = if !discrete {
@@ -172,22 +313,30 @@ pub struct Scheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: SchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    fair_share: FairShare,
 }
 
 impl<Backer: FcfsBacker> Scheduler<Backer> {
-    pub fn new(method: SchedulerMethod) -> Self {
+    /// If `row_adapters` is set, sequences using different LoRA adapters are scheduled together.
+    pub fn new(
+        method: SchedulerMethod,
+        fair_queuing: Option<HashMap<String, f64>>,
+        row_adapters: bool,
+    ) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
-            SchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
+            SchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager { row_adapters }),
         };
         Self {
             running: Vec::new(),
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            fair_share: FairShare::new(fair_queuing),
         }
     }
 
     pub fn add_seq(&mut self, seq: Sequence) {
+        self.fair_share.register(seq.user());
         if seq.is_running() {
             // prefill case
             self.running.push(seq);
@@ -200,6 +349,51 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         self.waiting.len()
     }
 
+    /// Apply a deadline to every running and waiting sequence, for example when draining the engine.
+    pub fn set_deadline_all(&mut self, deadline: Instant) {
+        for seq in self.running.iter_mut() {
+            seq.set_deadline(deadline);
+        }
+        let waiting = std::mem::take(&mut self.waiting);
+        for mut seq in waiting.into_iter() {
+            seq.set_deadline(deadline);
+            self.waiting.add(seq);
+        }
+    }
+
+    /// Remove the waiting sequences which have not been started and whose deadline has passed.
+    /// These are marked as done and returned so that the caller can notify the requester.
+    ///
+    /// A sequence is only removed if no sequence of its group has started, so that a request with
+    /// several choices is not split. Its started sequences stop with a timeout once they run.
+    pub fn remove_expired_waiting(&mut self) -> Vec<Sequence> {
+        let waiting = std::mem::take(&mut self.waiting)
+            .into_iter()
+            .collect::<Vec<_>>();
+        let group_started = |seq: &Sequence| {
+            self.running
+                .iter()
+                .chain(waiting.iter())
+                .any(|other| other.same_group(seq) && !other.is_waiting())
+        };
+        let expires = waiting
+            .iter()
+            .map(|seq| seq.is_waiting() && seq.is_expired() && !group_started(seq))
+            .collect::<Vec<_>>();
+        let mut new_waiting = Backer::new();
+        let mut expired = Vec::new();
+        for (seq, expires) in waiting.into_iter().zip(expires) {
+            if expires {
+                seq.set_state(SequenceState::Done(StopReason::Timeout));
+                expired.push(seq);
+            } else {
+                new_waiting.add(seq);
+            }
+        }
+        self.waiting = new_waiting;
+        expired
+    }
+
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
     /// The others are moved to the waiting list (retaining high priority due to start time),
     /// without a state modification.
@@ -225,24 +419,12 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         match (waiting.len(), running.len()) {
             (0, 0) => {
                 self.running = running;
+                self.fair_share.retain(&HashSet::new());
                 return SchedulerOutput {
                     prompt: vec![].into(),
                     completion: vec![].into(),
                 };
             }
-            (_, 0) => {
-                for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                    self.running.push(seq);
-                }
-                self.waiting = Backer::new();
-                let running = std::mem::take(&mut self.running);
-                self.running = self.bucket_and_waitlist_seqs(running);
-                return SchedulerOutput {
-                    prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
-                    completion: vec![].into(),
-                };
-            }
             (0, _) => {
                 self.running = self.bucket_and_waitlist_seqs(running);
                 if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
@@ -251,6 +433,7 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                self.charge_running();
                 return SchedulerOutput {
                     prompt: vec![].into(),
                     completion: self.running.iter_mut().collect::<Vec<_>>().into(),
@@ -259,8 +442,9 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
             _ => {}
         }
 
-        // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        // Sort the waiting seqs. This also applies when nothing is running, so that priorities and
+        // fair shares decide which sequences are admitted first.
+        waiting.sort_for_admission(&self.fair_share);
 
         // If the waiting sequence will fit, add it. Otherwise remove it
         let mut new_waiting = Backer::new();
@@ -271,7 +455,7 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                 }
                 running.push(seq);
             } else {
-                new_waiting.add(seq);
+                new_waiting.add(seq.add_urgency());
             }
         }
 
@@ -284,6 +468,7 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
 
         self.running = running;
         self.waiting = new_waiting;
+        self.charge_running();
 
         let mut completion = Vec::new();
         let mut prompt = Vec::new();
@@ -301,9 +486,218 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         }
     }
 
+    /// Charge each tenant for the tokens its running sequences will process this step, and forget
+    /// the tenants without running or waiting sequences.
+    fn charge_running(&mut self) {
+        let active = self
+            .running
+            .iter()
+            .chain(self.waiting.iter())
+            .map(Sequence::user)
+            .collect::<HashSet<_>>();
+        self.fair_share.retain(&active);
+        for seq in &self.running {
+            let n_tokens = if seq.is_prompt() { seq.len() } else { 1 };
+            self.fair_share.charge(seq.user(), n_tokens);
+        }
+    }
+
     fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
         match &self.method {
             SchedulerMethod::Fixed(n) => (running.len() + 1) <= **n,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::Mutex;
+
+    use super::{FairShare, FcfsBacker, PriorityBacker, Scheduler, SchedulerMethod};
+    use crate::sequence::{Sequence, SequenceGroup, SequenceState, StopReason, TestSequence};
+
+    fn group(n_choices: usize) -> Arc<Mutex<SequenceGroup>> {
+        Arc::new(Mutex::new(SequenceGroup::new(n_choices, false, false, 1)))
+    }
+
+    fn seq(
+        id: usize,
+        group: &Arc<Mutex<SequenceGroup>>,
+        priority: i32,
+        user: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> Sequence {
+        TestSequence {
+            tokens: vec![1, 2, 3, 4],
+            id,
+            group: Some(group.clone()),
+            priority,
+            user: user.map(ToString::to_string),
+            deadline,
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn admission_order(waiting: Vec<Sequence>, fair_share: &FairShare) -> Vec<usize> {
+        let mut backer = PriorityBacker::new();
+        for seq in waiting {
+            backer.add(seq);
+        }
+        backer.sort_for_admission(fair_share);
+        backer.into_iter().map(|seq| *seq.id()).collect()
+    }
+
+    #[test]
+    fn admits_by_priority_when_nothing_runs() {
+        let mut scheduler = Scheduler::<PriorityBacker>::new(
+            SchedulerMethod::Fixed(1.try_into().unwrap()),
+            None,
+            false,
+        );
+        for (id, priority) in [(0, 0), (1, 5), (2, 1)] {
+            scheduler.add_seq(seq(id, &group(1), priority, None, None));
+        }
+        let output = scheduler.schedule();
+        assert!(output.completion.is_empty());
+        let ids = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1]);
+        assert_eq!(scheduler.waiting_len(), 2);
+    }
+
+    #[test]
+    fn fair_share_forgets_idle_tenants() {
+        let mut scheduler = Scheduler::<PriorityBacker>::new(
+            SchedulerMethod::Fixed(1.try_into().unwrap()),
+            Some(HashMap::new()),
+            false,
+        );
+        for (id, user) in [(0, "a"), (1, "b")] {
+            scheduler.add_seq(seq(id, &group(1), 0, Some(user), None));
+        }
+        let output = scheduler.schedule();
+        assert_eq!(output.prompt.len(), 1);
+        output.prompt[0].set_state(SequenceState::Done(StopReason::Length(4)));
+        // The first tenant has no sequences left, the second one is still waiting.
+        scheduler.schedule();
+        let running_user = scheduler.running[0].user().map(ToString::to_string);
+        assert_eq!(
+            scheduler
+                .fair_share
+                .virtual_times
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&running_user]
+        );
+
+        scheduler.running[0].set_state(SequenceState::Done(StopReason::Length(4)));
+        scheduler.schedule();
+        assert!(scheduler.fair_share.virtual_times.is_empty());
+    }
+
+    #[test]
+    fn fair_share_weights() {
+        let weights = HashMap::from([("a".to_string(), 1.0), ("b".to_string(), 4.0)]);
+        let mut fair_share = FairShare::new(Some(weights));
+        fair_share.register(Some("a"));
+        fair_share.register(Some("b"));
+        fair_share.charge(Some("a"), 8);
+        fair_share.charge(Some("b"), 8);
+        assert_eq!(fair_share.virtual_time(Some("a")), 8.);
+        assert_eq!(fair_share.virtual_time(Some("b")), 2.);
+        // New tenants and anonymous requests start at the minimum virtual time.
+        fair_share.register(Some("c"));
+        assert_eq!(fair_share.virtual_time(Some("c")), 2.);
+        assert_eq!(fair_share.virtual_time(None), 2.);
+        // Anonymous requests are charged to a shared tenant, so they lose their precedence.
+        fair_share.register(None);
+        fair_share.charge(None, 8);
+        assert_eq!(fair_share.virtual_time(None), 10.);
+        let waiting = vec![
+            seq(0, &group(1), 0, None, None),
+            seq(1, &group(1), 0, Some("a"), None),
+        ];
+        assert_eq!(admission_order(waiting, &fair_share), vec![1, 0]);
+
+        let waiting = vec![
+            seq(0, &group(1), 0, Some("a"), None),
+            seq(1, &group(1), 0, Some("b"), None),
+        ];
+        assert_eq!(admission_order(waiting, &fair_share), vec![1, 0]);
+        // Without weights, tenants are admitted FCFS.
+        let waiting = vec![
+            seq(0, &group(1), 0, Some("a"), None),
+            seq(1, &group(1), 0, Some("b"), None),
+        ];
+        assert_eq!(admission_order(waiting, &FairShare::new(None)), vec![0, 1]);
+    }
+
+    #[test]
+    fn aging_prevents_starvation() {
+        let fair_share = FairShare::new(None);
+        let mut aged = seq(1, &group(1), 0, None, None);
+        for _ in 0..super::STARVATION_AGING_PASSES {
+            aged = aged.add_urgency();
+        }
+        // One pass short of catching up with priority 1.
+        let mut young = seq(2, &group(1), 0, None, None);
+        for _ in 1..super::STARVATION_AGING_PASSES {
+            young = young.add_urgency();
+        }
+        let waiting = vec![seq(0, &group(1), 1, None, None), young, aged];
+        // The aged sequence ties with the priority 1 one and is ordered by id.
+        assert_eq!(admission_order(waiting, &fair_share), vec![0, 1, 2]);
+
+        let mut aged = seq(1, &group(1), 0, None, None);
+        for _ in 0..2 * super::STARVATION_AGING_PASSES {
+            aged = aged.add_urgency();
+        }
+        let waiting = vec![seq(0, &group(1), 1, None, None), aged];
+        assert_eq!(admission_order(waiting, &fair_share), vec![1, 0]);
+    }
+
+    #[test]
+    fn expired_waiting_groups_are_not_split() {
+        let past = Instant::now() - Duration::from_secs(1);
+        let mut scheduler = Scheduler::<PriorityBacker>::new(
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+            None,
+            false,
+        );
+        // A request with 2 choices, neither started.
+        let unstarted = group(2);
+        scheduler.add_seq(seq(0, &unstarted, 0, None, Some(past)));
+        scheduler.add_seq(seq(1, &unstarted, 0, None, Some(past)));
+        // A request with 2 choices, one of which is running.
+        let started = group(2);
+        let running = seq(2, &started, 0, None, Some(past));
+        running.set_state(SequenceState::RunningCompletion);
+        scheduler.add_seq(running);
+        scheduler.add_seq(seq(3, &started, 0, None, Some(past)));
+        // A request whose deadline has not passed.
+        let later = Instant::now() + Duration::from_secs(3600);
+        scheduler.add_seq(seq(4, &group(1), 0, None, Some(later)));
+
+        let mut expired = scheduler
+            .remove_expired_waiting()
+            .iter()
+            .map(|seq| {
+                assert!(!seq.is_waiting() && !seq.is_running());
+                *seq.id()
+            })
+            .collect::<Vec<_>>();
+        expired.sort();
+        assert_eq!(expired, vec![0, 1]);
+        assert_eq!(scheduler.waiting_len(), 2);
+    }
+}
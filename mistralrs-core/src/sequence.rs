@@ -1,7 +1,7 @@
 use std::{
     fmt::Display,
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -10,7 +10,8 @@ use tokio::sync::{
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
-    response::CompletionChoice,
+    request::XLoraScalingsOutput,
+    response::{CompletionChoice, XLoraScalings},
     CompletionResponse,
 };
 use crate::{
@@ -34,6 +35,7 @@ pub enum StopReason {
         completion_bytes_pos: usize,
     },
     Canceled,
+    Timeout,
 }
 
 impl Display for StopReason {
@@ -43,6 +45,7 @@ impl Display for StopReason {
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
+            StopReason::Timeout => write!(f, "timeout"),
         }
     }
 }
@@ -77,10 +80,19 @@ pub struct Sequence {
     response_index: usize,
     creation_time: u64,
     prefill_prompt_toks: Option<Vec<u32>>,
+    /// Number of prompt tokens held by the cache of a prefilled prompt.
+    prefill_offset: usize,
     suffix: Option<String>,
     prefix: Option<String>,
     is_tmp: bool,
-    adapters: Option<Vec<String>>,
+    adapters: Option<Vec<(String, f64)>>,
+    priority: i32,
+    user: Option<String>,
+    deadline: Option<Instant>,
+    return_xlora_scalings: Option<XLoraScalingsOutput>,
+    fixed_xlora_scalings: Option<Vec<Vec<f64>>>,
+    session_id: Option<String>,
+    shifted_tokens: usize,
 
     // Cache
     scaling_cache: Option<Tensor>,
@@ -100,6 +112,9 @@ pub struct Sequence {
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
     input_images: Option<Vec<image::DynamicImage>>,
+    xlora_scalings: Vec<Vec<Vec<f32>>>,
+    xlora_scalings_sum: Vec<Vec<f32>>,
+    xlora_scalings_count: usize,
 
     // GPU things
     pub prompt_tok_per_sec: f32,
@@ -128,8 +143,14 @@ impl Sequence {
         recognizer: SequenceRecognizer,
         suffix: Option<String>,
         prefix: Option<String>,
-        adapters: Option<Vec<String>>,
+        adapters: Option<Vec<(String, f64)>>,
         input_images: Option<Vec<image::DynamicImage>>,
+        priority: i32,
+        user: Option<String>,
+        deadline: Option<Instant>,
+        return_xlora_scalings: Option<XLoraScalingsOutput>,
+        fixed_xlora_scalings: Option<Vec<Vec<f64>>>,
+        session_id: Option<String>,
     ) -> Self {
         let prompt_len = tokens.len();
         Self {
@@ -160,6 +181,7 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefill_offset: 0,
             suffix,
             prefix,
             cumulative_logprob: 0.,
@@ -172,6 +194,16 @@ impl Sequence {
             scheduling_urgency: 0,
             adapters,
             input_images,
+            priority,
+            user,
+            deadline,
+            return_xlora_scalings,
+            fixed_xlora_scalings,
+            session_id,
+            shifted_tokens: 0,
+            xlora_scalings: Vec::new(),
+            xlora_scalings_sum: Vec::new(),
+            xlora_scalings_count: 0,
         }
     }
 
@@ -185,27 +217,64 @@ impl Sequence {
         self
     }
 
-    /// Simple metric: (scheduling urgency) + log2(length)
+    /// Simple metric: (scheduling urgency) + log2(length) + (request priority)
     /// Takes into account: urgency (scales linear) and length (scales logarithmic)
     /// Scaling urgency is the number of scheduling passes where we have not been scheduled.
     pub fn compute_priority(&self) -> f64 {
         #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
-        (self.scheduling_urgency as f64) + (self.len() as f64).log2()
+        (self.scheduling_urgency as f64) + (self.len() as f64).log2() + f64::from(self.priority)
     }
 
+    /// The request priority, increased by one for every `aging_passes` scheduling passes
+    /// spent without being scheduled. This ensures low priority sequences are eventually run.
+    pub fn effective_priority(&self, aging_passes: usize) -> f64 {
+        #![allow(clippy::cast_precision_loss)]
+        f64::from(self.priority) + (self.scheduling_urgency / aging_passes.max(1)) as f64
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Tighten the deadline of this sequence. A later deadline than the current one is ignored.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+    }
+
+    /// Whether the wall-clock deadline of this sequence has passed.
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Start the prompt from a cache of its first tokens, so that only `toks` are run. The sequence
+    /// stays waiting until it is scheduled.
     pub fn prefill(
         mut self,
         cache: LayerCaches,
         xlora_cache: Option<LayerCaches>,
         toks: Vec<u32>,
     ) -> Self {
+        self.prefill_offset = self.tokens.len() - toks.len();
         self.cache = cache;
         self.xlora_cache = xlora_cache;
         self.prefill_prompt_toks = Some(toks);
-        self.set_state(SequenceState::RunningPrefillPrompt);
         self
     }
 
+    /// The position of the first token to run for a prompt prefilled from the prefix cache, which is
+    /// the number of prompt tokens it skips. This is 0 otherwise.
+    pub fn token_offset(&self) -> usize {
+        if self.prefill_prompt_toks.is_some() {
+            self.prefill_offset
+        } else {
+            0
+        }
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -337,6 +406,7 @@ impl Sequence {
         self.tokens.push(tok.token);
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+        self.prefill_offset = 0;
     }
 
     pub fn responder(&self) -> Sender<Response> {
@@ -378,7 +448,9 @@ impl Sequence {
         {
             // add_token was already called
             Some(StopReason::Length(self.max_len.unwrap()))
-        } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
+        } else if self.shifted_tokens == 0
+            && self.tokens.len().saturating_sub(self.prompt_len) == max_model_len
+        {
             Some(StopReason::ModelLength(max_model_len))
         } else {
             if !self.stop_strings.is_empty() {
@@ -392,6 +464,10 @@ impl Sequence {
                     }
                 }
             }
+            if self.is_expired() {
+                // Return the partial output generated before the deadline.
+                return Some(StopReason::Timeout);
+            }
             None
         }
     }
@@ -484,14 +560,76 @@ impl Sequence {
         get_mut_group!(self)
     }
 
+    /// Whether both sequences are choices of the same request.
+    pub fn same_group(&self, other: &Sequence) -> bool {
+        Arc::ptr_eq(&self.group, &other.group)
+    }
+
     pub fn add_streaming_chunk_choice_to_group(&self, chunk: ChunkChoice) {
         get_mut_group!(self).streaming_chunks.push(chunk);
     }
 
-    pub fn get_adapters(&self) -> Option<Vec<String>> {
+    /// The adapters of this sequence and the weight of each.
+    pub fn get_adapters(&self) -> Option<Vec<(String, f64)>> {
         self.adapters.clone()
     }
 
+    /// Fixed X-LoRA scalings which replace those of the classifier, indexed by `[layer][adapter]`.
+    pub fn fixed_xlora_scalings(&self) -> Option<&Vec<Vec<f64>>> {
+        self.fixed_xlora_scalings.as_ref()
+    }
+
+    /// The session under which the KV cache is pinned once this sequence finishes.
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// The number of positions which were discarded from the KV cache by context shifting.
+    pub fn shifted_tokens(&self) -> usize {
+        self.shifted_tokens
+    }
+
+    pub fn add_shifted_tokens(&mut self, n: usize) {
+        self.shifted_tokens += n;
+    }
+
+    pub fn returns_xlora_scalings(&self) -> bool {
+        self.return_xlora_scalings.is_some()
+    }
+
+    /// Add the X-LoRA scalings of some tokens, each indexed by `[layer][adapter]`.
+    pub fn add_xlora_scalings(&mut self, tokens: impl Iterator<Item = Vec<Vec<f32>>>) {
+        for token in tokens {
+            if self.xlora_scalings_sum.is_empty() {
+                self.xlora_scalings_sum = token.iter().map(|layer| vec![0.; layer.len()]).collect();
+            }
+            for (sum, layer) in self.xlora_scalings_sum.iter_mut().zip(&token) {
+                for (sum, scaling) in sum.iter_mut().zip(layer) {
+                    *sum += scaling;
+                }
+            }
+            self.xlora_scalings_count += 1;
+            if self.return_xlora_scalings == Some(XLoraScalingsOutput::PerToken) {
+                self.xlora_scalings.push(token);
+            }
+        }
+    }
+
+    /// The X-LoRA scalings to return in the response, if they were requested.
+    pub fn xlora_scalings(&self) -> Option<XLoraScalings> {
+        let output = self.return_xlora_scalings?;
+        #[allow(clippy::cast_precision_loss)]
+        let count = self.xlora_scalings_count.max(1) as f32;
+        Some(XLoraScalings {
+            tokens: (output == XLoraScalingsOutput::PerToken).then(|| self.xlora_scalings.clone()),
+            mean: self
+                .xlora_scalings_sum
+                .iter()
+                .map(|layer| layer.iter().map(|sum| sum / count).collect())
+                .collect(),
+        })
+    }
+
     pub fn take_images(&mut self) -> Option<Vec<image::DynamicImage>> {
         self.input_images.take()
     }
@@ -514,6 +652,7 @@ pub struct SequenceGroup {
     pub streaming_chunks: Vec<ChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    pub warnings: Vec<String>,
 }
 
 impl SequenceGroup {
@@ -530,6 +669,7 @@ impl SequenceGroup {
             streaming_chunks: Vec::new(),
             is_streaming,
             is_chat,
+            warnings: Vec::new(),
             best_of,
         }
     }
@@ -599,6 +739,7 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "chat.completion.chunk".to_string(),
+                    warnings: std::mem::take(&mut self.warnings),
                 }))
                 .await?;
         }
@@ -616,3 +757,61 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// A waiting [`Sequence`] for tests, with defaults for everything they do not set.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct TestSequence {
+    pub(crate) tokens: Vec<u32>,
+    pub(crate) id: usize,
+    pub(crate) is_xlora: bool,
+    /// A group of one choice if `None`.
+    pub(crate) group: Option<Arc<Mutex<SequenceGroup>>>,
+    pub(crate) priority: i32,
+    pub(crate) user: Option<String>,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) return_xlora_scalings: Option<XLoraScalingsOutput>,
+    pub(crate) fixed_xlora_scalings: Option<Vec<Vec<f64>>>,
+}
+
+#[cfg(test)]
+impl TestSequence {
+    pub(crate) fn build(self) -> Sequence {
+        let tokenizer = Arc::new(tokenizers::Tokenizer::new(
+            tokenizers::models::bpe::BPE::default(),
+        ));
+        let sampler = Sampler::new(None, 0, tokenizer, None, None, None, -1, 1.0);
+        // Responses to the sequence are dropped.
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let group = self
+            .group
+            .unwrap_or_else(|| Arc::new(Mutex::new(SequenceGroup::new(1, false, false, 1))));
+        Sequence::new_waiting(
+            self.tokens,
+            self.id,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            self.is_xlora,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            self.priority,
+            self.user,
+            self.deadline,
+            self.return_xlora_scalings,
+            self.fixed_xlora_scalings,
+            None,
+        )
+    }
+}
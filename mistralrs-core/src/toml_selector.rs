@@ -1,10 +1,10 @@
-use std::fs::File;
+use std::{fs::File, path::PathBuf};
 
 use serde::Deserialize;
 
 use crate::{
-    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, IsqPolicy,
+    Loader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
     SpeculativeLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
 };
 
@@ -67,8 +67,9 @@ enum TomlModelSelected {
     GGUF {
         /// `tok_model_id` is the local or remote model ID where you can find a `tokenizer_config.json` file.
         /// If the `chat_template` is specified, then it will be treated as a path and used over remote files,
-        /// removing all remote accesses.
-        tok_model_id: String,
+        /// removing all remote accesses. If neither is specified, the tokenizer and chat template are
+        /// read from the GGUF file metadata.
+        tok_model_id: Option<String>,
 
         /// Quantized model ID to find the `quantized_filename`, only applicable if `quantized` is set.
         /// This may be a HF hub repo or a local path.
@@ -225,6 +226,10 @@ pub struct TomlSelector {
 
     /// Speculative model selector
     speculative: Option<SpeculativeTomlModelSelected>,
+
+    /// Mixed-precision ISQ policy, such as `attn=Q8_0,mlp=Q4K,lm_head=skip`. Overrides the policy
+    /// given to the loader.
+    isq_policy: Option<IsqPolicy>,
 }
 
 #[derive(Clone)]
@@ -234,12 +239,16 @@ struct TomlLoaderInnerParams {
     no_kv_cache: bool,
     tokenizer_json: Option<String>,
     repeat_last_n: usize,
+    isq_policy: Option<IsqPolicy>,
+    isq_artifacts: Option<PathBuf>,
 }
 
 pub struct TomlLoaderArgs {
     pub use_flash_attn: bool,
     pub chat_template: Option<String>,
     pub no_kv_cache: bool,
+    pub isq_policy: Option<IsqPolicy>,
+    pub isq_artifacts: Option<PathBuf>,
 }
 
 fn loader_from_selected(
@@ -257,6 +266,8 @@ fn loader_from_selected(
             args.tokenizer_json,
             Some(model_id),
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
         TomlModelSelected::XLora {
             model_id,
@@ -282,6 +293,8 @@ fn loader_from_selected(
             args.no_kv_cache,
             tgt_non_granular_index,
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
         TomlModelSelected::Lora {
             model_id,
@@ -304,6 +317,8 @@ fn loader_from_selected(
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?,
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
         TomlModelSelected::GGUF {
             tok_model_id,
@@ -314,7 +329,7 @@ fn loader_from_selected(
                 repeat_last_n: args.repeat_last_n,
             },
             args.chat_template,
-            Some(tok_model_id),
+            tok_model_id,
             quantized_model_id,
             quantized_filename,
         )
@@ -449,6 +464,8 @@ fn loader_from_selected(
             args.tokenizer_json,
             Some(model_id),
         )
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(args.isq_artifacts)
         .build(arch),
     };
     Ok(loader)
@@ -464,6 +481,8 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
             no_kv_cache: args.no_kv_cache,
             tokenizer_json: selector.tokenizer_json,
             repeat_last_n: selector.repeat_last_n,
+            isq_policy: selector.isq_policy.or(args.isq_policy),
+            isq_artifacts: args.isq_artifacts,
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
         let loader = if let Some(speculative) = selector.speculative {
@@ -5,6 +5,8 @@ use candle_core::quantized::gguf_file;
 use std::collections::HashMap;
 use tracing::warn;
 
+use crate::layers::{RopeScaling, RopeScalingType};
+
 pub struct ContentMetadata<'a> {
     pub path_prefix: &'a str,
     pub metadata: &'a HashMap<String, gguf_file::Value>,
@@ -40,6 +42,29 @@ impl ContentMetadata<'_> {
         Ok(())
     }
 
+    // RoPE scaling from the `rope.scaling.*` keys, or the older `rope.scale_linear` key:
+    pub fn get_rope_scaling(&self) -> Result<Option<RopeScaling>> {
+        let scaling_type = self.get_value::<String>("rope.scaling.type").ok();
+        match scaling_type.as_deref() {
+            None | Some("none") => Ok(self
+                .get_value::<f32>("rope.scale_linear")
+                .ok()
+                .filter(|factor| *factor != 1.)
+                .map(|factor| RopeScaling::new(RopeScalingType::Linear, factor as f64))),
+            Some(scaling_type) => {
+                let mut scaling = RopeScaling::new(
+                    scaling_type.parse()?,
+                    self.get_value::<f32>("rope.scaling.factor")? as f64,
+                );
+                scaling.original_max_position_embeddings = self
+                    .get_value::<u32>("rope.scaling.original_context_length")
+                    .ok()
+                    .map(|len| len as usize);
+                Ok(Some(scaling))
+            }
+        }
+    }
+
     // Reference: https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#required
     pub fn verify_arch(&self, expected_arch: &str) -> Result<()> {
         let actual_arch: String = self
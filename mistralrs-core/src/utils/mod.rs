@@ -26,10 +26,8 @@ macro_rules! handle_seq_error {
             Ok(v) => v,
             Err(e) => {
                 use $crate::response::Response;
-                $response
-                    .send(Response::InternalError(e.into()))
-                    .await
-                    .expect("Expected receiver.");
+                // If the receiver is gone the request was canceled, so there is nobody to notify.
+                let _ = $response.send(Response::InternalError(e.into())).await;
                 return;
             }
         }
@@ -44,10 +42,8 @@ macro_rules! handle_seq_error_ok {
             Ok(v) => v,
             Err(e) => {
                 use $crate::response::Response;
-                $response
-                    .send(Response::InternalError(e.into()))
-                    .await
-                    .expect("Expected receiver.");
+                // If the receiver is gone the request was canceled, so there is nobody to notify.
+                let _ = $response.send(Response::InternalError(e.into())).await;
                 return Ok(());
             }
         }
@@ -63,11 +59,17 @@ macro_rules! handle_seq_error_stateaware_ok {
             Err(e) => {
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
-                $seq.responder()
+                if $seq
+                    .responder()
                     .send(Response::InternalError(e.into()))
                     .await
-                    .expect("Expected receiver.");
-                $seq.set_state(SequenceState::Error);
+                    .is_err()
+                {
+                    // The receiver is gone: treat it as a cancellation of this sequence.
+                    $seq.set_state(SequenceState::Done($crate::sequence::StopReason::Canceled));
+                } else {
+                    $seq.set_state(SequenceState::Error);
+                }
                 return Ok(());
             }
         }
@@ -110,6 +112,7 @@ macro_rules! handle_pipeline_forward_error {
                                 role: "assistant".to_string(),
                             },
                             logprobs: None,
+                            xlora_scalings: seq.xlora_scalings(),
                         };
                         seq.add_choice_to_group(choice);
                     } else {
@@ -118,6 +121,7 @@ macro_rules! handle_pipeline_forward_error {
                             index: seq.get_response_index(),
                             text: res,
                             logprobs: None,
+                            xlora_scalings: seq.xlora_scalings(),
                         };
                         seq.add_completion_choice_to_group(choice);
                     }
@@ -135,15 +139,16 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            warnings: group.warnings.clone(),
                         };
 
-                        seq.responder()
+                        // A closed channel only means the client has gone away.
+                        let _ = seq.responder()
                             .send(Response::ModelError(
                                 e.to_string(),
                                 partial_completion_response
                             ))
-                            .await
-                            .unwrap();
+                            .await;
                     } else {
                         let partial_completion_response = CompletionResponse {
                             id: seq.id().to_string(),
@@ -153,15 +158,16 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            warnings: group.warnings.clone(),
                         };
 
-                        seq.responder()
+                        // A closed channel only means the client has gone away.
+                        let _ = seq.responder()
                             .send(Response::CompletionModelError(
                                 e.to_string(),
                                 partial_completion_response
                             ))
-                            .await
-                            .unwrap();
+                            .await;
                     }
                 }
                 for seq in $seq_slice.iter_mut() {
@@ -182,6 +188,50 @@ macro_rules! handle_pipeline_forward_error {
     };
 }
 
+/// Handle a panic caught while stepping the pipeline: respond with an internal error to only
+/// the affected batch and reset the caches, which may have been left half-updated, so that the
+/// engine keeps serving other requests.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! handle_pipeline_step_panic {
+    ($stage: tt, $caught:expr, $seq_slice:expr, $pipeline:expr, $label:tt, $prefix_cacher:expr) => {
+        match $caught {
+            Ok(v) => v,
+            Err(payload) => {
+                use $crate::response::Response;
+                use $crate::sequence::SequenceState;
+                use tracing::error;
+                let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                    msg.to_string()
+                } else if let Some(msg) = payload.downcast_ref::<String>() {
+                    msg.clone()
+                } else {
+                    "unknown panic payload".to_string()
+                };
+                error!("{} - Model panicked: {msg}", $stage);
+                for seq in $seq_slice.iter_mut() {
+                    // A closed channel only means the client has gone away.
+                    let _ = seq
+                        .responder()
+                        .send(Response::InternalError(
+                            format!("{} - Model panicked: {msg}", $stage).into(),
+                        ))
+                        .await;
+                    seq.set_state(SequenceState::Error);
+                }
+
+                let mut p = get_mut_arcmutex!($pipeline);
+                p.set_none_cache(true, true);
+                if let Err(e) = $prefix_cacher.evict_all_to_cpu() {
+                    error!("Failed to evict the prefix cache after a panic: {e}");
+                }
+
+                continue $label;
+            }
+        }
+    };
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! get_mut_group {
@@ -271,9 +271,12 @@ impl Config<ParamsGGUF<'_>, Adapter<'_>> {
 }
 
 use crate::{
+    models::quantized_gemma::ModelWeights as QGemma,
     models::quantized_llama::ModelWeights as QLlama,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
+    models::quantized_qwen2::ModelWeights as QQwen2,
+    models::quantized_starcoder2::ModelWeights as QStarcoder2,
     xlora_models::{XLoraQLlama, XLoraQPhi3},
 };
 use akin::akin;
@@ -297,7 +300,7 @@ impl TryFrom<ModelParams<'_, ParamsGGML>> for XLoraQLlama {
 }
 
 akin! {
-    let &models_gguf = [QLlama, QPhi, QPhi3];
+    let &models_gguf = [QLlama, QPhi, QPhi3, QQwen2, QGemma, QStarcoder2];
 
     impl TryFrom<ModelParams<'_, ParamsGGUF<'_>>> for *models_gguf {
         type Error = candle_core::Error;
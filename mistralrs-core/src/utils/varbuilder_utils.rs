@@ -86,6 +86,18 @@ pub(crate) fn load_preload_adapters<'a>(
     }
 }
 
+/// Load the weights of an adapter which is added at runtime. The weights are kept on the CPU in
+/// F32; each layer moves its part to the device and dtype of the model.
+pub(crate) fn load_runtime_adapter<'a>(path: &PathBuf) -> Result<VarBuilder<'a>> {
+    let loaded_tensors =
+        Common::new().load_tensors_from_path(path, &Device::Cpu, DType::F32, true)?;
+    Ok(VarBuilder::from_tensors(
+        loaded_tensors,
+        DType::F32,
+        &Device::Cpu,
+    ))
+}
+
 // Presently this logic only needs to diverge for X-LoRA support via `get_name_key_pairs()`
 trait LoadTensors {
     fn load_tensors_from_path(
@@ -104,7 +116,13 @@ trait LoadTensors {
         // Take the filtered list of tensors to load, store with derived lookup key:
         let mut loaded_tensors = HashMap::new();
         for (load_name, key_name) in iter.with_progress(is_silent) {
-            let tensor = tensors.load(&load_name, device)?.to_dtype(dtype)?;
+            let tensor = tensors.load(&load_name, device)?;
+            // Integer tensors hold packed GPTQ/AWQ weights and must keep their bits.
+            let tensor = if tensor.dtype().is_float() {
+                tensor.to_dtype(dtype)?
+            } else {
+                tensor
+            };
 
             loaded_tensors.insert(key_name, tensor);
         }
@@ -17,7 +17,8 @@ use crate::{
         PhiRotaryEmbedding, RmsNorm, ScaledDotProductAttention,
     },
     pipeline::{
-        extract_logits, Cache, IsqModel, NormalLoadingMetadata, Phi3RopeScaling, VisionModel,
+        extract_logits, Cache, IsqModel, IsqTensor, IsqTensorRole, KvCacheEntry,
+        NormalLoadingMetadata, Phi3RopeScaling, VisionModel,
     },
     serde_default_fn,
     vision_models::clip::{Activation, ClipConfig, ClipVisionTransformer},
@@ -169,7 +170,7 @@ impl Attention {
         attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         position_ids: &[usize],
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -332,7 +333,7 @@ impl DecoderLayer {
         attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         position_ids: &[usize],
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
@@ -869,15 +870,19 @@ impl Model {
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         // TODO(EricLBuehler): more?
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, IsqTensorRole::LmHead));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.qkv_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.gate_up_proj, Some(i)));
-            tensors.push((&mut layer.mlp.down_proj, Some(i)));
+            tensors.push((
+                &mut layer.self_attn.qkv_proj,
+                Some(i),
+                IsqTensorRole::AttnQkv,
+            ));
+            tensors.push((&mut layer.self_attn.o_proj, Some(i), IsqTensorRole::AttnO));
+            tensors.push((&mut layer.mlp.gate_up_proj, Some(i), IsqTensorRole::Mlp));
+            tensors.push((&mut layer.mlp.down_proj, Some(i), IsqTensorRole::Mlp));
         }
         (tensors, &*self.mapper)
     }
@@ -301,6 +301,11 @@ impl XLoraClassifier {
         .to_dtype(dtype)
     }
 
+    /// The number of LoRA layers and of adapters which the scalings are given for.
+    pub fn scalings_shape(&self) -> (usize, usize) {
+        (self.model_layers, self.n_classes)
+    }
+
     pub fn get_global_scaling_weight(&self) -> f64 {
         self.config.global_scaling_weight
     }
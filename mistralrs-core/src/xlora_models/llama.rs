@@ -3,19 +3,19 @@
 use crate::{
     layers::ScaledDotProductAttention,
     lora::{linear_no_bias as linear, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::IsqModel,
+    pipeline::{IsqModel, IsqTensor, IsqTensorRole},
 };
-use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor};
-use candle_nn::{embedding, Embedding, Module, RotaryEmbedding, VarBuilder};
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use std::{collections::HashMap, sync::Arc};
 use tqdm::Iter;
 use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm},
+    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm, ScaledRotaryEmbedding},
     models::llama::Config,
-    pipeline::{self, extract_logits, LayerCaches, NormalLoadingMetadata, NormalModel},
+    pipeline::{self, extract_logits, KvCacheEntry, NormalLoadingMetadata, NormalModel},
 };
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
@@ -30,7 +30,7 @@ struct CausalSelfAttention {
     num_key_value_heads: usize,
     head_dim: usize,
     use_flash_attn: bool,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     max_seq_len: usize,
 }
 
@@ -43,7 +43,7 @@ impl CausalSelfAttention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         block_idx: usize,
-        kv_cache: &mut LayerCaches,
+        kv_cache: &mut [KvCacheEntry],
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -143,7 +143,7 @@ impl CausalSelfAttention {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<RotaryEmbedding>,
+        rope: Arc<ScaledRotaryEmbedding>,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
         let size_in = cfg.hidden_size;
@@ -316,7 +316,7 @@ impl Block {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         block_idx: usize,
-        kv_cache: &mut LayerCaches,
+        kv_cache: &mut [KvCacheEntry],
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -354,7 +354,7 @@ impl Block {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<RotaryEmbedding>,
+        rope: Arc<ScaledRotaryEmbedding>,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
         let attn = CausalSelfAttention::load(
@@ -428,7 +428,7 @@ impl XLoraLlama {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
                 for _ in 0..self.kv_cache.xlora_lock().len() {
-                    new_cache.push(None);
+                    new_cache.push(self.kv_cache.empty_entry());
                 }
 
                 self.kv_cache.xlora_lock().clone_from(&new_cache);
@@ -578,10 +578,11 @@ impl XLoraLlama {
         let mut blocks: Vec<_> = (0..cfg.num_hidden_layers)
             .map(|i| {
                 let rotary_emb = Arc::new(
-                    RotaryEmbedding::new(
+                    ScaledRotaryEmbedding::new(
                         cfg.rope_theta,
                         head_dim,
                         cfg.max_position_embeddings,
+                        cfg.rope_scaling.as_ref(),
                         mapper
                             .device_for(i, false)
                             .unwrap_or(&normal_loading_metadata.real_device),
@@ -651,31 +652,44 @@ impl XLoraLlama {
 }
 
 impl IsqModel for XLoraLlama {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((self.lm_head.inner(), None));
+        tensors.push((self.lm_head.inner(), None, IsqTensorRole::LmHead));
         for (i, layer) in self.blocks.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.attn.q_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnQ,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.attn.k_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnK,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.attn.v_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnV,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.attn.o_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnO,
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.c_fc1).unwrap().inner(),
+                Some(i),
+                IsqTensorRole::Mlp,
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.c_fc2).unwrap().inner(),
+                Some(i),
+                IsqTensorRole::Mlp,
             ));
-            tensors.push((Arc::get_mut(&mut layer.mlp.c_fc1).unwrap().inner(), Some(i)));
-            tensors.push((Arc::get_mut(&mut layer.mlp.c_fc2).unwrap().inner(), Some(i)));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.c_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::Mlp,
             ));
         }
         (tensors, &*self.mapper)
@@ -727,10 +741,18 @@ impl NormalModel for XLoraLlama {
     fn is_xlora(&self) -> bool {
         true
     }
+    fn xlora_scalings_shape(&self) -> Option<(usize, usize)> {
+        self.xlora_classifier
+            .as_ref()
+            .map(|classifier| classifier.scalings_shape())
+    }
+    fn supports_row_adapters(&self) -> bool {
+        true
+    }
     fn max_seq_len(&self) -> usize {
         self.blocks[0].attn.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         let mut sum = 0;
         for layer in self.blocks.iter_mut() {
             sum += Arc::get_mut(&mut layer.attn.k_proj)
@@ -758,6 +780,96 @@ impl NormalModel for XLoraLlama {
         }
         Ok(sum)
     }
+    fn activate_row_adapters(&mut self, rows: Vec<Option<Vec<(String, f64)>>>) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .activate_rows(&rows)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .activate_rows(&rows)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .activate_rows(&rows)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .activate_rows(&rows)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .activate_rows(&rows)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .activate_rows(&rows)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .activate_rows(&rows)?;
+        }
+        Ok(sum)
+    }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    fn unload_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for XLoraLlama {
@@ -3,7 +3,7 @@
 use crate::{
     layers::{MatMul, ScaledDotProductAttention},
     lora::{linear_no_bias, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::{IsqModel, NormalLoadingMetadata},
+    pipeline::{IsqModel, IsqTensor, IsqTensorRole, NormalLoadingMetadata},
 };
 /// Mixtral Model
 /// https://github.com/huggingface/transformers/blob/main/src/transformers/models/mixtral/modeling_mixtral.py
@@ -18,7 +18,7 @@ use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, RmsNorm},
     models::mixtral::Config,
-    pipeline::{extract_logits, Cache, NormalModel},
+    pipeline::{extract_logits, Cache, KvCacheEntry, NormalModel},
 };
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
@@ -119,7 +119,7 @@ impl Attention {
         attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -523,7 +523,7 @@ impl DecoderLayer {
         attention_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -685,7 +685,7 @@ impl XLoraModel {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
                 for _ in 0..self.cache.xlora_lock().len() {
-                    new_cache.push(None);
+                    new_cache.push(self.cache.empty_entry());
                 }
 
                 self.cache.xlora_lock().clone_from(&new_cache);
@@ -806,36 +806,53 @@ impl XLoraModel {
 }
 
 impl IsqModel for XLoraModel {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, IsqTensorRole::LmHead));
         for (i, layer) in self.layers.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.q_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnQ,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.k_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnK,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.v_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnV,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.o_proj).unwrap().inner(),
                 Some(i),
+                IsqTensorRole::AttnO,
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.block_sparse_moe.gate)
                     .unwrap()
                     .inner(),
                 Some(i),
+                IsqTensorRole::MoeGate,
             ));
             for expert in &mut layer.block_sparse_moe.experts {
-                tensors.push((Arc::get_mut(&mut expert.w1).unwrap().inner(), Some(i)));
-                tensors.push((Arc::get_mut(&mut expert.w2).unwrap().inner(), Some(i)));
-                tensors.push((Arc::get_mut(&mut expert.w3).unwrap().inner(), Some(i)));
+                tensors.push((
+                    Arc::get_mut(&mut expert.w1).unwrap().inner(),
+                    Some(i),
+                    IsqTensorRole::MoeExpert,
+                ));
+                tensors.push((
+                    Arc::get_mut(&mut expert.w2).unwrap().inner(),
+                    Some(i),
+                    IsqTensorRole::MoeExpert,
+                ));
+                tensors.push((
+                    Arc::get_mut(&mut expert.w3).unwrap().inner(),
+                    Some(i),
+                    IsqTensorRole::MoeExpert,
+                ));
             }
         }
         (tensors, &*self.mapper)
@@ -887,10 +904,15 @@ impl NormalModel for XLoraModel {
     fn is_xlora(&self) -> bool {
         true
     }
+    fn xlora_scalings_shape(&self) -> Option<(usize, usize)> {
+        self.xlora_classifier
+            .as_ref()
+            .map(|classifier| classifier.scalings_shape())
+    }
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
             sum += Arc::get_mut(&mut layer.self_attn.k_proj)
@@ -923,6 +945,72 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1)
+                    .unwrap()
+                    .load_adapter(name, vb, cfg)?;
+                sum += Arc::get_mut(&mut expert.w2)
+                    .unwrap()
+                    .load_adapter(name, vb, cfg)?;
+                sum += Arc::get_mut(&mut expert.w3)
+                    .unwrap()
+                    .load_adapter(name, vb, cfg)?;
+            }
+        }
+        Ok(sum)
+    }
+    fn unload_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .unload_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .unload_adapter(name)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1).unwrap().unload_adapter(name)?;
+                sum += Arc::get_mut(&mut expert.w2).unwrap().unload_adapter(name)?;
+                sum += Arc::get_mut(&mut expert.w3).unwrap().unload_adapter(name)?;
+            }
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for XLoraModel {
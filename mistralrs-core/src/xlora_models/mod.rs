@@ -8,6 +8,7 @@ mod phi2;
 mod phi3;
 mod quantized_llama;
 mod quantized_phi3;
+mod qwen2;
 
 use std::sync::Arc;
 
@@ -22,12 +23,26 @@ pub(crate) use phi2::Model as XLoraPhi2;
 pub(crate) use phi3::Model as XLoraPhi3;
 pub(crate) use quantized_llama::ModelWeights as XLoraQLlama;
 pub(crate) use quantized_phi3::ModelWeights as XLoraQPhi3;
+pub(crate) use qwen2::XLoraModel as XLoraQwen2;
 use tokio::sync::Mutex;
 
-use crate::{get_mut_arcmutex, pipeline::Cache};
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{Cache, KvCacheEntry},
+    sequence::Sequence,
+};
 
 use self::classifier::XLoraClassifier;
 
+/// Fixed X-LoRA scalings of a batch, which replace the classifier's scalings for some of its rows.
+#[derive(Debug, Clone)]
+pub(crate) struct FixedScalings {
+    /// Scalings of each row of the batch, with shape (batch, 1, LoRA layers, adapters).
+    scalings: Tensor,
+    /// Whether each row of the batch uses its fixed scalings.
+    rows: Vec<bool>,
+}
+
 pub struct NonGranularState {
     pub non_granular_index: Arc<Mutex<usize>>,
     pub tgt_non_granular_index: usize,
@@ -51,6 +66,8 @@ trait ScalingsMaker {
     ) -> Result<Tensor>;
     fn get_cache(&self) -> &Cache;
 
+    /// The scalings of the batch. Rows with fixed scalings use those instead of the classifier's,
+    /// and the scaling pass is skipped if every row has them.
     #[allow(clippy::too_many_arguments)]
     fn get_scalings(
         &mut self,
@@ -63,6 +80,68 @@ trait ScalingsMaker {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         position_ids: &[usize],
+    ) -> Result<Tensor> {
+        let fixed = self.get_cache().get_fixed_scalings().clone();
+        let scalings = match fixed {
+            Some(fixed) if fixed.rows.iter().all(|row| *row) => {
+                let (b_size, seq_len) = if no_kv_cache {
+                    input_ids_full.dims2()?
+                } else {
+                    input_ids.dims2()?
+                };
+                let (_, _, n_layers, n_classes) = fixed.scalings.dims4()?;
+                fixed
+                    .scalings
+                    .to_device(input_ids.device())?
+                    .to_dtype(self.dtype())?
+                    .broadcast_as((b_size, seq_len, n_layers, n_classes))?
+                    .contiguous()?
+            }
+            fixed => {
+                let scalings = self.get_classifier_scalings(
+                    input_ids,
+                    input_ids_full,
+                    seqlen_offsets,
+                    seqlen_offsets_full,
+                    start_offsets_kernel,
+                    start_offsets_kernel_full,
+                    no_kv_cache,
+                    non_granular_state,
+                    position_ids,
+                )?;
+                match fixed {
+                    Some(fixed) => {
+                        let rows = fixed.rows.iter().map(|row| u8::from(*row)).collect();
+                        let mask =
+                            Tensor::from_vec(rows, (fixed.rows.len(), 1, 1, 1), scalings.device())?
+                                .broadcast_as(scalings.shape())?;
+                        let fixed = fixed
+                            .scalings
+                            .to_device(scalings.device())?
+                            .to_dtype(scalings.dtype())?
+                            .broadcast_as(scalings.shape())?;
+                        mask.where_cond(&fixed, &scalings)?
+                    }
+                    None => scalings,
+                }
+            }
+        };
+        *self.get_cache().get_last_scalings() = Some(scalings.clone());
+        Ok(scalings)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn get_classifier_scalings(
+        &mut self,
+        input_ids: &Tensor,
+        input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        start_offsets_kernel: &Tensor,
+        start_offsets_kernel_full: &Tensor,
+        no_kv_cache: bool,
+        non_granular_state: &Option<NonGranularState>,
+        position_ids: &[usize],
     ) -> Result<Tensor> {
         let (b_size, _) = input_ids_full.dims2()?;
         let (_, seq_len) = input_ids.dims2()?;
@@ -97,10 +176,13 @@ trait ScalingsMaker {
 
             let mut new_cache = Vec::new();
             for _ in 0..self.get_cache().xlora_lock().len() {
-                new_cache.push(Some((
-                    Tensor::zeros((1,), DType::U8, &Device::Cpu)?,
-                    Tensor::zeros((1,), DType::U8, &Device::Cpu)?,
-                )));
+                new_cache.push(KvCacheEntry::new(
+                    Some((
+                        Tensor::zeros((1,), DType::U8, &Device::Cpu)?,
+                        Tensor::zeros((1,), DType::U8, &Device::Cpu)?,
+                    )),
+                    None,
+                ));
             }
             self.get_cache().lock().clone_from(&new_cache);
 
@@ -141,3 +223,213 @@ fn verify_sanity_adapters(ordering: &Ordering, supported_layers: &[&str]) -> Res
     }
     Ok(())
 }
+
+/// Check the X-LoRA options of a request against the (LoRA layers, adapters) `shape` of the scalings
+/// of the model, which is `None` if it is not an X-LoRA model.
+pub(crate) fn check_xlora_scalings(
+    shape: Option<(usize, usize)>,
+    returns_scalings: bool,
+    fixed: Option<&[Vec<f64>]>,
+) -> std::result::Result<(), String> {
+    match (shape, fixed) {
+        (None, _) if returns_scalings || fixed.is_some() => Err(
+            "X-LoRA scalings were requested or given for a model which is not an X-LoRA model."
+                .to_string(),
+        ),
+        (Some((n_layers, n_classes)), Some(scalings)) => {
+            if scalings.len() != 1 && scalings.len() != n_layers {
+                Err(format!(
+                    "Expected X-LoRA scalings for 1 or {n_layers} layers, got {}.",
+                    scalings.len()
+                ))
+            } else if let Some(row) = scalings.iter().find(|row| row.len() != n_classes) {
+                Err(format!(
+                    "Expected X-LoRA scalings for {n_classes} adapters per layer, got {}.",
+                    row.len()
+                ))
+            } else if scalings.iter().flatten().any(|x| !x.is_finite()) {
+                Err("X-LoRA scalings must be finite.".to_string())
+            } else {
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Gather the fixed scalings of each sequence of a batch, if any sequence has them. `shape` is the
+/// (LoRA layers, adapters) shape of the scalings of the model.
+pub(crate) fn make_fixed_scalings(
+    seqs: &[&mut Sequence],
+    shape: Option<(usize, usize)>,
+) -> Result<Option<FixedScalings>> {
+    let Some((n_layers, n_classes)) = shape else {
+        return Ok(None);
+    };
+    if seqs.iter().all(|seq| seq.fixed_xlora_scalings().is_none()) {
+        return Ok(None);
+    }
+    let mut scalings = Vec::with_capacity(seqs.len() * n_layers * n_classes);
+    let mut rows = Vec::with_capacity(seqs.len());
+    for seq in seqs {
+        match seq.fixed_xlora_scalings() {
+            Some(fixed) => {
+                for layer in 0..n_layers {
+                    // A single row of scalings is used for every layer.
+                    let layer = if fixed.len() == 1 { 0 } else { layer };
+                    scalings.extend(fixed[layer].iter().map(|x| *x as f32));
+                }
+                rows.push(true);
+            }
+            None => {
+                scalings.extend(std::iter::repeat(0f32).take(n_layers * n_classes));
+                rows.push(false);
+            }
+        }
+    }
+    Ok(Some(FixedScalings {
+        scalings: Tensor::from_vec(scalings, (seqs.len(), 1, n_layers, n_classes), &Device::Cpu)?,
+        rows,
+    }))
+}
+
+/// Add the scalings of the last forward pass, with shape (batch, tokens, LoRA layers, adapters), to
+/// the sequences which return them. Prompts are right padded, and completions add their last token.
+pub(crate) fn record_scalings(
+    seqs: &mut [&mut Sequence],
+    scalings: &Tensor,
+    is_prompt: bool,
+) -> Result<()> {
+    if !seqs.iter().any(|seq| seq.returns_xlora_scalings()) {
+        return Ok(());
+    }
+    let scalings = scalings.to_dtype(DType::F32)?.to_vec4::<f32>()?;
+    for (seq, row) in seqs.iter_mut().zip(scalings) {
+        if !seq.returns_xlora_scalings() {
+            continue;
+        }
+        let (skip, take) = if is_prompt {
+            (0, seq.len())
+        } else {
+            (row.len().saturating_sub(1), 1)
+        };
+        seq.add_xlora_scalings(row.into_iter().skip(skip).take(take));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Result, Tensor};
+
+    use super::{check_xlora_scalings, make_fixed_scalings, record_scalings};
+    use crate::{
+        request::XLoraScalingsOutput,
+        sequence::{Sequence, TestSequence},
+    };
+
+    fn seq(
+        n_tokens: usize,
+        returns: Option<XLoraScalingsOutput>,
+        fixed: Option<Vec<Vec<f64>>>,
+    ) -> Sequence {
+        TestSequence {
+            tokens: vec![1; n_tokens],
+            is_xlora: true,
+            return_xlora_scalings: returns,
+            fixed_xlora_scalings: fixed,
+            ..Default::default()
+        }
+        .build()
+    }
+
+    #[test]
+    fn fixed_scalings_shape_is_checked() {
+        let row = || vec![0.5, 0.5];
+        assert!(check_xlora_scalings(None, false, None).is_ok());
+        assert!(check_xlora_scalings(None, true, None).is_err());
+        assert!(check_xlora_scalings(None, false, Some(&[row()])).is_err());
+        assert!(check_xlora_scalings(Some((3, 2)), true, None).is_ok());
+
+        // One row for every layer, or a row per layer.
+        assert!(check_xlora_scalings(Some((3, 2)), false, Some(&[row()])).is_ok());
+        assert!(check_xlora_scalings(Some((3, 2)), false, Some(&[row(), row(), row()])).is_ok());
+        assert!(check_xlora_scalings(Some((3, 2)), false, Some(&[row(), row()])).is_err());
+        // Every row has a scaling per adapter.
+        let ragged = [row(), vec![1.0], row()];
+        assert!(check_xlora_scalings(Some((3, 2)), false, Some(&ragged)).is_err());
+        assert!(check_xlora_scalings(Some((3, 3)), false, Some(&[row()])).is_err());
+        assert!(check_xlora_scalings(Some((3, 2)), false, Some(&[vec![f64::NAN, 0.5]])).is_err());
+    }
+
+    #[test]
+    fn fixed_scalings_fill_each_row() -> Result<()> {
+        let mut shared = seq(2, None, Some(vec![vec![1., 2.]]));
+        let mut classified = seq(2, None, None);
+        let mut per_layer = seq(2, None, Some(vec![vec![1., 0.], vec![0., 1.]]));
+
+        let mut seqs = vec![&mut classified];
+        assert!(make_fixed_scalings(&seqs, Some((2, 2)))?.is_none());
+        seqs.insert(0, &mut shared);
+        seqs.push(&mut per_layer);
+        assert!(make_fixed_scalings(&seqs, None)?.is_none());
+
+        let fixed = make_fixed_scalings(&seqs, Some((2, 2)))?.unwrap();
+        assert_eq!(fixed.rows, vec![true, false, true]);
+        assert_eq!(
+            fixed.scalings.squeeze(1)?.to_vec3::<f32>()?,
+            vec![
+                vec![vec![1., 2.], vec![1., 2.]],
+                vec![vec![0., 0.], vec![0., 0.]],
+                vec![vec![1., 0.], vec![0., 1.]],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn recorded_scalings_skip_padding_and_average() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut per_token = seq(2, Some(XLoraScalingsOutput::PerToken), None);
+        let mut mean = seq(3, Some(XLoraScalingsOutput::Mean), None);
+        let mut untracked = seq(3, None, None);
+        let mut seqs = vec![&mut per_token, &mut mean, &mut untracked];
+
+        // The prompt of the first sequence is padded to 3 tokens.
+        let prompt = Tensor::new(
+            &[
+                [[[1f32, 3.]], [[3., 5.]], [[100., 100.]]],
+                [[[0., 0.]], [[2., 2.]], [[4., 4.]]],
+                [[[100., 100.]], [[100., 100.]], [[100., 100.]]],
+            ],
+            &dev,
+        )?;
+        record_scalings(&mut seqs, &prompt, true)?;
+        // Only the last token of a completion step is new.
+        let completion = Tensor::new(
+            &[
+                [[[100f32, 100.]], [[5., 7.]]],
+                [[[100., 100.]], [[6., 6.]]],
+                [[[100., 100.]], [[100., 100.]]],
+            ],
+            &dev,
+        )?;
+        record_scalings(&mut seqs, &completion, false)?;
+
+        let scalings = per_token.xlora_scalings().unwrap();
+        assert_eq!(
+            scalings.tokens,
+            Some(vec![
+                vec![vec![1., 3.]],
+                vec![vec![3., 5.]],
+                vec![vec![5., 7.]]
+            ])
+        );
+        assert_eq!(scalings.mean, vec![vec![3., 5.]]);
+        let scalings = mean.xlora_scalings().unwrap();
+        assert_eq!(scalings.tokens, None);
+        assert_eq!(scalings.mean, vec![vec![3., 3.]]);
+        assert!(untracked.xlora_scalings().is_none());
+        Ok(())
+    }
+}
@@ -8,13 +8,15 @@ use crate::lora::{
 use candle_core::quantized::QMatMul;
 use candle_core::quantized::{ggml_file, gguf_file};
 use candle_core::{DType, Device, Result, Tensor};
-use candle_nn::{Embedding, Module, RotaryEmbedding, VarBuilder};
+use candle_nn::{Embedding, Module, VarBuilder};
 use tqdm::Iter;
 use tracing::info;
 
 use crate::device_map::DeviceMapper;
-use crate::layers::{repeat_kv, CausalMasker, MatMul, QRmsNorm, ScaledDotProductAttention};
-use crate::pipeline::{extract_logits, Cache};
+use crate::layers::{
+    repeat_kv, CausalMasker, MatMul, QRmsNorm, ScaledDotProductAttention, ScaledRotaryEmbedding,
+};
+use crate::pipeline::{extract_logits, Cache, KvCacheEntry};
 use crate::DeviceMapMetadata;
 
 use super::classifier::XLoraClassifier;
@@ -175,7 +177,7 @@ struct LayerWeights {
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
-    rotary: RotaryEmbedding,
+    rotary: ScaledRotaryEmbedding,
 }
 
 impl LayerWeights {
@@ -186,7 +188,7 @@ impl LayerWeights {
         mask: &Option<Tensor>,
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -281,11 +283,12 @@ impl ModelConfig::FromAdapterGGML for ModelWeights {
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let rotary = RotaryEmbedding::new_partial(
+        let rotary = ScaledRotaryEmbedding::new_partial(
             10000.,
             head_dim,
             ct.hparams.n_rot as usize,
             MAX_SEQ_LEN as usize,
+            None,
             &ct.device,
             false,
             DType::F32,
@@ -474,6 +477,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
             rms_norm_eps,
             max_seq_len,
             rope_freq_base,
+            rope_scaling,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
         let head_dim = embedding_length / head_count;
@@ -485,21 +489,36 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
             rms_norm_eps,
         )?;
         let output = ct.tensor(reader, "output.weight", device)?;
+        // Llama 3 GGUF files store the scaled RoPE frequencies as per-frequency factors.
+        let rope_freq_factors = match ct.tensor(reader, "rope_freqs.weight", device) {
+            Ok(factors) => Some(factors.dequantize(device)?.to_vec1::<f32>()?),
+            Err(_) => None,
+        };
         let mut layers = Vec::with_capacity(block_count);
         let mut count = 0;
         let mapper = mapper.into_mapper(block_count, device)?;
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
             let device = mapper.device_for(layer_idx, false).unwrap_or(device);
-            let rotary = RotaryEmbedding::new_partial(
-                rope_freq_base,
-                head_dim,
-                rope_dim,
-                max_seq_len,
-                device,
-                false,
-                DType::F32,
-            )?;
+            let rotary = match &rope_freq_factors {
+                Some(factors) => ScaledRotaryEmbedding::from_freq_factors(
+                    rope_freq_base,
+                    rope_dim,
+                    factors,
+                    device,
+                    false,
+                )?,
+                None => ScaledRotaryEmbedding::new_partial(
+                    rope_freq_base,
+                    head_dim,
+                    rope_dim,
+                    max_seq_len,
+                    rope_scaling.as_ref(),
+                    device,
+                    false,
+                    DType::F32,
+                )?,
+            };
 
             let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
             let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
@@ -704,7 +723,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
             sum += layer.attention_wk.activate(&adapter_names)?;
@@ -732,6 +751,104 @@ impl ModelWeights {
         }
         Ok(sum)
     }
+    /// The shape of the X-LoRA scalings as (LoRA layers, adapters), if this is an X-LoRA model.
+    pub fn xlora_scalings_shape(&self) -> Option<(usize, usize)> {
+        self.xlora_classifier
+            .as_ref()
+            .map(|classifier| classifier.scalings_shape())
+    }
+    /// Whether sequences using different adapters can share a batch. The experts of mixture of
+    /// experts layers only see the tokens routed to them, not whole rows of the batch.
+    pub fn supports_row_adapters(&self) -> bool {
+        self.layers
+            .iter()
+            .all(|layer| matches!(layer.mlp_or_moe, MlpOrMoe::Mlp(_)))
+    }
+    pub fn activate_row_adapters(
+        &mut self,
+        rows: Vec<Option<Vec<(String, f64)>>>,
+    ) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.activate_rows(&rows)?;
+            sum += layer.attention_wo.activate_rows(&rows)?;
+            sum += layer.attention_wq.activate_rows(&rows)?;
+            sum += layer.attention_wv.activate_rows(&rows)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.activate_rows(&rows)?;
+                    sum += m.feed_forward_w2.activate_rows(&rows)?;
+                    sum += m.feed_forward_w3.activate_rows(&rows)?;
+                }
+                MlpOrMoe::MoE { .. } => candle_core::bail!(
+                    "Sequences using different adapters cannot share a batch in mixture of experts models."
+                ),
+            }
+        }
+        Ok(sum)
+    }
+    pub fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.load_adapter(name, vb, cfg)?;
+            sum += layer.attention_wo.load_adapter(name, vb, cfg)?;
+            sum += layer.attention_wq.load_adapter(name, vb, cfg)?;
+            sum += layer.attention_wv.load_adapter(name, vb, cfg)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.load_adapter(name, vb, cfg)?;
+                    sum += m.feed_forward_w2.load_adapter(name, vb, cfg)?;
+                    sum += m.feed_forward_w3.load_adapter(name, vb, cfg)?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.load_adapter(name, vb, cfg)?;
+                        sum += expert.feed_forward_w2.load_adapter(name, vb, cfg)?;
+                        sum += expert.feed_forward_w3.load_adapter(name, vb, cfg)?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
+    pub fn unload_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.unload_adapter(name)?;
+            sum += layer.attention_wo.unload_adapter(name)?;
+            sum += layer.attention_wq.unload_adapter(name)?;
+            sum += layer.attention_wv.unload_adapter(name)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.unload_adapter(name)?;
+                    sum += m.feed_forward_w2.unload_adapter(name)?;
+                    sum += m.feed_forward_w3.unload_adapter(name)?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.unload_adapter(name)?;
+                        sum += expert.feed_forward_w2.unload_adapter(name)?;
+                        sum += expert.feed_forward_w3.unload_adapter(name)?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
 
     #[allow(clippy::too_many_arguments)]
     fn inner_forward(
@@ -749,7 +866,7 @@ impl ModelWeights {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
                 for _ in 0..self.cache.xlora_lock().len() {
-                    new_cache.push(None);
+                    new_cache.push(self.cache.empty_entry());
                 }
 
                 self.cache.xlora_lock().clone_from(&new_cache);
@@ -15,7 +15,7 @@ use crate::lora::LoraConfig;
 use crate::lora::Merge;
 use crate::lora::Ordering;
 use crate::lora::QLoraLinear;
-use crate::pipeline::extract_logits;
+use crate::pipeline::{extract_logits, KvCacheEntry};
 use crate::DeviceMapMetadata;
 use candle_core::quantized::gguf_file;
 use candle_core::quantized::QMatMul;
@@ -115,7 +115,7 @@ impl LayerWeights {
         x: &Tensor,
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut KvCacheEntry,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -356,7 +356,13 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    /// The shape of the X-LoRA scalings as (LoRA layers, adapters), if this is an X-LoRA model.
+    pub fn xlora_scalings_shape(&self) -> Option<(usize, usize)> {
+        self.xlora_classifier
+            .as_ref()
+            .map(|classifier| classifier.scalings_shape())
+    }
+    pub fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
             sum += layer.attn_qkv.activate(&adapter_names)?;
@@ -366,6 +372,45 @@ impl ModelWeights {
         }
         Ok(sum)
     }
+    pub fn activate_row_adapters(
+        &mut self,
+        rows: Vec<Option<Vec<(String, f64)>>>,
+    ) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.activate_rows(&rows)?;
+            sum += layer.attn_output.activate_rows(&rows)?;
+            sum += layer.mlp.ffn_down.activate_rows(&rows)?;
+            sum += layer.mlp.ffn_up.activate_rows(&rows)?;
+        }
+        Ok(sum)
+    }
+    pub fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.load_adapter(name, vb, cfg)?;
+            sum += layer.attn_output.load_adapter(name, vb, cfg)?;
+            sum += layer.mlp.ffn_down.load_adapter(name, vb, cfg)?;
+            sum += layer.mlp.ffn_up.load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    pub fn unload_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapters cannot be loaded or unloaded for X-LoRA models, the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.unload_adapter(name)?;
+            sum += layer.attn_output.unload_adapter(name)?;
+            sum += layer.mlp.ffn_down.unload_adapter(name)?;
+            sum += layer.mlp.ffn_up.unload_adapter(name)?;
+        }
+        Ok(sum)
+    }
 
     pub fn inner_forward(
         &mut self,
@@ -382,7 +427,7 @@ impl ModelWeights {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
                 for _ in 0..self.cache.xlora_lock().len() {
-                    new_cache.push(None);
+                    new_cache.push(self.cache.empty_entry());
                 }
 
                 self.cache.xlora_lock().clone_from(&new_cache);
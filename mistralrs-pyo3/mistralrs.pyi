@@ -29,7 +29,14 @@ class ChatCompletionRequest:
     top_k: int | None = None
     grammar: str | None = None
     grammar_type: str | None = None
-    adapters: list[str] | None = None
+    adapters: list[str] | dict[str, float] | None = None
+    priority: int | None = None
+    user: str | None = None
+    timeout: float | None = None
+    return_xlora_scalings: str | None = None
+    xlora_scalings: list[list[float]] | None = None
+    session_id: str | None = None
+    truncation_strategy: str | None = None
 
 @dataclass
 class CompletionRequest:
@@ -54,7 +61,13 @@ class CompletionRequest:
     suffix: str | None = None
     grammar: str | None = None
     grammar_type: str | None = None
-    adapters: list[str] | None = None
+    adapters: list[str] | dict[str, float] | None = None
+    priority: int | None = None
+    user: str | None = None
+    timeout: float | None = None
+    return_xlora_scalings: str | None = None
+    xlora_scalings: list[list[float]] | None = None
+    session_id: str | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -63,6 +76,9 @@ class Architecture(Enum):
     Mixtral = "mixtral"
     Llama = "llama"
     Phi2 = "phi2"
+    Phi3 = "phi3"
+    Qwen2 = "qwen2"
+    Gemma2 = "gemma2"
 
 @dataclass
 class VisionArchitecture(Enum):
@@ -101,9 +117,9 @@ class Which(Enum):
         repeat_last_n: int = 64
     @dataclass
     class GGUF:
-        tok_model_id: str
         quantized_model_id: str
         quantized_filename: str
+        tok_model_id: str | None = None
         repeat_last_n: int = 64
     @dataclass
     class XLoraGGUF:
@@ -168,6 +184,19 @@ class Runner:
         chat_template: str | None = None,
         num_device_layers: int | None = None,
         in_situ_quant: str | None = None,
+        isq_policy: str | None = None,
+        kv_cache_quant: str | None = None,
+        prefix_cache_device_bytes: int | None = None,
+        prefix_cache_host_bytes: int | None = None,
+        prefix_cache_eviction: str | None = None,
+        prefix_cache_block_size: int = 16,
+        prefix_cache_disk_dir: str | None = None,
+        prefix_cache_disk_bytes: int | None = None,
+        session_device_bytes: int | None = None,
+        session_ttl: float | None = None,
+        context_shift: bool = False,
+        context_shift_sinks: int = 4,
+        context_shift_window: int | None = None,
     ) -> None:
         """
         Load a model.
@@ -187,6 +216,27 @@ class Runner:
             It is used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
         - `num_device_layers` sets the number of layers to load and run on the device.
         - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML).
+        - `isq_policy` sets an optional mixed-precision ISQ policy refining `in_situ_quant`, such as `attn=Q8_0,mlp=Q4K,lm_head=skip`.
+            See `docs/ISQ.md` for the policy format.
+        - `kv_cache_quant` stores the KV cache quantized to reduce its memory usage: `int8` (a scale per head and token) or `q4`
+            (a scale per group of 32 values).
+        - `prefix_cache_device_bytes` limits the size of the prefix caches on the device. Caches over the limit are moved to the CPU.
+        - `prefix_cache_host_bytes` limits the size of the prefix caches on the CPU, 4 GiB by default. Caches over the limit
+            are deleted.
+        - `prefix_cache_eviction` selects the cache to move or delete first: `lru` (least recently used, the default) or `lfu`
+            (least frequently used).
+        - `prefix_cache_block_size` is the granularity in tokens of the prefix reused from a prefix cache which only partially
+            matches a prompt.
+        - `prefix_cache_disk_dir` sets a directory in which prefix caches deleted from the CPU are saved, so that they are reused
+            by later prompts and after a restart.
+        - `prefix_cache_disk_bytes` limits the size of the prefix caches on the disk. The least recently used caches are deleted.
+        - `session_device_bytes` limits the size of the session caches on the device. The least recently used sessions are
+            moved to the CPU.
+        - `session_ttl` unpins the session caches unused for this many seconds, 1800 by default, saving them to
+            `prefix_cache_disk_dir` if it is set.
+        - `context_shift` keeps generating past the maximum sequence length: once the KV cache is full, the first
+            `context_shift_sinks` tokens and the most recent `context_shift_window` tokens (by default, half of the maximum
+            sequence length) are kept and those in between are discarded. Supported for Llama, Mistral and Qwen2 models.
         """
         ...
 
@@ -203,9 +253,10 @@ class Runner:
         Send a chat completion request to the mistral.rs engine, returning the response object.
         """
 
-    def send_re_isq(self, dtype: str) -> CompletionResponse:
+    def send_re_isq(self, dtype: str, policy: str | None = None) -> CompletionResponse:
         """
-        Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML then nothing will happen.
+        Send a request to re-ISQ the model, optionally with a mixed-precision ISQ policy. If the model was loaded as GGUF or GGML
+        then nothing will happen.
         """
 
     def activate_adapters(self, adapter_names: list[str]) -> None:
@@ -213,6 +264,69 @@ class Runner:
         Send a request to make the specified adapters the active adapters for the model.
         """
 
+    def load_adapter(self, name: str, source: str) -> int:
+        """
+        Load the LoRA adapter at `source`, a local directory or a Hugging Face model id, under `name`. The adapter can
+        then be activated. Returns the number of layers the adapter was loaded into.
+        """
+
+    def unload_adapter(self, name: str) -> int:
+        """
+        Unload an adapter, deactivating it if it is active. Returns the number of layers the adapter was unloaded from.
+        """
+
+    def list_adapters(self) -> list[str]:
+        """
+        List the names of the LoRA adapters loaded into the model.
+        """
+
+    def export_session(self, session_id: str, path: str) -> None:
+        """
+        Save the tokens and KV cache pinned under a session to a safetensors file.
+        """
+
+    def import_session(self, session_id: str, path: str) -> None:
+        """
+        Pin the tokens and KV cache saved by `export_session` under a session id, so that the next request of the session
+        continues from them.
+        """
+
+    def drop_session(self, session_id: str) -> None:
+        """
+        Unpin the KV cache of a session.
+        """
+
+    def prefix_cache_stats(self) -> PrefixCacheStats:
+        """
+        Hit, miss and eviction counters and the current size of the prefix cache.
+        """
+
+@dataclass
+class PrefixCacheStats:
+    hits: int
+    misses: int
+    hit_tokens: int
+    inserted: int
+    deduplicated: int
+    moved_to_host: int
+    evicted: int
+    entries: int
+    device_entries: int
+    device_bytes: int
+    host_bytes: int
+    disk_hits: int
+    written_to_disk: int
+    disk_evicted: int
+    disk_entries: int
+    disk_bytes: int
+    session_hits: int
+    sessions: int
+    session_bytes: int
+    session_device_bytes: int
+    sessions_moved_to_host: int
+    sessions_expired: int
+    saved_sessions: int
+
 @dataclass
 class Usage:
     completion_tokens: int
@@ -247,12 +361,18 @@ class ResponseLogprob:
 class Logprobs:
     content: list[ResponseLogprob] | None
 
+@dataclass
+class XLoraScalings:
+    tokens: list[list[list[float]]] | None
+    mean: list[list[float]]
+
 @dataclass
 class Choice:
     finish_reason: str
     index: int
     message: ResponseMessage
     logprobs: Logprobs
+    xlora_scalings: XLoraScalings | None
 
 @dataclass
 class ChatCompletionResponse:
@@ -263,6 +383,7 @@ class ChatCompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    warnings: list[str]
 
 @dataclass
 class Delta:
@@ -284,6 +405,7 @@ class ChatCompletionChunkResponse:
     model: str
     system_fingerprint: str
     object: str
+    warnings: list[str]
 
 @dataclass
 class CompletionChoice:
@@ -291,6 +413,7 @@ class CompletionChoice:
     index: int
     text: str
     # NOTE(EricLBuehler): `logprobs` in undocumented
+    xlora_scalings: XLoraScalings | None
 
 @dataclass
 class CompletionResponse:
@@ -301,3 +424,4 @@ class CompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    warnings: list[str]
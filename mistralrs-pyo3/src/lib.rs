@@ -1,7 +1,7 @@
 #![allow(clippy::too_many_arguments)]
 
 use base64::{engine::general_purpose, Engine};
-use candle_core::{quantized::GgmlDType, Result};
+use candle_core::Result;
 use either::Either;
 use indexmap::IndexMap;
 use reqwest::StatusCode;
@@ -11,17 +11,20 @@ use std::{
     fmt::Debug,
     str::FromStr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use stream::ChatCompletionStreamer;
 use tokio::sync::mpsc::channel;
 
 use candle_core::Device;
 use mistralrs_core::{
-    ChatCompletionResponse, CompletionResponse, Constraint, DeviceMapMetadata, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, MistralRs, MistralRsBuilder,
-    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, Request as _Request, RequestMessage,
-    Response, SamplingParams, SchedulerMethod, SpeculativeConfig, SpeculativeLoader, StopTokens,
-    TokenSource, VisionLoaderBuilder, VisionSpecificConfig,
+    parse_isq_value, ChatCompletionResponse, CompletionResponse, Constraint, ContextShift,
+    DeviceMapMetadata, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
+    GGUFSpecificConfig, IsqPolicy, KvCacheQuant, Loader, LoraAdapterFiles, MistralRs,
+    MistralRsBuilder, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig,
+    PrefixCacheEviction, PrefixCacheStats, Request as _Request, RequestMessage, Response,
+    SamplingParams, SchedulerMethod, SpeculativeConfig, SpeculativeLoader, StopTokens, TokenSource,
+    TruncationStrategy, VisionLoaderBuilder, VisionSpecificConfig, XLoraScalingsOutput,
 };
 use pyo3::{
     exceptions::{PyTypeError, PyValueError},
@@ -59,24 +62,6 @@ fn get_device() -> Result<Device> {
     Ok(res)
 }
 
-fn parse_isq(s: &str) -> std::result::Result<GgmlDType, String> {
-    match s {
-        "Q4_0" => Ok(GgmlDType::Q4_0),
-        "Q4_1" => Ok(GgmlDType::Q4_1),
-        "Q5_0" => Ok(GgmlDType::Q5_0),
-        "Q5_1" => Ok(GgmlDType::Q5_1),
-        "Q8_0" => Ok(GgmlDType::Q8_0),
-        "Q8_1" => Ok(GgmlDType::Q8_1),
-        "Q2K" => Ok(GgmlDType::Q2K),
-        "Q3K" => Ok(GgmlDType::Q3K),
-        "Q4K" => Ok(GgmlDType::Q4K),
-        "Q5K" => Ok(GgmlDType::Q5K),
-        "Q6K" => Ok(GgmlDType::Q6K),
-        "Q8K" => Ok(GgmlDType::Q8K),
-        _ => Err(format!("GGML type {s} unknown")),
-    }
-}
-
 #[pyclass]
 /// An object wrapping the underlying Rust system to handle requests and process conversations.
 struct Runner {
@@ -85,10 +70,50 @@ struct Runner {
 
 static NEXT_REQUEST_ID: Mutex<RefCell<usize>> = Mutex::new(RefCell::new(0));
 
+fn parse_isq_policy(s: &str) -> PyResult<IsqPolicy> {
+    s.parse().map_err(PyValueError::new_err)
+}
+
+fn parse_xlora_scalings_output(s: Option<String>) -> PyResult<Option<XLoraScalingsOutput>> {
+    match s.as_deref() {
+        None => Ok(None),
+        Some("per_token") => Ok(Some(XLoraScalingsOutput::PerToken)),
+        Some("mean") => Ok(Some(XLoraScalingsOutput::Mean)),
+        Some(other) => Err(PyValueError::new_err(format!(
+            "X-LoRA scalings output `{other}` is not `per_token` or `mean`"
+        ))),
+    }
+}
+
+fn parse_truncation_strategy(s: Option<String>) -> PyResult<Option<TruncationStrategy>> {
+    match s.as_deref() {
+        None => Ok(None),
+        Some("tokens") => Ok(Some(TruncationStrategy::Tokens)),
+        Some("oldest") => Ok(Some(TruncationStrategy::Oldest)),
+        Some("middle") => Ok(Some(TruncationStrategy::Middle)),
+        Some(other) => Err(PyValueError::new_err(format!(
+            "Truncation strategy `{other}` is not `tokens`, `oldest` or `middle`"
+        ))),
+    }
+}
+
+fn parse_timeout(timeout: Option<f64>) -> PyResult<Option<Duration>> {
+    mistralrs_core::parse_timeout(timeout).map_err(PyValueError::new_err)
+}
+
+/// Adapters given as a list of names have a weight of 1.
+fn weighted_adapters(adapters: Either<Vec<String>, HashMap<String, f64>>) -> IndexMap<String, f64> {
+    match adapters {
+        Either::Left(names) => names.into_iter().map(|name| (name, 1.0)).collect(),
+        Either::Right(weights) => weights.into_iter().collect(),
+    }
+}
+
 fn parse_which(
     which: Which,
     no_kv_cache: bool,
     chat_template: Option<String>,
+    isq_policy: Option<IsqPolicy>,
 ) -> PyResult<Box<dyn Loader>> {
     const REPEAT_LAST_N_DEFAULT: usize = 64;
     const GQA_DEFAULT: usize = 1;
@@ -113,6 +138,7 @@ fn parse_which(
             tokenizer_json,
             Some(model_id),
         )
+        .with_isq_policy(isq_policy)
         .build(arch.into()),
         Which::XLora {
             model_id,
@@ -141,6 +167,7 @@ fn parse_which(
             no_kv_cache,
             tgt_non_granular_index,
         )
+        .with_isq_policy(isq_policy)
         .build(arch.into()),
         Which::Lora {
             model_id,
@@ -166,6 +193,7 @@ fn parse_which(
             )
             .map_err(|e| PyValueError::new_err(e.to_string()))?,
         )
+        .with_isq_policy(isq_policy)
         .build(arch.into()),
         Which::GGUF {
             tok_model_id,
@@ -329,6 +357,7 @@ fn parse_which(
             tokenizer_json,
             Some(model_id),
         )
+        .with_isq_policy(isq_policy)
         .build(arch.into()),
     })
 }
@@ -346,7 +375,20 @@ impl Runner {
         which_draft = None,
         chat_template = None,
         num_device_layers = None,
-        in_situ_quant = None
+        in_situ_quant = None,
+        isq_policy = None,
+        kv_cache_quant = None,
+        prefix_cache_device_bytes = None,
+        prefix_cache_host_bytes = None,
+        prefix_cache_eviction = None,
+        prefix_cache_block_size = 16,
+        prefix_cache_disk_dir = None,
+        prefix_cache_disk_bytes = None,
+        session_device_bytes = None,
+        session_ttl = None,
+        context_shift = false,
+        context_shift_sinks = 4,
+        context_shift_window = None
     ))]
     fn new(
         which: Which,
@@ -359,6 +401,19 @@ impl Runner {
         chat_template: Option<String>,
         num_device_layers: Option<usize>,
         in_situ_quant: Option<String>,
+        isq_policy: Option<String>,
+        kv_cache_quant: Option<String>,
+        prefix_cache_device_bytes: Option<usize>,
+        prefix_cache_host_bytes: Option<usize>,
+        prefix_cache_eviction: Option<String>,
+        prefix_cache_block_size: usize,
+        prefix_cache_disk_dir: Option<String>,
+        prefix_cache_disk_bytes: Option<usize>,
+        session_device_bytes: Option<usize>,
+        session_ttl: Option<f64>,
+        context_shift: bool,
+        context_shift_sinks: usize,
+        context_shift_window: Option<usize>,
     ) -> PyResult<Self> {
         let tgt_non_granular_index = match which {
             Which::Plain { .. }
@@ -387,9 +442,15 @@ impl Runner {
             max_seqs
         };
 
-        let loader = parse_which(which, no_kv_cache, chat_template.clone())?;
+        let isq_policy = isq_policy.as_deref().map(parse_isq_policy).transpose()?;
+        let loader = parse_which(
+            which,
+            no_kv_cache,
+            chat_template.clone(),
+            isq_policy.clone(),
+        )?;
         let loader = if let Some(draft_which) = which_draft {
-            let draft = parse_which(draft_which, no_kv_cache, chat_template)?;
+            let draft = parse_which(draft_which, no_kv_cache, chat_template, isq_policy)?;
             Box::new(SpeculativeLoader {
                 target: loader,
                 draft,
@@ -403,7 +464,7 @@ impl Runner {
 
         let device = get_device().map_err(|e| PyValueError::new_err(e.to_string()))?;
         let isq = if let Some(isq) = in_situ_quant {
-            Some(parse_isq(&isq).map_err(|e| PyValueError::new_err(e.to_string()))?)
+            Some(parse_isq_value(&isq).map_err(|e| PyValueError::new_err(e.to_string()))?)
         } else {
             None
         };
@@ -422,7 +483,7 @@ impl Runner {
             )
             .map_err(|e| PyValueError::new_err(e.to_string()))?;
 
-        let mistralrs = MistralRsBuilder::new(
+        let mut builder = MistralRsBuilder::new(
             pipeline,
             SchedulerMethod::Fixed(
                 max_seqs
@@ -432,7 +493,46 @@ impl Runner {
         )
         .with_no_kv_cache(no_kv_cache)
         .with_prefix_cache_n(prefix_cache_n)
-        .build();
+        .with_prefix_cache_block_size(prefix_cache_block_size);
+        if let Some(bytes) = prefix_cache_device_bytes {
+            builder = builder.with_prefix_cache_device_bytes(bytes);
+        }
+        if let Some(bytes) = prefix_cache_host_bytes {
+            builder = builder.with_prefix_cache_host_bytes(bytes);
+        }
+        if let Some(dir) = prefix_cache_disk_dir {
+            builder = builder.with_prefix_cache_disk_dir(dir);
+        }
+        if let Some(bytes) = prefix_cache_disk_bytes {
+            builder = builder.with_prefix_cache_disk_bytes(bytes);
+        }
+        if let Some(bytes) = session_device_bytes {
+            builder = builder.with_session_device_bytes(bytes);
+        }
+        if let Some(ttl) = parse_timeout(session_ttl)? {
+            builder = builder.with_session_ttl(ttl);
+        }
+        if let Some(eviction) = prefix_cache_eviction {
+            builder = builder.with_prefix_cache_eviction(
+                eviction
+                    .parse::<PrefixCacheEviction>()
+                    .map_err(PyValueError::new_err)?,
+            );
+        }
+        if let Some(kv_cache_quant) = kv_cache_quant {
+            builder = builder.with_kv_cache_quant(
+                kv_cache_quant
+                    .parse::<KvCacheQuant>()
+                    .map_err(PyValueError::new_err)?,
+            );
+        }
+        if context_shift {
+            builder = builder.with_context_shift(ContextShift {
+                sink_tokens: context_shift_sinks,
+                window: context_shift_window,
+            });
+        }
+        let mistralrs = builder.build();
 
         Ok(Self { runner: mistralrs })
     }
@@ -662,7 +762,14 @@ impl Runner {
                 is_streaming: request.stream,
                 constraint,
                 suffix: None,
-                adapters: request.adapters.clone(),
+                adapters: request.adapters.clone().map(weighted_adapters),
+                priority: request.priority,
+                user: request.user.clone(),
+                timeout: parse_timeout(request.timeout)?,
+                return_xlora_scalings: request.return_xlora_scalings,
+                xlora_scalings: request.xlora_scalings.clone(),
+                session_id: request.session_id.clone(),
+                truncation_strategy: request.truncation_strategy,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -751,7 +858,14 @@ impl Runner {
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
-                adapters: request.adapters.clone(),
+                adapters: request.adapters.clone().map(weighted_adapters),
+                priority: request.priority,
+                user: request.user.clone(),
+                timeout: parse_timeout(request.timeout)?,
+                return_xlora_scalings: request.return_xlora_scalings,
+                xlora_scalings: request.xlora_scalings.clone(),
+                session_id: request.session_id.clone(),
+                truncation_strategy: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -774,11 +888,15 @@ impl Runner {
         })
     }
 
-    /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
-    /// then nothing will happen.
-    fn send_re_isq(&self, dtype: String) -> PyResult<()> {
-        let request =
-            _Request::ReIsq(parse_isq(&dtype).map_err(|e| PyValueError::new_err(e.to_string()))?);
+    /// Send a request to re-ISQ the model, optionally with a mixed-precision ISQ policy. If the
+    /// model was loaded as GGUF or GGML then nothing will happen.
+    #[pyo3(signature = (dtype, policy = None))]
+    fn send_re_isq(&self, dtype: String, policy: Option<String>) -> PyResult<()> {
+        let policy = policy.as_deref().map(parse_isq_policy).transpose()?;
+        let request = _Request::ReIsq(
+            parse_isq_value(&dtype).map_err(|e| PyValueError::new_err(e.to_string()))?,
+            policy,
+        );
         self.runner.get_sender().blocking_send(request).unwrap();
         Ok(())
     }
@@ -788,6 +906,73 @@ impl Runner {
         let request = _Request::ActivateAdapters(adapter_names);
         self.runner.get_sender().blocking_send(request).unwrap();
     }
+
+    /// Load the LoRA adapter at `source`, a local directory or a Hugging Face model id, under
+    /// `name`. The adapter can then be activated. Returns the number of layers the adapter was
+    /// loaded into.
+    fn load_adapter(&self, name: String, source: String) -> PyResult<usize> {
+        let adapter = LoraAdapterFiles::resolve(&source, &TokenSource::CacheToken)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let (tx, mut rx) = channel(1);
+        let request = _Request::LoadAdapter {
+            name,
+            source,
+            adapter,
+            response: tx,
+        };
+        self.runner.get_sender().blocking_send(request).unwrap();
+        rx.blocking_recv().unwrap().map_err(PyValueError::new_err)
+    }
+
+    /// Unload an adapter, deactivating it if it is active. Returns the number of layers the
+    /// adapter was unloaded from.
+    fn unload_adapter(&self, name: String) -> PyResult<usize> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::UnloadAdapter { name, response: tx };
+        self.runner.get_sender().blocking_send(request).unwrap();
+        rx.blocking_recv().unwrap().map_err(PyValueError::new_err)
+    }
+
+    /// Save the tokens and KV cache pinned under a session to a safetensors file.
+    fn export_session(&self, session_id: String, path: String) -> PyResult<()> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::ExportSession {
+            session_id,
+            path: path.into(),
+            response: tx,
+        };
+        self.runner.get_sender().blocking_send(request).unwrap();
+        rx.blocking_recv().unwrap().map_err(PyValueError::new_err)
+    }
+
+    /// Pin the tokens and KV cache saved by `export_session` under a session id, so that the
+    /// next request of the session continues from them.
+    fn import_session(&self, session_id: String, path: String) -> PyResult<()> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::ImportSession {
+            session_id,
+            path: path.into(),
+            response: tx,
+        };
+        self.runner.get_sender().blocking_send(request).unwrap();
+        rx.blocking_recv().unwrap().map_err(PyValueError::new_err)
+    }
+
+    /// Unpin the KV cache of a session.
+    fn drop_session(&self, session_id: String) {
+        let request = _Request::DropSession(session_id);
+        self.runner.get_sender().blocking_send(request).unwrap();
+    }
+
+    /// List the names of the LoRA adapters loaded into the model.
+    fn list_adapters(&self) -> Vec<String> {
+        self.runner.engine_status().adapters
+    }
+
+    /// Hit, miss and eviction counters and the current size of the prefix cache.
+    fn prefix_cache_stats(&self) -> PrefixCacheStats {
+        self.runner.engine_status().prefix_cache
+    }
 }
 
 #[pyclass]
@@ -810,7 +995,13 @@ struct CompletionRequest {
     top_k: Option<usize>,
     grammar: Option<String>,
     grammar_type: Option<String>,
-    adapters: Option<Vec<String>>,
+    adapters: Option<Either<Vec<String>, HashMap<String, f64>>>,
+    priority: Option<i32>,
+    user: Option<String>,
+    timeout: Option<f64>,
+    return_xlora_scalings: Option<XLoraScalingsOutput>,
+    xlora_scalings: Option<Vec<Vec<f64>>>,
+    session_id: Option<String>,
 }
 
 #[pymethods]
@@ -833,7 +1024,13 @@ impl CompletionRequest {
         top_k=None,
         grammar = None,
         grammar_type = None,
-        adapters = None
+        adapters = None,
+        priority = None,
+        user = None,
+        timeout = None,
+        return_xlora_scalings = None,
+        xlora_scalings = None,
+        session_id = None
     ))]
     fn new(
         prompt: String,
@@ -852,7 +1049,13 @@ impl CompletionRequest {
         top_k: Option<usize>,
         grammar: Option<String>,
         grammar_type: Option<String>,
-        adapters: Option<Vec<String>>,
+        adapters: Option<Either<Vec<String>, HashMap<String, f64>>>,
+        priority: Option<i32>,
+        user: Option<String>,
+        timeout: Option<f64>,
+        return_xlora_scalings: Option<String>,
+        xlora_scalings: Option<Vec<Vec<f64>>>,
+        session_id: Option<String>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -872,6 +1075,12 @@ impl CompletionRequest {
             grammar,
             grammar_type,
             adapters,
+            priority,
+            user,
+            timeout,
+            return_xlora_scalings: parse_xlora_scalings_output(return_xlora_scalings)?,
+            xlora_scalings,
+            session_id,
         })
     }
 }
@@ -905,7 +1114,14 @@ struct ChatCompletionRequest {
     top_k: Option<usize>,
     grammar: Option<String>,
     grammar_type: Option<String>,
-    adapters: Option<Vec<String>>,
+    adapters: Option<Either<Vec<String>, HashMap<String, f64>>>,
+    priority: Option<i32>,
+    user: Option<String>,
+    timeout: Option<f64>,
+    return_xlora_scalings: Option<XLoraScalingsOutput>,
+    xlora_scalings: Option<Vec<Vec<f64>>>,
+    session_id: Option<String>,
+    truncation_strategy: Option<TruncationStrategy>,
 }
 
 #[pymethods]
@@ -928,7 +1144,14 @@ impl ChatCompletionRequest {
         stream=false,
         grammar = None,
         grammar_type = None,
-        adapters = None
+        adapters = None,
+        priority = None,
+        user = None,
+        timeout = None,
+        return_xlora_scalings = None,
+        xlora_scalings = None,
+        session_id = None,
+        truncation_strategy = None
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -947,7 +1170,14 @@ impl ChatCompletionRequest {
         stream: Option<bool>,
         grammar: Option<String>,
         grammar_type: Option<String>,
-        adapters: Option<Vec<String>>,
+        adapters: Option<Either<Vec<String>, HashMap<String, f64>>>,
+        priority: Option<i32>,
+        user: Option<String>,
+        timeout: Option<f64>,
+        return_xlora_scalings: Option<String>,
+        xlora_scalings: Option<Vec<Vec<f64>>>,
+        session_id: Option<String>,
+        truncation_strategy: Option<String>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -1015,6 +1245,13 @@ impl ChatCompletionRequest {
             grammar,
             grammar_type,
             adapters,
+            priority,
+            user,
+            timeout,
+            return_xlora_scalings: parse_xlora_scalings_output(return_xlora_scalings)?,
+            xlora_scalings,
+            session_id,
+            truncation_strategy: parse_truncation_strategy(truncation_strategy)?,
         })
     }
 }
@@ -1038,6 +1275,8 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
+    m.add_class::<mistralrs_core::XLoraScalings>()?;
+    m.add_class::<mistralrs_core::PrefixCacheStats>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
     Ok(())
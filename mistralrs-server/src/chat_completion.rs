@@ -11,7 +11,7 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens};
+use crate::openai::{Adapters, ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
@@ -24,8 +24,8 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage,
-    Response, SamplingParams, StopTokens as InternalStopTokens,
+    parse_timeout, ChatCompletionResponse, Constraint, MistralRs, NormalRequest, Request,
+    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -150,6 +150,7 @@ async fn parse_request(
     oairequest: ChatCompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
+    timeout: Option<Duration>,
 ) -> Result<(Request, bool)> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
@@ -310,7 +311,14 @@ async fn parse_request(
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
                 None => Constraint::None,
             },
-            adapters: oairequest.adapters,
+            adapters: oairequest.adapters.map(Adapters::into_weighted),
+            priority: oairequest.priority,
+            user: oairequest.user,
+            timeout,
+            return_xlora_scalings: oairequest.return_xlora_scalings.map(Into::into),
+            xlora_scalings: oairequest.xlora_scalings,
+            session_id: oairequest.session_id,
+            truncation_strategy: oairequest.truncation_strategy.map(Into::into),
         }),
         is_streaming,
     ))
@@ -328,7 +336,12 @@ pub async fn chatcompletions(
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
     let (tx, mut rx) = channel(10_000);
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
+    let timeout = match parse_timeout(oairequest.timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return ChatCompletionResponder::ValidationError(e.into()),
+    };
+    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx, timeout).await
+    {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
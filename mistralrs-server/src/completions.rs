@@ -1,15 +1,15 @@
-use std::{error::Error, sync::Arc};
+use std::{error::Error, sync::Arc, time::Duration};
 use tokio::sync::mpsc::{channel, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::openai::{Adapters, CompletionRequest, Grammar, StopTokens};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
     response::IntoResponse,
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response,
-    SamplingParams, StopTokens as InternalStopTokens,
+    parse_timeout, CompletionResponse, Constraint, MistralRs, NormalRequest, Request,
+    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 use tracing::warn;
@@ -86,6 +86,7 @@ fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
+    timeout: Option<Duration>,
 ) -> Request {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
@@ -132,7 +133,14 @@ fn parse_request(
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
             None => Constraint::None,
         },
-        adapters: oairequest.adapters,
+        adapters: oairequest.adapters.map(Adapters::into_weighted),
+        priority: oairequest.priority,
+        user: oairequest.user,
+        timeout,
+        return_xlora_scalings: oairequest.return_xlora_scalings.map(Into::into),
+        xlora_scalings: oairequest.xlora_scalings,
+        session_id: oairequest.session_id,
+        truncation_strategy: None,
     })
 }
 
@@ -159,7 +167,11 @@ pub async fn completions(
             "Completion requests do not support streaming.".into(),
         );
     }
-    let request = parse_request(oairequest, state.clone(), tx);
+    let timeout = match parse_timeout(oairequest.timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return CompletionResponder::ValidationError(e.into()),
+    };
+    let request = parse_request(oairequest, state.clone(), tx, timeout);
     let sender = state.get_sender();
 
     if let Err(e) = sender.send(request).await {
@@ -0,0 +1,224 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use mistralrs_core::{EngineStatus, MistralRs};
+use serde::Serialize;
+
+/// The state of the health routes.
+#[derive(Clone)]
+pub struct HealthState {
+    pub mistralrs: Arc<MistralRs>,
+    /// Maximum time a single engine loop iteration may take before the engine is considered stuck.
+    pub max_step: Duration,
+}
+
+fn is_ready(status: &EngineStatus) -> bool {
+    status.is_alive && !status.is_shutting_down
+}
+
+fn is_live(status: &EngineStatus, max_step: Duration) -> bool {
+    // After shutdown the engine thread exits, but the server is still draining connections.
+    status.is_shutting_down
+        || (status.is_alive
+            && (status.is_idle || status.secs_since_last_step < max_step.as_secs_f64()))
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/health",
+    responses((status = 200, description = "Server is healthy"), (status = 503, description = "Server is not ready"))
+)]
+pub async fn health(State(state): State<HealthState>) -> (StatusCode, &'static str) {
+    ready(State(state)).await
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/health/ready",
+    responses((status = 200, description = "Model is loaded and accepting requests"), (status = 503, description = "Server is not ready"))
+)]
+pub async fn ready(State(state): State<HealthState>) -> (StatusCode, &'static str) {
+    let status = state.mistralrs.engine_status();
+    if status.is_shutting_down {
+        (StatusCode::SERVICE_UNAVAILABLE, "Shutting down")
+    } else if !is_ready(&status) {
+        (StatusCode::SERVICE_UNAVAILABLE, "Engine stopped")
+    } else {
+        (StatusCode::OK, "OK")
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/health/live",
+    responses((status = 200, description = "Engine loop is progressing"), (status = 503, description = "Engine is stopped or stuck"))
+)]
+pub async fn live(State(state): State<HealthState>) -> (StatusCode, &'static str) {
+    let status = state.mistralrs.engine_status();
+    if is_live(&status, state.max_step) {
+        (StatusCode::OK, "OK")
+    } else if !status.is_alive {
+        (StatusCode::SERVICE_UNAVAILABLE, "Engine stopped")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "Engine stuck")
+    }
+}
+
+#[derive(Serialize)]
+struct HealthDetails {
+    status: &'static str,
+    #[serde(flatten)]
+    engine: EngineStatus,
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/health/details",
+    responses((status = 200, description = "Detailed model and engine state"))
+)]
+pub async fn details(State(state): State<HealthState>) -> Response {
+    let engine = state.mistralrs.engine_status();
+    let (code, status) = if engine.is_shutting_down {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting_down")
+    } else if !is_live(&engine, state.max_step) {
+        (StatusCode::SERVICE_UNAVAILABLE, "unhealthy")
+    } else {
+        (StatusCode::OK, "ready")
+    };
+    (code, Json(HealthDetails { status, engine })).into_response()
+}
+
+/// The health routes of a loaded model.
+pub fn get_health_router<S>(state: HealthState) -> Router<S> {
+    Router::new()
+        .route("/health", get(health))
+        .route("/health/ready", get(ready))
+        .route("/health/live", get(live))
+        .route("/health/details", get(details))
+        .route("/", get(health))
+        .with_state(state)
+}
+
+#[derive(Serialize)]
+struct LoadingDetails {
+    status: &'static str,
+    model_id: String,
+    kind: String,
+}
+
+/// The router served while the model is loading. Liveness succeeds, while readiness
+/// and every other route report that the model is not loaded yet.
+pub fn get_loading_router(model_id: String, kind: String) -> Router {
+    let details = LoadingDetails {
+        status: "loading",
+        model_id,
+        kind,
+    };
+    let details = Arc::new(serde_json::to_value(details).expect("Serialization failed."));
+    let not_ready = || async { (StatusCode::SERVICE_UNAVAILABLE, "Loading") };
+    Router::new()
+        .route(
+            "/health/live",
+            get(|| async { (StatusCode::OK, "Loading") }),
+        )
+        .route("/health/ready", get(not_ready))
+        .route("/health", get(not_ready))
+        .route("/", get(not_ready))
+        .route(
+            "/health/details",
+            get(move || {
+                let details = details.clone();
+                async move { (StatusCode::SERVICE_UNAVAILABLE, Json((*details).clone())) }
+            }),
+        )
+        .fallback(|| async {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The model is loading. Please retry later.",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mistralrs_core::{EngineStatus, PrefixCacheStats};
+
+    use super::{is_live, is_ready};
+
+    const MAX_STEP: Duration = Duration::from_secs(300);
+
+    fn status(
+        is_alive: bool,
+        is_idle: bool,
+        secs_since_last_step: f64,
+        is_shutting_down: bool,
+    ) -> EngineStatus {
+        EngineStatus {
+            model_id: "model".to_string(),
+            kind: "normal".to_string(),
+            device: "Cpu".to_string(),
+            isq: None,
+            active_adapters: None,
+            adapters: vec![],
+            running_seqs: usize::from(!is_idle),
+            waiting_seqs: 0,
+            max_seqs: 16,
+            is_alive,
+            is_idle,
+            secs_since_last_step,
+            is_shutting_down,
+            prefix_cache: PrefixCacheStats::default(),
+        }
+    }
+
+    #[test]
+    fn running_engine_is_ready_and_live() {
+        let running = status(true, false, 1., false);
+        assert!(is_ready(&running));
+        assert!(is_live(&running, MAX_STEP));
+    }
+
+    #[test]
+    fn idle_engine_is_live_however_long_ago_it_stepped() {
+        let idle = status(true, true, 10. * MAX_STEP.as_secs_f64(), false);
+        assert!(is_ready(&idle));
+        assert!(is_live(&idle, MAX_STEP));
+    }
+
+    #[test]
+    fn stuck_engine_is_not_live() {
+        let stuck = status(true, false, MAX_STEP.as_secs_f64() + 1., false);
+        assert!(is_ready(&stuck));
+        assert!(!is_live(&stuck, MAX_STEP));
+        // A longer step limit allows long prefills.
+        assert!(is_live(&stuck, 2 * MAX_STEP));
+    }
+
+    #[test]
+    fn shutting_down_engine_is_live_but_not_ready() {
+        for is_alive in [true, false] {
+            let shutting_down = status(is_alive, false, MAX_STEP.as_secs_f64() + 1., true);
+            assert!(!is_ready(&shutting_down));
+            assert!(is_live(&shutting_down, MAX_STEP));
+        }
+    }
+
+    #[test]
+    fn stopped_engine_is_neither_ready_nor_live() {
+        let stopped = status(false, true, 0., false);
+        assert!(!is_ready(&stopped));
+        assert!(!is_live(&stopped, MAX_STEP));
+    }
+}
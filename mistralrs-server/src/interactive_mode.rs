@@ -80,6 +80,13 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
             constraint: Constraint::None,
             suffix: None,
             adapters: None,
+            priority: None,
+            user: None,
+            timeout: None,
+            return_xlora_scalings: None,
+            xlora_scalings: None,
+            session_id: None,
+            truncation_strategy: None,
         });
         sender.send(req).await.unwrap();
 
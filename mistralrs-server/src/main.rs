@@ -1,22 +1,37 @@
 use anyhow::Result;
 use axum::{
-    extract::{Json, State},
-    http::{self, Method},
+    extract::{Json, Request as HttpRequest, State},
+    http::{self, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response as HttpResponse},
     routing::{get, post},
     Router,
 };
 use candle_core::{quantized::GgmlDType, Device};
 use clap::Parser;
 use mistralrs_core::{
-    get_tgt_non_granular_index, DeviceMapMetadata, Loader, LoaderBuilder, MistralRs,
-    MistralRsBuilder, ModelSelected, Request, SchedulerMethod, TokenSource,
+    default_isq_artifacts_dir, get_tgt_non_granular_index, parse_isq_value, ContextShift,
+    DeviceMapMetadata, IsqPolicy, KvCacheQuant, Loader, LoaderBuilder, MistralRs, MistralRsBuilder,
+    ModelSelected, PrefixCacheEviction, Request, SchedulerMethod, TokenSource,
+    DEFAULT_PREFIX_CACHE_HOST_BYTES, DEFAULT_SESSION_TTL,
+};
+use openai::{
+    AdapterObject, AdapterObjects, Adapters, ChatCompletionRequest, Message, ModelObjects,
+    StopTokens, TruncationStrategy, XLoraScalingsOutput,
 };
-use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 mod chat_completion;
 mod completions;
-use crate::{chat_completion::__path_chatcompletions, completions::completions};
+mod health;
+use crate::{
+    chat_completion::__path_chatcompletions,
+    completions::completions,
+    health::{
+        __path_details, __path_health, __path_live, __path_ready, get_health_router,
+        get_loading_router, HealthState,
+    },
+};
 
 use crate::{chat_completion::chatcompletions, openai::ModelObject};
 mod interactive_mode;
@@ -32,22 +47,33 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
-fn parse_isq(s: &str) -> Result<GgmlDType, String> {
-    match s {
-        "Q4_0" => Ok(GgmlDType::Q4_0),
-        "Q4_1" => Ok(GgmlDType::Q4_1),
-        "Q5_0" => Ok(GgmlDType::Q5_0),
-        "Q5_1" => Ok(GgmlDType::Q5_1),
-        "Q8_0" => Ok(GgmlDType::Q8_0),
-        "Q8_1" => Ok(GgmlDType::Q8_1),
-        "Q2K" => Ok(GgmlDType::Q2K),
-        "Q3K" => Ok(GgmlDType::Q3K),
-        "Q4K" => Ok(GgmlDType::Q4K),
-        "Q5K" => Ok(GgmlDType::Q5K),
-        "Q6K" => Ok(GgmlDType::Q6K),
-        "Q8K" => Ok(GgmlDType::Q8K),
-        _ => Err(format!("GGML type {s} unknown")),
-    }
+fn parse_isq_policy(s: &str) -> Result<IsqPolicy, String> {
+    s.parse()
+}
+
+fn parse_kv_cache_quant(s: &str) -> Result<KvCacheQuant, String> {
+    s.parse()
+}
+
+fn parse_prefix_cache_eviction(s: &str) -> Result<PrefixCacheEviction, String> {
+    s.parse()
+}
+
+fn parse_tenant_weights(s: &str) -> Result<HashMap<String, f64>, String> {
+    s.split(',')
+        .map(|pair| {
+            let (tenant, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected `tenant=weight`, got `{pair}`"))?;
+            let weight = weight
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid weight for tenant `{tenant}`: {e}"))?;
+            if weight <= 0. {
+                return Err(format!("Weight for tenant `{tenant}` must be positive."));
+            }
+            Ok((tenant.to_string(), weight))
+        })
+        .collect()
 }
 
 #[derive(Parser)]
@@ -65,9 +91,10 @@ struct Args {
     #[clap(long, short)]
     log: Option<String>,
 
-    /// If a sequence is larger than the maximum model length, truncate the number
-    /// of tokens such that the sequence will fit at most the maximum length.
-    /// If `max_tokens` is not specified in the request, space for 10 tokens will be reserved instead.
+    /// If a sequence is larger than the maximum model length, truncate it such that it will fit at most the maximum
+    /// length. Chats drop their oldest turns, or tokens from the start if their last message alone is too long, and
+    /// other prompts drop tokens from the start, unless the request sets a `truncation_strategy`. If `max_tokens` is
+    /// not specified in the request, space for 10 tokens will be reserved instead.
     #[clap(long, short, action)]
     truncate_sequence: bool,
 
@@ -102,13 +129,102 @@ struct Args {
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
 
+    /// Maximum size in bytes of the prefix caches on the device. Caches over the limit are moved to the CPU.
+    #[arg(long)]
+    prefix_cache_device_bytes: Option<usize>,
+
+    /// Maximum size in bytes of the prefix caches on the CPU. Caches over the limit are deleted.
+    #[arg(long, default_value_t = DEFAULT_PREFIX_CACHE_HOST_BYTES)]
+    prefix_cache_host_bytes: usize,
+
+    /// Which prefix cache to move or delete first when over a limit: `lru` (least recently used) or `lfu` (least
+    /// frequently used).
+    #[arg(long, value_parser = parse_prefix_cache_eviction, default_value = "lru")]
+    prefix_cache_eviction: PrefixCacheEviction,
+
+    /// Granularity in tokens of the prefix reused from a prefix cache which only partially matches a prompt.
+    #[arg(long, default_value_t = 16)]
+    prefix_cache_block_size: usize,
+
+    /// Directory in which prefix caches deleted from the CPU are saved, so that they are reused by later prompts
+    /// and after a restart.
+    #[arg(long)]
+    prefix_cache_disk_dir: Option<String>,
+
+    /// Maximum size in bytes of the prefix caches on the disk. The least recently used caches are deleted.
+    #[arg(long)]
+    prefix_cache_disk_bytes: Option<usize>,
+
+    /// Maximum size in bytes of the session caches on the device. The least recently used sessions are moved to
+    /// the CPU.
+    #[arg(long)]
+    session_device_bytes: Option<usize>,
+
+    /// Unpin the session caches unused for this many seconds, saving them to the prefix cache disk directory if
+    /// one is set.
+    #[arg(long, default_value_t = DEFAULT_SESSION_TTL.as_secs())]
+    session_ttl: u64,
+
     /// Number of device layers to load and run on the device. All others will be on the CPU.
     #[arg(short, long)]
     num_device_layers: Option<usize>,
 
     /// In-situ quantization to apply. You may specify one of the GGML data type (except F32 or F16): formatted like this: `Q4_0` or `Q4K`.
-    #[arg(long = "isq", value_parser = parse_isq)]
+    #[arg(long = "isq", value_parser = parse_isq_value)]
     in_situ_quant: Option<GgmlDType>,
+
+    /// Mixed-precision ISQ policy refining `--isq`, formatted like `attn=Q8_0,mlp=Q4K,@first:2=Q6K,lm_head=skip`.
+    /// Each rule maps tensor roles and/or a layer range to a GGML data type or `skip`, and the first matching rule wins.
+    /// Tensors not matched by any rule use the `--isq` data type.
+    #[arg(long, value_parser = parse_isq_policy, requires = "in_situ_quant")]
+    isq_policy: Option<IsqPolicy>,
+
+    /// Save the tensors quantized by `--isq` as an ISQ artifact, and load it instead of quantizing again the next
+    /// time the model is loaded with the same ISQ settings. Artifacts are as large as the quantized model and are not
+    /// removed automatically. They are stored in `<cache dir>/mistralrs/isq` unless `--isq-artifacts-dir` is given.
+    #[arg(long, default_value_t = false, requires = "in_situ_quant")]
+    isq_artifacts: bool,
+
+    /// Directory to store ISQ artifacts in. Implies `--isq-artifacts`.
+    #[arg(long, requires = "in_situ_quant")]
+    isq_artifacts_dir: Option<PathBuf>,
+
+    /// Store the KV cache quantized to reduce its memory usage: `int8` (a scale per head and token) or `q4` (a scale per
+    /// group of 32 values). Keys and values are dequantized when read in the attention.
+    #[arg(long, value_parser = parse_kv_cache_quant)]
+    kv_cache_quant: Option<KvCacheQuant>,
+
+    /// Keep generating past the maximum sequence length: once the KV cache is full, keep the first (sink) tokens and
+    /// the most recent tokens and discard those in between. Supported for Llama, Mistral and Qwen2 models.
+    #[arg(long, default_value_t = false)]
+    context_shift: bool,
+
+    /// Number of sink tokens kept at the start of the KV cache by context shifting.
+    #[arg(long, default_value_t = 4)]
+    context_shift_sinks: usize,
+
+    /// Number of recent tokens kept by context shifting. Defaults to half of the maximum sequence length.
+    #[arg(long)]
+    context_shift_window: Option<usize>,
+
+    /// Enable weighted fair queuing between tenants, keyed by the `user` field of requests.
+    #[arg(long, default_value_t = false)]
+    fair_queuing: bool,
+
+    /// Relative weights for fair queuing, formatted like `tenant_a=2,tenant_b=0.5`. Tenants not listed have a weight of 1.
+    /// Implies `--fair-queuing`.
+    #[arg(long, value_parser = parse_tenant_weights)]
+    tenant_weights: Option<HashMap<String, f64>>,
+
+    /// On SIGTERM or Ctrl-C, the number of seconds to wait for running requests to finish before
+    /// they are stopped with their partial output.
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Number of seconds a single engine step may take before `/health/live` reports the engine as stuck. Raise
+    /// this if long prompts are prefilled on the CPU.
+    #[arg(long, default_value_t = 300)]
+    max_step_secs: u64,
 }
 
 #[utoipa::path(
@@ -129,14 +245,48 @@ async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
     })
 }
 
-#[utoipa::path(
-    get,
-    tag = "Mistral.rs",
-    path = "/health",
-    responses((status = 200, description = "Server is healthy"))
-)]
-async fn health() -> &'static str {
-    "OK"
+/// Reject new requests with 503 once the server has started shutting down.
+async fn reject_when_shutting_down(
+    State(state): State<Arc<MistralRs>>,
+    request: HttpRequest,
+    next: Next,
+) -> HttpResponse {
+    if state.is_shutting_down() && request.method() == Method::POST {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The server is shutting down.",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Resolves once SIGTERM or Ctrl-C is received and the engine has drained its running requests.
+async fn shutdown_signal(state: Arc<MistralRs>, drain_timeout: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install the Ctrl-C handler.");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, draining requests for up to {drain_timeout:?}.");
+    if let Err(e) = state.shutdown(drain_timeout).await {
+        warn!("Engine shutdown failed: {e:?}");
+    }
+    info!("Engine stopped, closing remaining connections.");
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -163,10 +313,97 @@ async fn activate_adapters(
     repr
 }
 
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/adapters",
+    responses((status = 200, description = "LoRA adapters loaded into the model", body = AdapterObjects))
+)]
+async fn adapters(State(state): State<Arc<MistralRs>>) -> Json<AdapterObjects> {
+    let status = state.engine_status();
+    let active = status.active_adapters.unwrap_or_default();
+    Json(AdapterObjects {
+        object: "list",
+        data: status
+            .adapters
+            .into_iter()
+            .map(|id| AdapterObject {
+                active: active.contains(&id),
+                id,
+                object: "adapter",
+            })
+            .collect(),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct AdapterLoadRequest {
+    #[schema(example = "adapter_4")]
+    name: String,
+    /// A local directory or a Hugging Face model id with `adapter_config.json` and the adapter safetensors.
+    #[schema(example = "lamm-mit/x-lora")]
+    source: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/adapters",
+    request_body = AdapterLoadRequest,
+    responses(
+        (status = 200, description = "Load a LoRA adapter into a running LoRA model"),
+        (status = 400, description = "The adapter cannot be found, is already loaded or does not fit the model"),
+    )
+)]
+async fn load_adapter(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<AdapterLoadRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let repr = format!("Adapter load: {} from {}", request.name, request.source);
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    state
+        .load_adapter(request.name, request.source)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(repr)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct AdapterUnloadRequest {
+    #[schema(example = "adapter_4")]
+    name: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/adapters/unload",
+    request_body = AdapterUnloadRequest,
+    responses(
+        (status = 200, description = "Unload a LoRA adapter, deactivating it if it is active"),
+        (status = 400, description = "The adapter is not loaded or the model has no LoRA adapters"),
+    )
+)]
+async fn unload_adapter(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<AdapterUnloadRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let repr = format!("Adapter unload: {}", request.name);
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    state
+        .unload_adapter(request.name)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(repr)
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
     ggml_type: String,
+    /// Mixed-precision ISQ policy, see `--isq-policy`.
+    #[schema(example = "attn=Q8_0,lm_head=skip")]
+    policy: Option<String>,
 }
 
 #[utoipa::path(
@@ -180,19 +417,106 @@ async fn re_isq(
     State(state): State<Arc<MistralRs>>,
     Json(request): Json<ReIsqRequest>,
 ) -> Result<String, String> {
-    let repr = format!("Re ISQ: {:?}", request.ggml_type);
+    let repr = format!("Re ISQ: {:?} {:?}", request.ggml_type, request.policy);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
-    let request = Request::ReIsq(parse_isq(&request.ggml_type)?);
+    let policy = request
+        .policy
+        .as_deref()
+        .map(parse_isq_policy)
+        .transpose()?;
+    let request = Request::ReIsq(parse_isq_value(&request.ggml_type)?, policy);
     state.get_sender().send(request).await.unwrap();
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct SessionDropRequest {
+    #[schema(example = "conversation-1")]
+    session_id: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/sessions/drop",
+    request_body = SessionDropRequest,
+    responses((status = 200, description = "Unpin the KV cache of a session"))
+)]
+async fn drop_session(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<SessionDropRequest>,
+) -> String {
+    let repr = format!("Session drop: {}", request.session_id);
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    let request = Request::DropSession(request.session_id);
+    state.get_sender().send(request).await.unwrap();
+    repr
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct SessionFileRequest {
+    #[schema(example = "conversation-1")]
+    session_id: String,
+    /// Path of the safetensors file on the server.
+    #[schema(example = "sessions/conversation-1.safetensors")]
+    path: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/sessions/export",
+    request_body = SessionFileRequest,
+    responses(
+        (status = 200, description = "Save the tokens and KV cache of a session to a file"),
+        (status = 400, description = "The session does not exist or the file cannot be written"),
+    )
+)]
+async fn export_session(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<SessionFileRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let repr = format!("Session export: {} to {}", request.session_id, request.path);
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    state
+        .export_session(request.session_id, request.path)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(repr)
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/sessions/import",
+    request_body = SessionFileRequest,
+    responses(
+        (status = 200, description = "Pin the tokens and KV cache saved to a file under a session"),
+        (status = 400, description = "The file cannot be read or does not match the model"),
+    )
+)]
+async fn import_session(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<SessionFileRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let repr = format!(
+        "Session import: {} from {}",
+        request.session_id, request.path
+    );
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    state
+        .import_session(request.session_id, request.path)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(repr)
+}
+
+fn get_router(state: Arc<MistralRs>, max_step: Duration) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, adapters, health, ready, live, details, chatcompletions),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, AdapterObjects, AdapterObject, Adapters, XLoraScalingsOutput, TruncationStrategy, ChatCompletionRequest, StopTokens, Message)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -219,10 +543,21 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
         .route("/v1/models", get(models))
-        .route("/health", get(health))
-        .route("/", get(health))
+        .merge(get_health_router(HealthState {
+            mistralrs: state.clone(),
+            max_step,
+        }))
         .route("/activate_adapters", post(activate_adapters))
+        .route("/v1/adapters", get(adapters).post(load_adapter))
+        .route("/v1/adapters/unload", post(unload_adapter))
         .route("/re_isq", post(re_isq))
+        .route("/v1/sessions/drop", post(drop_session))
+        .route("/v1/sessions/export", post(export_session))
+        .route("/v1/sessions/import", post(import_session))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            reject_when_shutting_down,
+        ))
         .with_state(state)
 }
 
@@ -241,10 +576,24 @@ async fn main() -> Result<()> {
         args.max_seqs = 1;
     }
 
+    let isq_artifacts = match args.isq_artifacts_dir {
+        Some(dir) => Some(dir),
+        None if args.isq_artifacts => {
+            let dir = default_isq_artifacts_dir();
+            if dir.is_none() {
+                warn!("There is no cache directory to store ISQ artifacts in, pass `--isq-artifacts-dir` to use them.");
+            }
+            dir
+        }
+        None => None,
+    };
+
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
         .with_no_kv_cache(args.no_kv_cache)
         .with_chat_template(args.chat_template)
         .with_use_flash_attn(use_flash_attn)
+        .with_isq_policy(args.isq_policy)
+        .with_isq_artifacts(isq_artifacts)
         .build()?;
 
     #[cfg(feature = "metal")]
@@ -267,20 +616,51 @@ async fn main() -> Result<()> {
         warn!("Using flash attention with a quantized model has no effect!")
     }
     info!("Model kind is: {}", loader.get_kind().to_string());
-    let pipeline = loader.load_model_from_hf(
-        None,
-        args.token_source,
-        None,
-        &device,
-        false,
-        args.num_device_layers
-            .map(DeviceMapMetadata::from_num_device_layers)
-            .unwrap_or(DeviceMapMetadata::dummy()),
-        args.in_situ_quant,
-    )?;
+
+    // Bind before loading the model so that health checks can report that it is loading.
+    let listener = if args.interactive_mode {
+        None
+    } else {
+        let port = args.port.as_ref().expect("Expected port to be specified.");
+        let ip = args.serve_ip.as_deref().unwrap_or("0.0.0.0");
+        let listener = std::net::TcpListener::bind(format!("{ip}:{port}"))?;
+        listener.set_nonblocking(true)?;
+        info!("Listening on http://{ip}:{port} while the model loads.");
+        Some(listener)
+    };
+    let loading_server = match &listener {
+        Some(listener) => {
+            let (loaded_tx, loaded_rx) = tokio::sync::oneshot::channel::<()>();
+            let app = get_loading_router(loader.get_id(), loader.get_kind().to_string());
+            let listener = tokio::net::TcpListener::from_std(listener.try_clone()?)?;
+            let handle = tokio::spawn(async move {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(async {
+                        let _ = loaded_rx.await;
+                    })
+                    .await
+            });
+            Some((loaded_tx, handle))
+        }
+        None => None,
+    };
+
+    let pipeline = tokio::task::block_in_place(|| {
+        loader.load_model_from_hf(
+            None,
+            args.token_source,
+            None,
+            &device,
+            false,
+            args.num_device_layers
+                .map(DeviceMapMetadata::from_num_device_layers)
+                .unwrap_or(DeviceMapMetadata::dummy()),
+            args.in_situ_quant,
+        )
+    })?;
     info!("Model loaded.");
 
-    let mistralrs = MistralRsBuilder::new(
+    let mut builder = MistralRsBuilder::new(
         pipeline,
         SchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
     )
@@ -288,25 +668,59 @@ async fn main() -> Result<()> {
     .with_truncate_sequence(args.truncate_sequence)
     .with_no_kv_cache(args.no_kv_cache)
     .with_prefix_cache_n(args.prefix_cache_n)
-    .build();
+    .with_prefix_cache_host_bytes(args.prefix_cache_host_bytes)
+    .with_prefix_cache_eviction(args.prefix_cache_eviction)
+    .with_prefix_cache_block_size(args.prefix_cache_block_size)
+    .with_session_ttl(Duration::from_secs(args.session_ttl));
+    if let Some(bytes) = args.prefix_cache_device_bytes {
+        builder = builder.with_prefix_cache_device_bytes(bytes);
+    }
+    if let Some(dir) = args.prefix_cache_disk_dir {
+        builder = builder.with_prefix_cache_disk_dir(dir);
+    }
+    if let Some(bytes) = args.prefix_cache_disk_bytes {
+        builder = builder.with_prefix_cache_disk_bytes(bytes);
+    }
+    if let Some(bytes) = args.session_device_bytes {
+        builder = builder.with_session_device_bytes(bytes);
+    }
+    if let Some(kv_cache_quant) = args.kv_cache_quant {
+        builder = builder.with_kv_cache_quant(kv_cache_quant);
+    }
+    if args.context_shift {
+        builder = builder.with_context_shift(ContextShift {
+            sink_tokens: args.context_shift_sinks,
+            window: args.context_shift_window,
+        });
+    }
+    if args.fair_queuing || args.tenant_weights.is_some() {
+        builder = builder.with_fair_queuing(args.tenant_weights.unwrap_or_default());
+    }
+    let mistralrs = builder.build();
 
     if args.interactive_mode {
         interactive_mode(mistralrs).await;
         return Ok(());
     }
 
-    let port = args.port.expect("Expected port to be specified.");
+    // Stop serving the loading routes and hand the listener over to the full router.
+    if let Some((loaded_tx, handle)) = loading_server {
+        let _ = loaded_tx.send(());
+        handle.await??;
+    }
+    let listener = tokio::net::TcpListener::from_std(
+        listener.expect("Listener is bound when not in interactive mode."),
+    )?;
 
-    let app = get_router(mistralrs);
+    let app = get_router(mistralrs.clone(), Duration::from_secs(args.max_step_secs));
 
-    let ip = if let Some(ref ip) = args.serve_ip {
-        ip.to_string()
-    } else {
-        "0.0.0.0".to_string()
-    };
-    let listener = tokio::net::TcpListener::bind(format!("{ip}:{}", port)).await?;
-    info!("Serving on http://{ip}:{}.", port);
-    axum::serve(listener, app).await?;
+    info!("Serving on http://{}.", listener.local_addr()?);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(
+            mistralrs,
+            Duration::from_secs(args.shutdown_timeout),
+        ))
+        .await?;
 
     Ok(())
 }
@@ -1,4 +1,5 @@
 use either::Either;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Deref};
 use utoipa::ToSchema;
@@ -42,6 +43,62 @@ pub enum StopTokens {
     Single(String),
 }
 
+/// The LoRA adapters of a request, either a list of names or a map of names to weights.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum Adapters {
+    Names(Vec<String>),
+    Weighted(HashMap<String, f64>),
+}
+
+impl Adapters {
+    /// Each adapter and its weight. Adapters given by name have a weight of 1.
+    pub fn into_weighted(self) -> IndexMap<String, f64> {
+        match self {
+            Self::Names(names) => names.into_iter().map(|name| (name, 1.0)).collect(),
+            Self::Weighted(weights) => weights.into_iter().collect(),
+        }
+    }
+}
+
+/// Which X-LoRA scalings to return: those of each generated token, or only their mean.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum XLoraScalingsOutput {
+    PerToken,
+    Mean,
+}
+
+impl From<XLoraScalingsOutput> for mistralrs_core::XLoraScalingsOutput {
+    fn from(output: XLoraScalingsOutput) -> Self {
+        match output {
+            XLoraScalingsOutput::PerToken => Self::PerToken,
+            XLoraScalingsOutput::Mean => Self::Mean,
+        }
+    }
+}
+
+/// How a chat longer than the maximum sequence length is truncated: by dropping tokens from the start of the prompt,
+/// the oldest turns, or the oldest turns after the first one. System messages and the last message are always kept
+/// when dropping turns.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncationStrategy {
+    Tokens,
+    Oldest,
+    Middle,
+}
+
+impl From<TruncationStrategy> for mistralrs_core::TruncationStrategy {
+    fn from(strategy: TruncationStrategy) -> Self {
+        match strategy {
+            TruncationStrategy::Tokens => Self::Tokens,
+            TruncationStrategy::Oldest => Self::Oldest,
+            TruncationStrategy::Middle => Self::Middle,
+        }
+    }
+}
+
 fn default_false() -> bool {
     false
 }
@@ -98,8 +155,28 @@ pub struct ChatCompletionRequest {
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
-    #[schema(example = json!(Option::None::<Vec<String>>))]
-    pub adapters: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<Adapters>))]
+    pub adapters: Option<Adapters>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    /// Timeout for the request in seconds. Partial output is returned if it expires while generating.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
+    /// Return the adapter scalings of an X-LoRA model in the response.
+    #[schema(example = json!(Option::None::<XLoraScalingsOutput>))]
+    pub return_xlora_scalings: Option<XLoraScalingsOutput>,
+    /// Fixed X-LoRA scalings as `[layer][adapter]`, used instead of the classifier. A single row is used for every layer.
+    #[schema(example = json!(Option::None::<Vec<Vec<f64>>>))]
+    pub xlora_scalings: Option<Vec<Vec<f64>>>,
+    /// Continue from the KV cache pinned by the previous request of this session, and pin this request's KV cache.
+    #[schema(example = json!(Option::None::<String>))]
+    pub session_id: Option<String>,
+    /// Truncate the chat if it is longer than the maximum sequence length, leaving room for `max_tokens`. A warning
+    /// is returned in the response.
+    #[schema(example = json!(Option::None::<TruncationStrategy>))]
+    pub truncation_strategy: Option<TruncationStrategy>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -116,6 +193,20 @@ pub struct ModelObjects {
     pub data: Vec<ModelObject>,
 }
 
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AdapterObject {
+    pub id: String,
+    pub object: &'static str,
+    /// Whether the adapter is in the active adapter set.
+    pub active: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AdapterObjects {
+    pub object: &'static str,
+    pub data: Vec<AdapterObject>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct CompletionRequest {
     #[schema(example = "mistral")]
@@ -154,14 +245,28 @@ pub struct CompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
-    #[serde(rename = "user")]
-    pub _user: Option<String>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
-    #[schema(example = json!(Option::None::<Vec<String>>))]
-    pub adapters: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<Adapters>))]
+    pub adapters: Option<Adapters>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Timeout for the request in seconds. Partial output is returned if it expires while generating.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
+    /// Return the adapter scalings of an X-LoRA model in the response.
+    #[schema(example = json!(Option::None::<XLoraScalingsOutput>))]
+    pub return_xlora_scalings: Option<XLoraScalingsOutput>,
+    /// Fixed X-LoRA scalings as `[layer][adapter]`, used instead of the classifier. A single row is used for every layer.
+    #[schema(example = json!(Option::None::<Vec<Vec<f64>>>))]
+    pub xlora_scalings: Option<Vec<Vec<f64>>>,
+    /// Continue from the KV cache pinned by the previous request of this session, and pin this request's KV cache.
+    #[schema(example = json!(Option::None::<String>))]
+    pub session_id: Option<String>,
 }
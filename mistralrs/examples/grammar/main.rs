@@ -51,6 +51,13 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::Regex("(- [^\n]*\n)+(- [^\n]*)(\n\n)?".to_string()), // Bullet list regex
         suffix: None,
         adapters: None,
+        priority: None,
+        user: None,
+        timeout: None,
+        return_xlora_scalings: None,
+        xlora_scalings: None,
+        session_id: None,
+        truncation_strategy: None,
     });
     mistralrs.get_sender().blocking_send(request)?;
 
@@ -58,6 +58,13 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        priority: None,
+        user: None,
+        timeout: None,
+        return_xlora_scalings: None,
+        xlora_scalings: None,
+        session_id: None,
+        truncation_strategy: None,
     });
 
     // Example: Make adapter_3 the active adapter
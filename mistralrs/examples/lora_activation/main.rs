@@ -1,3 +1,4 @@
+use indexmap::IndexMap;
 use std::{fs::File, sync::Arc};
 use tokio::sync::mpsc::channel;
 
@@ -57,7 +58,14 @@ fn main() -> anyhow::Result<()> {
         id: 0,
         constraint: Constraint::None,
         suffix: None,
-        adapters: Some(vec!["adapter_2".to_string()]),
+        adapters: Some(IndexMap::from([("adapter_2".to_string(), 1.0)])),
+        priority: None,
+        user: None,
+        timeout: None,
+        return_xlora_scalings: None,
+        xlora_scalings: None,
+        session_id: None,
+        truncation_strategy: None,
     });
 
     mistralrs.get_sender().blocking_send(request)?;
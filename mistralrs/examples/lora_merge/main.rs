@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use mistralrs::{merge_weighted_adapters, TokenSource};
+
+fn main() -> anyhow::Result<()> {
+    // Bake 0.7 of one adapter and 0.3 of another into the base model. Each adapter is a local
+    // directory or a Hugging Face model id with `adapter_config.json` and the adapter weights.
+    merge_weighted_adapters(
+        "HuggingFaceH4/zephyr-7b-beta",
+        &[
+            ("my-adapter-1".to_string(), 0.7),
+            ("my-adapter-2".to_string(), 0.3),
+        ],
+        &TokenSource::CacheToken,
+        Path::new("zephyr-7b-beta-merged"),
+    )?;
+    // The merged model can be loaded like any other plain model
+    println!("Wrote the merged model to zephyr-7b-beta-merged");
+    Ok(())
+}
@@ -4,7 +4,7 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, Device, DeviceMapMetadata, MistralRs, MistralRsBuilder, NormalLoaderBuilder,
     NormalLoaderType, NormalRequest, NormalSpecificConfig, Request, RequestMessage, Response,
-    SamplingParams, SchedulerMethod, TokenSource,
+    SamplingParams, SchedulerMethod, TokenSource, XLoraScalingsOutput,
 };
 
 fn setup() -> anyhow::Result<Arc<MistralRs>> {
@@ -60,12 +60,25 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        priority: None,
+        user: None,
+        timeout: None,
+        return_xlora_scalings: Some(XLoraScalingsOutput::Mean),
+        xlora_scalings: None,
+        session_id: None,
+        truncation_strategy: None,
     });
     mistralrs.get_sender().blocking_send(request)?;
 
     let response = rx.blocking_recv().unwrap();
     match response {
-        Response::CompletionDone(c) => println!("Text: {}", c.choices[0].text),
+        Response::CompletionDone(c) => {
+            println!("Text: {}", c.choices[0].text);
+            // The mean scalings of each layer, as `[layer][adapter]`.
+            if let Some(scalings) = &c.choices[0].xlora_scalings {
+                println!("Scalings of the first layer: {:?}", scalings.mean[0]);
+            }
+        }
         _ => unreachable!(),
     }
     Ok(())